@@ -0,0 +1,131 @@
+use std::time::{Duration, Instant};
+
+use amfs::{operations::mkfs_single, Disk, DiskFile, DiskMem, FSHandle, BLOCK_SIZE, META_OBJECTS};
+use amos_std::AMResult;
+
+/// Object the data workloads write to
+const DATA_OBJECT: u64 = META_OBJECTS;
+/// Bytes written per operation by the sequential workload
+const SEQ_CHUNK: usize = 16 * BLOCK_SIZE;
+
+/// Timings of one workload
+struct Timings {
+    name:  &'static str,
+    bytes: u64,
+    ops:   Vec<Duration>,
+    total: Duration,
+}
+
+impl Timings {
+    fn new(name: &'static str) -> Self {
+        Timings {
+            name,
+            bytes: 0,
+            ops: Vec::new(),
+            total: Duration::ZERO,
+        }
+    }
+
+    /// Runs and times one operation
+    fn time(&mut self, bytes: usize, op: impl FnOnce() -> AMResult<()>) -> AMResult<()> {
+        let start = Instant::now();
+        op()?;
+        let elapsed = start.elapsed();
+        self.ops.push(elapsed);
+        self.total += elapsed;
+        self.bytes += bytes as u64;
+        Ok(())
+    }
+
+    fn report(mut self) {
+        if self.ops.is_empty() {
+            return;
+        }
+        self.ops.sort();
+        let secs = self.total.as_secs_f64().max(f64::MIN_POSITIVE);
+        let rate = if self.bytes == 0 {
+            format!("{:>10.0} ops/s", self.ops.len() as f64 / secs)
+        } else {
+            format!("{:>10.1} MiB/s", self.bytes as f64 / secs / (1024.0 * 1024.0))
+        };
+        let pct = |p: usize| self.ops[(self.ops.len() - 1) * p / 100].as_micros();
+        println!(
+            "{:<14} {:>7} ops {} mean {:>6}us p50 {:>6}us p99 {:>6}us",
+            self.name,
+            self.ops.len(),
+            rate,
+            self.total.as_micros() / self.ops.len() as u128,
+            pct(50),
+            pct(99)
+        );
+    }
+}
+
+/// A small deterministic generator, so runs are comparable
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/// Formats a fresh disk and runs every workload against it
+pub fn run(file: Option<String>, blocks: usize, ops: usize, seed: u64) -> AMResult<()> {
+    let d: Disk = match &file {
+        Some(path) => {
+            let f = std::fs::File::create(path)?;
+            f.set_len((blocks * BLOCK_SIZE) as u64)?;
+            DiskFile::open(path)?
+        }
+        None => DiskMem::open(blocks),
+    };
+    mkfs_single(d.clone())?;
+    let fs = FSHandle::open(&[d])?;
+    let mut rng = seed.max(1);
+
+    // Fill a quarter of the disk, leaving room for copy-on-write
+    let data_len = blocks / 4 * BLOCK_SIZE;
+    fs.create_object(DATA_OBJECT, 0)?;
+    let chunk = vec![0xA5; SEQ_CHUNK];
+    let mut t = Timings::new("seq-write");
+    for pos in (0..data_len).step_by(SEQ_CHUNK) {
+        let len = SEQ_CHUNK.min(data_len - pos);
+        t.time(len, || fs.write_object(DATA_OBJECT, pos as u64, &chunk[..len]).map(|_| ()))?;
+    }
+    t.time(0, || fs.commit())?;
+    t.report();
+
+    let n_blocks = (data_len / BLOCK_SIZE) as u64;
+    let block = vec![0x5A; BLOCK_SIZE];
+    let mut t = Timings::new("rand-4k-write");
+    for _ in 0..ops {
+        let pos = xorshift(&mut rng) % n_blocks * BLOCK_SIZE as u64;
+        t.time(BLOCK_SIZE, || fs.write_object(DATA_OBJECT, pos, &block).map(|_| ()))?;
+    }
+    t.time(0, || fs.commit())?;
+    t.report();
+
+    let mut buf = vec![0; BLOCK_SIZE];
+    let mut t = Timings::new("rand-4k-read");
+    for _ in 0..ops {
+        let pos = xorshift(&mut rng) % n_blocks * BLOCK_SIZE as u64;
+        t.time(BLOCK_SIZE, || fs.read_object(DATA_OBJECT, pos, &mut buf).map(|_| ()))?;
+    }
+    t.report();
+
+    let mut t = Timings::new("create-delete");
+    let first = DATA_OBJECT + 1;
+    for id in first..first + ops as u64 {
+        t.time(0, || {
+            fs.create_object(id, 0)?;
+            fs.write_object(id, 0, b"benchmark")?;
+            Ok(())
+        })?;
+    }
+    for id in first..first + ops as u64 {
+        t.time(0, || fs.delete_object(id))?;
+    }
+    t.time(0, || fs.commit())?;
+    t.report();
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use std::io::{BufRead, Write};
+
+use amfs::{
+    operations::{classify, dump, dump_object, BlockType, DumpOptions},
+    u8_slice_as_any, AMPointerGlobal, Allocator, Disk, DiskGroup, Geometry, BLOCK_SIZE,
+};
+
+const HELP: &str = "\
+sb                  Dump the superblocks
+root N              Dump the metadata reachable from root slot N
+object N [data]     Dump object N's fragments, and optionally its contents
+block N             Dump block N, decoded if it's in use
+validate N OFFSET   Check the global pointer at byte OFFSET of block N
+help                Show this message
+quit                Leave the shell";
+
+/// Reads commands from stdin until it closes or the user quits
+pub fn shell(d: Disk) {
+    let color = colored::control::SHOULD_COLORIZE.should_colorize();
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("amfs> ");
+        std::io::stdout().flush().unwrap();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let res = match words.as_slice() {
+            [] => Ok(()),
+            ["quit"] | ["exit"] => break,
+            ["help"] => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            ["sb"] => show(&d, color, |o| o.only.insert("superblock".to_owned())),
+            ["root", n] => match n.parse() {
+                Ok(n) => show(&d, color, |o| {
+                    o.root = Some(n);
+                    o.only = [
+                        "fsgroup",
+                        "alloc_list",
+                        "alloc",
+                        "free_queue",
+                        "journal",
+                        "objects",
+                        "error",
+                    ]
+                    .iter()
+                        .map(|t| t.to_string())
+                        .collect();
+                }),
+                Err(_) => Err(format!("Invalid root slot: {}", n)),
+            },
+            ["object", n, rest @ ..] => match n.parse() {
+                Ok(id) => {
+                    let opts = DumpOptions {
+                        color,
+                        object_data: rest == ["data"],
+                        ..DumpOptions::default()
+                    };
+                    dump_object(d.clone(), id, &mut std::io::stdout(), &opts)
+                        .map_err(|e| format!("{:?}", e))
+                }
+                Err(_) => Err(format!("Invalid object ID: {}", n)),
+            },
+            ["block", n] => match n.parse() {
+                Ok(n) => block(&d, color, n),
+                Err(_) => Err(format!("Invalid block: {}", n)),
+            },
+            ["validate", n, offset] => match (n.parse(), offset.parse()) {
+                (Ok(n), Ok(offset)) => validate(&d, n, offset),
+                _ => Err(format!("Invalid location: {} {}", n, offset)),
+            },
+            _ => Err(format!("Unknown command: {}. Try help.", line.trim())),
+        };
+        if let Err(e) = res {
+            println!("{}", e);
+        }
+    }
+}
+
+/// Dumps the blocks selected by some options, leaving out the image header
+fn show<T>(d: &Disk, color: bool, select: impl FnOnce(&mut DumpOptions) -> T) -> Result<(), String> {
+    let mut opts = DumpOptions {
+        color,
+        ..DumpOptions::default()
+    };
+    select(&mut opts);
+    let mut out = Vec::new();
+    dump(d.clone(), &mut out, &opts).map_err(|e| format!("{:?}", e))?;
+    let text = String::from_utf8_lossy(&out);
+    for line in text.lines().skip(2) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Dumps a block, as raw bytes if no structure uses it
+fn block(d: &Disk, color: bool, n: u64) -> Result<(), String> {
+    let types = classify(d).map_err(|e| format!("{:?}", e))?;
+    match types.get(n as usize) {
+        None => Err(format!("Block {} is past the end of the image", n)),
+        Some(BlockType::Unused) => {
+            let mut buf = [0; BLOCK_SIZE];
+            d.clone()
+                .read_at(n, &mut buf)
+                .map_err(|e| format!("{:?}", e))?;
+            println!("Unused:");
+            for (i, row) in buf.chunks(16).enumerate() {
+                let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+                println!("\t{:06x} : {}", i * 16, hex.join(" "));
+            }
+            Ok(())
+        }
+        Some(_) => show(d, color, |o| o.range = Some((n, n + 1))),
+    }
+}
+
+/// Reads a global pointer out of a block, and checks it against the data it points to
+fn validate(d: &Disk, n: u64, offset: usize) -> Result<(), String> {
+    if offset + 16 > BLOCK_SIZE {
+        return Err(format!("Offset {} is past the end of the block", offset));
+    }
+    let mut buf = [0; BLOCK_SIZE];
+    d.clone()
+        .read_at(n, &mut buf)
+        .map_err(|e| format!("{:?}", e))?;
+    let p = unsafe { u8_slice_as_any::<AMPointerGlobal>(&buf[offset..offset + 16]) };
+    if p.is_null() {
+        println!("NULL");
+        return Ok(());
+    }
+    let mut dg = DiskGroup::single(Geometry::new(), d.clone(), Allocator::new(0));
+    let types = classify(d).map_err(|e| format!("{:?}", e))?;
+    if let Some(devid) = types.iter().find_map(|t| match t {
+        BlockType::Superblock(sb) => Some(sb.devid()),
+        _ => None,
+    }) {
+        dg.geo.device_ids = vec![devid];
+    }
+    let valid = p.validate(&[Some(dg)]).map_err(|e| format!("{:?}", e))?;
+    println!(
+        "{},{},{:08x} len {}: {}",
+        p.geo(),
+        p.dev(),
+        p.loc(),
+        p.length(),
+        if valid { "valid" } else { "invalid" }
+    );
+    Ok(())
+}
@@ -0,0 +1,391 @@
+#![cfg(not(tarpaulin_include))]
+#![allow(clippy::all)]
+#![allow(unknown_lints)]
+#![allow(require_stability_comment)]
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use amfs::{
+    operations::{
+        check, dump, dump_object, export_object, fsck_single_scan, import_file, mkfs, pack_tar,
+        scrub, unpack_tar, DumpFormat, DumpOptions, FsckOptions, MkfsOptions, ObjectTarget,
+    },
+    test::corrupt,
+    Disk, DiskFile, FSHandle, Retention,
+};
+use amos_std::AMResult;
+use clap::{Parser, Subcommand};
+
+mod bench;
+mod debug;
+
+/// Creates, inspects and repairs AMFS images
+#[derive(Parser)]
+#[clap(name = "amfs")]
+struct Cli {
+    /// Skip logging setup, and keep fsck quiet
+    #[clap(long, short, global = true)]
+    quiet:   bool,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Creates a new filesystem
+    Mkfs {
+        image:        String,
+        #[clap(long, default_value = "")]
+        label:        String,
+        /// Copy a host directory into the new filesystem
+        #[clap(long)]
+        populate:     Option<PathBuf>,
+        /// Number of root slots to cycle through
+        #[clap(long, default_value_t = 128)]
+        root_slots:   u8,
+        /// Zero the whole device first
+        #[clap(long)]
+        full_erase:   bool,
+        /// Discard the whole device first
+        #[clap(long)]
+        discard:      bool,
+        /// Forget old roots this many transactions behind the newest
+        #[clap(long)]
+        retain_txids: Option<u64>,
+        /// Forget old roots committed this many seconds ago
+        #[clap(long)]
+        retain_secs:  Option<u64>,
+    },
+    /// Checks a filesystem for errors
+    Fsck {
+        image:         String,
+        /// Repair what can be repaired
+        #[clap(long)]
+        fix:           bool,
+        #[clap(long)]
+        stop_on_error: bool,
+    },
+    /// Dumps a filesystem's structures
+    Dump {
+        image:  String,
+        #[clap(long)]
+        json:   bool,
+        /// Only verify checksums, printing a summary
+        #[clap(long)]
+        check:  bool,
+        /// Only dump these block types
+        #[clap(long, use_value_delimiter = true)]
+        only:   Vec<String>,
+        /// Only follow this root slot
+        #[clap(long)]
+        root:   Option<u8>,
+        /// Dump a single object instead
+        #[clap(long)]
+        object: Option<u64>,
+        /// Hexdump the object's contents
+        #[clap(long, requires = "object")]
+        data:   bool,
+    },
+    /// Opens an interactive shell for inspecting on-disk structures
+    Debug { image: String },
+    /// Times standard workloads on a fresh filesystem
+    Bench {
+        /// Run on a file instead of in memory. The file is overwritten.
+        #[clap(long)]
+        file:   Option<String>,
+        /// Size of the disk
+        #[clap(long, default_value_t = 16384)]
+        blocks: usize,
+        /// Operations per random and metadata workload
+        #[clap(long, default_value_t = 1000)]
+        ops:    usize,
+        #[clap(long, default_value_t = 1)]
+        seed:   u64,
+    },
+    /// Deliberately damages on-disk structures, to make fsck and recovery test cases
+    Corrupt {
+        image:  String,
+        #[clap(subcommand)]
+        action: CorruptAction,
+    },
+    /// Verifies the data of every object
+    Scrub { image: String },
+    /// Manages snapshots
+    Snapshot {
+        image:  String,
+        #[clap(subcommand)]
+        action: SnapshotAction,
+    },
+    /// Lists objects, with their sizes and fragment counts
+    Ls { image: String },
+    /// Shows a filesystem's identity
+    Info { image: String },
+    /// Copies between host files and objects, written as IMAGE:ID or IMAGE:/path/in/image
+    Cp { src: String, dst: String },
+    /// Packs the directory tree and loose objects into a tar archive
+    Tar { image: String, archive: PathBuf },
+    /// Adds the contents of a tar archive to a filesystem
+    Untar { image: String, archive: PathBuf },
+}
+
+#[derive(Subcommand)]
+enum CorruptAction {
+    /// Inverts a byte of a superblock
+    Superblock {
+        /// Which header location's superblock
+        which:  usize,
+        offset: usize,
+    },
+    /// Zeroes the FSGroup of a root slot, or of the latest root
+    Fsgroup { slot: Option<u8> },
+    /// Cuts the latest journal chain after its newest block
+    Journal,
+    /// Inverts a byte of any block
+    Block { block: u64, offset: usize },
+    /// Inverts random bytes of blocks in use, reproducibly for a seed
+    Random { seed: u64, count: usize },
+}
+
+#[derive(Subcommand)]
+enum SnapshotAction {
+    /// Snapshots the current state
+    Create,
+    /// Lists snapshots by transaction ID
+    List,
+    /// Deletes a snapshot
+    Delete { txid: u128 },
+}
+
+/// Splits an argument written as IMAGE:OBJECT, if IMAGE is an existing file
+fn image_target(arg: &str) -> Option<(String, ObjectTarget)> {
+    let (image, target) = arg.split_once(':')?;
+    if !Path::new(image).is_file() {
+        return None;
+    }
+    Some((image.to_owned(), ObjectTarget::parse(target)))
+}
+
+fn open_disk(image: &str) -> Disk {
+    DiskFile::open(image).unwrap_or_else(|e| fail(&format!("Can't open {}: {:?}", image, e)))
+}
+
+fn open_fs(image: &str) -> FSHandle {
+    FSHandle::open(&[open_disk(image)])
+        .unwrap_or_else(|e| fail(&format!("Can't mount {}: {:?}", image, e)))
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    std::process::exit(1);
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if !cli.quiet {
+        amfs::logging::init_console(amfs::logging::level_from_env(
+            amfs::logging::LevelFilter::Debug,
+        ))
+        .unwrap();
+    }
+
+    match cli.command {
+        Command::Mkfs {
+            image,
+            label,
+            populate,
+            root_slots,
+            full_erase,
+            discard,
+            retain_txids,
+            retain_secs,
+        } => {
+            let opts = MkfsOptions {
+                label,
+                populate,
+                root_slots,
+                full_erase,
+                discard,
+                retention: Retention {
+                    transactions: retain_txids,
+                    time:         retain_secs.map(Duration::from_secs),
+                },
+                ..MkfsOptions::default()
+            };
+            mkfs(open_disk(&image), &opts).unwrap_or_else(|e| fail(&format!("{:?}", e)));
+        }
+        Command::Fsck {
+            image,
+            fix,
+            stop_on_error,
+        } => {
+            let opts = FsckOptions {
+                fix,
+                stop_on_error,
+                verbose: !cli.quiet,
+                ..FsckOptions::default()
+            };
+            let report = fsck_single_scan(open_disk(&image), &opts, |_, _, _| {})
+                .unwrap_or_else(|e| fail(&format!("Check stopped: {:?}", e)));
+            let summary = &report.summary;
+            println!(
+                "{} errors ({} fixed), {} warnings, {} blocks scanned",
+                summary.errors, summary.fixed, summary.warnings, summary.blocks_scanned
+            );
+            if summary.errors != summary.fixed {
+                std::process::exit(4);
+            }
+        }
+        Command::Dump {
+            image,
+            json,
+            check: check_only,
+            only,
+            root,
+            object,
+            data,
+        } => {
+            let d = open_disk(&image);
+            if check_only {
+                let report = check(d).unwrap_or_else(|e| fail(&format!("{:?}", e)));
+                for (blk, problem) in &report.problems {
+                    eprintln!("Block {:08x}: {}", blk, problem);
+                }
+                println!(
+                    "Checked {} blocks: {} problems",
+                    report.blocks,
+                    report.problems.len()
+                );
+                if !report.problems.is_empty() {
+                    std::process::exit(1);
+                }
+                return;
+            }
+            let opts = DumpOptions {
+                format: if json {
+                    DumpFormat::Json
+                } else {
+                    DumpFormat::Text
+                },
+                color: !json && colored::control::SHOULD_COLORIZE.should_colorize(),
+                only: only.into_iter().collect(),
+                root,
+                object_data: data,
+                ..DumpOptions::default()
+            };
+            let stdout = std::io::stdout();
+            let res = match object {
+                Some(id) => dump_object(d, id, &mut stdout.lock(), &opts),
+                None => dump(d, &mut stdout.lock(), &opts),
+            };
+            res.unwrap_or_else(|e| fail(&format!("Dump failed: {:?}", e)));
+        }
+        Command::Debug { image } => debug::shell(open_disk(&image)),
+        Command::Bench {
+            file,
+            blocks,
+            ops,
+            seed,
+        } => bench::run(file, blocks, ops, seed).unwrap_or_else(|e| fail(&format!("{:?}", e))),
+        Command::Corrupt { image, action } => {
+            let d = open_disk(&image);
+            let res = match action {
+                CorruptAction::Superblock { which, offset } => {
+                    corrupt::flip_superblock_byte(&d, which, offset)
+                }
+                CorruptAction::Fsgroup { slot } => corrupt::zero_fsgroup(&d, slot),
+                CorruptAction::Journal => corrupt::truncate_journal(&d).map(|cut| {
+                    if !cut {
+                        println!("The journal has a single block, nothing to cut");
+                    }
+                }),
+                CorruptAction::Block { block, offset } => corrupt::flip_byte(&d, block, offset),
+                CorruptAction::Random { seed, count } => corrupt::corrupt_random(&d, seed, count)
+                    .map(|locs| {
+                        for (blk, offset) in locs {
+                            println!("Flipped block {:08x} offset {:03x}", blk, offset);
+                        }
+                    }),
+            };
+            res.unwrap_or_else(|e| fail(&format!("{:?}", e)));
+        }
+        Command::Scrub { image } => {
+            let report = scrub(&open_fs(&image)).unwrap_or_else(|e| fail(&format!("{:?}", e)));
+            for (id, p) in &report.damaged {
+                println!("Object {}: damaged fragment at {:08x}", id, p.loc());
+            }
+            println!(
+                "{} fragments read, {} damaged",
+                report.fragments,
+                report.damaged.len()
+            );
+            if !report.damaged.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Command::Snapshot { image, action } => {
+            let fs = open_fs(&image);
+            let res = match action {
+                SnapshotAction::Create => fs.snapshot_create().map(|txid| println!("{}", txid)),
+                SnapshotAction::List => fs.snapshot_list().map(|snaps| {
+                    for txid in snaps {
+                        println!("{}", txid);
+                    }
+                }),
+                SnapshotAction::Delete { txid } => fs.snapshot_delete(txid),
+            };
+            res.unwrap_or_else(|e| fail(&format!("{:?}", e)));
+        }
+        Command::Ls { image } => {
+            let objs = open_fs(&image)
+                .list_objects()
+                .unwrap_or_else(|e| fail(&format!("{:?}", e)));
+            for (id, size, frags) in objs {
+                println!("{:>8} {:>12} {:>4}", id, size, frags);
+            }
+        }
+        Command::Info { image } => {
+            let fs = open_fs(&image);
+            let info = (|| -> AMResult<()> {
+                println!("UUID:        {:032x}", fs.uuid()?);
+                println!("Label:       {}", fs.label()?);
+                println!("Created:     {}", fs.created()?);
+                println!("Last mount:  {}", fs.last_mount()?);
+                println!("Mount count: {}", fs.mount_count()?);
+                println!("Read-only:   {}", fs.is_read_only()?);
+                Ok(())
+            })();
+            info.unwrap_or_else(|e| fail(&format!("{:?}", e)));
+        }
+        Command::Cp { src, dst } => match (image_target(&src), image_target(&dst)) {
+            (Some((image, target)), None) => {
+                let fs = open_fs(&image);
+                let mut f = std::fs::File::create(&dst).unwrap();
+                export_object(&fs, &target, &mut f).unwrap_or_else(|e| fail(&format!("{:?}", e)));
+                f.flush().unwrap();
+            }
+            (None, Some((image, target))) => {
+                let fs = open_fs(&image);
+                import_file(&fs, &target, Path::new(&src))
+                    .unwrap_or_else(|e| fail(&format!("{:?}", e)));
+            }
+            _ => fail("Exactly one of the source and destination must be IMAGE:OBJECT"),
+        },
+        Command::Tar { image, archive } => {
+            let fs = open_fs(&image);
+            let mut f = std::io::BufWriter::new(std::fs::File::create(&archive).unwrap());
+            let n = pack_tar(&fs, &mut f).unwrap_or_else(|e| fail(&format!("{:?}", e)));
+            f.flush().unwrap();
+            println!("Packed {} entries", n);
+        }
+        Command::Untar { image, archive } => {
+            let fs = open_fs(&image);
+            let mut f = std::io::BufReader::new(std::fs::File::open(&archive).unwrap());
+            let n = unpack_tar(&fs, &mut f).unwrap_or_else(|e| fail(&format!("{:?}", e)));
+            println!("Unpacked {} entries", n);
+        }
+    }
+}
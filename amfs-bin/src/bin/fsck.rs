@@ -3,12 +3,98 @@
 #![allow(unknown_lints)]
 #![allow(require_stability_comment)]
 
-use amfs::{operations::fsck_single_scan, DiskFile};
+use amfs::{
+    operations::{
+        fsck_single_rebuild, fsck_single_scan, recover_orphans, FSCKErrorLoc, FSCKFinding,
+        FSCKSummary, FsckOptions,
+    },
+    DiskFile, FSHandle,
+};
+
+fn to_json(f: &FSCKFinding) -> String {
+    let location = match &f.location {
+        FSCKErrorLoc::Local(p) => format!("{{\"block\":{}}}", p.loc()),
+        FSCKErrorLoc::Global(p) => format!(
+            "{{\"dev\":{},\"geo\":{},\"block\":{}}}",
+            p.dev(),
+            p.geo(),
+            p.loc()
+        ),
+    };
+    format!(
+        "{{\"severity\":\"{:?}\",\"kind\":\"{:?}\",\"location\":{},\"fixed\":{}}}",
+        f.severity, f.kind, location, f.fixed
+    )
+}
+
+/// Maps a summary to the conventional fsck exit codes
+fn exit_code(summary: &FSCKSummary) -> i32 {
+    if summary.errors == 0 {
+        0
+    } else if summary.fixed == summary.errors {
+        1
+    } else {
+        4
+    }
+}
 
 fn main() {
-    amfs::test::logging::init_log();
+    amfs::logging::init_console(amfs::logging::level_from_env(
+        amfs::logging::LevelFilter::Debug,
+    ))
+    .unwrap();
 
-    let path = std::env::args().nth(1).unwrap();
+    let rebuild = std::env::args().any(|a| a == "--rebuild-allocators");
+    let json = std::env::args().any(|a| a == "--json");
+    let recover = std::env::args().any(|a| a == "--recover-orphans");
+    let opts = FsckOptions {
+        stop_on_error: std::env::args().any(|a| a == "--stop-on-error"),
+        verbose: !std::env::args().any(|a| a == "--quiet"),
+        fix: std::env::args().any(|a| a == "--fix"),
+        ..FsckOptions::default()
+    };
+    let path = std::env::args()
+        .skip(1)
+        .find(|a| !a.starts_with("--"))
+        .unwrap();
     let d = DiskFile::open(&path).unwrap();
-    fsck_single_scan(d).unwrap();
+    if rebuild {
+        fsck_single_rebuild(d.clone()).unwrap();
+    }
+    if recover {
+        let fs = FSHandle::open(&[d.clone()]).unwrap();
+        for id in recover_orphans(&fs).unwrap() {
+            eprintln!("Recovered orphaned extent as object {}", id);
+        }
+    }
+    let res = fsck_single_scan(d, &opts, |phase, done, total| {
+        eprint!(
+            "\r{:<12} {:>3}%",
+            format!("{:?}", phase),
+            done * 100 / total.max(1)
+        );
+        if done == total {
+            eprintln!();
+        }
+    });
+    let report = match res {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Check stopped: {:?}", e);
+            std::process::exit(4);
+        }
+    };
+    if json {
+        let entries: Vec<String> = report.findings.iter().map(to_json).collect();
+        println!("[{}]", entries.join(","));
+    }
+    let summary = &report.summary;
+    eprintln!(
+        "{} errors ({} fixed), {} warnings, {} blocks scanned",
+        summary.errors, summary.fixed, summary.warnings, summary.blocks_scanned
+    );
+    for (kind, count) in &summary.kinds {
+        eprintln!("\t{:?}: {}", kind, count);
+    }
+    std::process::exit(exit_code(summary));
 }
@@ -0,0 +1,45 @@
+#![cfg(not(tarpaulin_include))]
+#![allow(clippy::all)]
+#![allow(unknown_lints)]
+#![allow(require_stability_comment)]
+
+use amfs::{
+    operations::{diff_images, ObjectChange},
+    DiskFile,
+};
+
+fn main() {
+    let a = std::env::args()
+        .nth(1)
+        .expect("Usage: imgdiff <old image> <new image>");
+    let b = std::env::args()
+        .nth(2)
+        .expect("Usage: imgdiff <old image> <new image>");
+    let diff = diff_images(&DiskFile::open(&a).unwrap(), &DiskFile::open(&b).unwrap()).unwrap();
+
+    let loc = |l: Option<u64>| l.map_or_else(|| "NULL".to_string(), |l| format!("{:08x}", l));
+    println!("Latest root: {} -> {}", diff.latest.0, diff.latest.1);
+    for (slot, old, new) in diff.roots {
+        println!("Root {}: {} -> {}", slot, loc(old), loc(new));
+    }
+    for (blk, old, new) in diff.blocks {
+        if old == new {
+            println!("Block {:08x}: {} changed", blk, old);
+        } else {
+            println!("Block {:08x}: {} -> {}", blk, old, new);
+        }
+    }
+    for change in diff.objects {
+        match change {
+            ObjectChange::Created(id) => println!("Object {}: created", id),
+            ObjectChange::Deleted(id) => println!("Object {}: deleted", id),
+            ObjectChange::Modified(id, ranges) => {
+                print!("Object {}: modified", id);
+                for (start, end) in ranges {
+                    print!(" {:x}..{:x}", start, end);
+                }
+                println!();
+            }
+        }
+    }
+}
@@ -1,9 +1,21 @@
 #![allow(unknown_lints)]
 #![allow(require_stability_comment)]
 #![cfg(not(tarpaulin_include))]
-use amfs::operations::mkfs_single;
+use amfs::operations::{mkfs, MkfsOptions};
 
 fn main() {
-    let d = amfs::DiskFile::open("test.img").unwrap();
-    mkfs_single(d).unwrap();
+    let mut path = "test.img".to_string();
+    let mut opts = MkfsOptions::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--populate" => opts.populate = Some(args.next().unwrap().into()),
+            "--label" => opts.label = args.next().unwrap(),
+            "--full-erase" => opts.full_erase = true,
+            "--discard" => opts.discard = true,
+            _ => path = arg,
+        }
+    }
+    let d = amfs::DiskFile::open(&path).unwrap();
+    mkfs(d, &opts).unwrap();
 }
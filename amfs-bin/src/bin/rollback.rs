@@ -0,0 +1,49 @@
+#![cfg(not(tarpaulin_include))]
+#![allow(clippy::all)]
+#![allow(unknown_lints)]
+#![allow(require_stability_comment)]
+
+use amfs::{
+    operations::{list_roots, rollback},
+    DiskFile,
+};
+
+fn main() {
+    amfs::logging::init_console(amfs::logging::level_from_env(
+        amfs::logging::LevelFilter::Debug,
+    ))
+    .unwrap();
+
+    let path = std::env::args().nth(1).unwrap();
+    let d = DiskFile::open(&path).unwrap();
+    if let Some(slot) = std::env::args().nth(2) {
+        let slot: u8 = slot.parse().unwrap();
+        if let Err(e) = rollback(d, slot) {
+            eprintln!("Rollback failed: {:?}", e);
+            std::process::exit(1);
+        }
+        println!("Slot {} is now the latest root", slot);
+        return;
+    }
+    for (loc, slots) in list_roots(&d).unwrap() {
+        println!("Superblock at {}:", loc.loc());
+        let slots = match slots {
+            Some(slots) => slots,
+            None => {
+                println!("\tInvalid");
+                continue;
+            }
+        };
+        for s in slots {
+            println!(
+                "\t{:>3} {:>8} {:<40} {}{}",
+                s.slot,
+                s.pointer.loc(),
+                s.txid
+                    .map_or_else(|| "invalid".to_string(), |t| t.to_string()),
+                if s.latest { "latest " } else { "" },
+                if s.pinned { "pinned" } else { "" },
+            );
+        }
+    }
+}
@@ -32,19 +32,16 @@ pub fn generate_image(input: TokenStream) -> TokenStream {
 
         #generate_fn(&file);
 
-        let mut file = OpenOptions::new().read(true).open(&filename).unwrap();
-
-        let expected = amfs_tests::imagegen::get_checksums();
-
-        use data_encoding::HEXUPPER;
-        use sha2::{Sha256, Digest};
-        let mut sha256 = Sha256::new();
-        std::io::copy(&mut file, &mut sha256).unwrap();
-        let digest = sha256.finalize();
+        let digest = amfs_tests::imagegen::hash_image(&filename);
 
         test_dump(filename,format!("{:04}.dump", #num_to_gen));
 
-        assert_eq!(HEXUPPER.encode(digest.as_ref()),amfs_tests::imagegen::get_checksums()[#num_to_gen]);
+        assert_eq!(
+            digest,
+            amfs_tests::imagegen::get_checksums()[#num_to_gen],
+            "image {} changed; if that's intended, run `cargo run --bin regen_golden`",
+            #num_to_gen
+        );
 
 
 
@@ -88,18 +85,73 @@ pub fn assert_or_err(input: TokenStream) -> TokenStream {
     output.into()
 }
 
+/// Marks a test that sets up logging first.
+/// Given backends, as in `#[test_fs(mem, file, blocks = 1000)]`, the test takes an empty `Disk`
+/// for each of its parameters and is run once per backend, as tests named after the backend inside
+/// a module named after the test. Each backend name is a constructor in `amfs::test::backend`.
 #[cfg(not(tarpaulin_include))]
 #[proc_macro_attribute]
-pub fn test_fs(_: TokenStream, item: TokenStream) -> TokenStream {
+pub fn test_fs(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = syn::parse_macro_input!(attr as syn::AttributeArgs);
     let input_fn = syn::parse_macro_input!(item as syn::ItemFn);
     //panic!("{:#?}",input_fn);
-    let input_sig = input_fn.sig.clone();
-    let input_blk = input_fn.block.stmts.clone();
+    if args.is_empty() {
+        let input_sig = input_fn.sig.clone();
+        let input_blk = input_fn.block.stmts.clone();
+        let output = quote! {
+            #[test]
+            #input_sig {
+                amfs::test::logging::init_log();
+                #(#input_blk)*
+            }
+        };
+        return output.into();
+    }
+
+    let mut backends = Vec::new();
+    let mut blocks = 1000usize;
+    for arg in args {
+        match arg {
+            syn::NestedMeta::Meta(syn::Meta::Path(p)) => backends.push(
+                p.get_ident()
+                    .expect("backends are named by a single identifier")
+                    .clone(),
+            ),
+            syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) if nv.path.is_ident("blocks") => {
+                match nv.lit {
+                    syn::Lit::Int(n) => blocks = n.base10_parse().unwrap(),
+                    _ => panic!("blocks must be an integer"),
+                }
+            }
+            _ => panic!("expected a backend name or blocks = N"),
+        }
+    }
+    let name = input_fn.sig.ident.clone();
+    let count = input_fn.sig.inputs.len();
+    let args: Vec<_> = (0..count)
+        .map(|i| quote! { (*disks[#i]).clone() })
+        .collect();
+    let tests = backends.iter().map(|backend| {
+        quote! {
+            #[test]
+            fn #backend() {
+                amfs::test::logging::init_log();
+                let disks: Vec<_> = (0..#count)
+                    .map(|_| amfs::test::backend::#backend(#blocks))
+                    .collect();
+                #name(#(#args),*);
+            }
+        }
+    });
     let output = quote! {
-        #[test]
-        #input_sig {
-            amfs::test::logging::init_log();
-            #(#input_blk)*
+        #[cfg(test)]
+        mod #name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #input_fn
+
+            #(#tests)*
         }
     };
     output.into()
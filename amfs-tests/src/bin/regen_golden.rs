@@ -0,0 +1,12 @@
+#![cfg(not(tarpaulin_include))]
+
+//! Rewrites hashes.txt and dump_expected/ from the current image generators.
+//! Run as `cargo run --bin regen_golden` from the amfs-tests directory.
+
+fn main() {
+    amfs_tests::imagegen::regenerate();
+    println!(
+        "Regenerated {} images; review the changes to hashes.txt and dump_expected/",
+        amfs_tests::imagegen::GENERATORS.len()
+    );
+}
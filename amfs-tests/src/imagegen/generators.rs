@@ -66,8 +66,8 @@ pub fn generate_0004(f: &File) {
     let locs = d.get_header_locs().unwrap();
     for i in locs {
         let mut geo = Geometry::new();
-        geo.device_ids[0] = 0x1;
-        d.write_at(2, &geo).unwrap();
+        geo.device_ids = vec![0x1];
+        d.write_at(2, &geo.to_bytes().unwrap()).unwrap();
 
         let mut ptr = AMPointerLocal::new(2);
         ptr.update(d.clone()).unwrap();
@@ -92,8 +92,8 @@ pub fn generate_0005(f: &File) {
     let locs = d.get_header_locs().unwrap();
     for i in locs {
         let mut geo = Geometry::new();
-        geo.device_ids[0] = 0x0807060504030201;
-        d.write_at(2, &geo).unwrap();
+        geo.device_ids = vec![0x0807060504030201];
+        d.write_at(2, &geo.to_bytes().unwrap()).unwrap();
 
         let mut ptr = AMPointerLocal::new(2);
         ptr.update(d.clone()).unwrap();
@@ -118,7 +118,7 @@ pub fn generate_0006(f: &File) {
     let locs = d.get_header_locs().unwrap();
 
     let mut geo = Geometry::new();
-    geo.device_ids[0] = 0x0807060504030201;
+    geo.device_ids = vec![0x0807060504030201];
     let dg = DiskGroup::from_geo(geo, &[0x0807060504030201], &[d.clone()]).unwrap();
 
     for i in locs {
@@ -148,7 +148,7 @@ pub fn generate_0007(f: &File) {
     let locs = d.get_header_locs().unwrap();
 
     let mut geo = Geometry::new();
-    geo.device_ids[0] = 0x0807060504030201;
+    geo.device_ids = vec![0x0807060504030201];
     let dg = DiskGroup::from_geo(geo, &[0x0807060504030201], &[d.clone()]).unwrap();
 
     let mut group = AMPointerGlobal::new(3, 1, 0, 0);
@@ -185,7 +185,7 @@ pub fn generate_0008(f: &File) {
     let locs = d.get_header_locs().unwrap();
 
     let mut geo = Geometry::new();
-    geo.device_ids[0] = 0x0807060504030201;
+    geo.device_ids = vec![0x0807060504030201];
     let dg = DiskGroup::from_geo(geo, &[0x0807060504030201], &[d.clone()]).unwrap();
 
     let mut group = AMPointerGlobal::new(3, 1, 0, 0);
@@ -233,7 +233,7 @@ pub fn generate_0009(f: &File) {
     let locs = d.get_header_locs().unwrap();
 
     let mut geo = Geometry::new();
-    geo.device_ids[0] = 0x0807060504030201;
+    geo.device_ids = vec![0x0807060504030201];
     let dg = DiskGroup::from_geo(geo, &[0x0807060504030201], &[d.clone()]).unwrap();
 
     let mut group = AMPointerGlobal::new(3, 1, 0, 0);
@@ -289,7 +289,7 @@ pub fn generate_0010(f: &File) {
     let locs = d.get_header_locs().unwrap();
 
     let mut geo = Geometry::new();
-    geo.device_ids[0] = 0x0807060504030201;
+    geo.device_ids = vec![0x0807060504030201];
     let dg = DiskGroup::from_geo(geo, &[0x0807060504030201], &[d.clone()]).unwrap();
 
     let mut group = AMPointerGlobal::new(3, 1, 0, 0);
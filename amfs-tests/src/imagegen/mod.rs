@@ -1,7 +1,25 @@
 pub mod generators;
 mod utils;
 
-use std::sync::Once;
+use std::{fs::File, sync::Once};
+
+use data_encoding::HEXUPPER;
+use sha2::{Digest, Sha256};
+
+/// Every generator, indexed by image number
+pub const GENERATORS: &[fn(&File)] = &[
+    generators::generate_0000,
+    generators::generate_0001,
+    generators::generate_0002,
+    generators::generate_0003,
+    generators::generate_0004,
+    generators::generate_0005,
+    generators::generate_0006,
+    generators::generate_0007,
+    generators::generate_0008,
+    generators::generate_0009,
+    generators::generate_0010,
+];
 
 static mut CHECKSUMS: Vec<String> = Vec::new();
 static INIT: Once = Once::new();
@@ -21,3 +39,37 @@ fn load_checksums() -> Vec<String> {
     let res: Result<Vec<String>, _> = std::io::BufReader::new(file).lines().collect();
     res.unwrap()
 }
+
+/// Hashes an image file the way hashes.txt records it
+pub fn hash_image(path: &str) -> String {
+    let mut file = File::open(path).unwrap();
+    let mut sha256 = Sha256::new();
+    std::io::copy(&mut file, &mut sha256).unwrap();
+    HEXUPPER.encode(sha256.finalize().as_ref())
+}
+
+/// Rebuilds every image from the current generators, rewriting hashes.txt and dump_expected/.
+/// Run from the amfs-tests directory after a deliberate format change, and review the diff.
+pub fn regenerate() {
+    std::fs::create_dir_all("dump_expected").unwrap();
+    let mut hashes = Vec::new();
+    for (n, generate) in GENERATORS.iter().enumerate() {
+        let filename = format!("regen_{:04}.img", n);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&filename)
+            .unwrap();
+        generate(&file);
+        drop(file);
+        hashes.push(hash_image(&filename));
+        std::fs::write(
+            format!("dump_expected/{:04}.dump", n),
+            crate::dump_image(&filename),
+        )
+        .unwrap();
+        std::fs::remove_file(&filename).unwrap();
+    }
+    std::fs::write("hashes.txt", hashes.join("\n")).unwrap();
+}
@@ -4,12 +4,17 @@
 #[cfg(not(tarpaulin_include))]
 pub mod imagegen;
 
+/// Dumps an image as text, panicking if the dump fails
+pub fn dump_image(input: &str) -> Vec<u8> {
+    let mut dump_result = Vec::new();
+    let d = amfs::DiskFile::open(input).unwrap();
+    amfs::operations::dump(d, &mut dump_result, &Default::default())
+        .unwrap_or_else(|e| panic!("dump of {} failed: {:?}", input, e));
+    dump_result
+}
+
 pub fn test_dump(input: String, output: String) {
-    let dump_result = std::process::Command::new("/tmp/bin/dumpfs")
-        .arg(&input)
-        .output()
-        .unwrap()
-        .stdout;
+    let dump_result = dump_image(&input);
 
     std::fs::create_dir_all("dump_result").unwrap();
     std::fs::write(format!("dump_result/{}", output), dump_result).unwrap();
@@ -28,6 +33,9 @@ pub fn test_dump(input: String, output: String) {
             .unwrap()
             .wait()
             .unwrap();
-        panic!();
+        panic!(
+            "dump {} changed; if that's intended, run `cargo run --bin regen_golden`",
+            output
+        );
     }
 }
@@ -0,0 +1,31 @@
+use amfs::{operations::mkfs_single, Disk, FSHandle};
+use amfs_macros::*;
+
+#[test_fs(mem, file)]
+fn test_backend_roundtrip(d: Disk) {
+    assert_eq!(d.size().unwrap(), 1000);
+    mkfs_single(d.clone()).unwrap();
+
+    let fs = FSHandle::open(&[d.clone()]).unwrap();
+    fs.create_object(100, 0).unwrap();
+    fs.write_object(100, 0, &[7; 10000]).unwrap();
+    fs.commit().unwrap();
+    drop(fs);
+
+    let fs = FSHandle::open(&[d]).unwrap();
+    assert_eq!(fs.size_object(100).unwrap(), 10000);
+    let mut buf = [0u8; 10000];
+    fs.read_object(100, 0, &mut buf).unwrap();
+    assert!(buf.iter().all(|b| *b == 7));
+}
+
+#[test_fs(mem, file, blocks = 20)]
+fn test_backend_last_block(mut d: Disk) {
+    let last = d.size().unwrap() - 1;
+    assert_eq!(last, 19);
+    d.write_at(last, &[3; amfs::BLOCK_SIZE]).unwrap();
+    let mut buf = [0u8; amfs::BLOCK_SIZE];
+    d.read_at(last, &mut buf).unwrap();
+    assert_eq!(buf, [3; amfs::BLOCK_SIZE]);
+    assert!(d.read_at(last + 1, &mut buf).is_err());
+}
@@ -20,7 +20,7 @@ fn test_diskgroup_mismatch() {
         .collect();
 
     assert_eq!(
-        DiskGroup::from_geo(geometries[0], &[superblocks[0].devid()], &[d])
+        DiskGroup::from_geo(geometries[0].clone(), &[superblocks[0].devid()], &[d])
             .err()
             .unwrap()
             .downcast::<AMErrorFS>()
@@ -45,5 +45,5 @@ fn test_diskgroup() {
         .map(|x| x.get_geometry(d.clone(), 0).unwrap())
         .collect();
 
-    let _dg = DiskGroup::from_geo(geometries[0], &[superblocks[0].devid()], &[d]).unwrap();
+    let _dg = DiskGroup::from_geo(geometries[0].clone(), &[superblocks[0].devid()], &[d]).unwrap();
 }
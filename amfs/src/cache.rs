@@ -0,0 +1,232 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    convert::TryFrom,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use amos_std::AMResult;
+
+use crate::{memory::MemoryBudget, AMPointerGlobal, BLOCK_SIZE};
+
+/// Number of blocks kept by a mount's block cache
+pub(crate) const CACHE_BLOCKS: usize = 256;
+/// Number of blocks read ahead of a block read from an object being read sequentially
+pub(crate) const READAHEAD_BLOCKS: u64 = 8;
+
+/// How an application expects to access part of an object, for tuning caching
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    /// The whole object will be read in order, so blocks after each block read are read ahead
+    Sequential,
+    /// The range will be read soon, so it is read into the cache now
+    WillNeed,
+    /// The range won't be read again soon, so it is dropped from the cache. Stops read-ahead.
+    DontNeed,
+}
+
+/// A block of object data, aligned to a page so it can be mapped without copying
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[repr(C, align(4096))]
+pub struct AlignedBlock(pub [u8; BLOCK_SIZE]);
+
+impl Default for AlignedBlock {
+    #[cfg(feature = "unstable")]
+    fn default() -> Self {
+        AlignedBlock([0; BLOCK_SIZE])
+    }
+}
+
+impl Deref for AlignedBlock {
+    type Target = [u8; BLOCK_SIZE];
+    #[cfg(feature = "unstable")]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for AlignedBlock {
+    #[cfg(feature = "unstable")]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Whole blocks of object data, keyed by the fragment pointer they were read through and
+/// their index in its extent. Pointers carry the checksum of their extent, so a rewritten
+/// extent gets a new key and stale blocks are never returned.
+/// Blocks are evicted once there are too many, or the mount goes over its memory budget.
+#[derive(Debug)]
+pub(crate) struct BlockCache {
+    blocks:     BTreeMap<(AMPointerGlobal, u64), Arc<AlignedBlock>>,
+    /// Keys in insertion order, oldest first
+    order:      VecDeque<(AMPointerGlobal, u64)>,
+    capacity:   usize,
+    /// Objects advised as read sequentially
+    sequential: BTreeSet<u64>,
+    memory:     Arc<MemoryBudget>,
+}
+
+impl BlockCache {
+    /// Creates an empty cache holding at most `capacity` blocks
+    #[cfg(feature = "unstable")]
+    pub(crate) fn new(capacity: usize, memory: Arc<MemoryBudget>) -> Self {
+        BlockCache {
+            blocks: BTreeMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            sequential: BTreeSet::new(),
+            memory,
+        }
+    }
+    /// Looks up the `n`th block of a pointer's extent
+    #[cfg(feature = "unstable")]
+    pub(crate) fn get(&self, ptr: AMPointerGlobal, n: u64) -> Option<Arc<AlignedBlock>> {
+        self.blocks.get(&(ptr, n)).cloned()
+    }
+    /// Adds a block, evicting the oldest ones once full
+    #[cfg(feature = "unstable")]
+    pub(crate) fn insert(
+        &mut self,
+        ptr: AMPointerGlobal,
+        n: u64,
+        block: Arc<AlignedBlock>,
+    ) -> AMResult<()> {
+        if self.capacity == 0 || self.blocks.insert((ptr, n), block).is_some() {
+            return Ok(());
+        }
+        self.order.push_back((ptr, n));
+        self.trim(0)
+    }
+    /// Drops a block, if it is held
+    #[cfg(feature = "unstable")]
+    pub(crate) fn remove(&mut self, ptr: AMPointerGlobal, n: u64) -> AMResult<()> {
+        if self.blocks.remove(&(ptr, n)).is_some() {
+            self.order.retain(|k| *k != (ptr, n));
+        }
+        self.account()
+    }
+    /// Evicts the oldest blocks until the cache is within its capacity and the memory budget
+    /// has room for `extra` more bytes, or the cache is empty
+    #[cfg(feature = "unstable")]
+    pub(crate) fn trim(&mut self, extra: u64) -> AMResult<()> {
+        loop {
+            self.account()?;
+            let over = self.order.len() > self.capacity || self.memory.over(extra);
+            match self.order.pop_front() {
+                Some(key) if over => {
+                    self.blocks.remove(&key);
+                }
+                Some(key) => {
+                    self.order.push_front(key);
+                    return Ok(());
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+    /// Records the memory held by the cache in the budget
+    #[cfg(feature = "unstable")]
+    fn account(&self) -> AMResult<()> {
+        MemoryBudget::set(
+            &self.memory.cache,
+            u64::try_from(self.blocks.len() * BLOCK_SIZE)?,
+        );
+        Ok(())
+    }
+    /// Sets whether an object is read ahead
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_sequential(&mut self, id: u64, sequential: bool) {
+        if sequential {
+            self.sequential.insert(id);
+        } else {
+            self.sequential.remove(&id);
+        }
+    }
+    /// Returns whether an object is read ahead
+    #[cfg(feature = "unstable")]
+    pub(crate) fn is_sequential(&self, id: u64) -> bool {
+        self.sequential.contains(&id)
+    }
+    /// Returns the number of blocks held
+    #[cfg(feature = "unstable")]
+    pub(crate) fn len(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_block_cache() {
+    crate::test::logging::init_log();
+
+    let mut cache = BlockCache::new(2, Arc::default());
+    let ptr = AMPointerGlobal::new(10, 4, 0, 0);
+    for n in 0..3u8 {
+        cache
+            .insert(ptr, n.into(), Arc::new(AlignedBlock([n; BLOCK_SIZE])))
+            .unwrap();
+    }
+    assert_eq!(cache.len(), 2);
+    assert!(cache.get(ptr, 0).is_none());
+    cache.remove(ptr, 1).unwrap();
+    assert!(cache.get(ptr, 1).is_none());
+    assert_eq!(cache.get(ptr, 2).unwrap()[0], 2);
+    assert_eq!(&*cache.get(ptr, 2).unwrap() as *const _ as usize % 4096, 0);
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_read_object_block(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    fs.create_object(100, 0).unwrap();
+    let mut data = vec![1; BLOCK_SIZE * 2];
+    data.extend_from_slice(&[2; 100]);
+    fs.write_object(100, 0, &data).unwrap();
+    fs.commit().unwrap();
+
+    let first = fs.read_object_block(100, 1).unwrap();
+    assert_eq!(**first, [1; BLOCK_SIZE]);
+    let hits = fs.metrics().cache_hits;
+    let again = fs.read_object_block(100, 1).unwrap();
+    assert!(Arc::ptr_eq(&first, &again));
+    assert_eq!(fs.metrics().cache_hits, hits + 1);
+
+    let last = fs.read_object_block(100, 2).unwrap();
+    assert_eq!(&last[..100], &[2; 100][..]);
+    assert!(last[100..].iter().all(|b| *b == 0));
+    assert_eq!(**fs.read_object_block(100, 3).unwrap(), [0; BLOCK_SIZE]);
+
+    fs.write_object(100, u64::try_from(BLOCK_SIZE).unwrap(), &[3; 10])
+        .unwrap();
+    assert_eq!(fs.read_object_block(100, 1).unwrap()[..10], [3; 10]);
+    assert!(fs.read_object_block(101, 0).is_err());
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_advise(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    fs.create_object(100, 0).unwrap();
+    fs.write_object(100, 0, &[1; BLOCK_SIZE * 16]).unwrap();
+    fs.commit().unwrap();
+    let size = fs.size_object(100).unwrap();
+
+    fs.advise(100, 0..size, Advice::WillNeed).unwrap();
+    let hits = fs.metrics().cache_hits;
+    fs.read_object_block(100, 5).unwrap();
+    assert_eq!(fs.metrics().cache_hits, hits + 1);
+
+    fs.advise(100, 0..size, Advice::DontNeed).unwrap();
+    fs.read_object_block(100, 5).unwrap();
+    assert_eq!(fs.metrics().cache_hits, hits + 1);
+
+    fs.advise(100, 0..0, Advice::Sequential).unwrap();
+    fs.read_object_block(100, 6).unwrap();
+    assert_eq!(fs.metrics().cache_hits, hits + 1);
+    for n in 7..7 + READAHEAD_BLOCKS {
+        fs.read_object_block(100, n).unwrap();
+    }
+    assert_eq!(fs.metrics().cache_hits, hits + 1 + READAHEAD_BLOCKS);
+    assert!(fs.advise(101, 0..size, Advice::WillNeed).is_err());
+}
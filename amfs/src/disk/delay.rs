@@ -0,0 +1,181 @@
+use std::{
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use amos_std::{error::AMError, AMResult};
+
+use crate::disk::{Disk, DiskObj, SectorSize};
+
+/// How slow a `DiskDelay` pretends to be
+#[derive(Debug, Clone, Copy)]
+pub struct DelayOptions {
+    /// Time taken by every read, before transfer time
+    pub read_latency:  Duration,
+    /// Time taken by every write, before transfer time
+    pub write_latency: Duration,
+    /// Time taken by every sync or barrier
+    pub sync_latency:  Duration,
+    /// Transfer rate in bytes per second. Unlimited if `None`.
+    pub bandwidth:     Option<u64>,
+    /// Really sleep for the simulated time, instead of only adding it to the clock
+    pub sleep:         bool,
+}
+
+impl Default for DelayOptions {
+    #[cfg(feature = "unstable")]
+    fn default() -> Self {
+        DelayOptions {
+            read_latency:  Duration::from_micros(100),
+            write_latency: Duration::from_micros(100),
+            sync_latency:  Duration::from_millis(1),
+            bandwidth:     None,
+            sleep:         false,
+        }
+    }
+}
+
+/// A disk object that passes through to another disk, keeping a simulated clock of how long
+/// each operation would take on slower hardware.
+pub struct DiskDelay {
+    inner:   Disk,
+    opts:    DelayOptions,
+    elapsed: Duration,
+    reads:   u64,
+    writes:  u64,
+}
+
+impl DiskDelay {
+    /// Wraps a disk with the given delays.
+    #[cfg(feature = "unstable")]
+    pub fn new(inner: Disk, opts: DelayOptions) -> Self {
+        DiskDelay {
+            inner,
+            opts,
+            elapsed: Duration::ZERO,
+            reads: 0,
+            writes: 0,
+        }
+    }
+    /// Wraps a disk, returning the handle to mount along with the wrapper to inspect.
+    #[cfg(feature = "unstable")]
+    pub fn open(inner: Disk, opts: DelayOptions) -> (Disk, Arc<Mutex<DiskDelay>>) {
+        let delay = Arc::new(Mutex::new(DiskDelay::new(inner, opts)));
+        (Disk::new(delay.clone()), delay)
+    }
+    /// Returns the simulated time spent on operations so far.
+    #[cfg(feature = "unstable")]
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+    /// Returns the number of reads so far.
+    #[cfg(feature = "unstable")]
+    pub fn reads(&self) -> u64 {
+        self.reads
+    }
+    /// Returns the number of writes so far.
+    #[cfg(feature = "unstable")]
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+    /// Zeroes the clock and the operation counts.
+    #[cfg(feature = "unstable")]
+    pub fn reset(&mut self) {
+        self.elapsed = Duration::ZERO;
+        self.reads = 0;
+        self.writes = 0;
+    }
+    /// Adds an operation's latency and transfer time to the clock
+    #[cfg(feature = "unstable")]
+    fn charge(&mut self, latency: Duration, bytes: usize) -> AMResult<()> {
+        let mut cost = latency;
+        if let Some(bw) = self.opts.bandwidth {
+            assert_or_err!(bw != 0, AMError::TODO(0));
+            let nanos = u128::from(u64::try_from(bytes)?) * 1_000_000_000 / u128::from(bw);
+            cost += Duration::from_nanos(u64::try_from(nanos)?);
+        }
+        self.elapsed += cost;
+        if self.opts.sleep {
+            std::thread::sleep(cost);
+        }
+        Ok(())
+    }
+}
+
+impl DiskObj for DiskDelay {
+    #[cfg(feature = "unstable")]
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> AMResult<usize> {
+        self.reads += 1;
+        self.charge(self.opts.read_latency, buffer.len())?;
+        self.inner.read_at(block, buffer)
+    }
+    #[cfg(feature = "unstable")]
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> AMResult<usize> {
+        self.writes += 1;
+        self.charge(self.opts.write_latency, buffer.len())?;
+        self.inner.write_at(block, buffer)
+    }
+    #[cfg(feature = "unstable")]
+    fn size(&self) -> AMResult<u64> {
+        self.inner.size()
+    }
+    #[cfg(feature = "unstable")]
+    fn sync(&mut self) -> AMResult<()> {
+        self.charge(self.opts.sync_latency, 0)?;
+        self.inner.sync()
+    }
+    #[cfg(feature = "unstable")]
+    fn barrier(&mut self) -> AMResult<()> {
+        self.charge(self.opts.sync_latency, 0)?;
+        self.inner.barrier()
+    }
+    #[cfg(feature = "unstable")]
+    fn discard(&mut self, start: u64, count: u64) -> AMResult<()> {
+        self.inner.discard(start, count)
+    }
+    #[cfg(feature = "unstable")]
+    fn sector_size(&self) -> AMResult<SectorSize> {
+        self.inner.sector_size()
+    }
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_delay() {
+    crate::test::logging::init_log();
+
+    let opts = DelayOptions {
+        read_latency: Duration::from_micros(10),
+        write_latency: Duration::from_micros(20),
+        sync_latency: Duration::from_micros(500),
+        bandwidth: Some(4096 * 1000),
+        ..DelayOptions::default()
+    };
+    let (mut d, delay) = DiskDelay::open(crate::DiskMem::open(10), opts);
+    let mut buf = [0u8; crate::BLOCK_SIZE];
+
+    d.write_at(1, &[1; crate::BLOCK_SIZE]).unwrap();
+    d.read_at(1, &mut buf).unwrap();
+    d.sync().unwrap();
+    let transfer = Duration::from_nanos(
+        u64::try_from(crate::BLOCK_SIZE).unwrap() * 1_000_000_000 / (4096 * 1000),
+    );
+    assert_eq!(
+        delay.lock().unwrap().elapsed(),
+        Duration::from_micros(530) + 2 * transfer
+    );
+    assert_eq!((delay.lock().unwrap().reads(), delay.lock().unwrap().writes()), (1, 1));
+
+    delay.lock().unwrap().reset();
+    assert_eq!(delay.lock().unwrap().elapsed(), Duration::ZERO);
+
+    let (d, delay) = DiskDelay::open(crate::DiskMem::open(1000), DelayOptions::default());
+    crate::operations::mkfs_single(d.clone()).unwrap();
+    let fs = crate::FSHandle::open(&[d]).unwrap();
+    delay.lock().unwrap().reset();
+    fs.create_object(100, 0).unwrap();
+    fs.commit().unwrap();
+    assert!(delay.lock().unwrap().writes() > 0);
+    assert!(delay.lock().unwrap().elapsed() >= Duration::from_millis(1));
+}
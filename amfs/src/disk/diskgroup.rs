@@ -1,19 +1,53 @@
-use std::{
-    collections::BTreeMap,
-    convert::{TryFrom, TryInto},
-};
+use std::{collections::BTreeMap, convert::TryFrom, sync::Arc};
 
 use amos_std::{error::AMErrorFS, AMResult};
 
-use crate::{AMPointerGlobal, Allocator, Disk, Fragment, Geometry, GeometryFlavor, BLOCK_SIZE};
+use crate::{
+    events::Hooks, metrics::Metrics, AMPointerGlobal, AllocStrategy, Allocator, Disk, Fragment,
+    Geometry, GeometryFlavor, BLOCK_SIZE, HEAD_DEVICES, MAX_EXTENT_BLOCKS,
+};
+
+/// Longest extent allocated for object data, in blocks. Writes copy whole fragments, so this
+/// bounds what a small write into a large object costs.
+pub(crate) const DATA_EXTENT_BLOCKS: u64 = 1024;
+
+/// Where an allocation should preferably be placed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocHint {
+    /// Just after a given extent, so related data stays contiguous
+    Near(AMPointerGlobal),
+    /// Anywhere on the same device as a given extent
+    SameDevice(AMPointerGlobal),
+    /// In the data zone, away from metadata
+    Data,
+    /// In the data zone of the emptiest device other than a given extent's
+    OtherDevice(AMPointerGlobal),
+}
+
+/// How allocations not hinted to be near an existing extent pick a disk group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Always the first disk group
+    First,
+    /// The disk group with the most free space
+    MostFree,
+    /// Each disk group in turn
+    RoundRobin,
+}
 
 /// Represents a group of disks associated with a geometry
 #[derive(Debug, Clone)]
 pub struct DiskGroup {
     /// The group's geometry object
-    pub geo:           Geometry,
-    disks:             Vec<Disk>,
-    pub(crate) allocs: Vec<Allocator>,
+    pub geo:            Geometry,
+    /// Index of the group's geometry in the superblocks, recorded in the pointers it allocates
+    pub(crate) index:   u8,
+    disks:              Vec<Disk>,
+    pub(crate) allocs:  Vec<Allocator>,
+    /// Counters of the filesystem this group is mounted in
+    pub(crate) metrics: Arc<Metrics>,
+    /// Event hooks of the filesystem this group is mounted in
+    pub(crate) hooks:   Arc<Hooks>,
 }
 
 impl DiskGroup {
@@ -21,102 +55,226 @@ impl DiskGroup {
     #[cfg(feature = "stable")]
     pub fn single(g: Geometry, d: Disk, a: Allocator) -> DiskGroup {
         DiskGroup {
-            geo:    g,
-            disks:  vec![d],
-            allocs: vec![a],
+            geo:     g,
+            index:   0,
+            disks:   vec![d],
+            allocs:  vec![a],
+            metrics: Arc::default(),
+            hooks:   Arc::default(),
+        }
+    }
+    /// Creates a disk group containing several disks, in the order of the geometry's device IDs
+    #[cfg(feature = "unstable")]
+    pub fn multi(g: Geometry, ds: Vec<Disk>, allocs: Vec<Allocator>) -> DiskGroup {
+        DiskGroup {
+            geo: g,
+            index: 0,
+            disks: ds,
+            allocs,
+            metrics: Arc::default(),
+            hooks: Arc::default(),
         }
     }
     /// Creates a disk group containing a single disk
     #[cfg(feature = "stable")]
     pub fn from_geo(g: Geometry, devids: &[u64], ds: &[Disk]) -> AMResult<DiskGroup> {
         let mut disks = Vec::new();
-        for devid in g.device_ids {
-            if devid == 0 {
-                break;
-            }
+        for devid in &g.device_ids {
             let disk_no = devids
                 .iter()
-                .position(|r| *r == devid)
+                .position(|r| r == devid)
                 .ok_or(AMErrorFS::UnknownDevId)?;
             disks.push(ds[disk_no].clone());
         }
         Ok(DiskGroup {
             geo: g,
+            index: 0,
             disks,
             allocs: Vec::new(),
+            metrics: Arc::default(),
+            hooks: Arc::default(),
         })
     }
     /// Initializes out allocator set from an allocator map
     #[cfg(feature = "stable")]
     pub fn load_allocators(&mut self, allocs: BTreeMap<u64, Allocator>) -> AMResult<()> {
-        for devid in self.geo.device_ids {
-            if devid == 0 {
-                break;
-            }
+        for devid in &self.geo.device_ids {
             self.allocs
-                .push(allocs.get(&devid).ok_or(AMErrorFS::NoAllocator)?.clone());
+                .push(allocs.get(devid).ok_or(AMErrorFS::NoAllocator)?.clone());
         }
         Ok(())
     }
     /// Gets the nth disk
     #[cfg(feature = "stable")]
     pub fn get_disk(&self, n: u8) -> AMResult<Disk> {
-        if self.geo.device_ids[n as usize] != 0 {
-            Ok(self.disks[n as usize].clone())
+        if usize::from(n) < self.geo.device_ids.len() {
+            Ok(self.disks[usize::from(n)].clone())
         } else {
             Err(AMErrorFS::DiskID.into())
         }
     }
+    /// Gets the disk holding a pointer's extent
+    #[cfg(feature = "unstable")]
+    pub fn get_disk_for(&self, ptr: AMPointerGlobal) -> AMResult<Disk> {
+        self.get_disk(self.device_of(ptr))
+    }
+    /// Gets the index of the device holding a pointer's extent. Single disk groups ignore
+    /// the pointer's device field.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn device_of(&self, ptr: AMPointerGlobal) -> u8 {
+        match self.geo.flavor() {
+            GeometryFlavor::Single => 0,
+            GeometryFlavor::Striped => ptr.dev(),
+        }
+    }
     /// Allocates a block
     #[cfg(feature = "unstable")]
-    pub fn alloc_blocks(&mut self, n: u64) -> AMResult<AMPointerGlobal> {
+    pub fn alloc_blocks(&mut self, n: u64, hint: Option<AllocHint>) -> AMResult<AMPointerGlobal> {
+        let _span = tracing::trace_span!("alloc", blocks = n).entered();
+        Metrics::add(&self.metrics.allocations, 1);
         Ok(match self.geo.flavor() {
             GeometryFlavor::Single => {
-                let ptr = self.allocs[0].alloc_blocks(n)?;
-                AMPointerGlobal::new(ptr, 1, 0, 0)
+                let len = Self::extent_len(n)?;
+                let ptr = match hint {
+                    Some(AllocHint::Near(p)) => {
+                        self.allocs[0].alloc_near(n, p.loc() + u64::from(p.length()))?
+                    }
+                    Some(AllocHint::Data | AllocHint::OtherDevice(_)) => {
+                        self.allocs[0].alloc_data(n)?
+                    }
+                    // There's only one device to allocate on
+                    Some(AllocHint::SameDevice(_)) | None => self.allocs[0].alloc_blocks(n)?,
+                };
+                AMPointerGlobal::new(ptr, len, self.index, 0)
+            }
+            GeometryFlavor::Striped => {
+                let len = Self::extent_len(n)?;
+                let (dev, ptr) = match hint {
+                    Some(AllocHint::Near(p)) => (
+                        p.dev(),
+                        self.allocs[usize::from(p.dev())]
+                            .alloc_near(n, p.loc() + u64::from(p.length()))?,
+                    ),
+                    Some(AllocHint::SameDevice(p)) => {
+                        (p.dev(), self.allocs[usize::from(p.dev())].alloc_blocks(n)?)
+                    }
+                    Some(AllocHint::Data) => {
+                        let dev = self.emptiest(None)?;
+                        (dev, self.allocs[usize::from(dev)].alloc_data(n)?)
+                    }
+                    Some(AllocHint::OtherDevice(p)) => {
+                        let dev = self.emptiest(Some(p.dev()))?;
+                        (dev, self.allocs[usize::from(dev)].alloc_data(n)?)
+                    }
+                    None => {
+                        let dev = self.emptiest(None)?;
+                        (dev, self.allocs[usize::from(dev)].alloc_blocks(n)?)
+                    }
+                };
+                AMPointerGlobal::new(ptr, len, self.index, dev)
             }
-            _ => unimplemented!(), // TODO(#3): Add support for additional geometries
         })
     }
-    /// Allocates a block
+    /// Checks an allocation fits in one pointer
+    #[cfg(feature = "unstable")]
+    fn extent_len(n: u64) -> AMResult<u16> {
+        assert_or_err!(n <= MAX_EXTENT_BLOCKS, AMErrorFS::AllocFailed);
+        Ok(u16::try_from(n)?)
+    }
+    /// Gets the index of the device with the most free space, of those pointers can address.
+    /// Avoids the `except` device unless it is the only one.
     #[cfg(feature = "unstable")]
-    pub fn alloc_bytes(&mut self, n: u64) -> AMResult<Vec<Fragment>> {
+    fn emptiest(&self, except: Option<u8>) -> AMResult<u8> {
+        let devs = 0..self.allocs.len().min(HEAD_DEVICES);
+        let free = |i: &usize| self.allocs[*i].free_space();
+        let dev = devs
+            .clone()
+            .filter(|i| except.map_or(true, |e| usize::from(e) != *i))
+            .max_by_key(free)
+            .or_else(|| devs.max_by_key(free))
+            .ok_or(AMErrorFS::NoAllocator)?;
+        Ok(u8::try_from(dev)?)
+    }
+    /// Sets how the group's allocators pick free extents
+    #[cfg(feature = "unstable")]
+    pub fn set_strategy(&mut self, strategy: AllocStrategy) {
+        for a in &mut self.allocs {
+            a.set_strategy(strategy);
+        }
+    }
+    /// Returns the blocks a pointer addresses to their allocator.
+    /// The pointer may cover only part of an allocated extent, the rest stays allocated.
+    #[cfg(feature = "unstable")]
+    pub fn free(&mut self, ptr: AMPointerGlobal) -> AMResult<()> {
+        let dev = usize::from(self.device_of(ptr));
+        self.allocs[dev].free_range(ptr.loc(), u64::from(ptr.length()))
+    }
+    /// Allocates space for a number of bytes, as one fragment per contiguous run.
+    /// Runs are capped at `DATA_EXTENT_BLOCKS`, and shrink to fit when free space is fragmented.
+    #[cfg(feature = "unstable")]
+    pub fn alloc_bytes(&mut self, n: u64, mut hint: Option<AllocHint>) -> AMResult<Vec<Fragment>> {
         Ok(match self.geo.flavor() {
-            GeometryFlavor::Single => {
+            GeometryFlavor::Single | GeometryFlavor::Striped => {
+                let block_size = u64::try_from(BLOCK_SIZE)?;
                 let mut res = Vec::new();
-                let mut size_rem = usize::try_from(n)?;
+                let mut size_rem = n;
                 loop {
-                    let ptr = self.allocs[0].alloc_blocks(1)?;
-                    let size_frag = if size_rem > BLOCK_SIZE {
-                        BLOCK_SIZE
-                    } else {
-                        size_rem
+                    let blocks_rem = std::cmp::max(1, (size_rem + block_size - 1) / block_size);
+                    let mut run = std::cmp::min(blocks_rem, DATA_EXTENT_BLOCKS);
+                    run = std::cmp::max(1, std::cmp::min(run, self.largest_free()));
+                    let ptr = loop {
+                        match self.alloc_blocks(run, hint.or(Some(AllocHint::Data))) {
+                            Ok(ptr) => break ptr,
+                            // The hinted device may not have a run this long
+                            Err(_) if run > 1 => run /= 2,
+                            Err(e) => return Err(e),
+                        }
                     };
-                    res.push(Fragment::new(
-                        size_frag.try_into()?,
-                        0,
-                        AMPointerGlobal::new(ptr, 1, 0, 0),
-                    ));
-                    if size_rem <= BLOCK_SIZE {
+                    // Keep the rest of the fragments following on from this one
+                    hint = Some(AllocHint::Near(ptr));
+                    let size_frag = std::cmp::min(size_rem, run * block_size);
+                    res.push(Fragment::new(size_frag, 0, ptr));
+                    size_rem -= size_frag;
+                    if size_rem == 0 {
                         break;
                     }
-                    size_rem -= BLOCK_SIZE;
                 }
                 res
             }
-            _ => unimplemented!(), // TODO(#3): Add support for additional geometries
         })
     }
+    /// Gets the free space on every device, in blocks
+    #[cfg(feature = "unstable")]
+    pub fn free_space(&self) -> u64 {
+        self.allocs.iter().map(Allocator::free_space).sum()
+    }
+    /// Gets the size of the largest free extent on any device
+    #[cfg(feature = "unstable")]
+    fn largest_free(&self) -> u64 {
+        self.allocs
+            .iter()
+            .map(Allocator::largest_free)
+            .max()
+            .unwrap_or(0)
+    }
     /// Allocates a block
     #[cfg(feature = "unstable")]
     pub fn alloc_many(&mut self, count: u64) -> AMResult<Vec<AMPointerGlobal>> {
+        Metrics::add(&self.metrics.allocations, count);
         Ok(match self.geo.flavor() {
             GeometryFlavor::Single => self.allocs[0]
                 .alloc_many(count)?
                 .iter()
-                .map(|x| AMPointerGlobal::new(*x, 1, 0, 0))
+                .map(|x| AMPointerGlobal::new(*x, 1, self.index, 0))
                 .collect(),
-            _ => unimplemented!(), // TODO(#3): Add support for additional geometries
+            GeometryFlavor::Striped => {
+                let dev = self.emptiest(None)?;
+                self.allocs[usize::from(dev)]
+                    .alloc_many(count)?
+                    .iter()
+                    .map(|x| AMPointerGlobal::new(*x, 1, self.index, dev))
+                    .collect()
+            }
         })
     }
     /// Syncs the disks
@@ -127,4 +285,66 @@ impl DiskGroup {
         }
         Ok(())
     }
+    /// Places a write barrier on every disk
+    #[cfg(feature = "unstable")]
+    pub fn barrier(&mut self) -> AMResult<()> {
+        for d in &mut self.disks {
+            d.barrier()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_alloc_bytes() {
+    crate::test::logging::init_log();
+
+    let block_size = u64::try_from(BLOCK_SIZE).unwrap();
+    let mut dg = crate::test::dg::create_dg_mem_single(3000);
+    let frags = dg.alloc_bytes(10 * block_size + 100, None).unwrap();
+    assert_eq!(frags.len(), 1);
+    assert_eq!(frags[0].size, 10 * block_size + 100);
+    assert_eq!(frags[0].pointer.length(), 11);
+
+    let frags = dg
+        .alloc_bytes((DATA_EXTENT_BLOCKS + 1) * block_size, None)
+        .unwrap();
+    assert_eq!(frags.len(), 2);
+    assert_eq!(frags[0].pointer.length(), 1024);
+    assert_eq!(frags[1].pointer.length(), 1);
+    assert_eq!(
+        frags[1].pointer.loc(),
+        frags[0].pointer.loc() + DATA_EXTENT_BLOCKS
+    );
+
+    assert_eq!(dg.alloc_bytes(0, None).unwrap()[0].size, 0);
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_get_disk_for() {
+    crate::test::logging::init_log();
+
+    let ds = vec![crate::DiskMem::open(10), crate::DiskMem::open(10)];
+    let mut geo = Geometry::new();
+    geo.device_ids = vec![1, 2];
+    geo.flavor = GeometryFlavor::Striped;
+    let dg = DiskGroup::multi(geo, ds.clone(), vec![Allocator::new(10); 2]);
+    let dgs = [Some(dg.clone())];
+
+    let ptr = AMPointerGlobal::new(3, 1, 0, 1);
+    ptr.write(0, BLOCK_SIZE, &dgs, &[7; BLOCK_SIZE]).unwrap();
+    let mut buf = [0; BLOCK_SIZE];
+    ds[1].clone().read_at(3, &mut buf).unwrap();
+    assert_eq!(buf, [7; BLOCK_SIZE]);
+    ds[0].clone().read_at(3, &mut buf).unwrap();
+    assert_eq!(buf, [0; BLOCK_SIZE]);
+    assert!(dg.get_disk_for(AMPointerGlobal::new(3, 1, 0, 2)).is_err());
+
+    // A single disk group has only one disk to choose
+    let single = crate::test::dg::create_dg_mem_single(10);
+    let ptr = AMPointerGlobal::new(3, 1, 0, 5);
+    assert_eq!(single.device_of(ptr), 0);
+    single.get_disk_for(ptr).unwrap();
 }
@@ -0,0 +1,204 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use amos_std::{error::AMError, AMResult};
+
+use crate::disk::{Disk, DiskObj, SectorSize};
+
+/// A disk object that passes through to another disk, failing in programmed ways.
+/// Keep an `Arc` to it alongside the `Disk` handle to reprogram it between operations.
+pub struct DiskFaulty {
+    inner:   Disk,
+    writes:  u64,
+    fail_at: Option<u64>,
+    cut_at:  Option<u64>,
+    corrupt: BTreeSet<u64>,
+}
+
+impl DiskFaulty {
+    /// Wraps a disk. No faults are programmed until asked for.
+    #[cfg(feature = "unstable")]
+    pub fn new(inner: Disk) -> Self {
+        DiskFaulty {
+            inner,
+            writes: 0,
+            fail_at: None,
+            cut_at: None,
+            corrupt: BTreeSet::new(),
+        }
+    }
+    /// Wraps a disk, returning the handle to mount along with the wrapper to program.
+    #[cfg(feature = "unstable")]
+    pub fn open(inner: Disk) -> (Disk, Arc<Mutex<DiskFaulty>>) {
+        let faulty = Arc::new(Mutex::new(DiskFaulty::new(inner)));
+        (Disk::new(faulty.clone()), faulty)
+    }
+    /// Makes the `n`th write from now fail, counting from 1. Later writes succeed again.
+    #[cfg(feature = "unstable")]
+    pub fn fail_write(&mut self, n: u64) {
+        self.fail_at = Some(self.writes + n);
+    }
+    /// Simulates a power cut after `n` more writes. Every write after that is dropped.
+    #[cfg(feature = "unstable")]
+    pub fn power_cut_after(&mut self, n: u64) {
+        self.cut_at = Some(self.writes + n);
+    }
+    /// Makes reads of a block return its contents with every bit inverted.
+    #[cfg(feature = "unstable")]
+    pub fn corrupt_block(&mut self, block: u64) {
+        self.corrupt.insert(block);
+    }
+    /// Clears every programmed fault, restoring power.
+    #[cfg(feature = "unstable")]
+    pub fn reset(&mut self) {
+        self.fail_at = None;
+        self.cut_at = None;
+        self.corrupt.clear();
+    }
+    /// Returns the number of writes attempted so far, including failed and dropped ones.
+    #[cfg(feature = "unstable")]
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+    /// Returns whether the simulated power cut has happened.
+    #[cfg(feature = "unstable")]
+    pub fn is_cut(&self) -> bool {
+        matches!(self.cut_at, Some(c) if self.writes >= c)
+    }
+}
+
+impl DiskObj for DiskFaulty {
+    #[cfg(feature = "unstable")]
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> AMResult<usize> {
+        let res = self.inner.read_at(block, buffer)?;
+        if self.corrupt.contains(&block) {
+            for b in buffer.iter_mut() {
+                *b = !*b;
+            }
+        }
+        Ok(res)
+    }
+    #[cfg(feature = "unstable")]
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> AMResult<usize> {
+        let cut = self.is_cut();
+        self.writes += 1;
+        if self.fail_at == Some(self.writes) {
+            return Err(AMError::TODO(0).into());
+        }
+        if cut {
+            // The disk reports success, but nothing reaches it
+            return Ok(buffer.len());
+        }
+        self.inner.write_at(block, buffer)
+    }
+    #[cfg(feature = "unstable")]
+    fn size(&self) -> AMResult<u64> {
+        self.inner.size()
+    }
+    #[cfg(feature = "unstable")]
+    fn sync(&mut self) -> AMResult<()> {
+        if self.is_cut() {
+            return Ok(());
+        }
+        self.inner.sync()
+    }
+    #[cfg(feature = "unstable")]
+    fn barrier(&mut self) -> AMResult<()> {
+        if self.is_cut() {
+            return Ok(());
+        }
+        self.inner.barrier()
+    }
+    #[cfg(feature = "unstable")]
+    fn discard(&mut self, start: u64, count: u64) -> AMResult<()> {
+        if self.is_cut() {
+            return Ok(());
+        }
+        self.inner.discard(start, count)
+    }
+    #[cfg(feature = "unstable")]
+    fn sector_size(&self) -> AMResult<SectorSize> {
+        self.inner.sector_size()
+    }
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_faulty() {
+    crate::test::logging::init_log();
+
+    let (mut d, faulty) = DiskFaulty::open(crate::DiskMem::open(10));
+    let mut buf = [0u8; crate::BLOCK_SIZE];
+
+    faulty.lock().unwrap().fail_write(2);
+    d.write_at(1, &[1; crate::BLOCK_SIZE]).unwrap();
+    assert!(d.write_at(2, &[2; crate::BLOCK_SIZE]).is_err());
+    d.write_at(2, &[2; crate::BLOCK_SIZE]).unwrap();
+
+    faulty.lock().unwrap().corrupt_block(1);
+    d.read_at(1, &mut buf).unwrap();
+    assert_eq!(buf, [!1; crate::BLOCK_SIZE]);
+    d.read_at(2, &mut buf).unwrap();
+    assert_eq!(buf, [2; crate::BLOCK_SIZE]);
+
+    faulty.lock().unwrap().reset();
+    faulty.lock().unwrap().power_cut_after(1);
+    d.write_at(3, &[3; crate::BLOCK_SIZE]).unwrap();
+    assert!(faulty.lock().unwrap().is_cut());
+    d.write_at(4, &[4; crate::BLOCK_SIZE]).unwrap();
+    d.read_at(4, &mut buf).unwrap();
+    assert_eq!(buf, [0; crate::BLOCK_SIZE]);
+    assert_eq!(faulty.lock().unwrap().writes(), 5);
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_commit_power_cut() {
+    use amos_std::error::AMErrorFS;
+
+    crate::test::logging::init_log();
+
+    let mem = Arc::new(Mutex::new(crate::DiskMem::new(1000)));
+    let base = Disk::new(mem.clone());
+    crate::operations::mkfs_single(base.clone()).unwrap();
+    let fs = crate::FSHandle::open(&[base.clone()]).unwrap();
+    fs.create_object(100, 0).unwrap();
+    fs.write_object(100, 0, &[1; 16]).unwrap();
+    fs.commit().unwrap();
+    drop(fs);
+    let before = mem.lock().unwrap().snapshot();
+
+    // Count the writes a full commit takes, then cut power after each of them in turn
+    let (d, faulty) = DiskFaulty::open(base.clone());
+    let fs = crate::FSHandle::open(&[d]).unwrap();
+    fs.write_object(100, 0, &[2; 16]).unwrap();
+    let start = faulty.lock().unwrap().writes();
+    fs.commit().unwrap();
+    let total = faulty.lock().unwrap().writes() - start;
+    drop(fs);
+
+    for cut in 0..total {
+        mem.lock().unwrap().restore(&before).unwrap();
+        let (d, faulty) = DiskFaulty::open(base.clone());
+        let fs = crate::FSHandle::open(&[d]).unwrap();
+        fs.write_object(100, 0, &[2; 16]).unwrap();
+        faulty.lock().unwrap().power_cut_after(cut);
+        // Reading back the superblocks notices the writes lost to the cut
+        match fs.commit() {
+            Err(e) => assert_eq!(e.downcast::<AMErrorFS>().unwrap(), AMErrorFS::Checksum),
+            Ok(()) => panic!("commit survived a power cut after {} writes", cut),
+        }
+        drop(fs);
+
+        let fs = crate::FSHandle::open(&[base.clone()]).unwrap();
+        let mut buf = [0u8; 16];
+        fs.read_object(100, 0, &mut buf).unwrap();
+        assert!(
+            buf == [1; 16] || buf == [2; 16],
+            "torn commit after {} writes",
+            cut
+        );
+    }
+}
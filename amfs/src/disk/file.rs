@@ -1,9 +1,8 @@
 use std::{
-    cell::RefCell,
     convert::TryInto,
     fs::{File, OpenOptions},
     io::{Read, Seek, SeekFrom, Write},
-    rc::Rc,
+    sync::{Arc, Mutex},
 };
 
 use amos_std::{error::AMError, AMResult};
@@ -12,8 +11,7 @@ use crate::{disk::DiskObj, BLOCK_SIZE};
 
 /// A disk object stored in a file.
 pub struct DiskFile {
-    f:    File,
-    size: u64,
+    f: File,
 }
 
 impl DiskFile {
@@ -31,23 +29,55 @@ impl DiskFile {
             res.set_len((100 * BLOCK_SIZE).try_into().or(Err(AMError::TODO(0)))?)?;
             res
         };
-        let size = file.metadata()?.len();
-        Ok(super::Disk(Rc::new(RefCell::new(DiskFile {
+        Ok(super::Disk::new(Arc::new(Mutex::new(DiskFile {
             f: file,
-            size,
         }))))
     }
     /// Creates a disk object using a file.
     #[cfg(feature = "stable")]
     pub fn open_file(file: File) -> AMResult<super::Disk> {
-        let size = file.metadata()?.len();
-        Ok(super::Disk(Rc::new(RefCell::new(DiskFile {
+        Ok(super::Disk::new(Arc::new(Mutex::new(DiskFile {
             f: file,
-            size,
         }))))
     }
 }
 
+/// Reads a number from a block device's sysfs directory
+#[cfg(all(feature = "unstable", target_os = "linux"))]
+fn read_sysfs(dir: &std::path::Path, name: &str) -> Option<u64> {
+    std::fs::read_to_string(dir.join(name))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Looks up a block device's sectors in sysfs. Partitions keep their queue limits in the
+/// directory of their parent device.
+#[cfg(all(feature = "unstable", target_os = "linux"))]
+fn block_device_sectors(f: &File) -> Option<super::SectorSize> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let meta = f.metadata().ok()?;
+    if !meta.file_type().is_block_device() {
+        return None;
+    }
+    let dev = meta.rdev();
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let dir = std::path::PathBuf::from(format!("/sys/dev/block/{}:{}", major, minor));
+    let queue = if dir.join("queue").is_dir() {
+        dir.join("queue")
+    } else {
+        dir.join("../queue")
+    };
+    Some(super::SectorSize {
+        logical:          read_sysfs(&queue, "logical_block_size")?,
+        physical:         read_sysfs(&queue, "physical_block_size")?,
+        alignment_offset: read_sysfs(&dir, "alignment_offset").unwrap_or(0),
+    })
+}
+
 impl DiskObj for DiskFile {
     #[cfg(feature = "stable")]
     fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> AMResult<usize> {
@@ -69,11 +99,23 @@ impl DiskObj for DiskFile {
     }
     #[cfg(feature = "unstable")]
     fn size(&self) -> AMResult<u64> {
-        Ok(self.size / (BLOCK_SIZE as u64))
+        // Asked each time, as the file can grow under us
+        Ok(self.f.metadata()?.len() / (BLOCK_SIZE as u64))
     }
     #[cfg(feature = "stable")]
     fn sync(&mut self) -> AMResult<()> {
         self.f.sync_all().or(Err(AMError::TODO(0)))?;
         Ok(())
     }
+    #[cfg(feature = "unstable")]
+    fn barrier(&mut self) -> AMResult<()> {
+        // The file's metadata doesn't change, only its data needs to reach the disk
+        self.f.sync_data().or(Err(AMError::TODO(0)))?;
+        Ok(())
+    }
+    #[cfg(all(feature = "unstable", target_os = "linux"))]
+    fn sector_size(&self) -> AMResult<super::SectorSize> {
+        // Regular files go through the page cache, so any sector size works for them
+        Ok(block_device_sectors(&self.f).unwrap_or_default())
+    }
 }
@@ -1,27 +1,63 @@
-use std::{cell::RefCell, convert::TryFrom, rc::Rc};
+use std::{
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+};
 
 use amos_std::{error::AMError, AMResult};
 
-use crate::{disk::DiskObj, BLOCK_SIZE};
+use crate::{
+    disk::{DiskObj, SectorSize},
+    BLOCK_SIZE,
+};
 
 /// A disk object stored in a file.
+#[derive(Clone)]
 pub struct DiskMem {
-    data: Vec<[u8; BLOCK_SIZE]>,
-    size: u64,
+    data:   Vec<[u8; BLOCK_SIZE]>,
+    size:   u64,
+    sector: SectorSize,
 }
 
 impl DiskMem {
     /// Creates a disk object using a filename.
     #[cfg(feature = "stable")]
     pub fn open(size: usize) -> super::Disk {
-        let mut data = Vec::new();
-        for _ in 0..size {
-            data.push([0; BLOCK_SIZE]);
+        super::Disk::new(Arc::new(Mutex::new(DiskMem::new(size))))
+    }
+    /// Creates a zeroed disk object, for callers that keep hold of it alongside the handle.
+    #[cfg(feature = "unstable")]
+    pub fn new(size: usize) -> Self {
+        DiskMem {
+            data:   vec![[0; BLOCK_SIZE]; size],
+            size:   size as u64,
+            sector: SectorSize::default(),
         }
-        super::Disk(Rc::new(RefCell::new(DiskMem {
-            data,
-            size: size as u64,
-        })))
+    }
+    /// Sets the sector geometry the disk reports, to stand in for 512e or 4Kn devices.
+    #[cfg(feature = "unstable")]
+    pub fn set_sector_size(&mut self, sector: SectorSize) {
+        self.sector = sector;
+    }
+    /// Copies the current contents. The copy is itself a disk that can be opened.
+    #[cfg(feature = "unstable")]
+    pub fn snapshot(&self) -> Self {
+        self.clone()
+    }
+    /// Puts back the contents of a snapshot taken from this disk.
+    #[cfg(feature = "unstable")]
+    pub fn restore(&mut self, snapshot: &Self) -> AMResult<()> {
+        assert_or_err!(snapshot.size == self.size, AMError::TODO(0));
+        self.data.clone_from(&snapshot.data);
+        Ok(())
+    }
+    /// Lists the blocks whose contents differ from a snapshot's.
+    #[cfg(feature = "unstable")]
+    pub fn changed_blocks(&self, snapshot: &Self) -> Vec<u64> {
+        (0..self.size)
+            .zip(self.data.iter().zip(snapshot.data.iter()))
+            .filter(|(_, (a, b))| a != b)
+            .map(|(i, _)| i)
+            .collect()
     }
 }
 
@@ -48,4 +84,54 @@ impl DiskObj for DiskMem {
     fn sync(&mut self) -> AMResult<()> {
         Ok(())
     }
+    #[cfg(feature = "unstable")]
+    fn barrier(&mut self) -> AMResult<()> {
+        Ok(())
+    }
+    #[cfg(feature = "unstable")]
+    fn discard(&mut self, start: u64, count: u64) -> AMResult<()> {
+        for block in start..start + count {
+            *self
+                .data
+                .get_mut(usize::try_from(block).or(Err(AMError::TODO(0)))?)
+                .ok_or(AMError::TODO(0))? = [0; BLOCK_SIZE];
+        }
+        Ok(())
+    }
+    #[cfg(feature = "unstable")]
+    fn sector_size(&self) -> AMResult<SectorSize> {
+        Ok(self.sector)
+    }
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_snapshot_restore() {
+    crate::test::logging::init_log();
+
+    let mem = Arc::new(Mutex::new(DiskMem::new(1000)));
+    let d = super::Disk::new(mem.clone());
+    crate::operations::mkfs_single(d.clone()).unwrap();
+    let before = mem.lock().unwrap().snapshot();
+
+    let fs = crate::FSHandle::open(&[d.clone()]).unwrap();
+    fs.create_object(100, 200).unwrap();
+    fs.commit().unwrap();
+    let changed = mem.lock().unwrap().changed_blocks(&before);
+    let headers: Vec<u64> = d
+        .get_header_locs()
+        .unwrap()
+        .iter()
+        .map(|p| p.loc())
+        .collect();
+    assert!(changed.iter().any(|b| headers.contains(b)));
+
+    mem.lock().unwrap().restore(&before).unwrap();
+    assert!(mem.lock().unwrap().changed_blocks(&before).is_empty());
+    let fs = crate::FSHandle::open(&[d]).unwrap();
+    assert!(fs.size_object(100).is_err());
+
+    let replay = super::Disk::new(Arc::new(Mutex::new(before)));
+    crate::FSHandle::open(&[replay]).unwrap();
+    assert!(mem.lock().unwrap().restore(&DiskMem::new(10)).is_err());
 }
@@ -1,12 +1,52 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    convert::TryFrom,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
 
 use amos_std::{error::AMError, AMResult};
 
-use crate::AMPointerLocal;
+use crate::{AMPointerLocal, BLOCK_SIZE};
 
-/// A handle to a disk
+/// A handle to a disk. Clones share the underlying disk object and its IO statistics.
 #[derive(Clone)]
-pub struct Disk(pub Rc<RefCell<dyn DiskObj>>);
+pub struct Disk(pub Arc<Mutex<dyn DiskObj>>, Arc<Mutex<DiskStats>>);
+
+/// IO counts and byte totals for one disk handle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskStats {
+    /// Successful reads
+    pub reads:         u64,
+    /// Successful writes
+    pub writes:        u64,
+    /// Successful syncs
+    pub syncs:         u64,
+    /// Bytes read
+    pub bytes_read:    u64,
+    /// Bytes written
+    pub bytes_written: u64,
+}
+
+/// Sector geometry reported by a disk, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectorSize {
+    /// Smallest unit the disk can address
+    pub logical:          u64,
+    /// Smallest unit the disk writes atomically. Smaller writes are read-modify-write.
+    pub physical:         u64,
+    /// How far the start of the disk lies from a physical sector boundary
+    pub alignment_offset: u64,
+}
+
+impl Default for SectorSize {
+    #[cfg(feature = "unstable")]
+    fn default() -> Self {
+        SectorSize {
+            logical:          512,
+            physical:         512,
+            alignment_offset: 0,
+        }
+    }
+}
 
 impl std::fmt::Debug for Disk {
     #[cfg(feature = "unstable")]
@@ -16,31 +56,89 @@ impl std::fmt::Debug for Disk {
 }
 
 impl Disk {
+    /// Wraps a disk object in a handle with fresh IO statistics.
+    #[cfg(feature = "stable")]
+    pub fn new(obj: Arc<Mutex<dyn DiskObj>>) -> Self {
+        Disk(obj, Arc::default())
+    }
     /// Reads a given block into the buffer.
     #[cfg(feature = "stable")]
     pub fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> AMResult<usize> {
-        self.0.borrow_mut().read_at(block, buffer)
+        let n = self.obj()?.read_at(block, buffer)?;
+        self.update_stats(|s| {
+            s.reads += 1;
+            s.bytes_read += u64::try_from(n)?;
+            Ok(())
+        })?;
+        Ok(n)
     }
     /// Writes a block to a given location.
     #[cfg(feature = "stable")]
     pub fn write_at(&mut self, block: u64, buffer: &[u8]) -> AMResult<usize> {
-        self.0.borrow_mut().write_at(block, buffer)
+        let n = self.obj()?.write_at(block, buffer)?;
+        self.update_stats(|s| {
+            s.writes += 1;
+            s.bytes_written += u64::try_from(n)?;
+            Ok(())
+        })?;
+        Ok(n)
     }
     /// Returns the size of the disk.
     #[cfg(feature = "stable")]
     pub fn size(&self) -> AMResult<u64> {
-        self.0.borrow_mut().size()
+        self.obj()?.size()
     }
     /// Syncs the FS's content to disk.
     #[cfg(feature = "stable")]
     pub fn sync(&mut self) -> AMResult<()> {
-        self.0.borrow_mut().sync()
+        self.obj()?.sync()?;
+        self.update_stats(|s| {
+            s.syncs += 1;
+            Ok(())
+        })
+    }
+    /// Orders writes: everything written before the barrier is stable before anything after it.
+    #[cfg(feature = "unstable")]
+    pub fn barrier(&mut self) -> AMResult<()> {
+        self.obj()?.barrier()
+    }
+    /// Tells the disk a range of blocks is unused, so it can drop their contents.
+    #[cfg(feature = "unstable")]
+    pub fn discard(&mut self, start: u64, count: u64) -> AMResult<()> {
+        self.obj()?.discard(start, count)
     }
 
+    /// Returns the disk's sector geometry.
+    #[cfg(feature = "unstable")]
+    pub fn sector_size(&self) -> AMResult<SectorSize> {
+        self.obj()?.sector_size()
+    }
+    /// Checks blocks can be written to the disk whole. Fails if blocks don't split into logical
+    /// sectors, and warns if block writes, including those of the superblocks, can tear because
+    /// they don't line up with physical sectors.
+    #[cfg(feature = "unstable")]
+    pub fn check_alignment(&self) -> AMResult<()> {
+        let sectors = self.sector_size()?;
+        let block_size = u64::try_from(BLOCK_SIZE)?;
+        assert_or_err!(
+            sectors.logical != 0 && block_size % sectors.logical == 0,
+            AMError::TODO(0)
+        );
+        if sectors.physical == 0
+            || block_size % sectors.physical != 0
+            || sectors.alignment_offset % sectors.physical != 0
+        {
+            warn!(
+                "Blocks don't line up with {} byte physical sectors (offset {}), writes may tear",
+                sectors.physical, sectors.alignment_offset
+            );
+        }
+        Ok(())
+    }
     /// Calculates the expected position of a disk's headers.
     #[cfg(feature = "unstable")]
     pub fn get_header_locs(&self) -> AMResult<[AMPointerLocal; 4]> {
-        let size = self.0.borrow().size()?;
+        let size = self.obj()?.size()?;
         if size < 4 {
             return Err(AMError::TODO(0).into());
         }
@@ -51,10 +149,33 @@ impl Disk {
         res[3].set_loc(size - 1);
         Ok(res)
     }
+
+    /// Returns the IO statistics gathered through this handle and its clones.
+    #[cfg(feature = "unstable")]
+    pub fn stats(&self) -> DiskStats {
+        *self.1.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Zeroes the IO statistics.
+    #[cfg(feature = "unstable")]
+    pub fn reset_stats(&self) {
+        *self.1.lock().unwrap_or_else(PoisonError::into_inner) = DiskStats::default();
+    }
+    #[cfg(feature = "stable")]
+    fn update_stats(&self, f: impl FnOnce(&mut DiskStats) -> AMResult<()>) -> AMResult<()> {
+        let mut stats = self.stats();
+        f(&mut stats)?;
+        *self.1.lock().unwrap_or_else(PoisonError::into_inner) = stats;
+        Ok(())
+    }
+    #[cfg(feature = "stable")]
+    fn obj(&self) -> AMResult<MutexGuard<'_, dyn DiskObj + 'static>> {
+        self.0.lock().or(Err(AMError::Poison.into()))
+    }
 }
 
 /// A disk object. Has a size, supports reading/writing of blocks.
-pub trait DiskObj {
+/// Disks are shared between threads, so they have to be `Send`.
+pub trait DiskObj: Send {
     /// Reads a given block into the buffer.
     fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> AMResult<usize>;
     /// Writes a block to a given location.
@@ -63,12 +184,168 @@ pub trait DiskObj {
     fn size(&self) -> AMResult<u64>;
     /// Syncs the FS's content to disk.
     fn sync(&mut self) -> AMResult<()>;
+    /// Orders writes: everything written before the barrier is stable before anything after it.
+    /// Disks with no cheaper way to order writes flush them all.
+    fn barrier(&mut self) -> AMResult<()> {
+        self.sync()
+    }
+    /// Tells the disk a range of blocks is unused. Their contents are undefined afterwards.
+    /// Disks with no way to drop blocks ignore it.
+    fn discard(&mut self, _start: u64, _count: u64) -> AMResult<()> {
+        Ok(())
+    }
+    /// Returns the disk's sector geometry. Disks that can't tell report 512 byte sectors.
+    fn sector_size(&self) -> AMResult<SectorSize> {
+        Ok(SectorSize::default())
+    }
 }
 
-pub use diskgroup::DiskGroup;
+pub use delay::{DelayOptions, DiskDelay};
+pub use diskgroup::{AllocHint, DiskGroup, Placement};
+pub use faulty::DiskFaulty;
 pub use file::DiskFile;
 pub use mem::DiskMem;
 
+pub mod delay;
 pub mod diskgroup;
+pub mod faulty;
 pub mod file;
 pub mod mem;
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_commit_barriers() {
+    crate::test::logging::init_log();
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Op {
+        Write(u64),
+        Barrier,
+        Sync,
+    }
+    struct Recorder {
+        inner: Disk,
+        log:   Arc<Mutex<Vec<Op>>>,
+    }
+    impl DiskObj for Recorder {
+        fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> AMResult<usize> {
+            self.inner.read_at(block, buffer)
+        }
+        fn write_at(&mut self, block: u64, buffer: &[u8]) -> AMResult<usize> {
+            self.log.lock().unwrap().push(Op::Write(block));
+            self.inner.write_at(block, buffer)
+        }
+        fn size(&self) -> AMResult<u64> {
+            self.inner.size()
+        }
+        fn sync(&mut self) -> AMResult<()> {
+            self.log.lock().unwrap().push(Op::Sync);
+            self.inner.sync()
+        }
+        fn barrier(&mut self) -> AMResult<()> {
+            self.log.lock().unwrap().push(Op::Barrier);
+            self.inner.barrier()
+        }
+    }
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let d = Disk::new(Arc::new(Mutex::new(Recorder {
+        inner: crate::DiskMem::open(1000),
+        log:   log.clone(),
+    })));
+    crate::operations::mkfs_single(d.clone()).unwrap();
+    let fs = crate::FSHandle::open(&[d.clone()]).unwrap();
+    fs.create_object(1, 200).unwrap();
+    log.lock().unwrap().clear();
+    fs.commit().unwrap();
+
+    let headers: Vec<u64> = d
+        .get_header_locs()
+        .unwrap()
+        .iter()
+        .map(|p| p.loc())
+        .collect();
+    let is_sb = |op: &Op| matches!(op, Op::Write(b) if headers.contains(b));
+    let log = log.lock().unwrap();
+    let first_sb = log.iter().position(is_sb).unwrap();
+    let last_sb = log.iter().rposition(is_sb).unwrap();
+    // Everything else is written and fenced off before the first superblock
+    assert!(log[..first_sb].contains(&Op::Barrier));
+    // Superblock copies go out in two phases with a sync between them
+    assert!(log[first_sb..=last_sb]
+        .iter()
+        .all(|op| is_sb(op) || *op == Op::Sync));
+    assert_eq!(log.last(), Some(&Op::Sync));
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_disk_stats() {
+    crate::test::logging::init_log();
+
+    let mut d = crate::DiskMem::open(10);
+    let copy = d.clone();
+    let mut buf = [0u8; crate::BLOCK_SIZE];
+    d.write_at(1, &[1; crate::BLOCK_SIZE]).unwrap();
+    d.read_at(1, &mut buf).unwrap();
+    d.sync().unwrap();
+    let expected = DiskStats {
+        reads:         1,
+        writes:        1,
+        syncs:         1,
+        bytes_read:    crate::BLOCK_SIZE as u64,
+        bytes_written: crate::BLOCK_SIZE as u64,
+    };
+    assert_eq!(copy.stats(), expected);
+    copy.reset_stats();
+    assert_eq!(d.stats(), DiskStats::default());
+
+    let ds = [crate::DiskMem::open(200), crate::DiskMem::open(300)];
+    crate::operations::mkfs_multi(&ds, crate::GeometryFlavor::Striped).unwrap();
+    let fs = crate::FSHandle::open(&ds).unwrap();
+    for d in &ds {
+        d.reset_stats();
+    }
+    for id in 0..4 {
+        fs.create_object(id, 0).unwrap();
+        fs.write_object(id, 0, &[1; 40 * crate::BLOCK_SIZE])
+            .unwrap();
+    }
+    fs.commit().unwrap();
+    let stats = fs.disk_stats().unwrap();
+    assert_eq!(stats.len(), 2);
+    assert!(stats.values().all(|s| s.writes > 0 && s.syncs > 0));
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_sector_size() {
+    crate::test::logging::init_log();
+
+    let with_sectors = |logical, physical, alignment_offset| {
+        let mut mem = crate::DiskMem::new(1000);
+        mem.set_sector_size(SectorSize {
+            logical,
+            physical,
+            alignment_offset,
+        });
+        Disk::new(Arc::new(Mutex::new(mem)))
+    };
+    assert_eq!(
+        crate::DiskMem::open(10).sector_size().unwrap(),
+        SectorSize::default()
+    );
+
+    // 512e and 4Kn devices, and misaligned ones, which only warn
+    for (logical, physical, offset) in [(512, 4096, 0), (4096, 4096, 0), (512, 4096, 512)] {
+        let d = with_sectors(logical, physical, offset);
+        d.check_alignment().unwrap();
+        crate::operations::mkfs_single(d.clone()).unwrap();
+        crate::FSHandle::open(&[d]).unwrap();
+    }
+
+    // Blocks have to be whole logical sectors
+    let d = with_sectors(8192, 8192, 0);
+    assert!(d.check_alignment().is_err());
+    assert!(crate::operations::mkfs_single(d).is_err());
+}
@@ -16,3 +16,7 @@
 //! If we are migrating away from the old geometry, blocks are rewritten in the background to match the new geometry.
 //!
 //! Once all old blocks are rewritten, the old geometry is removed from the geometry table, and any disks present only in the old geometry can be removed.
+//!
+//! Each geometry lists the IDs of its devices. The first 256 are held in the geometry's own block,
+//! ended by a zero when there are fewer. Longer lists record their length after those,
+//! and continue in a chain of blocks holding 510 IDs each, which needs the `DeviceTable` feature.
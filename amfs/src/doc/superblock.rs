@@ -26,10 +26,18 @@
 //!
 //! Each superblock contains the device ID of the disk on which it resides.
 //!
+//! Superblocks also identify the volume as a whole: a UUID, a label of up to 64 bytes, its creation time, and when and how many times it's been mounted.
+//!
+//! Superblocks record the on-disk format version they were written with. Disks from older versions are brought up to date with [operations::upgrade](crate::operations::upgrade) before mounting.
+//!
 //! Superblocks contain a set of [feature flags](crate::AMFeatures), allowing compatibility tests between the on-disk format and the loaded driver.
+//! The flags are split into [classes](crate::FeatureClass) by bit: a driver refuses disks with unknown incompatible features, mounts those with unknown read-only compatible features read-only, and ignores unknown compatible features.
 //!
 //! Since superblocks aren't referenced by pointers, they contain a built-in checksum to enable integrity checking.
+//! They also carry a generation, bumped by every update, so a copy an interrupted update didn't reach is recognised as stale even though its checksum holds.
 //!
 //! Superblocks contain an array of up to 16 geometries. This allows the pool to be reconfigured while online. (see [doc::geometry](crate::doc::geometry) for more details)
 //!
 //! Superblocks contain an array of 128 pointers to a root [FSGroup](crate::FSGroup). When loading, if the latest is not valid, we walk backwards until we encounter a valid one.
+//! On volumes of several disks, each root is written once per disk, and each disk's superblocks point at its own copy, so a damaged disk doesn't take the root with it.
+//! Old roots can also be forgotten sooner, by a [Retention](crate::Retention) policy recorded by mkfs and overridable per mount.
@@ -0,0 +1,147 @@
+use std::sync::Mutex;
+
+use amos_std::{error::AMError, AMResult};
+
+use crate::AMPointerGlobal;
+
+/// Called with the transaction ID of each commit once it is on disk
+pub type CommitHook = Box<dyn Fn(u128) + Send + Sync>;
+/// Called with the pointer of each block that fails its checksum
+pub type CorruptionHook = Box<dyn Fn(AMPointerGlobal) + Send + Sync>;
+/// Called with the number of free blocks when it drops below a threshold
+pub type SpaceLowHook = Box<dyn Fn(u64) + Send + Sync>;
+
+/// A space-low hook, with whether it has fired since free space last reached its threshold
+struct SpaceLow {
+    threshold: u64,
+    fired:     bool,
+    hook:      SpaceLowHook,
+}
+
+/// Callbacks registered on a filesystem, shared with its disk groups.
+/// Hooks run on the thread that noticed the event, often with the filesystem locked,
+/// so they must not call back into the handle.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    commit:     Mutex<Vec<CommitHook>>,
+    corruption: Mutex<Vec<CorruptionHook>>,
+    space_low:  Mutex<Vec<SpaceLow>>,
+}
+
+impl Hooks {
+    /// Registers a commit hook
+    #[cfg(feature = "unstable")]
+    pub(crate) fn add_commit(&self, hook: CommitHook) -> AMResult<()> {
+        self.commit.lock().or(Err(AMError::Poison))?.push(hook);
+        Ok(())
+    }
+    /// Registers a corruption hook
+    #[cfg(feature = "unstable")]
+    pub(crate) fn add_corruption(&self, hook: CorruptionHook) -> AMResult<()> {
+        self.corruption.lock().or(Err(AMError::Poison))?.push(hook);
+        Ok(())
+    }
+    /// Registers a space-low hook for a threshold in blocks
+    #[cfg(feature = "unstable")]
+    pub(crate) fn add_space_low(&self, threshold: u64, hook: SpaceLowHook) -> AMResult<()> {
+        self.space_low
+            .lock()
+            .or(Err(AMError::Poison))?
+            .push(SpaceLow {
+                threshold,
+                fired: false,
+                hook,
+            });
+        Ok(())
+    }
+    /// Runs the commit hooks. A hook that panicked earlier poisons its list, which stops all of them.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn committed(&self, txid: u128) {
+        if let Ok(hooks) = self.commit.lock() {
+            for hook in hooks.iter() {
+                hook(txid);
+            }
+        }
+    }
+    /// Runs the corruption hooks
+    #[cfg(feature = "unstable")]
+    pub(crate) fn corrupted(&self, ptr: AMPointerGlobal) {
+        if let Ok(hooks) = self.corruption.lock() {
+            for hook in hooks.iter() {
+                hook(ptr);
+            }
+        }
+    }
+    /// Runs each space-low hook whose threshold free space has just dropped below.
+    /// A hook fires once per drop, and is rearmed when free space climbs back to its threshold.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn free_space(&self, free: u64) {
+        if let Ok(mut hooks) = self.space_low.lock() {
+            for h in hooks.iter_mut() {
+                if free >= h.threshold {
+                    h.fired = false;
+                } else if !h.fired {
+                    h.fired = true;
+                    (h.hook)(free);
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Hooks {
+    #[cfg(feature = "unstable")]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks").finish_non_exhaustive()
+    }
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_hooks(mut d: crate::Disk) {
+    use std::sync::Arc;
+
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    let commits = Arc::new(Mutex::new(Vec::new()));
+    let sink = commits.clone();
+    fs.on_commit(Box::new(move |txid| sink.lock().unwrap().push(txid)))
+        .unwrap();
+    let low = Arc::new(Mutex::new(Vec::new()));
+    let sink = low.clone();
+    fs.on_space_low(
+        u64::MAX,
+        Box::new(move |free| sink.lock().unwrap().push(free)),
+    )
+    .unwrap();
+
+    fs.create_object(100, 0).unwrap();
+    fs.commit().unwrap();
+    fs.commit().unwrap();
+    let commits = commits.lock().unwrap().clone();
+    assert_eq!(commits.len(), 2);
+    assert_eq!(commits[1], commits[0] + 1);
+    // The threshold is never reached, so the hook fires only on the first drop
+    assert_eq!(low.lock().unwrap().len(), 1);
+
+    let corrupt = Arc::new(Mutex::new(Vec::new()));
+    let sink = corrupt.clone();
+    fs.on_corruption(Box::new(move |ptr| sink.lock().unwrap().push(ptr)))
+        .unwrap();
+    fs.write_object(100, 0, &[1; crate::BLOCK_SIZE]).unwrap();
+    fs.commit().unwrap();
+    let ptr = fs
+        .read()
+        .unwrap()
+        .get_objects()
+        .unwrap()
+        .get_object(100)
+        .unwrap()
+        .unwrap()
+        .frags()[0]
+        .pointer;
+    d.write_at(ptr.loc(), &[0; crate::BLOCK_SIZE]).unwrap();
+    d.sync().unwrap();
+    let report = crate::operations::scrub(&fs).unwrap();
+    assert_eq!(report.damaged.len(), 1);
+    assert_eq!(*corrupt.lock().unwrap(), vec![ptr]);
+}
@@ -16,13 +16,56 @@ macro_rules! set {
     };
 }
 
+/// First feature bit of the read-only compatible region. Bits below it are incompatible.
+pub const RO_COMPAT_START: usize = 1024;
+/// First feature bit of the compatible region
+pub const COMPAT_START: usize = 1536;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug)]
+/// How a driver must treat a disk using a feature it doesn't know.
+/// Ordered from least to most restrictive.
+pub enum FeatureClass {
+    /// The disk can be mounted as usual
+    Compat,
+    /// The disk can only be mounted read-only
+    ReadOnlyCompat,
+    /// The disk can't be mounted
+    Incompat,
+}
+
+impl FeatureClass {
+    /// Gets the class of a feature bit from the region it lies in
+    #[cfg(feature = "stable")]
+    pub fn of(bit: usize) -> FeatureClass {
+        if bit >= COMPAT_START {
+            FeatureClass::Compat
+        } else if bit >= RO_COMPAT_START {
+            FeatureClass::ReadOnlyCompat
+        } else {
+            FeatureClass::Incompat
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, EnumIter, Debug)]
-/// The set of defined AMFS features
+/// The set of defined AMFS features. Their values are their bits, which decide their class.
 pub enum AMFeatures {
     /// The base feature, always true
     Base,
     /// The never feature, always false
     Never,
+    /// Objects may carry flags in the object tree. Set once any object is given flags.
+    ObjectFlags,
+    /// Pointers may address extents longer than 255 blocks. Set once one is allocated.
+    LongExtents,
+    /// Geometries may list more than 256 devices, continuing their device table across blocks
+    DeviceTable,
+    /// Objects may keep prior versions, listed from each root. Set once any object is versioned.
+    Versions,
+    /// Allocators may be stored as bitmaps rather than extent lists. Set once one is.
+    BitmapAllocators,
+    /// Allocators may keep a zone for metadata apart from data. Set when made with one.
+    ZonedAllocators,
 }
 
 impl AMFeatures {
@@ -43,6 +86,18 @@ impl AMFeatures {
             .map(|x| *x as usize)
             .collect::<BTreeSet<usize>>()
     }
+    /// Returns every feature this driver can mount, including those only set once used
+    #[cfg(feature = "unstable")]
+    pub fn supported_set() -> BTreeSet<usize> {
+        let mut res = AMFeatures::current_set();
+        res.insert(AMFeatures::ObjectFlags as usize);
+        res.insert(AMFeatures::LongExtents as usize);
+        res.insert(AMFeatures::DeviceTable as usize);
+        res.insert(AMFeatures::Versions as usize);
+        res.insert(AMFeatures::BitmapAllocators as usize);
+        res.insert(AMFeatures::ZonedAllocators as usize);
+        res
+    }
     /// Converts a bit array to a set of features
     #[cfg(feature = "stable")]
     pub fn bit2set(map: &BitArr!(for 2048)) -> BTreeSet<AMFeatures> {
@@ -0,0 +1,179 @@
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use amos_std::AMResult;
+
+use crate::{FSHandle, BLOCK_SIZE};
+
+/// A cursor over a single object, usable wherever std::io streams are expected
+#[derive(Clone, Debug)]
+pub struct ObjectFile {
+    fs:     FSHandle,
+    id:     u64,
+    pos:    u64,
+    /// Read straight from disk, leaving the block cache alone
+    direct: bool,
+}
+
+impl ObjectFile {
+    /// Opens an object, with the cursor at its start. Reads go through the block cache.
+    #[cfg(feature = "unstable")]
+    pub fn open(fs: FSHandle, id: u64) -> ObjectFile {
+        ObjectFile {
+            fs,
+            id,
+            pos: 0,
+            direct: false,
+        }
+    }
+    /// Opens an object for direct IO, with the cursor at its start. Reads bypass the block
+    /// cache, so streaming through a large object doesn't evict blocks other readers need.
+    /// Writes never go through the cache either way.
+    #[cfg(feature = "unstable")]
+    pub fn open_direct(fs: FSHandle, id: u64) -> ObjectFile {
+        ObjectFile {
+            direct: true,
+            ..ObjectFile::open(fs, id)
+        }
+    }
+    /// Gets the ID of the underlying object
+    #[cfg(feature = "unstable")]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    /// Returns whether reads bypass the block cache
+    #[cfg(feature = "unstable")]
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+    /// Reads from the cursor a block at a time through the block cache
+    #[cfg(feature = "unstable")]
+    fn read_cached(&self, buf: &mut [u8]) -> AMResult<u64> {
+        let size = self.fs.size_object(self.id)?;
+        if self.pos >= size {
+            return Ok(0);
+        }
+        let len = std::cmp::min(buf.len(), usize::try_from(size - self.pos)?);
+        let block_size = u64::try_from(BLOCK_SIZE)?;
+        let mut done = 0;
+        while done < len {
+            let pos = self.pos + u64::try_from(done)?;
+            let block = self.fs.read_object_block(self.id, pos / block_size)?;
+            let offs = usize::try_from(pos % block_size)?;
+            let n = std::cmp::min(BLOCK_SIZE - offs, len - done);
+            buf[done..done + n].copy_from_slice(&block[offs..offs + n]);
+            done += n;
+        }
+        Ok(u64::try_from(len)?)
+    }
+}
+
+/// Converts a filesystem error into an IO error
+#[cfg(feature = "unstable")]
+fn to_io<E: std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{:?}", e))
+}
+
+impl Read for ObjectFile {
+    #[cfg(feature = "unstable")]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = if self.direct {
+            self.fs.read_object(self.id, self.pos, buf)
+        } else {
+            self.read_cached(buf)
+        }
+        .map_err(to_io)?;
+        self.pos += n;
+        usize::try_from(n).map_err(to_io)
+    }
+}
+
+impl Write for ObjectFile {
+    #[cfg(feature = "unstable")]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self
+            .fs
+            .write_object(self.id, self.pos, buf)
+            .map_err(to_io)?;
+        self.pos += n;
+        usize::try_from(n).map_err(to_io)
+    }
+    #[cfg(feature = "unstable")]
+    fn flush(&mut self) -> io::Result<()> {
+        self.fs.commit().map_err(to_io)
+    }
+}
+
+impl Seek for ObjectFile {
+    #[cfg(feature = "unstable")]
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::Current(n) => (self.pos, n),
+            SeekFrom::End(n) => (self.fs.size_object(self.id).map_err(to_io)?, n),
+        };
+        let new_pos = if offset < 0 {
+            base.checked_sub(offset.unsigned_abs())
+        } else {
+            base.checked_add(offset.unsigned_abs())
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_objectfile(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    fs.create_object(1, 8).unwrap();
+    let mut f = ObjectFile::open(fs.clone(), 1);
+    f.write_all(&[1, 2, 3, 4]).unwrap();
+    assert_eq!(f.seek(SeekFrom::Current(-2)).unwrap(), 2);
+    let mut buf = [0u8; 2];
+    f.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [3, 4]);
+    assert_eq!(f.seek(SeekFrom::End(-1)).unwrap(), 7);
+    assert!(f.seek(SeekFrom::Current(-8)).is_err());
+    f.seek(SeekFrom::Start(0)).unwrap();
+    let mut contents = Vec::new();
+    f.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, [1, 2, 3, 4, 0, 0, 0, 0]);
+    f.flush().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_objectfile_direct(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    fs.create_object(1, 0).unwrap();
+    let data: Vec<u8> = (0..BLOCK_SIZE * 3)
+        .map(|i| u8::try_from(i % 251).unwrap())
+        .collect();
+    fs.write_object(1, 0, &data).unwrap();
+    fs.commit().unwrap();
+
+    let mut f = ObjectFile::open_direct(fs.clone(), 1);
+    assert!(f.is_direct());
+    let mut contents = Vec::new();
+    f.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, data);
+    let hits = fs.metrics().cache_hits;
+    fs.read_object_block(1, 1).unwrap();
+    assert_eq!(fs.metrics().cache_hits, hits);
+
+    let mut f = ObjectFile::open(fs.clone(), 1);
+    f.seek(SeekFrom::Start(100)).unwrap();
+    let mut buf = vec![0u8; BLOCK_SIZE * 2];
+    f.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data[100..100 + BLOCK_SIZE * 2]);
+    assert_eq!(fs.metrics().cache_hits, hits + 1);
+}
@@ -1,7 +1,11 @@
 use std::{
     collections::{BTreeMap, BTreeSet, VecDeque},
-    convert::TryInto,
-    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    convert::{TryFrom, TryInto},
+    io::{IoSlice, IoSliceMut},
+    ops::Range,
+    sync::{mpsc, Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak},
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use amos_std::{
@@ -10,59 +14,592 @@ use amos_std::{
 };
 
 use crate::{
-    features::AMFeatures, AMPointerGlobal, Allocator, Disk, DiskGroup, FSGroup, Fragment,
-    JournalEntry, Object, ObjectSet, Superblock,
+    cache::{Advice, AlignedBlock, BlockCache, CACHE_BLOCKS, READAHEAD_BLOCKS},
+    events::{CommitHook, CorruptionHook, Hooks, SpaceLowHook},
+    features::{AMFeatures, FeatureClass},
+    locks::{LockKind, LockTable},
+    memory::{MemoryBudget, MemoryUsage, Reservation},
+    metrics::{Metrics, MetricsExporter, MetricsSnapshot},
+    AMPointerGlobal, AMPointerLocal, AllocHint, AllocStrategy, Allocator, Disk, DiskGroup,
+    DiskStats, FSGroup, Fragment, Geometry, JournalEntry, Object, ObjectSet, Placement, Superblock,
+    VersionHistory, BLOCK_SIZE, FORMAT_VERSION, INLINE_MAX, MAX_EXTENT_BLOCKS, META_OBJECTS,
 };
 
+/// The shortest the background committer sleeps for between checks
+const COMMIT_POLL: Duration = Duration::from_millis(10);
+
 /// A handle to a disk
 #[derive(Clone, Debug)]
-pub struct FSHandle(Arc<RwLock<AMFS>>);
+pub struct FSHandle {
+    fs:      Arc<RwLock<AMFS>>,
+    /// The object set readers see. Sets are copy-on-write, so readers never wait on changes or commits.
+    view:    Arc<RwLock<ObjectSet>>,
+    /// Shared with the filesystem, so counters can be read while a commit holds the lock
+    metrics: Arc<Metrics>,
+    /// Shared with the filesystem, so hooks can be registered while a commit holds the lock
+    hooks:   Arc<Hooks>,
+    /// Advisory byte-range locks. They only exist in memory, and only for this mount.
+    locks:   Arc<Mutex<LockTable>>,
+    /// Blocks handed out by `read_object_block`
+    cache:   Arc<Mutex<BlockCache>>,
+    /// Shared with the filesystem and the cache, so usage can be read while a commit holds the lock
+    memory:  Arc<MemoryBudget>,
+}
 
 impl FSHandle {
     /// Creates an AMFS object to mount the fs on a disk
     #[cfg(feature = "unstable")]
     pub fn open(d: &[Disk]) -> AMResult<Self> {
-        Ok(Self(Arc::new(RwLock::new(AMFS::open(d)?))))
+        Self::from_fs(AMFS::open(d)?)
+    }
+    /// Mounts the filesystem read-only, as of the root with a given transaction ID
+    #[cfg(feature = "unstable")]
+    pub fn open_at_root(d: &[Disk], txid: u128) -> AMResult<Self> {
+        Self::from_fs(AMFS::open_at_root(d, txid)?)
+    }
+    /// Wraps a mounted filesystem, publishing its object set to readers
+    #[cfg(feature = "unstable")]
+    fn from_fs(fs: AMFS) -> AMResult<Self> {
+        let view = fs.get_objects()?.clone();
+        let metrics = fs.metrics.clone();
+        let hooks = fs.hooks.clone();
+        let memory = fs.memory.clone();
+        Ok(Self {
+            fs: Arc::new(RwLock::new(fs)),
+            view: Arc::new(RwLock::new(view)),
+            metrics,
+            hooks,
+            locks: Arc::default(),
+            cache: Arc::new(Mutex::new(BlockCache::new(CACHE_BLOCKS, memory.clone()))),
+            memory,
+        })
+    }
+    /// Returns the counters gathered since mounting
+    #[cfg(feature = "unstable")]
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+    /// Sets a callback handed the counters after every commit, or removes it if `None`
+    #[cfg(feature = "unstable")]
+    pub fn set_metrics_exporter(&self, exporter: Option<MetricsExporter>) -> AMResult<()> {
+        self.metrics.set_exporter(exporter)
+    }
+    /// Returns the memory held by the cache, write buffers and journal
+    #[cfg(feature = "unstable")]
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.memory.usage()
+    }
+    /// Returns the memory budget in bytes, if there is one
+    #[cfg(feature = "unstable")]
+    pub fn memory_budget(&self) -> Option<u64> {
+        self.memory.limit()
+    }
+    /// Limits the memory held by the cache, write buffers and journal, or removes the limit if
+    /// `None`. Cached blocks are evicted to fit. Once over budget, writes first evict cached
+    /// blocks, then commit to empty the journal, and fail if that still doesn't make room.
+    #[cfg(feature = "unstable")]
+    pub fn set_memory_budget(&self, limit: Option<u64>) -> AMResult<()> {
+        self.memory.set_limit(limit);
+        self.cache.lock().or(Err(AMError::Poison))?.trim(0)
+    }
+    /// Accounts for a write buffer, making room in the memory budget for it if need be
+    #[cfg(feature = "unstable")]
+    fn reserve(&self, bytes: u64) -> AMResult<Reservation<'_>> {
+        if self.memory.over(bytes) {
+            self.cache.lock().or(Err(AMError::Poison))?.trim(bytes)?;
+        }
+        if self.memory.over(bytes) {
+            let mut handle = self.write()?;
+            if handle.dirty > 0 {
+                handle.commit()?;
+            }
+        }
+        self.memory.reserve(bytes)
+    }
+    /// Returns the IO statistics of each disk, by device ID
+    #[cfg(feature = "unstable")]
+    pub fn disk_stats(&self) -> AMResult<BTreeMap<u64, DiskStats>> {
+        Ok(self
+            .read()?
+            .disks
+            .iter()
+            .map(|(id, d)| (*id, d.stats()))
+            .collect())
+    }
+    /// Registers a callback run with the transaction ID of each commit, once it is on disk.
+    /// Hooks run with the filesystem locked, and must not call back into the handle.
+    #[cfg(feature = "unstable")]
+    pub fn on_commit(&self, hook: CommitHook) -> AMResult<()> {
+        self.hooks.add_commit(hook)
+    }
+    /// Registers a callback run with the pointer of each block found failing its checksum
+    #[cfg(feature = "unstable")]
+    pub fn on_corruption(&self, hook: CorruptionHook) -> AMResult<()> {
+        self.hooks.add_corruption(hook)
+    }
+    /// Registers a callback run with the number of free blocks when a commit leaves fewer
+    /// than `threshold`. It runs again only after free space has recovered past the threshold.
+    #[cfg(feature = "unstable")]
+    pub fn on_space_low(&self, threshold: u64, hook: SpaceLowHook) -> AMResult<()> {
+        self.hooks.add_space_low(threshold, hook)
+    }
+    /// Counts blocks brought back into a consistent state by a repair
+    #[cfg(feature = "unstable")]
+    pub(crate) fn record_repair(&self, blocks: u64) {
+        Metrics::add(&self.metrics.repaired_blocks, blocks);
     }
     /// Write changes to disk
     #[cfg(feature = "unstable")]
     pub fn commit(&self) -> AMResult<()> {
         self.write()?.commit()
     }
+    /// Commits, then pins the new root as a snapshot. Returns the snapshot's transaction ID
+    #[cfg(feature = "unstable")]
+    pub fn snapshot_create(&self) -> AMResult<u128> {
+        self.write()?.snapshot_create()
+    }
+    /// Lists the transaction IDs of every snapshot
+    #[cfg(feature = "unstable")]
+    pub fn snapshot_list(&self) -> AMResult<Vec<u128>> {
+        self.read()?.snapshot_list()
+    }
+    /// Deletes the snapshot with a given transaction ID
+    #[cfg(feature = "unstable")]
+    pub fn snapshot_delete(&self, txid: u128) -> AMResult<()> {
+        self.write()?.snapshot_delete(txid)
+    }
     /// Reads the object corresponding to a given ID
     #[cfg(feature = "stable")]
     pub fn read_object(&self, id: u64, start: u64, data: &mut [u8]) -> AMResult<u64> {
-        self.read()?.read_object(id, start, data)
+        let _span = tracing::debug_span!("read_object", id, start, len = data.len()).entered();
+        let view = self.view()?;
+        view.read_object(id, start, data, view.diskgroups())
+    }
+    /// Reads the `n`th block of the object corresponding to a given ID as a shared, page-aligned
+    /// buffer. Blocks stored whole on disk come from the block cache, so mapping them costs no
+    /// copies. Inline data, holes and the partial last block are copied into a new buffer, and
+    /// blocks past the end of the object read as zeroes.
+    #[cfg(feature = "unstable")]
+    pub fn read_object_block(&self, id: u64, n: u64) -> AMResult<Arc<AlignedBlock>> {
+        let _span = tracing::debug_span!("read_object_block", id, n).entered();
+        let view = self.view()?;
+        let obj = view.get_object(id)?.ok_or(AMErrorFS::NoObject)?;
+        if let Some((ptr, idx)) = obj.block_source(n)? {
+            let mut cache = self.cache.lock().or(Err(AMError::Poison))?;
+            if let Some(block) = cache.get(ptr, idx) {
+                Metrics::add(&self.metrics.cache_hits, 1);
+                return Ok(block);
+            }
+            let block = Self::fill_block(&mut cache, &view, ptr, idx)?;
+            if cache.is_sequential(id) {
+                Self::prefetch(&mut cache, &view, &obj, n + 1..n + 1 + READAHEAD_BLOCKS)?;
+            }
+            return Ok(block);
+        }
+        let mut block = AlignedBlock::default();
+        obj.read(
+            n * u64::try_from(BLOCK_SIZE)?,
+            &mut block.0,
+            view.diskgroups(),
+        )?;
+        Ok(Arc::new(block))
+    }
+    /// Tells the cache how a byte range of the object corresponding to a given ID will be read.
+    /// `Sequential` applies to the whole object, whatever the range.
+    #[cfg(feature = "unstable")]
+    pub fn advise(&self, id: u64, range: Range<u64>, advice: Advice) -> AMResult<()> {
+        let view = self.view()?;
+        let obj = view.get_object(id)?.ok_or(AMErrorFS::NoObject)?;
+        let block_size = u64::try_from(BLOCK_SIZE)?;
+        let end = std::cmp::min(range.end, obj.size()?);
+        let blocks = range.start / block_size..(end + block_size - 1) / block_size;
+        let mut cache = self.cache.lock().or(Err(AMError::Poison))?;
+        match advice {
+            Advice::Sequential => cache.set_sequential(id, true),
+            Advice::WillNeed => Self::prefetch(&mut cache, &view, &obj, blocks)?,
+            Advice::DontNeed => {
+                cache.set_sequential(id, false);
+                for n in blocks {
+                    if let Some((ptr, idx)) = obj.block_source(n)? {
+                        cache.remove(ptr, idx)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Reads a block of a fragment's extent into the cache
+    #[cfg(feature = "unstable")]
+    fn fill_block(
+        cache: &mut BlockCache,
+        view: &ObjectSet,
+        ptr: AMPointerGlobal,
+        idx: u64,
+    ) -> AMResult<Arc<AlignedBlock>> {
+        let mut block = AlignedBlock::default();
+        ptr.read(
+            usize::try_from(idx)? * BLOCK_SIZE,
+            BLOCK_SIZE,
+            view.diskgroups(),
+            &mut block.0,
+        )?;
+        let block = Arc::new(block);
+        cache.insert(ptr, idx, block.clone())?;
+        Ok(block)
+    }
+    /// Reads the given blocks of an object into the cache, skipping those already there and
+    /// those not stored whole
+    #[cfg(feature = "unstable")]
+    fn prefetch(
+        cache: &mut BlockCache,
+        view: &ObjectSet,
+        obj: &Object,
+        blocks: Range<u64>,
+    ) -> AMResult<()> {
+        for n in blocks {
+            if let Some((ptr, idx)) = obj.block_source(n)? {
+                if cache.get(ptr, idx).is_none() {
+                    Self::fill_block(cache, view, ptr, idx)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Reads the object corresponding to a given ID into a sequence of buffers
+    #[cfg(feature = "unstable")]
+    pub fn read_object_vectored(
+        &self,
+        id: u64,
+        start: u64,
+        bufs: &mut [IoSliceMut],
+    ) -> AMResult<u64> {
+        let _span = tracing::debug_span!("read_object", id, start, bufs = bufs.len()).entered();
+        let view = self.view()?;
+        view.read_object_vectored(id, start, bufs, view.diskgroups())
+    }
+    /// Writes a sequence of buffers to the object corresponding to a given ID
+    #[cfg(feature = "unstable")]
+    pub fn write_object_vectored(&self, id: u64, start: u64, bufs: &[IoSlice]) -> AMResult<u64> {
+        let _span = tracing::debug_span!("write_object", id, start, bufs = bufs.len()).entered();
+        let len: usize = bufs.iter().map(|b| b.len()).sum();
+        let _buffer = self.reserve(u64::try_from(len)?)?;
+        self.modify(|fs| fs.write_object_vectored(id, start, bufs))
     }
     /// Gets the size of the object corresponding to a given ID
     #[cfg(feature = "stable")]
     pub fn size_object(&self, id: u64) -> AMResult<u64> {
-        self.read()?.size_object(id)
+        self.view()?.size_object(id)
     }
     /// Writes to the object corresponding to a given ID
     #[cfg(feature = "unstable")]
     pub fn write_object(&self, id: u64, start: u64, data: &[u8]) -> AMResult<u64> {
-        self.write()?.write_object(id, start, data)
+        let _span = tracing::debug_span!("write_object", id, start, len = data.len()).entered();
+        let _buffer = self.reserve(u64::try_from(data.len())?)?;
+        self.modify(|fs| fs.write_object(id, start, data))
     }
     /// Writes to the object corresponding to a given ID
     #[cfg(feature = "unstable")]
     pub fn create_object(&self, id: u64, size: u64) -> AMResult<()> {
-        self.write()?.create_object(id, size)
+        self.modify(|fs| fs.create_object(id, size))
     }
     /// Truncates the object corresponding to a given ID
     #[cfg(feature = "unstable")]
     pub fn truncate_object(&self, id: u64, size: u64) -> AMResult<()> {
-        self.write()?.truncate_object(id, size)
+        self.modify(|fs| fs.truncate_object(id, size))
+    }
+    /// Lists every object as an (ID, size, fragment count) triple, in ID order
+    #[cfg(feature = "unstable")]
+    pub fn list_objects(&self) -> AMResult<impl Iterator<Item = (u64, u64, usize)>> {
+        self.view()?.list_objects()
+    }
+    /// Moves an object's data into consecutive blocks. Returns whether anything was moved.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn defrag_object(&self, id: u64) -> AMResult<bool> {
+        self.modify(|fs| fs.defrag_object(id))
+    }
+    /// Moves an object's data off a set of (geometry, device) pairs, and rewrites the object tree
+    /// nodes above it. Returns the extents moved, at their old places.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn rebalance_object(
+        &self,
+        id: u64,
+        sources: &BTreeSet<(u8, u8)>,
+    ) -> AMResult<Vec<AMPointerGlobal>> {
+        self.modify(|fs| fs.rebalance_object(id, sources))
+    }
+    /// Takes in space added to the end of the disks. Returns the number of blocks added.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn grow(&self) -> AMResult<u64> {
+        self.modify(|fs| fs.grow())
+    }
+    /// Moves everything to below a block and cuts the filesystem down to end there
+    #[cfg(feature = "unstable")]
+    pub(crate) fn shrink(&self, new_size: u64) -> AMResult<()> {
+        self.modify(|fs| fs.shrink(new_size))
+    }
+    /// Commits, then finds the extents that are allocated but that nothing refers to
+    #[cfg(feature = "unstable")]
+    pub(crate) fn find_orphans(&self) -> AMResult<Vec<AMPointerGlobal>> {
+        self.write()?.find_orphans()
+    }
+    /// Gathers orphaned extents into new objects. Returns the new objects' IDs.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn recover_orphans(&self) -> AMResult<Vec<u64>> {
+        self.modify(|fs| fs.recover_orphans())
+    }
+    /// Gets when the last scrub finished, as seconds since the Unix epoch, and how many damaged
+    /// fragments it found. The time is 0 if the filesystem was never scrubbed.
+    #[cfg(feature = "unstable")]
+    pub fn scrub_status(&self) -> AMResult<(u64, u64)> {
+        let root = self.read()?.get_root_group()?;
+        Ok((root.last_scrub(), root.scrub_errors()))
+    }
+    /// Records the outcome of a scrub, to be written with the next commit
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_scrub_result(&self, time: u64, errors: u64) -> AMResult<()> {
+        self.write()?.scrub = Some((time, errors));
+        Ok(())
+    }
+    /// Gets the transaction ID of the last root received from a send stream, 0 if none
+    #[cfg(feature = "unstable")]
+    pub fn received(&self) -> AMResult<u128> {
+        Ok(self.read()?.get_root_group()?.received())
+    }
+    /// Records a root received from a send stream, to be written with the next commit
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_received(&self, txid: u128) -> AMResult<()> {
+        self.write()?.received = Some(txid);
+        Ok(())
+    }
+    /// Checks whether the filesystem is mounted read-only
+    #[cfg(feature = "unstable")]
+    pub fn is_read_only(&self) -> AMResult<bool> {
+        Ok(self.read()?.read_only)
+    }
+    /// Gets the volume UUID
+    #[cfg(feature = "unstable")]
+    pub fn uuid(&self) -> AMResult<u128> {
+        Ok(self.read()?.get_superblock()?.uuid())
+    }
+    /// Gets the volume label
+    #[cfg(feature = "unstable")]
+    pub fn label(&self) -> AMResult<String> {
+        Ok(self.read()?.get_superblock()?.label())
+    }
+    /// Gets when the filesystem was made, as seconds since the Unix epoch
+    #[cfg(feature = "unstable")]
+    pub fn created(&self) -> AMResult<u64> {
+        Ok(self.read()?.get_superblock()?.created())
+    }
+    /// Gets when the filesystem was last mounted writable, as seconds since the Unix epoch
+    #[cfg(feature = "unstable")]
+    pub fn last_mount(&self) -> AMResult<u64> {
+        Ok(self.read()?.get_superblock()?.last_mount())
+    }
+    /// Gets how many times the filesystem has been mounted writable
+    #[cfg(feature = "unstable")]
+    pub fn mount_count(&self) -> AMResult<u64> {
+        Ok(self.read()?.get_superblock()?.mount_count())
+    }
+    /// Creates an object sharing the contents of another, copy-on-write
+    #[cfg(feature = "unstable")]
+    pub fn clone_object(&self, src: u64, dst: u64) -> AMResult<()> {
+        self.modify(|fs| fs.clone_object(src, dst))
+    }
+    /// Deletes the object corresponding to a given ID, releasing its data
+    #[cfg(feature = "unstable")]
+    pub fn delete_object(&self, id: u64) -> AMResult<()> {
+        self.modify(|fs| fs.delete_object(id))
+    }
+    /// Moves an object to a new ID, replacing any object already there
+    #[cfg(feature = "unstable")]
+    pub fn rename_object(&self, from: u64, to: u64) -> AMResult<()> {
+        self.modify(|fs| fs.rename_object(from, to))
+    }
+    /// Keeps up to `limit` prior versions of an object, one for each commit it was changed
+    /// after, or stops keeping them and releases those kept if 0.
+    /// Versions share unchanged extents with the object, so they cost far less than snapshots.
+    #[cfg(feature = "unstable")]
+    pub fn set_versions(&self, id: u64, limit: u64) -> AMResult<()> {
+        self.modify(|fs| fs.set_versions(id, limit))
+    }
+    /// Lists the transactions whose contents of an object are kept as versions, oldest first
+    #[cfg(feature = "unstable")]
+    pub fn list_versions(&self, id: u64) -> AMResult<Vec<u128>> {
+        Ok(self.read()?.list_versions(id))
+    }
+    /// Reads a version of an object, as it was when a given transaction was committed
+    #[cfg(feature = "unstable")]
+    pub fn read_version(&self, id: u64, txid: u128, start: u64, data: &mut [u8]) -> AMResult<u64> {
+        self.read()?.read_version(id, txid, start, data)
+    }
+    /// Applies a batch of operations under one lock acquisition, then commits them together.
+    /// The batch is checked before anything is applied, so one that can't apply changes nothing.
+    /// If it fails part way regardless, say on a disk error, its changes are dropped uncommitted.
+    #[cfg(feature = "unstable")]
+    pub fn apply(&self, ops: &[BatchOp]) -> AMResult<()> {
+        let len: usize = ops
+            .iter()
+            .map(|op| match op {
+                BatchOp::Write { data, .. } => data.len(),
+                _ => 0,
+            })
+            .sum();
+        let _buffer = self.reserve(u64::try_from(len)?)?;
+        let mut handle = self.write()?;
+        let res = handle.apply(ops);
+        *self.view.write().or(Err(AMError::Poison))? = handle.get_objects()?.clone();
+        handle.account_memory()?;
+        res
+    }
+    /// Takes an advisory lock on `len` bytes of an object from `start` for an owner, such as a
+    /// process or open file. A length of 0 locks to the end of the object, however far it grows.
+    /// Never waits: returns false, changing nothing, if another owner holds a conflicting lock.
+    #[cfg(feature = "unstable")]
+    pub fn lock_range(
+        &self,
+        id: u64,
+        owner: u64,
+        start: u64,
+        len: u64,
+        kind: LockKind,
+    ) -> AMResult<bool> {
+        assert_or_err!(self.view()?.exists_object(id)?, AMErrorFS::NoObject);
+        self.locks
+            .lock()
+            .or(Err(AMError::Poison))?
+            .lock(id, owner, start, len, kind)
+    }
+    /// Releases an owner's locks on a range of an object, keeping those on the rest
+    #[cfg(feature = "unstable")]
+    pub fn unlock_range(&self, id: u64, owner: u64, start: u64, len: u64) -> AMResult<()> {
+        self.locks
+            .lock()
+            .or(Err(AMError::Poison))?
+            .unlock(id, owner, start, len)
+    }
+    /// Releases every lock an owner holds, as when it exits
+    #[cfg(feature = "unstable")]
+    pub fn unlock_all(&self, owner: u64) -> AMResult<()> {
+        self.locks
+            .lock()
+            .or(Err(AMError::Poison))?
+            .unlock_all(owner);
+        Ok(())
+    }
+    /// Finds the owner of a lock that would stop `owner` locking a range, if any
+    #[cfg(feature = "unstable")]
+    pub fn lock_conflict(
+        &self,
+        id: u64,
+        owner: u64,
+        start: u64,
+        len: u64,
+        kind: LockKind,
+    ) -> AMResult<Option<u64>> {
+        self.locks
+            .lock()
+            .or(Err(AMError::Poison))?
+            .conflict(id, owner, start, len, kind)
+    }
+    /// Gets an object's flags
+    #[cfg(feature = "unstable")]
+    pub fn object_flags(&self, id: u64) -> AMResult<u64> {
+        Ok(self
+            .view()?
+            .get_object(id)?
+            .ok_or(AMErrorFS::NoObject)?
+            .flags())
+    }
+    /// Replaces an object's flags with a combination of `FLAG_IMMUTABLE`, `FLAG_APPEND_ONLY`
+    /// and `FLAG_NO_COMPRESS`. Flags can be changed even on immutable objects.
+    #[cfg(feature = "unstable")]
+    pub fn set_object_flags(&self, id: u64, flags: u64) -> AMResult<()> {
+        self.modify(|fs| fs.set_object_flags(id, flags))
     }
     /// Syncs the disks
     #[cfg(feature = "stable")]
     pub fn sync(&self) -> AMResult<()> {
         self.write()?.sync()
     }
+    /// Sets how a disk group picks free extents to allocate from
+    #[cfg(feature = "unstable")]
+    pub fn set_alloc_strategy(&self, group: usize, strategy: AllocStrategy) -> AMResult<()> {
+        self.write()?
+            .diskgroups
+            .get_mut(group)
+            .and_then(Option::as_mut)
+            .ok_or(AMErrorFS::NoDiskgroup)?
+            .set_strategy(strategy);
+        Ok(())
+    }
+    /// Sets how allocations are spread across disk groups. Round-robin restarts at the first.
+    #[cfg(feature = "unstable")]
+    pub fn set_placement(&self, placement: Placement) -> AMResult<()> {
+        let mut handle = self.write()?;
+        handle.placement = placement;
+        handle.next_group = 0;
+        Ok(())
+    }
+    /// Sets when changes are committed without an explicit call to commit, or disables it.
+    /// While a policy is set, a background thread commits idle changes once the interval is up.
+    #[cfg(feature = "unstable")]
+    pub fn set_commit_policy(&self, policy: Option<CommitPolicy>) -> AMResult<()> {
+        let old = {
+            let mut handle = self.write()?;
+            handle.auto_commit = policy;
+            handle.committer.take()
+        };
+        // Stopped without the lock held, as it may be waiting on it to commit
+        if let Some(committer) = old {
+            committer.stop();
+        }
+        if policy.is_some() {
+            let mut handle = self.write()?;
+            let wait = handle.commit_wait();
+            handle.committer = Some(Committer::spawn(Arc::downgrade(&self.fs), wait)?);
+        }
+        Ok(())
+    }
+    /// Sets how long old roots are retained for this mount, overriding the one recorded by mkfs
+    #[cfg(feature = "unstable")]
+    pub fn set_retention(&self, policy: Retention) -> AMResult<()> {
+        policy.check()?;
+        self.write()?.retention = policy;
+        Ok(())
+    }
+    /// Commits pending changes if the commit policy says they're due.
+    /// Changes trigger this themselves, and the background committer does once the interval is up,
+    /// so it's only needed to commit sooner than that.
+    #[cfg(feature = "unstable")]
+    pub fn poll_commit(&self) -> AMResult<()> {
+        let mut handle = self.write()?;
+        if handle.dirty > 0 && handle.commit_due() {
+            handle.commit()?;
+        }
+        Ok(())
+    }
+    /// Applies a change, then commits if the commit policy says it's due
+    #[cfg(feature = "unstable")]
+    fn modify<T, F: FnOnce(&mut AMFS) -> AMResult<T>>(&self, f: F) -> AMResult<T> {
+        let mut handle = self.write()?;
+        let res = f(&mut handle)?;
+        *self.view.write().or(Err(AMError::Poison))? = handle.get_objects()?.clone();
+        handle.account_memory()?;
+        handle.dirty += 1;
+        if handle.commit_due() {
+            handle.commit()?;
+        }
+        Ok(res)
+    }
     /// Allocates a n-block chunk
     #[cfg(feature = "stable")]
-    pub(crate) fn alloc_blocks(&mut self, n: u64) -> AMResult<Option<AMPointerGlobal>> {
-        self.write()?.alloc_blocks(n)
+    pub(crate) fn alloc_blocks(
+        &mut self,
+        n: u64,
+        hint: Option<AllocHint>,
+    ) -> AMResult<Option<AMPointerGlobal>> {
+        self.write()?.alloc_blocks(n, hint)
     }
     /// Reallocates a pointer
     #[cfg(feature = "stable")]
@@ -76,11 +613,139 @@ impl FSHandle {
     }
     #[cfg(feature = "stable")]
     pub(crate) fn write(&self) -> AMResult<RwLockWriteGuard<AMFS>> {
-        Ok(self.0.write().or(Err(AMError::Poison))?)
+        let res = self.fs.write().or(Err(AMError::Poison))?;
+        // Old roots, and disks with unknown read-only compatible features, are mounted read-only
+        assert_or_err!(!res.read_only, AMError::TODO(0));
+        Ok(res)
     }
     #[cfg(feature = "stable")]
     pub(crate) fn read(&self) -> AMResult<RwLockReadGuard<AMFS>> {
-        Ok(self.0.read().or(Err(AMError::Poison))?)
+        Ok(self.fs.read().or(Err(AMError::Poison))?)
+    }
+    /// Gets the object set as of the last change
+    #[cfg(feature = "unstable")]
+    fn view(&self) -> AMResult<ObjectSet> {
+        Ok(self.view.read().or(Err(AMError::Poison))?.clone())
+    }
+}
+
+/// When to commit without an explicit call to commit, bounding how much is lost if the application
+/// never commits. The change that makes a commit due makes it, and a background thread commits
+/// changes left idle once the interval is up. The thread stops when the policy is cleared or the
+/// filesystem is dropped, which commits whatever is still pending.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommitPolicy {
+    /// Commit once this long has passed since the last commit
+    pub interval:        Duration,
+    /// Commit once this many changes are pending
+    pub dirty_threshold: u64,
+}
+
+/// The thread committing in the background while a commit policy is set
+#[derive(Debug)]
+struct Committer {
+    /// Dropped to tell the thread to stop
+    stop:   mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Committer {
+    /// Starts a thread committing the filesystem whenever its commit policy says changes are due,
+    /// first checking after `wait`. It only holds the filesystem while committing, so it never
+    /// keeps it mounted.
+    #[cfg(feature = "unstable")]
+    fn spawn(fs: Weak<RwLock<AMFS>>, wait: Duration) -> AMResult<Committer> {
+        let (stop, stopped) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("amfs-committer".into())
+            .spawn(move || Committer::run(&fs, &stopped, wait))?;
+        Ok(Committer { stop, thread })
+    }
+    /// Sleeps until a commit could be due, and commits if one is, until told to stop or the
+    /// filesystem is dropped
+    #[cfg(feature = "unstable")]
+    fn run(fs: &Weak<RwLock<AMFS>>, stopped: &mpsc::Receiver<()>, mut wait: Duration) {
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(wait) {
+            let fs = match fs.upgrade() {
+                Some(fs) => fs,
+                None => return,
+            };
+            let mut handle = match fs.write() {
+                Ok(handle) => handle,
+                Err(_) => return,
+            };
+            if handle.dirty > 0 && handle.commit_due() {
+                if let Err(e) = handle.commit() {
+                    error!("Background commit failed: {:?}", e);
+                }
+            }
+            wait = handle.commit_wait();
+        }
+    }
+    /// Stops the thread, waiting for any commit it's making.
+    /// The thread can drop the filesystem itself, in which case it's left to finish on its own.
+    #[cfg(feature = "unstable")]
+    fn stop(self) {
+        drop(self.stop);
+        if self.thread.thread().id() != std::thread::current().id()
+            && self.thread.join().is_err()
+        {
+            error!("Background committer panicked");
+        }
+    }
+}
+
+/// An operation in a batch passed to [FSHandle::apply]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchOp<'a> {
+    /// Creates an object, replacing any object already there
+    Create {
+        /// The new object's ID
+        id:   u64,
+        /// The new object's size
+        size: u64,
+    },
+    /// Writes to an object
+    Write {
+        /// The object's ID
+        id:    u64,
+        /// Where in the object to write
+        start: u64,
+        /// The data to write
+        data:  &'a [u8],
+    },
+    /// Deletes an object
+    Delete(u64),
+    /// Moves an object to a new ID, replacing any object already there
+    Rename {
+        /// The object's ID
+        from: u64,
+        /// The ID to move it to
+        to:   u64,
+    },
+}
+
+/// How long old roots, and the blocks freed since them, are kept from reuse.
+/// Unpinned roots past either limit are forgotten by the next commit, trading how far back
+/// the filesystem can be rolled back for free space. The number of root slots bounds it either way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Forget roots more than this many transactions older than the newest. At least 1.
+    pub transactions: Option<u64>,
+    /// Forget roots committed longer ago than this. At least a second.
+    pub time:         Option<Duration>,
+}
+
+impl Retention {
+    /// Fails for limits that can't be recorded
+    #[cfg(feature = "unstable")]
+    pub(crate) fn check(&self) -> AMResult<()> {
+        assert_or_err!(self.transactions != Some(0), AMError::TODO(0));
+        assert_or_err!(
+            self.time.map_or(true, |t| t.as_secs() != 0),
+            AMError::TODO(0)
+        );
+        Ok(())
     }
 }
 
@@ -96,12 +761,42 @@ pub struct AMFS {
     journal:     VecDeque<JournalEntry>,
     objects:     Option<ObjectSet>,
     free_queue:  BTreeMap<u128, Vec<AMPointerGlobal>>,
+    refcounts:   BTreeMap<AMPointerGlobal, u64>,
     cur_txid:    u128,
+    read_only:   bool,
+    auto_commit: Option<CommitPolicy>,
+    committer:   Option<Committer>,
+    dirty:       u64,
+    last_commit: Instant,
+    scrub:       Option<(u64, u64)>,
+    received:    Option<u128>,
+    metrics:     Arc<Metrics>,
+    hooks:       Arc<Hooks>,
+    memory:      Arc<MemoryBudget>,
+    placement:   Placement,
+    /// The disk group round-robin placement tries next
+    next_group:  usize,
+    retention:   Retention,
+    versions:    BTreeMap<u64, VersionHistory>,
 }
 
 impl AMFS {
     #[cfg(feature = "unstable")]
     fn open(d: &[Disk]) -> AMResult<AMFS> {
+        let mut res = AMFS::load(d)?;
+        if res.read_only {
+            return Ok(res);
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        res.update_superblocks(|sb| sb.record_mount(time))?;
+        res.sync()?;
+        Ok(res)
+    }
+    /// Loads a filesystem from its disks without recording a mount
+    #[cfg(feature = "unstable")]
+    fn load(d: &[Disk]) -> AMResult<AMFS> {
         let mut res = AMFS {
             diskgroups:  vec![None; 16],
             disks:       BTreeMap::new(),
@@ -112,21 +807,119 @@ impl AMFS {
             journal:     VecDeque::new(),
             objects:     None,
             free_queue:  BTreeMap::new(),
+            refcounts:   BTreeMap::new(),
             cur_txid:    0,
+            read_only:   false,
+            auto_commit: None,
+            committer:   None,
+            dirty:       0,
+            last_commit: Instant::now(),
+            scrub:       None,
+            received:    None,
+            metrics:     Arc::default(),
+            hooks:       Arc::default(),
+            memory:      Arc::default(),
+            placement:   Placement::MostFree,
+            next_group:  0,
+            retention:   Retention::default(),
+            versions:    BTreeMap::new(),
         };
         let devids = res.load_superblocks(d)?;
         res.build_diskgroups(&devids, d)?;
         res.load_allocators()?;
-        assert!(res.test_features(AMFeatures::current_set())?);
+        res.check_features()?;
+        res.retention = res.get_superblock()?.retention();
+        let version = res.get_superblock()?.version();
+        if version != FORMAT_VERSION {
+            error!(
+                "Format version {} can't be mounted, expected {}",
+                version, FORMAT_VERSION
+            );
+            return Err(AMError::TODO(0).into());
+        }
         let obj_ptr = res.get_root_group()?.get_obj_ptr();
         res.objects = Some(ObjectSet::read(res.diskgroups.clone(), obj_ptr));
-        res.cur_txid = res.get_root_group()?.txid() + 1;
+        res.check_txids()?;
+        Ok(res)
+    }
+    #[cfg(feature = "unstable")]
+    fn open_at_root(d: &[Disk], txid: u128) -> AMResult<AMFS> {
+        let mut res = AMFS::load(d)?;
+        res.objects = Some(res.get_objects_at(txid)?);
+        res.read_only = true;
         Ok(res)
     }
+    /// Gets the object set as of the root with a given transaction ID
+    #[cfg(feature = "unstable")]
+    pub(crate) fn get_objects_at(&self, txid: u128) -> AMResult<ObjectSet> {
+        let sb = self.get_superblock()?;
+        let group = (0..128)
+            .filter(|i| !sb.rootnodes(*i).is_null())
+            .filter_map(|i| FSGroup::read(&self.diskgroups, sb.rootnodes(i)).ok())
+            .find(|g| g.txid() == txid)
+            .ok_or(AMErrorFS::NoFSGroup)?;
+        Ok(ObjectSet::read(
+            self.diskgroups.clone(),
+            group.get_obj_ptr(),
+        ))
+    }
+    /// Checks that the latest root is newer than every other retained root, and numbers the next
+    /// commit after all of them. Mounts read-only if not, so nothing is committed over roots that
+    /// are out of order.
+    #[cfg(feature = "unstable")]
+    fn check_txids(&mut self) -> AMResult<()> {
+        let sb = self.get_superblock()?;
+        let latest = usize::from(sb.latest_root());
+        let mut txids = BTreeMap::new();
+        for i in 0..128 {
+            let ptr = sb.rootnodes(i);
+            if ptr.is_null() {
+                continue;
+            }
+            if let Ok(group) = FSGroup::read(&self.diskgroups, ptr) {
+                txids.insert(i, group.txid());
+            }
+        }
+        // A damaged latest root can't be compared against, but repeats are still caught
+        let newest = txids.get(&latest).copied();
+        let mut seen = BTreeSet::new();
+        for (i, txid) in txids.iter().filter(|(i, _)| **i != latest) {
+            if newest.is_some_and(|n| *txid >= n) || !seen.insert(*txid) {
+                error!(
+                    "Root in slot {} has txid {} out of order with the latest root, mounting read-only",
+                    i, txid
+                );
+                self.read_only = true;
+            }
+        }
+        self.cur_txid = txids
+            .values()
+            .copied()
+            .fold(self.get_root_group()?.txid(), u128::max)
+            + 1;
+        Ok(())
+    }
+    /// Refuses disks with unknown incompatible features, and mounts those with unknown
+    /// read-only compatible features read-only
     #[cfg(feature = "stable")]
-    fn test_features(&self, features: BTreeSet<usize>) -> AMResult<bool> {
-        Ok(self.get_superblock()?.test_features(features))
+    fn check_features(&mut self) -> AMResult<()> {
+        match self
+            .get_superblock()?
+            .unknown_features(&AMFeatures::supported_set())
+        {
+            Some(FeatureClass::Incompat) => {
+                error!("Disk uses unknown incompatible features");
+                return Err(AMError::TODO(0).into());
+            }
+            Some(FeatureClass::ReadOnlyCompat) => {
+                warn!("Disk uses unknown read-only compatible features, mounting read-only");
+                self.read_only = true;
+            }
+            Some(FeatureClass::Compat) | None => {}
+        }
+        Ok(())
     }
+    /// Gets the superblock with the newest root, preferring the newest generation among those
     #[cfg(feature = "stable")]
     pub(crate) fn get_superblock(&self) -> AMResult<Superblock> {
         Ok(self
@@ -134,23 +927,14 @@ impl AMFS {
             .values()
             .flatten()
             .filter_map(|x| *x)
-            .fold(None, |acc: Option<(u128, Superblock)>, x| {
-                if let Some((max, _)) = acc {
-                    if let Ok(group) = x.get_group(&self.diskgroups) {
-                        if group.txid() > max {
-                            Some((group.txid(), x))
-                        } else {
-                            acc
-                        }
-                    } else {
-                        acc
-                    }
-                } else {
-                    if let Ok(group) = x.get_group(&self.diskgroups) {
-                        Some((group.txid(), x))
-                    } else {
-                        acc
-                    }
+            .fold(None, |acc: Option<((u128, u64), Superblock)>, x| {
+                let key = match x.get_group(&self.diskgroups) {
+                    Ok(group) => (group.txid(), x.generation()),
+                    Err(_) => return acc,
+                };
+                match acc {
+                    Some((max, _)) if key <= max => acc,
+                    _ => Some((key, x)),
                 }
             })
             .ok_or(AMErrorFS::NoFSGroup)?
@@ -164,10 +948,19 @@ impl AMFS {
     fn load_superblocks(&mut self, ds: &[Disk]) -> AMResult<Vec<u64>> {
         let mut res = Vec::with_capacity(ds.len());
         for d in ds {
+            d.check_alignment()?;
             let mut disk_devid = None;
             let sb_locs = d.get_header_locs()?;
             for (i, loc) in sb_locs.iter().enumerate() {
                 if let Ok(hdr) = Superblock::read(d.clone(), *loc) {
+                    if hdr.block_size() != BLOCK_SIZE {
+                        error!(
+                            "Disk uses {}-byte blocks, only {}-byte blocks can be mounted",
+                            hdr.block_size(),
+                            BLOCK_SIZE
+                        );
+                        return Err(AMError::TODO(0).into());
+                    }
                     let devid = hdr.devid();
                     info!("Superblock {:x}:{} OK", devid, i);
                     self.superblocks.entry(devid).or_insert([None; 4])[i] = Some(hdr);
@@ -180,6 +973,28 @@ impl AMFS {
             }
             res.push(disk_devid.ok_or(AMErrorFS::NoSuperblock)?);
         }
+        // A copy an update didn't reach can still have a valid checksum. It's replaced with the
+        // newest copy, so the next update doesn't write its stale contents back.
+        for (devid, copies) in self.superblocks.iter_mut() {
+            let newest = copies
+                .iter()
+                .flatten()
+                .copied()
+                .max_by_key(Superblock::generation);
+            if let Some(newest) = newest {
+                for (i, copy) in copies.iter_mut().enumerate() {
+                    if copy.is_some_and(|sb| sb.generation() < newest.generation()) {
+                        warn!(
+                            "Superblock {:x}:{} is stale, replacing it with generation {}",
+                            devid,
+                            i,
+                            newest.generation()
+                        );
+                        *copy = Some(newest);
+                    }
+                }
+            }
+        }
         Ok(res)
     }
     #[cfg(feature = "stable")]
@@ -199,8 +1014,11 @@ impl AMFS {
                                     i.try_into().or(Err(AMErrorFS::NoDiskgroup))?,
                                 ) {
                                     info!("Built diskgroup using {:x}:{}:{}", devid, sbn, i);
-                                    self.diskgroups[i] =
-                                        Some(DiskGroup::from_geo(geo, devids, ds)?);
+                                    let mut dg = DiskGroup::from_geo(geo, devids, ds)?;
+                                    dg.index = u8::try_from(i)?;
+                                    dg.metrics = self.metrics.clone();
+                                    dg.hooks = self.hooks.clone();
+                                    self.diskgroups[i] = Some(dg);
                                 } else {
                                     error!("Corrupt geometry: {:x}:{}:{}", devid, sbn, i);
                                 }
@@ -225,33 +1043,96 @@ impl AMFS {
             .get_superblock()?
             .get_group(&self.diskgroups)?
             .get_free_queue(&self.diskgroups)?;
+        self.refcounts = self
+            .get_superblock()?
+            .get_group(&self.diskgroups)?
+            .get_refcounts(&self.diskgroups)?;
+        self.versions = self
+            .get_superblock()?
+            .get_group(&self.diskgroups)?
+            .get_versions(&self.diskgroups)?;
         Ok(())
     }
     #[cfg(feature = "unstable")]
-    pub(crate) fn alloc_blocks(&mut self, n: u64) -> AMResult<Option<AMPointerGlobal>> {
+    pub(crate) fn alloc_blocks(
+        &mut self,
+        n: u64,
+        hint: Option<AllocHint>,
+    ) -> AMResult<Option<AMPointerGlobal>> {
         let lock = self.lock.clone();
         let _handle = lock.read().or(Err(AMError::Poison))?;
 
-        let mut res = self.diskgroups[0]
+        let group = self.pick_group(hint)?;
+        let mut res = self.diskgroups[usize::from(group)]
             .clone()
             .ok_or(AMErrorFS::NoDiskgroup)?
-            .alloc_blocks(n)?;
+            .alloc_blocks(n, hint)?;
         res.update(&self.diskgroups)?;
+        if res.is_long() {
+            self.enable_feature(AMFeatures::LongExtents)?;
+        }
         self.journal.push_back(JournalEntry::Alloc(res));
 
         Ok(Some(res))
     }
+    /// Chooses the disk group to allocate from by the placement policy.
+    /// Allocations hinted relative to an extent stay in its group.
+    #[cfg(feature = "unstable")]
+    fn pick_group(&mut self, hint: Option<AllocHint>) -> AMResult<u8> {
+        if let Some(AllocHint::Near(p) | AllocHint::SameDevice(p) | AllocHint::OtherDevice(p)) =
+            hint
+        {
+            return Ok(p.geo());
+        }
+        let groups: Vec<(usize, &DiskGroup)> = self
+            .diskgroups
+            .iter()
+            .enumerate()
+            .filter_map(|(i, dg)| Some((i, dg.as_ref()?)))
+            .collect();
+        let group = match self.placement {
+            Placement::First => groups.first(),
+            // Ties go to the first group
+            Placement::MostFree => groups.iter().rev().max_by_key(|(_, dg)| dg.free_space()),
+            Placement::RoundRobin => groups
+                .iter()
+                .find(|(i, _)| *i >= self.next_group)
+                .or_else(|| groups.first()),
+        }
+        .map(|(i, _)| *i)
+        .ok_or(AMErrorFS::NoDiskgroup)?;
+        self.next_group = group + 1;
+        Ok(u8::try_from(group)?)
+    }
+    /// Sets a feature bit on every superblock, if it isn't set already.
+    /// Used for features only set once something on disk needs them.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn enable_feature(&mut self, feature: AMFeatures) -> AMResult<()> {
+        let bit = feature as usize;
+        if !self.get_superblock()?.features()[bit] {
+            self.update_superblocks(|sb| sb.set_feature(bit, true))?;
+        }
+        Ok(())
+    }
     #[cfg(feature = "unstable")]
-    pub(crate) fn alloc_bytes(&mut self, n: u64) -> AMResult<Vec<Fragment>> {
+    pub(crate) fn alloc_bytes(
+        &mut self,
+        n: u64,
+        hint: Option<AllocHint>,
+    ) -> AMResult<Vec<Fragment>> {
         let lock = self.lock.clone();
         let _handle = lock.read().or(Err(AMError::Poison))?;
 
-        let mut res = self.diskgroups[0]
+        let group = self.pick_group(hint)?;
+        let mut res = self.diskgroups[usize::from(group)]
             .clone()
             .ok_or(AMError::TODO(0))?
-            .alloc_bytes(n)?;
+            .alloc_bytes(n, hint)?;
         for p in &mut res {
             p.pointer.update(&self.diskgroups)?;
+            if p.pointer.is_long() {
+                self.enable_feature(AMFeatures::LongExtents)?;
+            }
         }
         //TODO: self.journal.push_back(JournalEntry::Alloc(res));
 
@@ -259,11 +1140,20 @@ impl AMFS {
     }
     #[cfg(feature = "unstable")]
     pub(crate) fn realloc(&mut self, ptr: AMPointerGlobal) -> AMResult<Option<AMPointerGlobal>> {
+        self.realloc_near(ptr, AllocHint::Near(ptr))
+    }
+    /// Copies an extent to a new location chosen by a hint, and frees the old one
+    #[cfg(feature = "unstable")]
+    pub(crate) fn realloc_near(
+        &mut self,
+        ptr: AMPointerGlobal,
+        hint: AllocHint,
+    ) -> AMResult<Option<AMPointerGlobal>> {
         let lock = self.lock.clone();
         let _handle = lock.read().or(Err(AMError::Poison))?;
 
         let n = ptr.length();
-        let new_ptr = if let Some(p) = self.alloc_blocks(n.into())? {
+        let new_ptr = if let Some(p) = self.alloc_blocks(n.into(), Some(hint))? {
             p
         } else {
             return Ok(None);
@@ -273,12 +1163,32 @@ impl AMFS {
         self.free(ptr)?;
         Ok(Some(new_ptr))
     }
+    /// Finds a run of free blocks, leaving it free. Returns a zero-length pointer to its start,
+    /// so an allocation hinted to be near it begins there.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn find_free_run(&mut self, n: u64) -> AMResult<AMPointerGlobal> {
+        let group = self.pick_group(Some(AllocHint::Data))?;
+        let mut dg = self.diskgroups[usize::from(group)]
+            .clone()
+            .ok_or(AMErrorFS::NoDiskgroup)?;
+        let run = dg.alloc_blocks(n, Some(AllocHint::Data))?;
+        dg.free(run)?;
+        Ok(AMPointerGlobal::new(run.loc(), 0, run.geo(), run.dev()))
+    }
     #[cfg(feature = "unstable")]
     pub(crate) fn free(&mut self, ptr: AMPointerGlobal) -> AMResult<()> {
         info!("Freeing {}", ptr);
         let lock = self.lock.clone();
         let _handle = lock.read().or(Err(AMError::Poison))?;
 
+        if let Some(count) = self.refcounts.get_mut(&ptr) {
+            // The extent is shared, only drop this reference
+            *count -= 1;
+            if *count <= 1 {
+                self.refcounts.remove(&ptr);
+            }
+            return Ok(());
+        }
         self.journal.push_back(JournalEntry::Free(ptr));
         if let Some(e) = self.free_queue.get_mut(&self.cur_txid) {
             e.push(ptr);
@@ -289,6 +1199,45 @@ impl AMFS {
         Ok(())
     }
     #[cfg(feature = "unstable")]
+    pub(crate) fn get_free_queue(&self) -> &BTreeMap<u128, Vec<AMPointerGlobal>> {
+        &self.free_queue
+    }
+    /// Removes entries from the free queue, so their blocks are never freed
+    #[cfg(feature = "unstable")]
+    pub(crate) fn drop_frees(&mut self, entries: &[(u128, AMPointerGlobal)]) {
+        for (txid, ptr) in entries {
+            if let Some(ptrs) = self.free_queue.get_mut(txid) {
+                if let Some(i) = ptrs.iter().position(|p| p == ptr) {
+                    ptrs.remove(i);
+                }
+            }
+        }
+        self.free_queue.retain(|_, ptrs| !ptrs.is_empty());
+    }
+    /// Adds a reference to an extent, so freeing it only drops a reference until the last one goes
+    #[cfg(feature = "unstable")]
+    pub(crate) fn add_ref(&mut self, ptr: AMPointerGlobal) {
+        *self.refcounts.entry(ptr).or_insert(1) += 1;
+    }
+    /// Gets the reference counts of shared extents, by extent
+    #[cfg(feature = "unstable")]
+    pub(crate) fn get_refcounts(&self) -> &BTreeMap<AMPointerGlobal, u64> {
+        &self.refcounts
+    }
+    /// Replaces the reference counts of shared extents
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_refcounts(&mut self, counts: BTreeMap<AMPointerGlobal, u64>) {
+        self.refcounts = counts;
+    }
+    #[cfg(feature = "unstable")]
+    pub(crate) fn get_txid(&self) -> u128 {
+        self.cur_txid
+    }
+    #[cfg(feature = "unstable")]
+    pub(crate) fn get_diskgroups(&self) -> &[Option<DiskGroup>] {
+        &self.diskgroups
+    }
+    #[cfg(feature = "unstable")]
     pub(crate) fn get_objects(&self) -> AMResult<&ObjectSet> {
         Ok(self.objects.as_ref().expect("PANIC"))
     }
@@ -296,15 +1245,24 @@ impl AMFS {
     pub(crate) fn get_objects_mut(&mut self) -> AMResult<&mut ObjectSet> {
         Ok(self.objects.as_mut().expect("PANIC"))
     }
-    #[cfg(feature = "stable")]
-    fn read_object(&self, id: u64, start: u64, data: &mut [u8]) -> AMResult<u64> {
-        self.get_objects()?
-            .read_object(id, start, data, &self.diskgroups)
-    }
-    /// Gets the size of the object corresponding to a given ID
-    #[cfg(feature = "stable")]
-    fn size_object(&self, id: u64) -> AMResult<u64> {
-        self.get_objects()?.size_object(id)
+    /// Writes a sequence of buffers to the object corresponding to a given ID
+    #[cfg(feature = "unstable")]
+    fn write_object_vectored(&mut self, id: u64, start: u64, bufs: &[IoSlice]) -> AMResult<u64> {
+        let diskgroups = &self.diskgroups.clone();
+        let mut obj = self
+            .get_objects()?
+            .get_object(id)?
+            .ok_or(AMErrorFS::NoObject)?;
+        obj.check_modify(Some(start))?;
+        self.keep_version(id)?;
+        let mut res = 0;
+        for buf in bufs {
+            res += obj.write(self, start + res, buf, diskgroups)?;
+        }
+        let objs = self.get_objects()?.clone();
+        let objs = objs.set_object(self, id, obj)?;
+        *self.get_objects_mut()? = objs;
+        Ok(res)
     }
     /// Truncates the object corresponding to a given ID
     #[cfg(feature = "stable")]
@@ -315,6 +1273,8 @@ impl AMFS {
             .get_objects()?
             .get_object(id)?
             .ok_or(AMErrorFS::NoObject)?;
+        obj.check_modify(None)?;
+        self.keep_version(id)?;
         obj.truncate(self, len, diskgroups)?;
         let objs = self.get_objects()?.clone();
         let objs = objs.set_object(self, id, obj)?;
@@ -329,23 +1289,554 @@ impl AMFS {
             .get_objects()?
             .get_object(id)?
             .ok_or(AMErrorFS::NoObject)?;
+        obj.check_modify(Some(start))?;
+        self.keep_version(id)?;
         let res = obj.write(self, start, data, diskgroups)?;
         let objs = self.get_objects()?.clone();
         let objs = objs.set_object(self, id, obj)?;
         *self.get_objects_mut()? = objs;
         Ok(res)
     }
+    /// Moves an object's data into consecutive blocks. Returns whether anything was moved.
+    #[cfg(feature = "unstable")]
+    fn defrag_object(&mut self, id: u64) -> AMResult<bool> {
+        let mut obj = self
+            .get_objects()?
+            .get_object(id)?
+            .ok_or(AMErrorFS::NoObject)?;
+        if !obj.defrag(self)? {
+            return Ok(false);
+        }
+        let objs = self.get_objects()?.clone();
+        let objs = objs.set_object(self, id, obj)?;
+        *self.get_objects_mut()? = objs;
+        Ok(true)
+    }
+    /// Moves an object's data off a set of (geometry, device) pairs, and rewrites the object tree
+    /// nodes above it. Returns the extents moved, at their old places.
+    #[cfg(feature = "unstable")]
+    fn rebalance_object(
+        &mut self,
+        id: u64,
+        sources: &BTreeSet<(u8, u8)>,
+    ) -> AMResult<Vec<AMPointerGlobal>> {
+        let mut obj = self
+            .get_objects()?
+            .get_object(id)?
+            .ok_or(AMErrorFS::NoObject)?;
+        let moved = obj.rebalance(self, sources)?;
+        // The rewritten nodes are allocated afresh, on the emptiest devices
+        let objs = self.get_objects()?.clone();
+        let objs = objs.set_object(self, id, obj)?;
+        *self.get_objects_mut()? = objs;
+        Ok(moved)
+    }
+    /// Extends the allocator of each disk that has grown, and moves its tail headers to the new end.
+    /// The headers are written at their new place by the next commit.
+    #[cfg(feature = "unstable")]
+    fn grow(&mut self) -> AMResult<u64> {
+        let mut added = 0;
+        for disk_id in self.diskids.clone() {
+            let disk = self.disks.get(&disk_id).ok_or(AMErrorFS::UnknownDevId)?;
+            let new_size = disk.size()?;
+            let new_locs: Vec<u64> = disk.get_header_locs()?.iter().map(|p| p.loc()).collect();
+            let alloc = self
+                .allocators
+                .get_mut(&disk_id)
+                .ok_or(AMErrorFS::NoAllocator)?;
+            let old_size = alloc.total_space();
+            if new_size <= old_size {
+                continue;
+            }
+            info!(
+                "Growing disk {:x} from {} to {} blocks",
+                disk_id, old_size, new_size
+            );
+            let old_locs = [0, 1, old_size - 2, old_size - 1];
+            alloc.grow(new_size)?;
+            // Nothing reads the old tail headers any more, now that the disk is bigger
+            for loc in &old_locs {
+                if !new_locs.contains(loc) {
+                    alloc.free(*loc)?;
+                }
+            }
+            for loc in &new_locs {
+                if !old_locs.contains(loc) {
+                    alloc.mark_used(*loc, 1)?;
+                }
+            }
+            // Tail headers that couldn't be found at mount are recreated from the others
+            let sbs = self
+                .superblocks
+                .get_mut(&disk_id)
+                .ok_or(AMErrorFS::NoSuperblock)?;
+            let sb = sbs
+                .iter()
+                .flatten()
+                .next()
+                .copied()
+                .ok_or(AMErrorFS::NoSuperblock)?;
+            for s in sbs.iter_mut() {
+                s.get_or_insert(sb);
+            }
+            added += new_size - old_size;
+        }
+        Ok(added)
+    }
+    /// Moves everything at or past a block of the only disk to below it, and cuts the filesystem
+    /// down to end there. Older roots are dropped, so this fails if there are snapshots.
+    #[cfg(feature = "unstable")]
+    fn shrink(&mut self, new_size: u64) -> AMResult<()> {
+        assert_or_err!(self.diskids.len() == 1, AMError::TODO(0));
+        let disk_id = *self.diskids.iter().next().ok_or(AMErrorFS::UnknownDevId)?;
+        let old_size = self
+            .allocators
+            .get(&disk_id)
+            .ok_or(AMErrorFS::NoAllocator)?
+            .total_space();
+        assert_or_err!(new_size >= 4 && new_size <= old_size, AMError::TODO(0));
+        if new_size == old_size {
+            return Ok(());
+        }
+        let sb = self.get_superblock()?;
+        assert_or_err!((0..128).all(|i| !sb.is_pinned(i)), AMError::TODO(0));
+        // Kept versions aren't moved, so they'd be cut off
+        assert_or_err!(
+            self.versions.values().all(|h| h.versions.is_empty()),
+            AMError::TODO(0)
+        );
+        // Older roots and the blocks they hold on to would all have to move too
+        self.commit()?;
+        self.drop_old_roots()?;
+        self.commit()?;
+        // Both copies of what moves exist until the next commit. The old tail headers aren't moved.
+        let mut alloc = self
+            .allocators
+            .get(&disk_id)
+            .ok_or(AMErrorFS::NoAllocator)?
+            .clone();
+        // Everything but the old tail headers has to fit below the new ones
+        assert_or_err!(alloc.used_space() <= new_size, AMErrorFS::AllocFailed);
+        info!(
+            "Shrinking disk {:x} from {} to {} blocks",
+            disk_id, old_size, new_size
+        );
+        // The new tail headers go in the last two blocks
+        let end = new_size - 2;
+        alloc.set_limit(Some(end));
+        let res = self.evacuate(end).and_then(|_| {
+            // Once the root that still used the old copies is gone, the next commit frees them
+            self.drop_old_roots()?;
+            self.commit()
+        });
+        alloc.set_limit(None);
+        res?;
+        alloc.free(old_size - 2)?;
+        alloc.free(old_size - 1)?;
+        if let Err(e) = alloc.shrink(new_size) {
+            error!("Blocks past the new end are still in use");
+            alloc.mark_used(old_size - 2, 1)?;
+            alloc.mark_used(old_size - 1, 1)?;
+            return Err(e);
+        }
+        alloc.mark_used(end, 1)?;
+        alloc.mark_used(end + 1, 1)?;
+        // The next commit writes the tail headers at the new end
+        self.commit()?;
+        // Stale headers at the old end would be found again if the disk isn't cut down
+        let mut disk = self
+            .disks
+            .get(&disk_id)
+            .ok_or(AMErrorFS::UnknownDevId)?
+            .clone();
+        for loc in [old_size - 2, old_size - 1] {
+            if loc >= new_size {
+                disk.write_at(loc, &[0; BLOCK_SIZE])?;
+            }
+        }
+        disk.sync()
+    }
+    /// Moves all objects out of the blocks at or past an end, then commits
+    #[cfg(feature = "unstable")]
+    fn evacuate(&mut self, end: u64) -> AMResult<()> {
+        let ids: Vec<u64> = self.get_objects()?.get_objects()?.into_keys().collect();
+        for id in ids {
+            let mut obj = self
+                .get_objects()?
+                .get_object(id)?
+                .ok_or(AMErrorFS::NoObject)?;
+            obj.evacuate(self, end)?;
+            // Rewriting every object moves every node of the object tree too
+            let objs = self.get_objects()?.clone();
+            let objs = objs.set_object(self, id, obj)?;
+            *self.get_objects_mut()? = objs;
+        }
+        self.commit()
+    }
     /// Writes to the object corresponding to a given ID
     #[cfg(feature = "unstable")]
     fn create_object(&mut self, id: u64, size: u64) -> AMResult<()> {
-        let ptr = self.alloc_blocks(1)?.ok_or(AMError::TODO(0))?;
-        let frag = Fragment::new(size, 0, ptr);
-        let obj = Object::new(&[frag]);
+        if self.get_objects()?.exists_object(id)? {
+            // Release whatever the object referenced before, keeping a version if asked to
+            self.truncate_object(id, 0)?;
+        }
+        let obj = if size <= u64::try_from(INLINE_MAX)? {
+            // Small enough to live in the object's leaf entry
+            Object::inline(&vec![0; usize::try_from(size)?])
+        } else {
+            // Storage is allocated once it's written to
+            Object::new(&[Fragment::hole(size)])
+        };
         let objs = self.get_objects()?.clone();
         let objs = objs.set_object(self, id, obj)?;
         *self.get_objects_mut()? = objs;
         Ok(())
     }
+    /// Creates an object sharing the contents of another, copy-on-write
+    #[cfg(feature = "unstable")]
+    fn clone_object(&mut self, src: u64, dst: u64) -> AMResult<()> {
+        let mut obj = self
+            .get_objects()?
+            .get_object(src)?
+            .ok_or(AMErrorFS::NoObject)?;
+        if src == dst {
+            return Ok(());
+        }
+        if self.get_objects()?.exists_object(dst)? {
+            // Release whatever the destination referenced before
+            self.truncate_object(dst, 0)?;
+        }
+        // Flags belong to the source, not its contents
+        obj.set_flags(0)?;
+        for f in obj.frags() {
+            if !f.is_hole() {
+                self.add_ref(f.pointer);
+            }
+        }
+        let objs = self.get_objects()?.clone();
+        let objs = objs.set_object(self, dst, obj)?;
+        *self.get_objects_mut()? = objs;
+        Ok(())
+    }
+    /// Deletes an object, releasing its data
+    #[cfg(feature = "unstable")]
+    fn delete_object(&mut self, id: u64) -> AMResult<()> {
+        let obj = self
+            .get_objects()?
+            .get_object(id)?
+            .ok_or(AMErrorFS::NoObject)?;
+        obj.check_modify(None)?;
+        self.truncate_object(id, 0)?;
+        let objs = self.get_objects()?.clone();
+        let objs = objs.remove_object(self, id)?;
+        *self.get_objects_mut()? = objs;
+        Ok(())
+    }
+    /// Moves an object to a new ID, releasing whatever the destination referenced before
+    #[cfg(feature = "unstable")]
+    fn rename_object(&mut self, from: u64, to: u64) -> AMResult<()> {
+        let obj = self
+            .get_objects()?
+            .get_object(from)?
+            .ok_or(AMErrorFS::NoObject)?;
+        obj.check_modify(None)?;
+        if from == to {
+            return Ok(());
+        }
+        if self.get_objects()?.exists_object(to)? {
+            self.truncate_object(to, 0)?;
+        }
+        self.keep_version(from)?;
+        let objs = self.get_objects()?.clone();
+        let objs = objs.set_object(self, to, obj)?;
+        let objs = objs.remove_object(self, from)?;
+        *self.get_objects_mut()? = objs;
+        Ok(())
+    }
+    /// Applies a batch of operations, then commits. A batch failing part way has its changes
+    /// dropped, and the blocks it allocated or freed are given back to the state they were in.
+    #[cfg(feature = "unstable")]
+    fn apply(&mut self, ops: &[BatchOp]) -> AMResult<()> {
+        self.check_batch(ops)?;
+        let allocators: Vec<_> = self
+            .allocators
+            .values()
+            .map(|a| (a.clone(), a.save()))
+            .collect();
+        let objects = self.objects.clone();
+        let free_queue = self.free_queue.clone();
+        let refcounts = self.refcounts.clone();
+        let versions = self.versions.clone();
+        let journal = self.journal.len();
+        let res = ops.iter().try_for_each(|op| match *op {
+            BatchOp::Create { id, size } => self.create_object(id, size),
+            BatchOp::Write { id, start, data } => self.write_object(id, start, data).map(|_| ()),
+            BatchOp::Delete(id) => self.delete_object(id),
+            BatchOp::Rename { from, to } => self.rename_object(from, to),
+        });
+        if let Err(e) = res {
+            warn!("Batch failed part way, dropping its changes");
+            self.objects = objects;
+            self.free_queue = free_queue;
+            self.refcounts = refcounts;
+            self.versions = versions;
+            self.journal.truncate(journal);
+            for (alloc, state) in allocators {
+                alloc.restore(state);
+            }
+            return Err(e);
+        }
+        self.commit()
+    }
+    /// Keeps up to `limit` prior versions of an object, or stops keeping them if 0.
+    /// Changes made before an object is first versioned are committed, so versions only ever
+    /// hold committed contents.
+    #[cfg(feature = "unstable")]
+    fn set_versions(&mut self, id: u64, limit: u64) -> AMResult<()> {
+        if limit == 0 {
+            if let Some(history) = self.versions.remove(&id) {
+                for obj in history.versions.values() {
+                    self.release_version(obj)?;
+                }
+            }
+            return Ok(());
+        }
+        assert_or_err!(self.get_objects()?.exists_object(id)?, AMErrorFS::NoObject);
+        if !self.versions.contains_key(&id) {
+            self.commit()?;
+        }
+        self.enable_feature(AMFeatures::Versions)?;
+        self.versions.entry(id).or_default().limit = limit;
+        self.trim_versions(id)
+    }
+    /// Keeps the contents of a versioned object as of the last commit, before its first change
+    /// since then. The version shares the object's extents, so only what changes is copied.
+    #[cfg(feature = "unstable")]
+    fn keep_version(&mut self, id: u64) -> AMResult<()> {
+        let txid = self.cur_txid.saturating_sub(1);
+        if !self
+            .versions
+            .get(&id)
+            .is_some_and(|h| !h.versions.contains_key(&txid))
+        {
+            return Ok(());
+        }
+        let mut obj = match self.get_objects()?.get_object(id)? {
+            Some(obj) => obj,
+            None => return Ok(()),
+        };
+        if obj.is_inline() {
+            // Versions are kept as fragments, so inline data gets a block of its own
+            let diskgroups = self.diskgroups.clone();
+            obj.spill(self, &diskgroups)?;
+        } else {
+            for f in obj.frags() {
+                if !f.is_hole() {
+                    self.add_ref(f.pointer);
+                }
+            }
+        }
+        if let Some(history) = self.versions.get_mut(&id) {
+            history.versions.insert(txid, obj);
+        }
+        self.trim_versions(id)
+    }
+    /// Releases the oldest versions of an object past its limit
+    #[cfg(feature = "unstable")]
+    fn trim_versions(&mut self, id: u64) -> AMResult<()> {
+        loop {
+            let oldest = match self.versions.get_mut(&id) {
+                Some(h) if u64::try_from(h.versions.len())? > h.limit => h.versions.pop_first(),
+                _ => None,
+            };
+            match oldest {
+                Some((_, obj)) => self.release_version(&obj)?,
+                None => return Ok(()),
+            }
+        }
+    }
+    /// Drops a version's references to its extents
+    #[cfg(feature = "unstable")]
+    fn release_version(&mut self, obj: &Object) -> AMResult<()> {
+        for f in obj.frags() {
+            if !f.is_hole() {
+                self.free(f.pointer)?;
+            }
+        }
+        Ok(())
+    }
+    /// Lists the transactions whose contents of an object are kept, oldest first
+    #[cfg(feature = "unstable")]
+    fn list_versions(&self, id: u64) -> Vec<u128> {
+        self.versions
+            .get(&id)
+            .map_or_else(Vec::new, |h| h.versions.keys().copied().collect())
+    }
+    /// Reads an object as it was in a transaction whose contents of it are kept
+    #[cfg(feature = "unstable")]
+    fn read_version(&self, id: u64, txid: u128, start: u64, data: &mut [u8]) -> AMResult<u64> {
+        self.versions
+            .get(&id)
+            .and_then(|h| h.versions.get(&txid))
+            .ok_or(AMErrorFS::NoObject)?
+            .read(start, data, &self.diskgroups)
+    }
+    /// Gets the prior versions kept of every versioned object
+    #[cfg(feature = "unstable")]
+    pub(crate) fn get_versions(&self) -> &BTreeMap<u64, VersionHistory> {
+        &self.versions
+    }
+    /// Checks that every operation in a batch can apply, following the objects it creates,
+    /// deletes and renames along the way
+    #[cfg(feature = "unstable")]
+    fn check_batch(&self, ops: &[BatchOp]) -> AMResult<()> {
+        let objs = self.get_objects()?;
+        // Objects the batch has touched so far, or None for those it has deleted
+        let mut touched: BTreeMap<u64, Option<Object>> = BTreeMap::new();
+        let get = |touched: &BTreeMap<u64, Option<Object>>, id| match touched.get(&id) {
+            Some(obj) => Ok(obj.clone()),
+            None => objs.get_object(id),
+        };
+        for op in ops {
+            match *op {
+                BatchOp::Create { id, .. } => {
+                    if let Some(old) = get(&touched, id)? {
+                        old.check_modify(None)?;
+                    }
+                    touched.insert(id, Some(Object::new(&[])));
+                }
+                BatchOp::Write { id, start, .. } => {
+                    get(&touched, id)?
+                        .ok_or(AMErrorFS::NoObject)?
+                        .check_modify(Some(start))?;
+                }
+                BatchOp::Delete(id) => {
+                    get(&touched, id)?
+                        .ok_or(AMErrorFS::NoObject)?
+                        .check_modify(None)?;
+                    touched.insert(id, None);
+                }
+                BatchOp::Rename { from, to } => {
+                    let obj = get(&touched, from)?.ok_or(AMErrorFS::NoObject)?;
+                    obj.check_modify(None)?;
+                    if from != to {
+                        if let Some(old) = get(&touched, to)? {
+                            old.check_modify(None)?;
+                        }
+                        touched.insert(to, Some(obj));
+                        touched.insert(from, None);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Replaces an object's flags. The first time any object gets flags, the superblocks are
+    /// marked as using them, so drivers that don't know about flags refuse the disk.
+    #[cfg(feature = "unstable")]
+    fn set_object_flags(&mut self, id: u64, flags: u64) -> AMResult<()> {
+        let mut obj = self
+            .get_objects()?
+            .get_object(id)?
+            .ok_or(AMErrorFS::NoObject)?;
+        obj.set_flags(flags)?;
+        if flags != 0 {
+            self.enable_feature(AMFeatures::ObjectFlags)?;
+        }
+        let objs = self.get_objects()?.clone();
+        let objs = objs.set_object(self, id, obj)?;
+        *self.get_objects_mut()? = objs;
+        Ok(())
+    }
+    /// Commits, then finds the extents that are allocated but that nothing refers to:
+    /// not a retained root, an object, or a free still waiting on a root to go
+    #[cfg(feature = "unstable")]
+    fn find_orphans(&mut self) -> AMResult<Vec<AMPointerGlobal>> {
+        assert_or_err!(self.diskids.len() == 1, AMError::TODO(0));
+        self.commit()?;
+        let disk_id = *self.diskids.iter().next().ok_or(AMErrorFS::UnknownDevId)?;
+        let mut reachable: BTreeSet<u64> =
+            self.header_locs(disk_id)?.iter().map(|p| p.loc()).collect();
+        let sb = self.get_superblock()?;
+        let disk = self.disks.get(&disk_id).ok_or(AMErrorFS::UnknownDevId)?;
+        for i in 0..16 {
+            let ptr = sb.geometries(i);
+            if !ptr.is_null() {
+                reachable.insert(ptr.loc());
+                let geo = Geometry::read(disk.clone(), ptr)?;
+                reachable.extend(geo.continuation_blocks().iter().map(AMPointerLocal::loc));
+            }
+        }
+        for i in 0..128 {
+            let ptr = sb.rootnodes(i);
+            if ptr.is_null() {
+                continue;
+            }
+            let group = FSGroup::read(&self.diskgroups, ptr)?;
+            reachable.insert(ptr.loc());
+            reachable.extend(group.get_blocks(&self.diskgroups)?.iter().map(|b| b.loc()));
+            let objs = ObjectSet::read(self.diskgroups.clone(), group.get_obj_ptr());
+            reachable.extend(objs.get_blocks()?.iter().map(|b| b.loc()));
+            let versions = group.get_versions(&self.diskgroups)?;
+            let kept = versions
+                .into_values()
+                .flat_map(|h| h.versions.into_values());
+            for obj in objs.get_objects()?.into_values().chain(kept) {
+                for f in obj.frags() {
+                    if !f.is_hole() {
+                        reachable.insert(f.pointer.loc());
+                    }
+                }
+            }
+        }
+        for ptrs in self.free_queue.values() {
+            reachable.extend(ptrs.iter().map(|p| p.loc()));
+        }
+        let alloc = self
+            .allocators
+            .get(&disk_id)
+            .ok_or(AMErrorFS::NoAllocator)?;
+        let mut res = Vec::new();
+        for (start, ext) in alloc.extents() {
+            if !ext.used || reachable.range(start..start + ext.size).next().is_some() {
+                continue;
+            }
+            if ext.size <= MAX_EXTENT_BLOCKS {
+                res.push(AMPointerGlobal::new(start, u16::try_from(ext.size)?, 0, 0));
+            } else {
+                warn!("Orphaned extent at {} is too long to point to", start);
+            }
+        }
+        Ok(res)
+    }
+    /// Gathers orphaned extents into new objects past the highest object ID.
+    /// Returns the new objects' IDs.
+    #[cfg(feature = "unstable")]
+    fn recover_orphans(&mut self) -> AMResult<Vec<u64>> {
+        let orphans = self.find_orphans()?;
+        let mut id = self
+            .get_objects()?
+            .get_objects()?
+            .keys()
+            .next_back()
+            .map_or(META_OBJECTS, |i| std::cmp::max(i + 1, META_OBJECTS));
+        let mut res = Vec::new();
+        for mut ptr in orphans {
+            info!("Recovering orphaned extent at {} as object {}", ptr, id);
+            ptr.update(&self.diskgroups)?;
+            if ptr.is_long() {
+                self.enable_feature(AMFeatures::LongExtents)?;
+            }
+            let size = u64::from(ptr.length()) * u64::try_from(BLOCK_SIZE)?;
+            let obj = Object::new(&[Fragment::new(size, 0, ptr)]);
+            let objs = self.get_objects()?.clone();
+            let objs = objs.set_object(self, id, obj)?;
+            *self.get_objects_mut()? = objs;
+            res.push(id);
+            id += 1;
+        }
+        Ok(res)
+    }
     /// Syncs the disks
     #[cfg(feature = "stable")]
     fn sync(&mut self) -> AMResult<()> {
@@ -356,30 +1847,1158 @@ impl AMFS {
         }
         Ok(())
     }
+    /// Places a write barrier on every disk
+    #[cfg(feature = "unstable")]
+    fn barrier(&mut self) -> AMResult<()> {
+        for dg in self.diskgroups.iter_mut().flatten() {
+            dg.barrier()?;
+        }
+        Ok(())
+    }
     #[cfg(feature = "unstable")]
     fn commit(&mut self) -> AMResult<()> {
+        let _span = tracing::info_span!("commit", txid = %self.cur_txid).entered();
         let lock = self.lock.clone();
         let _handle = lock.write().or(Err(AMError::Poison))?;
-        let mut dg = self.diskgroups[0].clone().ok_or(AMErrorFS::NoDiskgroup)?;
+        let group = self.pick_group(None)?;
+        let mut dg = self.diskgroups[usize::from(group)]
+            .clone()
+            .ok_or(AMErrorFS::NoDiskgroup)?;
+        let slot = self
+            .get_superblock()?
+            .next_root()
+            .ok_or(AMErrorFS::NoFSGroup)?;
         let mut root_group = self.get_root_group()?;
         root_group.objects = self.get_objects()?.ptr;
-        let mut root_ptr = dg.alloc_blocks(1)?;
-        root_group.write_free_queue(&[Some(dg.clone())], &self.free_queue)?;
-        root_group.write_allocators(&mut [Some(dg.clone())], &mut self.allocators)?;
-        root_group.write(&[Some(dg)], &mut root_ptr)?;
-        // Write superblocks
-        for disk_id in &self.diskids {
-            for i in 0..4 {
-                if let Some(sb) = &mut self.superblocks.get_mut(disk_id).ok_or(AMError::TODO(0))?[i]
-                {
-                    sb.latest_root += 1;
-                    sb.rootnodes[usize::from(sb.latest_root)] = root_ptr;
-                    let header_locs = self.disks[disk_id].get_header_locs()?;
-                    sb.write(self.disks[disk_id].clone(), header_locs[i])?;
+        root_group.set_txid(self.cur_txid);
+        root_group.set_committed(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |t| t.as_secs()),
+        );
+        if let Some((time, errors)) = self.scrub.take() {
+            root_group.set_scrub(time, errors);
+        }
+        if let Some(txid) = self.received.take() {
+            root_group.set_received(txid);
+        }
+        // Expired roots stay in the superblocks until this commit's are written,
+        // so their blocks are only queued, and retire with the next commit
+        let mut released = self.expired_roots()?;
+        self.retire_frees()?;
+        if !released.contains(&usize::from(slot)) {
+            released.push(usize::from(slot));
+        }
+        self.release_roots(&released)?;
+        let mut root_ptr = dg.alloc_blocks(1, None)?;
+        // Allocated before the allocators are written, so they record the copies too
+        let mut replicas = self.alloc_root_replicas(root_ptr)?;
+        // The root's metadata goes in the same group as the root
+        root_group.write_free_queue(&self.diskgroups, group, &self.free_queue)?;
+        root_group.write_refcounts(&self.diskgroups, group, &self.refcounts)?;
+        root_group.write_versions(&self.diskgroups, group, &self.versions)?;
+        root_group.write_allocators(&mut self.diskgroups, group, &mut self.allocators)?;
+        if self.allocators.values().any(Allocator::is_bitmap) {
+            self.enable_feature(AMFeatures::BitmapAllocators)?;
+        }
+        root_group.write(&self.diskgroups, &mut root_ptr)?;
+        for ptr in replicas.values_mut() {
+            root_group.write(&self.diskgroups, ptr)?;
+        }
+        // The new root must be stable before any superblock points at it
+        self.barrier()?;
+        // Write superblocks, each pointing at the copy of the root on its own device
+        self.update_superblocks(|sb| {
+            for i in &released {
+                sb.rootnodes[*i] = AMPointerGlobal::null();
+            }
+            sb.latest_root = slot;
+            sb.rootnodes[usize::from(slot)] =
+                replicas.get(&sb.devid()).copied().unwrap_or(root_ptr);
+        })?;
+        self.sync()?;
+        self.hooks.committed(self.cur_txid);
+        self.hooks
+            .free_space(self.allocators.values().map(Allocator::free_space).sum());
+        self.cur_txid += 1;
+        self.dirty = 0;
+        self.last_commit = Instant::now();
+        // Everything journaled is on disk now
+        self.journal.clear();
+        self.account_memory()?;
+        Metrics::add(&self.metrics.commits, 1);
+        self.metrics.export()
+    }
+    /// Records the memory held by the journal and free queue in the budget
+    #[cfg(feature = "unstable")]
+    fn account_memory(&self) -> AMResult<()> {
+        let frees: usize = self.free_queue.values().map(Vec::len).sum();
+        let bytes = self.journal.len() * std::mem::size_of::<JournalEntry>()
+            + frees * std::mem::size_of::<AMPointerGlobal>();
+        MemoryBudget::set(&self.memory.journal, u64::try_from(bytes)?);
+        Ok(())
+    }
+    /// Checks whether the commit policy calls for a commit
+    #[cfg(feature = "unstable")]
+    fn commit_due(&self) -> bool {
+        match self.auto_commit {
+            Some(policy) => {
+                self.dirty >= policy.dirty_threshold
+                    || self.last_commit.elapsed() >= policy.interval
+            }
+            None => false,
+        }
+    }
+    /// Gets how long the background committer can sleep before a commit could be due.
+    /// Once the interval is up with nothing pending, the next change commits itself.
+    #[cfg(feature = "unstable")]
+    fn commit_wait(&self) -> Duration {
+        let interval = self.auto_commit.map_or(Duration::ZERO, |p| p.interval);
+        let wait = match interval.checked_sub(self.last_commit.elapsed()) {
+            Some(left) if !left.is_zero() => left,
+            _ => interval,
+        };
+        wait.max(COMMIT_POLL)
+    }
+    /// Gets where a disk's headers go: its first two blocks, and the last two the filesystem covers.
+    /// The filesystem can end before the disk does, after a shrink.
+    #[cfg(feature = "unstable")]
+    fn header_locs(&self, disk_id: u64) -> AMResult<[AMPointerLocal; 4]> {
+        let mut res = self
+            .disks
+            .get(&disk_id)
+            .ok_or(AMErrorFS::UnknownDevId)?
+            .get_header_locs()?;
+        if let Some(alloc) = self.allocators.get(&disk_id) {
+            let size = alloc.total_space();
+            res[2].set_loc(size - 2);
+            res[3].set_loc(size - 1);
+        }
+        Ok(res)
+    }
+    /// Allocates a block for a copy of a new root on every device but the one holding it,
+    /// so losing the root's device doesn't lose the root. Returns them by device ID.
+    #[cfg(feature = "unstable")]
+    fn alloc_root_replicas(
+        &mut self,
+        root: AMPointerGlobal,
+    ) -> AMResult<BTreeMap<u64, AMPointerGlobal>> {
+        let dg = self.diskgroups[usize::from(root.geo())]
+            .as_ref()
+            .ok_or(AMErrorFS::NoDiskgroup)?;
+        let primary = *dg
+            .geo
+            .device_ids
+            .get(usize::from(dg.device_of(root)))
+            .ok_or(AMErrorFS::DiskID)?;
+        let mut res = BTreeMap::new();
+        for disk_id in self.diskids.iter().filter(|id| **id != primary) {
+            // Pointers can only name the first devices of a geometry
+            let place = self.diskgroups.iter().flatten().find_map(|dg| {
+                let dev = dg.geo.device_ids.iter().position(|id| id == disk_id)?;
+                Some((dg.clone(), u8::try_from(dev).ok()?))
+            });
+            let (mut dg, dev) = match place {
+                Some(place) => place,
+                None => continue,
+            };
+            let hint = AllocHint::SameDevice(AMPointerGlobal::new(0, 0, dg.index, dev));
+            res.insert(*disk_id, dg.alloc_blocks(1, Some(hint))?);
+        }
+        Ok(res)
+    }
+    /// Gets every copy of the root in a slot, as the superblocks of each device point to their own
+    #[cfg(feature = "unstable")]
+    fn root_replicas(&self, slot: usize) -> BTreeSet<AMPointerGlobal> {
+        self.superblocks
+            .values()
+            .flatten()
+            .flatten()
+            .map(|sb| sb.rootnodes(slot))
+            .filter(|ptr| !ptr.is_null())
+            .collect()
+    }
+    /// Applies a change to every superblock and writes them out.
+    /// One copy at each end of every disk is written, synced and read back before the other two
+    /// are touched, so a crash part way always leaves two consistent copies.
+    #[cfg(feature = "unstable")]
+    fn update_superblocks<F: FnMut(&mut Superblock)>(&mut self, mut f: F) -> AMResult<()> {
+        let generation = self
+            .superblocks
+            .values()
+            .flatten()
+            .flatten()
+            .map(Superblock::generation)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        for pair in &[[0, 2], [1, 3]] {
+            for disk_id in &self.diskids {
+                let header_locs = self.header_locs(*disk_id)?;
+                for i in pair {
+                    if let Some(sb) =
+                        &mut self.superblocks.get_mut(disk_id).ok_or(AMError::TODO(0))?[*i]
+                    {
+                        f(sb);
+                        sb.set_generation(generation);
+                        sb.write(self.disks[disk_id].clone(), header_locs[*i])?;
+                    }
+                }
+            }
+            self.sync()?;
+            for disk_id in &self.diskids {
+                let header_locs = self.header_locs(*disk_id)?;
+                for i in pair {
+                    if let Some(sb) = &self.superblocks[disk_id][*i] {
+                        let written =
+                            Superblock::read(self.disks[disk_id].clone(), header_locs[*i])?;
+                        assert_or_err!(*written == **sb, AMErrorFS::Checksum);
+                    }
                 }
             }
         }
+        Ok(())
+    }
+    /// Commits, then pins the new root so later commits never overwrite it
+    #[cfg(feature = "unstable")]
+    fn snapshot_create(&mut self) -> AMResult<u128> {
+        self.commit()?;
+        let slot = usize::from(self.get_superblock()?.latest_root());
+        self.update_superblocks(|sb| sb.set_pinned(slot, true))?;
         self.sync()?;
+        Ok(self.cur_txid - 1)
+    }
+    /// Lists the transaction IDs of every snapshot
+    #[cfg(feature = "unstable")]
+    fn snapshot_list(&self) -> AMResult<Vec<u128>> {
+        let sb = self.get_superblock()?;
+        let mut res = Vec::new();
+        for i in 0..128 {
+            if sb.is_pinned(i) {
+                res.push(FSGroup::read(&self.diskgroups, sb.rootnodes(i))?.txid());
+            }
+        }
+        res.sort_unstable();
+        Ok(res)
+    }
+    /// Unpins a snapshot, letting its root slot be reused
+    #[cfg(feature = "unstable")]
+    fn snapshot_delete(&mut self, txid: u128) -> AMResult<()> {
+        let sb = self.get_superblock()?;
+        let mut slot = None;
+        for i in 0..128 {
+            if sb.is_pinned(i) && FSGroup::read(&self.diskgroups, sb.rootnodes(i))?.txid() == txid {
+                slot = Some(i);
+            }
+        }
+        let slot = slot.ok_or(AMErrorFS::NoFSGroup)?;
+        self.update_superblocks(|sb| sb.set_pinned(slot, false))?;
+        self.sync()
+    }
+    /// Forgets every root but the latest, freeing their metadata. Fails if any is a snapshot.
+    #[cfg(feature = "unstable")]
+    fn drop_old_roots(&mut self) -> AMResult<()> {
+        let sb = self.get_superblock()?;
+        let latest = usize::from(sb.latest_root());
+        let old: Vec<usize> = (0..128)
+            .filter(|i| *i != latest && !sb.rootnodes(*i).is_null())
+            .collect();
+        assert_or_err!(old.iter().all(|i| !sb.is_pinned(*i)), AMError::TODO(0));
+        let replicas: Vec<AMPointerGlobal> =
+            old.iter().flat_map(|i| self.root_replicas(*i)).collect();
+        // Stop pointing at the roots first, so a crash only leaks their blocks
+        self.update_superblocks(|sb| {
+            for i in &old {
+                sb.rootnodes[*i] = AMPointerGlobal::null();
+            }
+        })?;
+        self.sync()?;
+        // Roots can share allocator blocks, so each block is freed once
+        let kept = self.retained_alloc_blocks(&[])?;
+        let mut blocks = BTreeMap::new();
+        for i in old {
+            let ptr = sb.rootnodes(i);
+            let group = match FSGroup::read(&self.diskgroups, ptr) {
+                Ok(group) => group,
+                Err(_) => {
+                    warn!("Old root at {} is corrupt, leaking its blocks", ptr);
+                    continue;
+                }
+            };
+            for blk in group.get_blocks(&self.diskgroups)? {
+                if !kept.contains(&(blk.geo(), blk.loc())) {
+                    blocks.insert((blk.geo(), blk.loc()), blk);
+                }
+            }
+        }
+        for ptr in replicas {
+            blocks.insert((ptr.geo(), ptr.loc()), ptr);
+        }
+        debug!("Releasing {} blocks of old roots", blocks.len());
+        for blk in blocks.into_values() {
+            self.diskgroups[usize::from(blk.geo())]
+                .as_mut()
+                .ok_or(AMErrorFS::NoDiskgroup)?
+                .free(blk)?;
+        }
+        Ok(())
+    }
+    /// Frees the metadata of roots about to be overwritten or forgotten, through the free queue.
+    /// Their blocks are queued as freed just after each root, so the next commit retires them,
+    /// and the commit dropping the roots can't reuse them before it's durable.
+    /// Their object sets are freed through the free queue already, as later roots share them.
+    #[cfg(feature = "unstable")]
+    fn release_roots(&mut self, slots: &[usize]) -> AMResult<()> {
+        // Unchanged allocator blocks are carried over into later roots, which may still be retained
+        let shared = self.retained_alloc_blocks(slots)?;
+        // Roots can share allocator blocks, so each block is freed once
+        let mut seen = BTreeSet::new();
+        for slot in slots {
+            let replicas = self.root_replicas(*slot);
+            if replicas.is_empty() {
+                continue;
+            }
+            // Any intact copy describes the root's metadata
+            let group = match replicas
+                .iter()
+                .find_map(|ptr| FSGroup::read(&self.diskgroups, *ptr).ok())
+            {
+                Some(group) => group,
+                None => {
+                    warn!(
+                        "Superseded root in slot {} is corrupt, leaking its blocks",
+                        slot
+                    );
+                    continue;
+                }
+            };
+            let mut blocks = group.get_blocks(&self.diskgroups)?;
+            blocks.extend(replicas);
+            blocks.retain(|b| {
+                let key = (b.geo(), b.loc());
+                !shared.contains(&key) && seen.insert(key)
+            });
+            debug!(
+                "Releasing {} blocks of root txid {}",
+                blocks.len(),
+                group.txid()
+            );
+            self.free_queue
+                .entry(group.txid() + 1)
+                .or_insert_with(Vec::new)
+                .extend(blocks);
+        }
+        Ok(())
+    }
+    /// Gets the allocator blocks of every retained root, except those in the given slots
+    #[cfg(feature = "unstable")]
+    fn retained_alloc_blocks(&self, except: &[usize]) -> AMResult<BTreeSet<(u8, u64)>> {
+        let sb = self.get_superblock()?;
+        let mut res = BTreeSet::new();
+        for i in 0..128 {
+            let ptr = sb.rootnodes(i);
+            if ptr.is_null() || except.contains(&i) {
+                continue;
+            }
+            if let Ok(group) = FSGroup::read(&self.diskgroups, ptr) {
+                for blk in group.get_alloc_blocks(&self.diskgroups)? {
+                    res.insert((blk.geo(), blk.loc()));
+                }
+            }
+        }
+        Ok(res)
+    }
+    /// Gets the slots of unpinned roots past the retention limits.
+    /// The commit releases them, and its superblocks stop pointing at them.
+    #[cfg(feature = "unstable")]
+    fn expired_roots(&self) -> AMResult<Vec<usize>> {
+        let mut res = Vec::new();
+        let Retention { transactions, time } = self.retention;
+        if transactions.is_none() && time.is_none() {
+            return Ok(res);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        let sb = self.get_superblock()?;
+        for i in 0..128 {
+            let ptr = sb.rootnodes(i);
+            if ptr.is_null() || sb.is_pinned(i) || i == usize::from(sb.latest_root()) {
+                continue;
+            }
+            let group = match FSGroup::read(&self.diskgroups, ptr) {
+                Ok(group) => group,
+                Err(_) => continue,
+            };
+            let too_many =
+                transactions.is_some_and(|n| group.txid() + u128::from(n) < self.cur_txid);
+            // Roots from before commit times were recorded only expire by count
+            let too_old = time.is_some_and(|t| {
+                group.committed() != 0 && now.saturating_sub(group.committed()) > t.as_secs()
+            });
+            if too_many || too_old {
+                debug!("Forgetting root txid {} in slot {}", group.txid(), i);
+                res.push(i);
+            }
+        }
+        Ok(res)
+    }
+    /// Returns blocks freed in transactions no retained root can see to their allocators
+    #[cfg(feature = "unstable")]
+    fn retire_frees(&mut self) -> AMResult<()> {
+        let sb = self.get_superblock()?;
+        let mut oldest = self.cur_txid;
+        for i in 0..128 {
+            let ptr = sb.rootnodes(i);
+            if ptr.is_null() {
+                continue;
+            }
+            if let Ok(group) = FSGroup::read(&self.diskgroups, ptr) {
+                oldest = std::cmp::min(oldest, group.txid());
+            }
+        }
+        // Blocks freed in txid n are still referenced by roots before n
+        let keep = self.free_queue.split_off(&(oldest + 1));
+        let retired = std::mem::replace(&mut self.free_queue, keep);
+        for (txid, ptrs) in retired {
+            debug!("Retiring {} frees from txid {}", ptrs.len(), txid);
+            for ptr in ptrs {
+                self.diskgroups[usize::from(ptr.geo())]
+                    .as_mut()
+                    .ok_or(AMErrorFS::NoDiskgroup)?
+                    .free(ptr)?;
+            }
+        }
         Ok(())
     }
 }
+
+impl Drop for AMFS {
+    #[cfg(feature = "unstable")]
+    fn drop(&mut self) {
+        if let Some(committer) = self.committer.take() {
+            committer.stop();
+        }
+        // Don't lose changes the commit policy would have committed later
+        if self.auto_commit.is_some() && self.dirty > 0 && !self.read_only {
+            if let Err(e) = self.commit() {
+                error!("Final commit failed: {:?}", e);
+            }
+        }
+    }
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_commit_policy(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    let txid = fs.read().unwrap().get_txid();
+    fs.set_commit_policy(Some(CommitPolicy {
+        interval:        Duration::from_secs(3600),
+        dirty_threshold: 3,
+    }))
+    .unwrap();
+    fs.create_object(1, 4).unwrap();
+    fs.write_object(1, 0, &[1]).unwrap();
+    assert_eq!(fs.read().unwrap().get_txid(), txid);
+    fs.write_object(1, 1, &[2]).unwrap();
+    assert_eq!(fs.read().unwrap().get_txid(), txid + 1);
+    fs.poll_commit().unwrap();
+    assert_eq!(fs.read().unwrap().get_txid(), txid + 1);
+
+    fs.set_commit_policy(Some(CommitPolicy {
+        interval:        Duration::ZERO,
+        dirty_threshold: u64::MAX,
+    }))
+    .unwrap();
+    fs.poll_commit().unwrap();
+    assert_eq!(fs.read().unwrap().get_txid(), txid + 1);
+    fs.write_object(1, 2, &[3]).unwrap();
+    assert_eq!(fs.read().unwrap().get_txid(), txid + 2);
+    fs.set_commit_policy(None).unwrap();
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_background_commit() {
+    crate::test::logging::init_log();
+
+    let d = crate::DiskMem::open(1000);
+    crate::operations::mkfs_single(d.clone()).unwrap();
+    let fs = FSHandle::open(&[d.clone()]).unwrap();
+    fs.set_commit_policy(Some(CommitPolicy {
+        interval:        Duration::from_millis(500),
+        dirty_threshold: u64::MAX,
+    }))
+    .unwrap();
+    fs.commit().unwrap();
+    let txid = fs.read().unwrap().get_txid();
+    fs.create_object(1, 4).unwrap();
+    assert_eq!(fs.read().unwrap().get_txid(), txid);
+
+    // Left idle, the change is committed once the interval is up
+    let start = Instant::now();
+    while fs.read().unwrap().get_txid() == txid {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(fs.read().unwrap().get_txid(), txid + 1);
+    // Nothing is committed while nothing changes
+    std::thread::sleep(Duration::from_millis(700));
+    assert_eq!(fs.read().unwrap().get_txid(), txid + 1);
+    fs.set_commit_policy(None).unwrap();
+    assert!(fs.read().unwrap().committer.is_none());
+
+    // Dropping the filesystem stops the thread, committing what it hadn't yet
+    fs.set_commit_policy(Some(CommitPolicy {
+        interval:        Duration::from_secs(3600),
+        dirty_threshold: u64::MAX,
+    }))
+    .unwrap();
+    fs.write_object(1, 0, &[7]).unwrap();
+    drop(fs);
+    let fs = FSHandle::open(&[d]).unwrap();
+    let mut buf = [0u8; 1];
+    fs.read_object(1, 0, &mut buf).unwrap();
+    assert_eq!(buf, [7]);
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_refcount_keys(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    let mut handle = fs.write().unwrap();
+    let shared = AMPointerGlobal::new(100, 1, 0, 0);
+    let other_dev = AMPointerGlobal::new(100, 1, 0, 1);
+    let other_geo = AMPointerGlobal::new(100, 1, 1, 0);
+    handle.add_ref(shared);
+
+    // Extents at the same location elsewhere aren't shared with it
+    handle.free(other_dev).unwrap();
+    handle.free(other_geo).unwrap();
+    let queued: Vec<_> = handle.free_queue.values().flatten().copied().collect();
+    assert!(queued.contains(&other_dev));
+    assert!(queued.contains(&other_geo));
+    assert_eq!(handle.refcounts.get(&shared), Some(&2));
+    handle.free(shared).unwrap();
+    assert!(handle.refcounts.is_empty());
+    assert!(!handle.free_queue.values().flatten().any(|p| *p == shared));
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_read_during_commit(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    fs.create_object(1, 200).unwrap();
+    fs.write_object(1, 0, &[1, 2, 3, 4]).unwrap();
+    // Readers on other threads don't take the lock held while changes and commits are made
+    let reader = FSHandle::clone(&fs);
+    let handle = fs.write().unwrap();
+    let read = std::thread::spawn(move || {
+        let mut buf = [0u8; 4];
+        assert_eq!(reader.read_object(1, 0, &mut buf).unwrap(), 4);
+        (buf, reader.size_object(1).unwrap())
+    })
+    .join()
+    .unwrap();
+    assert_eq!(read, ([1, 2, 3, 4], 200));
+    drop(handle);
+
+    // Every read sees a whole write, while another thread writes and commits
+    let writer = FSHandle::clone(&fs);
+    let commits = std::thread::spawn(move || {
+        for i in 0..20 {
+            writer.write_object(1, 0, &[i; 4]).unwrap();
+            writer.commit().unwrap();
+        }
+    });
+    let mut buf = [0u8; 4];
+    while !commits.is_finished() {
+        fs.read_object(1, 0, &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == buf[0]));
+    }
+    commits.join().unwrap();
+    fs.read_object(1, 0, &mut buf).unwrap();
+    assert_eq!(buf, [19; 4]);
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_feature_classes(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(1, 0).unwrap();
+    fs.commit().unwrap();
+
+    let set_feature = |bit| {
+        for loc in d.get_header_locs().unwrap() {
+            let mut sb = Superblock::read(d.clone(), loc).unwrap();
+            sb.set_feature(bit, true);
+            sb.write(d.clone(), loc).unwrap();
+        }
+    };
+
+    set_feature(crate::COMPAT_START);
+    let fs2 = FSHandle::open(&[d.clone()]).unwrap();
+    assert!(!fs2.is_read_only().unwrap());
+    fs2.write_object(1, 0, &[1]).unwrap();
+    fs2.commit().unwrap();
+    drop(fs2);
+
+    set_feature(crate::RO_COMPAT_START);
+    let fs2 = FSHandle::open(&[d.clone()]).unwrap();
+    assert!(fs2.is_read_only().unwrap());
+    assert!(fs2.write_object(1, 0, &[2]).is_err());
+    assert!(fs2.create_object(2, 0).is_err());
+    assert!(fs2.commit().is_err());
+    assert!(fs2.snapshot_create().is_err());
+    let mut buf = [0u8; 1];
+    fs2.read_object(1, 0, &mut buf).unwrap();
+    assert_eq!(buf, [1]);
+    assert!(fs2.size_object(2).is_err());
+    drop(fs2);
+
+    set_feature(AMFeatures::Never as usize);
+    assert!(FSHandle::open(&[d]).is_err());
+}
+
+#[test_fs(mem, file, blocks = 1000)]
+#[allow(clippy::unwrap_used)]
+pub fn test_placement(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    // Add a second geometry over the same disk, so there are two groups to place in
+    {
+        let mut handle = fs.write().unwrap();
+        let mut dg = handle.diskgroups[0].clone().unwrap();
+        let loc = handle.alloc_blocks(1, None).unwrap().unwrap().loc();
+        let geo_ptr = dg
+            .geo
+            .write(dg.get_disk(0).unwrap(), AMPointerLocal::new(loc))
+            .unwrap();
+        handle
+            .update_superblocks(|sb| sb.geometries[1] = geo_ptr)
+            .unwrap();
+        dg.index = 1;
+        handle.diskgroups[1] = Some(dg);
+        // The object set keeps its own copy of the groups
+        let obj_ptr = handle.get_objects().unwrap().ptr;
+        handle.objects = Some(ObjectSet::read(handle.diskgroups.clone(), obj_ptr));
+    }
+    fs.set_placement(Placement::RoundRobin).unwrap();
+    {
+        let mut handle = fs.write().unwrap();
+        let ptrs: Vec<AMPointerGlobal> = (0..3)
+            .map(|_| handle.alloc_blocks(1, None).unwrap().unwrap())
+            .collect();
+        let geos: Vec<u8> = ptrs.iter().map(AMPointerGlobal::geo).collect();
+        assert_eq!(geos, vec![0, 1, 0]);
+        // Extents placed near another stay in its group
+        let near = handle
+            .alloc_blocks(1, Some(AllocHint::Near(ptrs[1])))
+            .unwrap()
+            .unwrap();
+        assert_eq!(near.geo(), 1);
+        for p in ptrs.into_iter().chain([near]) {
+            handle.free(p).unwrap();
+        }
+    }
+    for id in 100..104 {
+        fs.create_object(id, 0).unwrap();
+        fs.write_object(id, 0, &[u8::try_from(id).unwrap(); BLOCK_SIZE * 2])
+            .unwrap();
+    }
+    fs.commit().unwrap();
+
+    // Roots and data in either group are found again on mount
+    let fs2 = FSHandle::open(&[d]).unwrap();
+    for id in 100..104 {
+        let mut buf = [0u8; BLOCK_SIZE * 2];
+        fs2.read_object(id, 0, &mut buf).unwrap();
+        assert_eq!(buf, [u8::try_from(id).unwrap(); BLOCK_SIZE * 2]);
+    }
+    fs2.set_placement(Placement::First).unwrap();
+    fs2.create_object(200, 0).unwrap();
+    fs2.write_object(200, 0, &[1; BLOCK_SIZE * 2]).unwrap();
+    let handle = fs2.read().unwrap();
+    let obj = handle
+        .get_objects()
+        .unwrap()
+        .get_object(200)
+        .unwrap()
+        .unwrap();
+    assert_eq!(obj.frags()[0].pointer.geo(), 0);
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_root_replicas() {
+    crate::test::logging::init_log();
+
+    let ds = [crate::DiskMem::open(1000), crate::DiskMem::open(1000)];
+    crate::operations::mkfs_multi(&ds, crate::GeometryFlavor::Striped).unwrap();
+    let mut fs = FSHandle::open(&ds).unwrap();
+    fs.create_object(100, 0).unwrap();
+    fs.write_object(100, 0, &[1; 16]).unwrap();
+    fs.commit().unwrap();
+    for dev in 0..2u8 {
+        let (copies, disk) = {
+            let handle = fs.read().unwrap();
+            let slot = usize::from(handle.get_superblock().unwrap().latest_root());
+            let disk = handle.diskgroups[0]
+                .as_ref()
+                .unwrap()
+                .get_disk(dev)
+                .unwrap();
+            (handle.root_replicas(slot), disk)
+        };
+        // Each device's superblocks point at a copy on that device
+        let devs: Vec<u8> = copies.iter().map(AMPointerGlobal::dev).collect();
+        assert_eq!(devs.len(), 2);
+        assert!(devs.contains(&0) && devs.contains(&1));
+        let ptr = copies.iter().find(|p| p.dev() == dev).unwrap();
+        crate::test::corrupt::flip_byte(&disk, ptr.loc(), 0).unwrap();
+
+        // The other copy is still found
+        fs = FSHandle::open(&ds).unwrap();
+        let mut buf = [0; 16];
+        fs.read_object(100, 0, &mut buf).unwrap();
+        assert_eq!(buf, [dev + 1; 16]);
+        fs.write_object(100, 0, &[dev + 2; 16]).unwrap();
+        fs.commit().unwrap();
+    }
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_txid_order(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(100, 0).unwrap();
+    fs.commit().unwrap();
+    fs.commit().unwrap();
+    let (latest, txid) = {
+        let handle = fs.read().unwrap();
+        let latest = handle.get_superblock().unwrap().latest_root();
+        (latest, handle.get_root_group().unwrap().txid())
+    };
+    assert_eq!(fs.read().unwrap().cur_txid, txid + 1);
+
+    // Reopening carries on numbering after the latest root
+    let reopened = FSHandle::open(&[d.clone()]).unwrap();
+    assert_eq!(reopened.read().unwrap().cur_txid, txid + 1);
+    assert!(!reopened.is_read_only().unwrap());
+    drop(reopened);
+
+    // An older root claiming to be newer stops anything being committed over it
+    crate::test::corrupt::set_fsgroup_txid(&d, latest - 1, txid + 5).unwrap();
+    let reopened = FSHandle::open(&[d]).unwrap();
+    assert!(reopened.is_read_only().unwrap());
+    assert_eq!(reopened.read().unwrap().cur_txid, txid + 6);
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_release_root() {
+    crate::test::logging::init_log();
+
+    let d = crate::DiskMem::open(1000);
+    let opts = crate::operations::MkfsOptions {
+        root_slots: 4,
+        ..crate::operations::MkfsOptions::default()
+    };
+    crate::operations::mkfs(d.clone(), &opts).unwrap();
+    let fs = FSHandle::open(&[d]).unwrap();
+    for _ in 0..8 {
+        fs.commit().unwrap();
+    }
+    let queued = |fs: &FSHandle| -> BTreeSet<AMPointerGlobal> {
+        let handle = fs.read().unwrap();
+        handle.free_queue.values().flatten().copied().collect()
+    };
+    let (old, blocks) = {
+        let handle = fs.read().unwrap();
+        let sb = handle.get_superblock().unwrap();
+        let old = sb.rootnodes(usize::from(sb.next_root().unwrap()));
+        let group = FSGroup::read(&handle.diskgroups, old).unwrap();
+        (old, group.get_blocks(&handle.diskgroups).unwrap())
+    };
+
+    // The superseded root waits in the free queue until the next commit
+    fs.commit().unwrap();
+    assert!(queued(&fs).contains(&old));
+    assert!(blocks.iter().any(|b| queued(&fs).contains(b)));
+    fs.commit().unwrap();
+    assert!(!queued(&fs).contains(&old));
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_expire_root_frees() {
+    crate::test::logging::init_log();
+
+    let d = crate::DiskMem::open(1000);
+    let opts = crate::operations::MkfsOptions {
+        root_slots: 16,
+        retention: Retention {
+            transactions: Some(1),
+            time:         None,
+        },
+        ..crate::operations::MkfsOptions::default()
+    };
+    crate::operations::mkfs(d.clone(), &opts).unwrap();
+    let fs = FSHandle::open(&[d]).unwrap();
+    for _ in 0..4 {
+        fs.commit().unwrap();
+    }
+    let queued = |fs: &FSHandle| -> BTreeSet<AMPointerGlobal> {
+        let handle = fs.read().unwrap();
+        handle.free_queue.values().flatten().copied().collect()
+    };
+    // The oldest root is the next to expire
+    let old = {
+        let handle = fs.read().unwrap();
+        let sb = handle.get_superblock().unwrap();
+        (0..128)
+            .map(|i| sb.rootnodes(i))
+            .filter(|ptr| !ptr.is_null())
+            .min_by_key(|ptr| FSGroup::read(&handle.diskgroups, *ptr).unwrap().txid())
+            .unwrap()
+    };
+
+    // The commit forgetting it only queues its blocks, as the old superblocks point at it
+    fs.commit().unwrap();
+    {
+        let handle = fs.read().unwrap();
+        let sb = handle.get_superblock().unwrap();
+        assert!((0..128).all(|i| sb.rootnodes(i) != old));
+        let extents = handle.diskgroups[0].as_ref().unwrap().allocs[0].extents();
+        let (start, ex) = extents.range(..=old.loc()).next_back().unwrap();
+        assert!(ex.used && start + ex.size > old.loc());
+    }
+    assert!(queued(&fs).contains(&old));
+    fs.commit().unwrap();
+    assert!(!queued(&fs).contains(&old));
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_retention() {
+    crate::test::logging::init_log();
+
+    let d = crate::DiskMem::open(1000);
+    let opts = crate::operations::MkfsOptions {
+        root_slots: 16,
+        retention: Retention {
+            transactions: Some(2),
+            time:         None,
+        },
+        ..crate::operations::MkfsOptions::default()
+    };
+    crate::operations::mkfs(d.clone(), &opts).unwrap();
+    let fs = FSHandle::open(&[d.clone()]).unwrap();
+    let roots = |fs: &FSHandle| {
+        let sb = fs.read().unwrap().get_superblock().unwrap();
+        (0..128).filter(|i| !sb.rootnodes(*i).is_null()).count()
+    };
+    for _ in 0..10 {
+        fs.commit().unwrap();
+    }
+    // The newest root and the two before it
+    assert_eq!(roots(&fs), 3);
+    // Snapshots are kept however old they get
+    fs.snapshot_create().unwrap();
+    for _ in 0..5 {
+        fs.commit().unwrap();
+    }
+    assert_eq!(roots(&fs), 4);
+    drop(fs);
+
+    // The policy is kept on disk, and mounts can override it
+    let fs = FSHandle::open(&[d]).unwrap();
+    assert_eq!(fs.read().unwrap().retention.transactions, Some(2));
+    fs.set_retention(Retention::default()).unwrap();
+    for _ in 0..5 {
+        fs.commit().unwrap();
+    }
+    assert_eq!(roots(&fs), 9);
+    assert!(fs
+        .set_retention(Retention {
+            transactions: Some(0),
+            time:         None,
+        })
+        .is_err());
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_staggered_superblocks() {
+    crate::test::logging::init_log();
+
+    let base = crate::DiskMem::open(1000);
+    crate::operations::mkfs_single(base.clone()).unwrap();
+    let locs = base.get_header_locs().unwrap();
+    let latest = |i: usize| {
+        Superblock::read(base.clone(), locs[i])
+            .unwrap()
+            .latest_root()
+    };
+    let (d, faulty) = crate::DiskFaulty::open(base.clone());
+    let fs = FSHandle::open(&[d]).unwrap();
+    fs.commit().unwrap();
+    let old = latest(1);
+
+    // The first copies read back wrong, so the others are left as they were
+    faulty.lock().unwrap().corrupt_block(locs[0].loc());
+    assert!(fs.commit().is_err());
+    assert_ne!(latest(0), old);
+    assert_eq!((latest(1), latest(3)), (old, old));
+    drop(fs);
+    FSHandle::open(&[base]).unwrap();
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_superblock_generation() {
+    crate::test::logging::init_log();
+
+    let d = crate::DiskMem::open(1000);
+    crate::operations::mkfs_single(d.clone()).unwrap();
+    let locs = d.get_header_locs().unwrap();
+    let sb = |i: usize| Superblock::read(d.clone(), locs[i]).unwrap();
+    let fs = FSHandle::open(&[d.clone()]).unwrap();
+    let txid = fs.snapshot_create().unwrap();
+    let mut stale = [0; BLOCK_SIZE];
+    d.clone().read_at(locs[1].loc(), &mut stale).unwrap();
+    fs.snapshot_delete(txid).unwrap();
+    drop(fs);
+    let generation = sb(0).generation();
+    assert!((0..4).all(|i| sb(i).generation() == generation));
+
+    // A copy an update didn't reach still has a valid checksum and the same latest root
+    d.clone().write_at(locs[1].loc(), &stale).unwrap();
+    let slot = usize::from(sb(0).latest_root());
+    assert!(sb(1).generation() < generation);
+    assert_eq!(sb(1).latest_root(), sb(0).latest_root());
+    assert!(sb(1).is_pinned(slot));
+
+    // Mounting replaces it with the newest copy, rather than bringing the snapshot back
+    let fs = FSHandle::open(&[d.clone()]).unwrap();
+    assert!(fs.snapshot_list().unwrap().is_empty());
+    assert!((0..4).all(|i| sb(i).generation() == generation + 1));
+    assert!(!sb(1).is_pinned(slot));
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_apply() {
+    crate::test::logging::init_log();
+
+    let mem = Arc::new(Mutex::new(crate::DiskMem::new(1000)));
+    let base = Disk::new(mem.clone());
+    crate::operations::mkfs_single(base.clone()).unwrap();
+    let fs = FSHandle::open(&[base.clone()]).unwrap();
+    for id in 100..102 {
+        fs.create_object(id, 0).unwrap();
+        fs.write_object(id, 0, &[1; 16]).unwrap();
+    }
+    fs.commit().unwrap();
+    drop(fs);
+    let before = mem.lock().unwrap().snapshot();
+    let read = |fs: &FSHandle, id: u64| {
+        let mut buf = [0u8; 16];
+        fs.read_object(id, 0, &mut buf).ok().map(|_| buf)
+    };
+    let ops = [
+        BatchOp::Create { id: 102, size: 0 },
+        BatchOp::Write {
+            id:    102,
+            start: 0,
+            data:  &[2; 16],
+        },
+        BatchOp::Rename {
+            from: 102,
+            to:   100,
+        },
+        BatchOp::Delete(101),
+    ];
+
+    let fs = FSHandle::open(&[base.clone()]).unwrap();
+    fs.apply(&ops).unwrap();
+    drop(fs);
+    let fs = FSHandle::open(&[base.clone()]).unwrap();
+    assert_eq!(read(&fs, 100), Some([2; 16]));
+    assert_eq!((read(&fs, 101), read(&fs, 102)), (None, None));
+
+    // Batches that can't apply change nothing
+    fs.set_object_flags(100, crate::FLAG_IMMUTABLE).unwrap();
+    let bad = [BatchOp::Create { id: 103, size: 0 }, BatchOp::Delete(100)];
+    assert!(fs.apply(&bad).is_err());
+    assert!(fs.apply(&[BatchOp::Delete(101)]).is_err());
+    assert!(!fs.list_objects().unwrap().any(|(id, _, _)| id == 103));
+    drop(fs);
+
+    // Nor do batches failing part way
+    for n in 1..32 {
+        mem.lock().unwrap().restore(&before).unwrap();
+        let (d, faulty) = crate::DiskFaulty::open(base.clone());
+        let fs = FSHandle::open(&[d]).unwrap();
+        faulty.lock().unwrap().fail_write(n);
+        if fs.apply(&ops).is_err() {
+            let state = (read(&fs, 100), read(&fs, 101));
+            assert!(
+                state == (Some([1; 16]), Some([1; 16])) || state == (Some([2; 16]), None),
+                "partial batch after {} writes",
+                n
+            );
+        }
+    }
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_apply_replace() {
+    crate::test::logging::init_log();
+
+    let d = crate::DiskMem::open(1000);
+    let opts = crate::operations::MkfsOptions {
+        retention: Retention {
+            transactions: Some(1),
+            time:         None,
+        },
+        ..crate::operations::MkfsOptions::default()
+    };
+    crate::operations::mkfs(d.clone(), &opts).unwrap();
+    let fs = FSHandle::open(&[d]).unwrap();
+    let free_space = |fs: &FSHandle| {
+        // Enough commits for every queued free to be retired
+        for _ in 0..4 {
+            fs.commit().unwrap();
+        }
+        let handle = fs.read().unwrap();
+        handle
+            .allocators
+            .values()
+            .map(Allocator::free_space)
+            .sum::<u64>()
+    };
+    for id in 100..103 {
+        fs.create_object(id, 0).unwrap();
+        fs.write_object(id, 0, &[1; 4 * BLOCK_SIZE]).unwrap();
+    }
+    let before = free_space(&fs);
+
+    // Renaming or creating over an object releases the blocks it held
+    fs.apply(&[BatchOp::Rename {
+        from: 101,
+        to:   100,
+    }])
+    .unwrap();
+    let renamed = free_space(&fs);
+    assert!(renamed >= before + 4);
+    fs.apply(&[BatchOp::Create { id: 102, size: 0 }]).unwrap();
+    assert!(free_space(&fs) >= renamed + 4);
+    assert_eq!(fs.size_object(102).unwrap(), 0);
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_apply_rollback_alloc() {
+    crate::test::logging::init_log();
+
+    let base = crate::DiskMem::open(1000);
+    crate::operations::mkfs_single(base.clone()).unwrap();
+    let (d, faulty) = crate::DiskFaulty::open(base);
+    let fs = FSHandle::open(&[d]).unwrap();
+    for id in 100..102 {
+        fs.create_object(id, 0).unwrap();
+    }
+    fs.commit().unwrap();
+    let free_space = |fs: &FSHandle| {
+        let handle = fs.read().unwrap();
+        handle
+            .allocators
+            .values()
+            .map(Allocator::free_space)
+            .sum::<u64>()
+    };
+    let before = free_space(&fs);
+    let ops = [
+        BatchOp::Write {
+            id:    100,
+            start: 0,
+            data:  &[1; 4 * BLOCK_SIZE],
+        },
+        BatchOp::Write {
+            id:    101,
+            start: 0,
+            data:  &[2; 4 * BLOCK_SIZE],
+        },
+    ];
+
+    // A batch failing after allocating gives the blocks back
+    faulty.lock().unwrap().fail_write(2);
+    assert!(fs.apply(&ops).is_err());
+    assert_eq!(fs.size_object(100).unwrap(), 0);
+    assert_eq!(free_space(&fs), before);
+    fs.apply(&ops).unwrap();
+    assert_eq!(fs.size_object(101).unwrap(), 4 * BLOCK_SIZE as u64);
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_versions() {
+    crate::test::logging::init_log();
+
+    let d = crate::DiskMem::open(1000);
+    crate::operations::mkfs_single(d.clone()).unwrap();
+    let fs = FSHandle::open(&[d.clone()]).unwrap();
+    fs.create_object(100, 0).unwrap();
+    fs.write_object(100, 0, &[1; BLOCK_SIZE]).unwrap();
+    // Inline objects are versioned too
+    fs.create_object(101, 0).unwrap();
+    fs.write_object(101, 0, &[1; 16]).unwrap();
+    fs.set_versions(100, 2).unwrap();
+    fs.set_versions(101, 2).unwrap();
+    assert!(fs.list_versions(100).unwrap().is_empty());
+    for n in 2..5 {
+        // Only the first change after a commit keeps a version
+        fs.write_object(100, 0, &[n; BLOCK_SIZE]).unwrap();
+        fs.write_object(100, 0, &[n; 8]).unwrap();
+        fs.write_object(101, 0, &[n; 16]).unwrap();
+        fs.commit().unwrap();
+    }
+    let check = |fs: &FSHandle| {
+        for (id, len) in [(100, BLOCK_SIZE), (101, 16)].iter().copied() {
+            let txids = fs.list_versions(id).unwrap();
+            assert_eq!(txids.len(), 2);
+            for (txid, n) in txids.into_iter().zip(2..) {
+                let mut buf = vec![0; len];
+                let read = fs.read_version(id, txid, 0, &mut buf).unwrap();
+                assert_eq!(read, u64::try_from(len).unwrap());
+                assert_eq!(buf, vec![n; len]);
+            }
+        }
+    };
+    check(&fs);
+
+    // Versions outlive the object, and are found again on mount
+    fs.delete_object(100).unwrap();
+    fs.commit().unwrap();
+    assert_eq!(fs.list_versions(100).unwrap().len(), 2);
+    drop(fs);
+    let fs = FSHandle::open(&[d.clone()]).unwrap();
+    let newest = *fs.list_versions(100).unwrap().last().unwrap();
+    let mut buf = [0u8; 8];
+    fs.read_version(100, newest, 0, &mut buf).unwrap();
+    assert_eq!(buf, [4; 8]);
+    assert!(fs.read_version(100, 0, 0, &mut buf).is_err());
+
+    // Dropping the versions releases them
+    fs.set_versions(100, 0).unwrap();
+    fs.set_versions(101, 0).unwrap();
+    assert!(fs.list_versions(100).unwrap().is_empty());
+    fs.commit().unwrap();
+    drop(fs);
+    let report = crate::operations::fsck_single_scan(d, &Default::default(), |_, _, _| {}).unwrap();
+    assert_eq!(report.summary.errors, 0);
+}
@@ -12,6 +12,9 @@
 #![deny(clippy::cast_possible_truncation)]
 
 //! AMFS, AMOS Filesystem.
+//!
+//! Commits, allocations, object reads and writes, and fsck phases run inside `tracing` spans.
+//! Install a `tracing` subscriber to time them; without one, they cost next to nothing.
 
 #[macro_use]
 extern crate more_asserts;
@@ -22,25 +25,51 @@ extern crate log;
 #[macro_use]
 extern crate amfs_macros;
 
+// `test_fs` tests refer to the crate by name, from inside it as from other crates
+extern crate self as amfs;
+
 /// The filesystem's block size. All allocations are a multiple of this size.
 pub const BLOCK_SIZE: usize = 4096;
 
+/// The block sizes a volume can record in its superblock. Only `BLOCK_SIZE` can be mounted.
+pub const BLOCK_SIZES: [usize; 3] = [4096, 8192, 65536];
+
 /// The filesystem's signature. Appears at the start of top-level headers.
 pub const SIGNATURE: &[u8; 8] = b"amosAMFS";
 
-use std::sync::atomic::AtomicBool;
+/// The on-disk format version written by this driver. Older disks must be upgraded before mounting.
+pub const FORMAT_VERSION: u64 = 1;
+
+use std::{cell::Cell, sync::atomic::AtomicBool};
 
 use self::fs::AMFS;
 pub use self::{
-    disk::{Disk, DiskFile, DiskGroup, DiskMem},
-    features::AMFeatures,
-    fs::FSHandle,
+    cache::{Advice, AlignedBlock},
+    disk::{
+        AllocHint, DelayOptions, Disk, DiskDelay, DiskFaulty, DiskFile, DiskGroup, DiskMem,
+        DiskStats, Placement, SectorSize,
+    },
+    events::{CommitHook, CorruptionHook, SpaceLowHook},
+    features::{AMFeatures, FeatureClass, COMPAT_START, RO_COMPAT_START},
+    file::ObjectFile,
+    fs::{BatchOp, CommitPolicy, FSHandle, Retention},
+    location::{take_error_location, ErrorLocation},
+    locks::LockKind,
+    memory::MemoryUsage,
+    metrics::{MetricsExporter, MetricsSnapshot},
     ondisk::*,
 };
 
+mod cache;
 mod disk;
+mod events;
 mod features;
+mod file;
 mod fs;
+mod location;
+mod locks;
+mod memory;
+mod metrics;
 
 mod ondisk;
 
@@ -53,6 +82,9 @@ pub mod operations;
 /// Documentation-only module
 pub mod doc;
 
+/// Logging setup for embedders and the bundled binaries
+pub mod logging;
+
 /// Converts any object into a u8 slice\
 /// # Safety
 /// This function is only safe for types with stable ABI representations. In practice, this means only structs with repr(C)
@@ -78,3 +110,23 @@ static CHECKSUMS_ENABLED: AtomicBool = AtomicBool::new(true);
 pub unsafe fn disable_checksums() {
     CHECKSUMS_ENABLED.store(false, std::sync::atomic::Ordering::Relaxed)
 }
+
+thread_local! {
+    static CHECKSUMS_SKIPPED: Cell<bool> = Cell::new(false);
+}
+
+/// Runs a closure with checksum verification disabled on the calling thread only
+#[cfg(feature = "unstable")]
+pub(crate) fn without_checksums<T>(f: impl FnOnce() -> T) -> T {
+    let prev = CHECKSUMS_SKIPPED.with(|c| c.replace(true));
+    let res = f();
+    CHECKSUMS_SKIPPED.with(|c| c.set(prev));
+    res
+}
+
+/// Checks if checksums are verified on the calling thread
+#[cfg(feature = "stable")]
+pub(crate) fn checksums_enabled() -> bool {
+    CHECKSUMS_ENABLED.load(std::sync::atomic::Ordering::Relaxed)
+        && !CHECKSUMS_SKIPPED.with(Cell::get)
+}
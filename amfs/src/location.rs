@@ -0,0 +1,112 @@
+use std::{cell::Cell, fmt};
+
+use amos_std::AMResult;
+
+use crate::{AMPointerGlobal, DiskGroup};
+
+/// Where on disk a failed read, write or checksum check happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorLocation {
+    /// ID of the device holding the block, if known
+    pub devid: Option<u64>,
+    /// Index of the geometry the block was reached through, for blocks behind global pointers
+    pub geo:   Option<u8>,
+    /// Block number on the device
+    pub block: u64,
+}
+
+impl ErrorLocation {
+    /// Locates a block addressed directly on a device
+    #[cfg(feature = "unstable")]
+    pub fn local(devid: Option<u64>, block: u64) -> Self {
+        ErrorLocation {
+            devid,
+            geo: None,
+            block,
+        }
+    }
+    /// Locates the `n`th block of a global pointer's extent, looking up its device ID
+    #[cfg(feature = "unstable")]
+    pub fn global(ptr: AMPointerGlobal, n: u64, diskgroups: &[Option<DiskGroup>]) -> Self {
+        let devid = diskgroups
+            .get(usize::from(ptr.geo()))
+            .and_then(Option::as_ref)
+            .and_then(|dg| dg.geo.device_ids.get(usize::from(dg.device_of(ptr))))
+            .copied();
+        ErrorLocation {
+            devid,
+            geo: Some(ptr.geo()),
+            block: ptr.loc() + n,
+        }
+    }
+}
+
+impl fmt::Display for ErrorLocation {
+    #[cfg(feature = "unstable")]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block {:#x}", self.block)?;
+        if let Some(devid) = self.devid {
+            write!(f, " of device {:016x}", devid)?;
+        }
+        if let Some(geo) = self.geo {
+            write!(f, " (geometry {})", geo)?;
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static LAST_LOCATION: Cell<Option<ErrorLocation>> = Cell::new(None);
+}
+
+/// Logs where a failed result came from, and records it for `take_error_location`.
+/// Successful results pass through untouched.
+#[cfg(feature = "unstable")]
+pub(crate) fn at_location<T>(res: AMResult<T>, loc: impl FnOnce() -> ErrorLocation) -> AMResult<T> {
+    if let Err(e) = &res {
+        let loc = loc();
+        warn!("{:?} at {}", e, loc);
+        LAST_LOCATION.with(|c| c.set(Some(loc)));
+    }
+    res
+}
+
+/// Takes the location of the most recent block-level failure on the calling thread.
+/// Errors keep their original types, so this is how callers find out which block failed.
+#[cfg(feature = "unstable")]
+pub fn take_error_location() -> Option<ErrorLocation> {
+    LAST_LOCATION.with(Cell::take)
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_error_location() {
+    use amos_std::error::AMErrorFS;
+
+    use crate::{test::corrupt::flip_byte, FSGroup};
+
+    crate::test::logging::init_log();
+
+    let mut dg = crate::test::dg::create_dg_mem_single(100);
+    let mut ptr = dg.alloc_blocks(1, None).unwrap();
+    let dgs = [Some(dg.clone())];
+    FSGroup::new().write(&dgs, &mut ptr).unwrap();
+    FSGroup::read(&dgs, ptr).unwrap();
+    assert_eq!(take_error_location(), None);
+
+    flip_byte(&dg.get_disk(0).unwrap(), ptr.loc(), 100).unwrap();
+    assert_eq!(
+        FSGroup::read(&dgs, ptr)
+            .err()
+            .unwrap()
+            .downcast::<AMErrorFS>()
+            .unwrap(),
+        AMErrorFS::Checksum
+    );
+    let loc = take_error_location().unwrap();
+    assert_eq!(
+        (loc.devid, loc.geo, loc.block),
+        (Some(1), Some(0), ptr.loc())
+    );
+    assert_eq!(take_error_location(), None);
+}
@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+
+use amos_std::{error::AMError, AMResult};
+
+/// How a byte range is locked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// Any number of owners may hold overlapping shared locks
+    Shared,
+    /// No other owner may hold any lock overlapping an exclusive one
+    Exclusive,
+}
+
+/// A locked range of an object, from `start` up to but not including `end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RangeLock {
+    owner: u64,
+    start: u64,
+    end:   u64,
+    kind:  LockKind,
+}
+
+/// Advisory byte-range locks, by object ID.
+///
+/// Locks follow POSIX record lock rules: an owner's own locks never conflict with each other,
+/// and locking a range it already holds replaces the kind for that range.
+/// Acquiring never waits, so owners can't deadlock on each other.
+#[derive(Debug, Default)]
+pub(crate) struct LockTable {
+    locks: BTreeMap<u64, Vec<RangeLock>>,
+}
+
+/// Gets the end of a range, where a length of 0 runs to the end of the object however far it grows
+#[cfg(feature = "unstable")]
+fn range_end(start: u64, len: u64) -> AMResult<u64> {
+    if len == 0 {
+        Ok(u64::MAX)
+    } else {
+        Ok(start.checked_add(len).ok_or(AMError::TODO(0))?)
+    }
+}
+
+impl LockTable {
+    /// Finds the owner of a lock that would stop `owner` locking a range
+    #[cfg(feature = "unstable")]
+    pub(crate) fn conflict(
+        &self,
+        id: u64,
+        owner: u64,
+        start: u64,
+        len: u64,
+        kind: LockKind,
+    ) -> AMResult<Option<u64>> {
+        let end = range_end(start, len)?;
+        Ok(self.locks.get(&id).and_then(|locks| {
+            locks
+                .iter()
+                .find(|l| {
+                    l.owner != owner
+                        && l.start < end
+                        && start < l.end
+                        && (kind == LockKind::Exclusive || l.kind == LockKind::Exclusive)
+                })
+                .map(|l| l.owner)
+        }))
+    }
+    /// Locks a range for an owner. Returns false, changing nothing, if another owner's lock conflicts.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn lock(
+        &mut self,
+        id: u64,
+        owner: u64,
+        start: u64,
+        len: u64,
+        kind: LockKind,
+    ) -> AMResult<bool> {
+        if self.conflict(id, owner, start, len, kind)?.is_some() {
+            return Ok(false);
+        }
+        self.unlock(id, owner, start, len)?;
+        let end = range_end(start, len)?;
+        let locks = self.locks.entry(id).or_default();
+        locks.push(RangeLock {
+            owner,
+            start,
+            end,
+            kind,
+        });
+        locks.sort_by_key(|l| (l.owner, l.start));
+        // Merge the owner's touching locks of the same kind
+        let mut merged: Vec<RangeLock> = Vec::with_capacity(locks.len());
+        for l in locks.drain(..) {
+            match merged.last_mut() {
+                Some(m) if m.owner == l.owner && m.kind == l.kind && m.end == l.start => {
+                    m.end = l.end
+                }
+                _ => merged.push(l),
+            }
+        }
+        *locks = merged;
+        Ok(true)
+    }
+    /// Unlocks a range for an owner, splitting any of its locks that cover more than the range
+    #[cfg(feature = "unstable")]
+    pub(crate) fn unlock(&mut self, id: u64, owner: u64, start: u64, len: u64) -> AMResult<()> {
+        let end = range_end(start, len)?;
+        if let Some(locks) = self.locks.get_mut(&id) {
+            let mut res = Vec::with_capacity(locks.len());
+            for l in locks.drain(..) {
+                if l.owner != owner || l.end <= start || end <= l.start {
+                    res.push(l);
+                    continue;
+                }
+                if l.start < start {
+                    res.push(RangeLock { end: start, ..l });
+                }
+                if end < l.end {
+                    res.push(RangeLock { start: end, ..l });
+                }
+            }
+            *locks = res;
+            if locks.is_empty() {
+                self.locks.remove(&id);
+            }
+        }
+        Ok(())
+    }
+    /// Drops every lock an owner holds, on any object
+    #[cfg(feature = "unstable")]
+    pub(crate) fn unlock_all(&mut self, owner: u64) {
+        for locks in self.locks.values_mut() {
+            locks.retain(|l| l.owner != owner);
+        }
+        self.locks.retain(|_, locks| !locks.is_empty());
+    }
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_locks() {
+    use LockKind::{Exclusive, Shared};
+
+    let mut t = LockTable::default();
+    assert!(t.lock(1, 10, 0, 100, Shared).unwrap());
+    assert!(t.lock(1, 20, 50, 100, Shared).unwrap());
+    assert!(!t.lock(1, 30, 90, 10, Exclusive).unwrap());
+    assert_eq!(t.conflict(1, 30, 90, 10, Exclusive).unwrap(), Some(10));
+    // Other objects and disjoint ranges are independent
+    assert!(t.lock(2, 30, 90, 10, Exclusive).unwrap());
+    assert!(t.lock(1, 30, 150, 10, Exclusive).unwrap());
+
+    // Upgrading fails while another owner shares the range, and succeeds once it lets go
+    assert!(!t.lock(1, 10, 60, 10, Exclusive).unwrap());
+    t.unlock(1, 20, 0, 0).unwrap();
+    assert!(t.lock(1, 10, 60, 10, Exclusive).unwrap());
+    assert!(t.lock(1, 20, 0, 60, Shared).unwrap());
+    assert!(!t.lock(1, 20, 65, 1, Shared).unwrap());
+    assert!(t.lock(1, 20, 70, 30, Shared).unwrap());
+
+    // Unlocking the middle of a range splits it
+    t.unlock(1, 10, 60, 10).unwrap();
+    assert!(t.lock(1, 20, 60, 10, Exclusive).unwrap());
+    assert!(!t.lock(1, 20, 0, 100, Exclusive).unwrap());
+
+    // Zero-length locks run to the end of the object
+    assert!(!t.lock(1, 40, 155, 0, Exclusive).unwrap());
+    t.unlock_all(30);
+    assert!(t.lock(1, 40, 155, 0, Exclusive).unwrap());
+    assert!(t.lock(1, 50, u64::MAX - 1, 1, Shared).is_ok());
+    assert!(t.lock(1, 50, u64::MAX, 1, Shared).is_err());
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_lock_range(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    fs.create_object(1, 0).unwrap();
+    assert!(fs.lock_range(2, 1, 0, 0, LockKind::Shared).is_err());
+    assert!(fs.lock_range(1, 1, 0, 10, LockKind::Exclusive).unwrap());
+    // Clones of the handle share the mount's locks
+    let other = fs.clone();
+    assert!(!other.lock_range(1, 2, 5, 10, LockKind::Shared).unwrap());
+    assert_eq!(
+        other.lock_conflict(1, 2, 5, 10, LockKind::Shared).unwrap(),
+        Some(1)
+    );
+    fs.unlock_all(1).unwrap();
+    assert!(other.lock_range(1, 2, 5, 10, LockKind::Shared).unwrap());
+    other.unlock_range(1, 2, 0, 0).unwrap();
+    assert_eq!(
+        fs.lock_conflict(1, 1, 0, 0, LockKind::Exclusive).unwrap(),
+        None
+    );
+}
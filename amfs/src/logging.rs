@@ -0,0 +1,59 @@
+//! The filesystem logs through the `log` facade, so embedders can route its messages to any
+//! logger they already have installed. The functions here cover the common cases without
+//! pulling in the test logger.
+
+pub use log::LevelFilter;
+
+/// Environment variable read by `level_from_env`
+pub const LOG_ENV: &str = "AMFS_LOG";
+
+/// Caps how verbose the filesystem's logging is, whichever logger is installed.
+#[cfg(feature = "stable")]
+pub fn set_verbosity(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Reads the log level from `AMFS_LOG` (`off`, `error`, `warn`, `info`, `debug` or `trace`),
+/// falling back to `default` if it is unset or unparseable.
+#[cfg(feature = "stable")]
+pub fn level_from_env(default: LevelFilter) -> LevelFilter {
+    std::env::var(LOG_ENV)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Installs a console logger at the given level.
+/// Fails if a logger is already installed, so embedders with their own logger keep it.
+#[cfg(all(feature = "stable", feature = "log4rs"))]
+pub fn init_console(level: LevelFilter) -> amos_std::AMResult<()> {
+    use log4rs::{
+        append::console::ConsoleAppender,
+        config::{Appender, Config, Root},
+        encode::pattern::PatternEncoder,
+    };
+
+    let encoder = PatternEncoder::new("{h({l:>5})} {t:.<25} - {m}{n}");
+
+    let stdout = ConsoleAppender::builder()
+        .encoder(Box::new(encoder))
+        .build();
+    let config = Config::builder()
+        .appender(Appender::builder().build("stdout", Box::new(stdout)))
+        .build(Root::builder().appender("stdout").build(level))?;
+
+    log4rs::init_config(config)?;
+    Ok(())
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_level_from_env() {
+    std::env::remove_var(LOG_ENV);
+    assert_eq!(level_from_env(LevelFilter::Info), LevelFilter::Info);
+    std::env::set_var(LOG_ENV, "trace");
+    assert_eq!(level_from_env(LevelFilter::Info), LevelFilter::Trace);
+    std::env::set_var(LOG_ENV, "loud");
+    assert_eq!(level_from_env(LevelFilter::Warn), LevelFilter::Warn);
+    std::env::remove_var(LOG_ENV);
+}
@@ -0,0 +1,151 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use amos_std::{error::AMError, AMResult};
+
+/// Memory held by a mount, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Blocks held by the block cache
+    pub cache:   u64,
+    /// Write buffers being copied to disk
+    pub buffers: u64,
+    /// The journal and free queue, which only shrink on commit
+    pub journal: u64,
+}
+
+impl MemoryUsage {
+    /// Sums every kind of usage
+    #[cfg(feature = "unstable")]
+    pub fn total(&self) -> u64 {
+        self.cache + self.buffers + self.journal
+    }
+}
+
+/// Memory accounting shared by a filesystem, its handle and its block cache
+#[derive(Debug)]
+pub(crate) struct MemoryBudget {
+    /// The most memory to use, or `u64::MAX` for no limit
+    limit:              AtomicU64,
+    pub(crate) cache:   AtomicU64,
+    pub(crate) buffers: AtomicU64,
+    pub(crate) journal: AtomicU64,
+}
+
+impl Default for MemoryBudget {
+    #[cfg(feature = "unstable")]
+    fn default() -> Self {
+        MemoryBudget {
+            limit:   AtomicU64::new(u64::MAX),
+            cache:   AtomicU64::new(0),
+            buffers: AtomicU64::new(0),
+            journal: AtomicU64::new(0),
+        }
+    }
+}
+
+impl MemoryBudget {
+    /// Sets the most memory to use, or removes the limit if `None`
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_limit(&self, limit: Option<u64>) {
+        self.limit
+            .store(limit.unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+    /// Gets the most memory to use, if limited
+    #[cfg(feature = "unstable")]
+    pub(crate) fn limit(&self) -> Option<u64> {
+        Some(self.limit.load(Ordering::Relaxed)).filter(|l| *l != u64::MAX)
+    }
+    /// Records the current size of one kind of usage
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set(counter: &AtomicU64, bytes: u64) {
+        counter.store(bytes, Ordering::Relaxed);
+    }
+    /// Reads every kind of usage
+    #[cfg(feature = "unstable")]
+    pub(crate) fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            cache:   self.cache.load(Ordering::Relaxed),
+            buffers: self.buffers.load(Ordering::Relaxed),
+            journal: self.journal.load(Ordering::Relaxed),
+        }
+    }
+    /// Returns whether using `extra` more bytes would go over the limit
+    #[cfg(feature = "unstable")]
+    pub(crate) fn over(&self, extra: u64) -> bool {
+        self.usage().total().saturating_add(extra) > self.limit.load(Ordering::Relaxed)
+    }
+    /// Accounts for a buffer until the returned reservation is dropped.
+    /// Fails if the buffer doesn't fit, leaving the caller to free memory and retry.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn reserve(&self, bytes: u64) -> AMResult<Reservation<'_>> {
+        assert_or_err!(!self.over(bytes), AMError::TODO(0));
+        self.buffers.fetch_add(bytes, Ordering::Relaxed);
+        Ok(Reservation {
+            budget: self,
+            bytes,
+        })
+    }
+}
+
+/// Buffer memory accounted for in a budget, released on drop
+#[derive(Debug)]
+pub(crate) struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes:  u64,
+}
+
+impl Drop for Reservation<'_> {
+    #[cfg(feature = "unstable")]
+    fn drop(&mut self) {
+        self.budget.buffers.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_budget() {
+    crate::test::logging::init_log();
+
+    let budget = MemoryBudget::default();
+    assert_eq!(budget.limit(), None);
+    budget.set_limit(Some(100));
+    MemoryBudget::set(&budget.journal, 40);
+    let res = budget.reserve(50).unwrap();
+    assert_eq!(budget.usage().total(), 90);
+    assert!(budget.reserve(20).is_err());
+    drop(res);
+    assert_eq!(budget.usage().buffers, 0);
+    budget.reserve(60).unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_memory_budget(d: crate::Disk) {
+    use std::convert::TryFrom;
+
+    use crate::BLOCK_SIZE;
+
+    let bs = u64::try_from(BLOCK_SIZE).unwrap();
+
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    fs.create_object(100, 0).unwrap();
+    fs.write_object(100, 0, &[1; BLOCK_SIZE * 8]).unwrap();
+    fs.commit().unwrap();
+    for n in 0..8 {
+        fs.read_object_block(100, n).unwrap();
+    }
+    assert_eq!(fs.memory_usage().cache, 8 * bs);
+
+    // Shrinking the budget evicts cached blocks, oldest first
+    fs.set_memory_budget(Some(4 * bs)).unwrap();
+    assert!(fs.memory_usage().total() <= 4 * bs);
+
+    // Writes too big for the budget are refused, once flushing hasn't made room
+    assert!(fs.write_object(100, 0, &[2; BLOCK_SIZE * 8]).is_err());
+    fs.write_object(100, 0, &[2; BLOCK_SIZE]).unwrap();
+    assert_eq!(fs.memory_usage().buffers, 0);
+    assert!(fs.memory_usage().journal > 0);
+    fs.commit().unwrap();
+    fs.set_memory_budget(None).unwrap();
+    fs.write_object(100, 0, &[3; BLOCK_SIZE * 8]).unwrap();
+}
@@ -0,0 +1,133 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use amos_std::{error::AMError, AMResult};
+
+use crate::{AMPointerGlobal, DiskGroup};
+
+/// Callback handed the counters after every commit, for feeding a monitoring system
+pub type MetricsExporter = Box<dyn Fn(&MetricsSnapshot) + Send + Sync>;
+
+/// The counters of a mounted filesystem at one point in time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Blocks read through disk groups
+    pub blocks_read:       u64,
+    /// Blocks written through disk groups
+    pub blocks_written:    u64,
+    /// Extents allocated
+    pub allocations:       u64,
+    /// Commits completed
+    pub commits:           u64,
+    /// Metadata nodes and object fragments that failed their checksum
+    pub checksum_failures: u64,
+    /// Blocks returned to service by fsck fixes
+    pub repaired_blocks:   u64,
+    /// Whole-block object reads served from the block cache
+    pub cache_hits:        u64,
+}
+
+/// Counters shared by a filesystem and its disk groups
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) blocks_read:       AtomicU64,
+    pub(crate) blocks_written:    AtomicU64,
+    pub(crate) allocations:       AtomicU64,
+    pub(crate) commits:           AtomicU64,
+    pub(crate) checksum_failures: AtomicU64,
+    pub(crate) repaired_blocks:   AtomicU64,
+    pub(crate) cache_hits:        AtomicU64,
+    exporter:                     Mutex<Option<MetricsExporter>>,
+}
+
+impl Metrics {
+    /// Adds to a counter
+    #[cfg(feature = "unstable")]
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+    /// Reads every counter
+    #[cfg(feature = "unstable")]
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            blocks_read:       self.blocks_read.load(Ordering::Relaxed),
+            blocks_written:    self.blocks_written.load(Ordering::Relaxed),
+            allocations:       self.allocations.load(Ordering::Relaxed),
+            commits:           self.commits.load(Ordering::Relaxed),
+            checksum_failures: self.checksum_failures.load(Ordering::Relaxed),
+            repaired_blocks:   self.repaired_blocks.load(Ordering::Relaxed),
+            cache_hits:        self.cache_hits.load(Ordering::Relaxed),
+        }
+    }
+    /// Replaces the exporter
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_exporter(&self, exporter: Option<MetricsExporter>) -> AMResult<()> {
+        *self.exporter.lock().or(Err(AMError::Poison))? = exporter;
+        Ok(())
+    }
+    /// Hands the current counters to the exporter, if there is one
+    #[cfg(feature = "unstable")]
+    pub(crate) fn export(&self) -> AMResult<()> {
+        if let Some(exporter) = &*self.exporter.lock().or(Err(AMError::Poison))? {
+            exporter(&self.snapshot());
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Metrics {
+    #[cfg(feature = "unstable")]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.snapshot().fmt(f)
+    }
+}
+
+/// Counts a checksum failure against the disk group a pointer refers to, and runs its
+/// filesystem's corruption hooks
+#[cfg(feature = "unstable")]
+pub(crate) fn report_checksum_failure(diskgroups: &[Option<DiskGroup>], ptr: AMPointerGlobal) {
+    if let Some(Some(dg)) = diskgroups.get(usize::from(ptr.geo())) {
+        Metrics::add(&dg.metrics.checksum_failures, 1);
+        dg.hooks.corrupted(ptr);
+    }
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_metrics(d: crate::Disk) {
+    use std::sync::Arc;
+
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    let before = fs.metrics();
+    let exported = Arc::new(Mutex::new(Vec::new()));
+    let sink = exported.clone();
+    fs.set_metrics_exporter(Some(Box::new(move |m| sink.lock().unwrap().push(*m))))
+        .unwrap();
+
+    fs.create_object(100, 0).unwrap();
+    fs.write_object(100, 0, &[1; crate::BLOCK_SIZE * 2])
+        .unwrap();
+    fs.commit().unwrap();
+    let mut buf = [0u8; crate::BLOCK_SIZE * 2];
+    fs.read_object(100, 0, &mut buf).unwrap();
+
+    let after = fs.metrics();
+    assert_eq!(after.commits, before.commits + 1);
+    assert!(after.allocations > before.allocations);
+    assert!(after.blocks_written >= before.blocks_written + 2);
+    assert!(after.blocks_read >= before.blocks_read + 2);
+    assert_eq!(after.checksum_failures, 0);
+
+    let exported = exported.lock().unwrap();
+    assert_eq!(exported.len(), 1);
+    assert_eq!(exported[0].commits, after.commits);
+
+    fs.set_metrics_exporter(None).unwrap();
+    fs.commit().unwrap();
+    assert_eq!(exported.len(), 1);
+}
@@ -1,112 +1,182 @@
-use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
 
 use amos_std::{
     error::{AMError, AMErrorFS},
     AMResult,
 };
 
-use crate::{AMPointerGlobal, DiskGroup, LinkedListGlobal};
+use crate::{ondisk::linkedlist::write_block, AMPointerGlobal, DiskGroup, LinkedListGlobal};
 
-/// A reference-counted pointer to a disk object
+/// A reference-counted pointer to an allocator, shareable between threads
 #[derive(Clone, Debug)]
-pub struct Allocator(pub Rc<RefCell<AllocatorObj>>);
+pub struct Allocator(pub Arc<Mutex<AllocatorObj>>);
 
 impl Allocator {
     /// Creates a new allocator
     #[cfg(feature = "stable")]
     pub fn new(size: u64) -> Self {
-        Allocator(Rc::new(RefCell::new(AllocatorObj::new(size))))
+        Allocator(Arc::new(Mutex::new(AllocatorObj::new(size))))
     }
     /// Reads a superblock from disk.
     #[cfg(feature = "stable")]
     pub fn read(d: &[Option<DiskGroup>], ptr: AMPointerGlobal) -> AMResult<Self> {
-        Ok(Allocator(Rc::new(RefCell::new(AllocatorObj::read(
+        Ok(Allocator(Arc::new(Mutex::new(AllocatorObj::read(
             d, ptr,
         )?))))
     }
     /// Marks an extent used
     #[cfg(feature = "stable")]
     pub fn mark_used(&mut self, start: u64, size: u64) -> AMResult<()> {
-        self.0.borrow_mut().mark_used(start, size)
+        self.obj().mark_used(start, size)
     }
     /// Allocates a contiguous space of a given size
     #[cfg(feature = "stable")]
     pub fn alloc_blocks(&mut self, size: u64) -> AMResult<u64> {
-        self.0.borrow_mut().alloc(size)
+        self.obj().alloc(size)
+    }
+    /// Allocates a contiguous space of a given size, as close after a block as possible
+    #[cfg(feature = "unstable")]
+    pub fn alloc_near(&mut self, size: u64, near: u64) -> AMResult<u64> {
+        self.obj().alloc_near(size, near)
+    }
+    /// Sets blocks aside, so later allocations from the reservation can't fail for lack of space
+    #[cfg(feature = "unstable")]
+    pub fn reserve(&mut self, count: u64) -> AMResult<Reservation> {
+        self.obj().reserve(count)
+    }
+    /// Gives back the unallocated part of a reservation
+    #[cfg(feature = "unstable")]
+    pub fn release(&mut self, r: Reservation) {
+        self.obj().release(r)
+    }
+    /// Allocates a contiguous space of a given size out of a reservation
+    #[cfg(feature = "unstable")]
+    pub fn alloc_reserved(&mut self, r: &mut Reservation, size: u64) -> AMResult<u64> {
+        self.obj().alloc_reserved(r, size)
     }
     /// Allocates several blocks, not necessarily contiguous
     #[cfg(feature = "unstable")]
     pub fn alloc_many(&mut self, count: u64) -> AMResult<Vec<u64>> {
-        self.0.borrow_mut().alloc_many(count)
+        self.obj().alloc_many(count)
     }
     /// Writes an allocator to disk.
     #[cfg(feature = "stable")]
     pub fn write(&mut self, d: &mut [Option<DiskGroup>]) -> AMResult<AMPointerGlobal> {
-        self.0.borrow_mut().write(d)
+        self.obj().write(d)
     }
     /// Frees a block of space
     #[cfg(feature = "stable")]
     pub fn free(&mut self, start: u64) -> AMResult<()> {
-        self.0.borrow_mut().free(start)
+        self.obj().free(start)
+    }
+    /// Frees part of an allocated extent, keeping the rest of it allocated
+    #[cfg(feature = "unstable")]
+    pub fn free_range(&mut self, start: u64, size: u64) -> AMResult<()> {
+        self.obj().free_range(start, size)
     }
     /// Returns the amount of space free
     #[cfg(feature = "stable")]
     pub fn free_space(&self) -> u64 {
-        self.0.borrow().free_space()
+        self.obj().free_space()
     }
     /// Returns the amount of space in use
     #[cfg(feature = "stable")]
     pub fn used_space(&self) -> u64 {
-        self.0.borrow().used_space()
+        self.obj().used_space()
     }
     /// Returns the total space belonging to this allocator
     #[cfg(feature = "stable")]
     pub fn total_space(&self) -> u64 {
-        self.0.borrow().total_space()
+        self.obj().total_space()
+    }
+    /// Returns the number of free extents
+    #[cfg(feature = "unstable")]
+    pub fn free_extents(&self) -> usize {
+        self.obj().free_extents()
+    }
+    /// Returns the size of the largest free extent
+    #[cfg(feature = "unstable")]
+    pub fn largest_free(&self) -> u64 {
+        self.obj().largest_free()
+    }
+    /// Returns how fragmented the free space is, from 0 (one extent) to nearly 1 (many small ones)
+    #[cfg(feature = "unstable")]
+    pub fn fragmentation(&self) -> f64 {
+        self.obj().fragmentation()
+    }
+    /// Reserves the blocks before a given one for metadata. Data is placed after them while there's room.
+    #[cfg(feature = "unstable")]
+    pub fn set_meta_zone(&mut self, end: u64) {
+        self.obj().meta_end = end;
+    }
+    /// Extends the allocator to a larger size, adding free space at the end
+    #[cfg(feature = "unstable")]
+    pub fn grow(&mut self, size: u64) -> AMResult<()> {
+        self.obj().grow(size)
+    }
+    /// Cuts the allocator down to a smaller size. Everything past the new end has to be free.
+    #[cfg(feature = "unstable")]
+    pub fn shrink(&mut self, size: u64) -> AMResult<()> {
+        self.obj().shrink(size)
+    }
+    /// Keeps allocations below a block, or lifts the limit
+    #[cfg(feature = "unstable")]
+    pub fn set_limit(&mut self, limit: Option<u64>) {
+        self.obj().limit = limit;
+    }
+    /// Sets how free extents are picked
+    #[cfg(feature = "unstable")]
+    pub fn set_strategy(&mut self, strategy: AllocStrategy) {
+        self.obj().strategy = strategy;
+    }
+    /// Checks whether the allocator is stored as a bitmap, which needs the `BitmapAllocators` feature
+    #[cfg(feature = "unstable")]
+    pub fn is_bitmap(&self) -> bool {
+        self.obj().is_bitmap()
+    }
+    /// Gets the end of the metadata zone, or 0 if there are no zones
+    #[cfg(feature = "unstable")]
+    pub fn meta_zone(&self) -> u64 {
+        self.obj().meta_end
+    }
+    /// Allocates a contiguous space for data, in the data zone if there's room
+    #[cfg(feature = "unstable")]
+    pub fn alloc_data(&mut self, size: u64) -> AMResult<u64> {
+        self.obj().alloc_data(size)
     }
     /// Gets the list of extents
     #[cfg(feature = "unstable")]
     pub fn extents(&self) -> BTreeMap<u64, Extent> {
-        self.0.borrow().extents.clone()
+        self.obj().extents.clone()
     }
-    /// Preallocates blocks needed to store the allocator
+    /// Preallocates blocks needed to store the allocator, on top of those already in `res`.
+    /// Returns whether any were added.
     #[cfg(feature = "unstable")]
     pub fn prealloc(
         &self,
         diskgroups: &mut [Option<DiskGroup>],
         n: u8,
-    ) -> AMResult<Vec<AMPointerGlobal>> {
-        let extents_per_block = (crate::BLOCK_SIZE
-            - std::mem::size_of::<crate::ondisk::linkedlist::LLGHeader>())
-            / std::mem::size_of::<u64>();
-        let extents = self.0.borrow().extents.len() + 1;
-        let blocks = if extents == 0 {
-            1
-        } else {
-            (extents + (extents_per_block - 1)) / extents_per_block
-        };
-        let mut res = diskgroups[n as usize]
-            .as_mut()
-            .ok_or(AMErrorFS::NoDiskgroup)?
-            .alloc_many(blocks as u64)?;
+        res: &mut Vec<AMPointerGlobal>,
+    ) -> AMResult<bool> {
+        let mut added = false;
         loop {
-            let extents = self.0.borrow().extents.len();
-            let blocks = if extents == 0 {
-                1
-            } else {
-                (extents + (extents_per_block - 1)) / extents_per_block
-            };
+            // Allocating the blocks changes the allocator, and so how many blocks it needs
+            let blocks = self.obj().blocks_needed()?;
             if res.len() >= blocks {
-                break;
+                return Ok(added);
             }
             res.append(
                 &mut diskgroups[n as usize]
                     .as_mut()
                     .ok_or(AMErrorFS::NoDiskgroup)?
                     .alloc_many((blocks - res.len()) as u64)?,
-            )
+            );
+            added = true;
         }
-        Ok(res)
     }
     /// Writes out the allocator into a preallocated set of blocks
     #[cfg(feature = "unstable")]
@@ -115,15 +185,78 @@ impl Allocator {
         diskgroups: &mut [Option<DiskGroup>],
         blocks: &[AMPointerGlobal],
     ) -> AMResult<AMPointerGlobal> {
-        self.0.borrow_mut().write_preallocd(diskgroups, blocks)
+        self.obj().write_preallocd(diskgroups, blocks)
+    }
+    /// Copies the allocator's state, so changes made after can be undone with `restore`
+    #[cfg(feature = "unstable")]
+    pub fn save(&self) -> AllocatorObj {
+        self.obj().clone()
+    }
+    /// Puts back a state copied by `save`, undoing every allocation and free since
+    #[cfg(feature = "unstable")]
+    pub fn restore(&self, state: AllocatorObj) {
+        *self.obj() = state;
+    }
+    /// Locks the allocator. A panic while it was locked leaves it as usable as it was.
+    #[cfg(feature = "stable")]
+    fn obj(&self) -> MutexGuard<'_, AllocatorObj> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
     }
 }
 
+/// Gets the number of list entries that fit in a linked-list block
+#[cfg(feature = "unstable")]
+fn entries_per_block() -> usize {
+    (crate::BLOCK_SIZE - std::mem::size_of::<crate::ondisk::linkedlist::LLGHeader>())
+        / std::mem::size_of::<u64>()
+}
+
 /// The filesystem's block allocator
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AllocatorObj {
-    size:    u64,
-    extents: BTreeMap<u64, Extent>,
+    size:     u64,
+    extents:  BTreeMap<u64, Extent>,
+    reserved: u64,
+    meta_end: u64,
+    strategy: AllocStrategy,
+    cursor:   u64,
+    written:  Vec<(AMPointerGlobal, Vec<u64>)>,
+    limit:    Option<u64>,
+}
+
+/// How an allocator picks the free extent to allocate from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocStrategy {
+    /// The lowest extent that fits, preferring exact matches
+    FirstFit,
+    /// The smallest extent that fits
+    BestFit,
+    /// The first extent that fits after the previous allocation
+    NextFit,
+}
+
+/// Set in the on-disk size entry when the next entry is the end of the metadata zone
+const ZONED_FLAG: u64 = 0x4000000000000000;
+/// Set in the on-disk size entry when the extents are stored as a bitmap instead of a list
+const BITMAP_FLAG: u64 = 0x2000000000000000;
+
+/// Extent lists longer than this many blocks are stored as bitmaps, if that's smaller
+pub const BITMAP_THRESHOLD: usize = 4;
+
+/// Blocks set aside in an allocator for one operation.
+/// Must be given back with `Allocator::release` once the operation is done.
+#[derive(Debug, PartialEq, Eq)]
+#[must_use]
+pub struct Reservation {
+    blocks: u64,
+}
+
+impl Reservation {
+    /// Gets the number of reserved blocks not yet allocated
+    #[cfg(feature = "unstable")]
+    pub fn remaining(&self) -> u64 {
+        self.blocks
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -140,6 +273,12 @@ impl AllocatorObj {
         Self {
             size,
             extents: extent_map,
+            reserved: 0,
+            meta_end: 0,
+            strategy: AllocStrategy::FirstFit,
+            cursor: 0,
+            written: Vec::new(),
+            limit: None,
         }
     }
     /// Returns the amount of space free
@@ -169,47 +308,150 @@ impl AllocatorObj {
     fn total_space(&self) -> u64 {
         self.size
     }
+    /// Returns the number of free extents
+    #[cfg(feature = "unstable")]
+    fn free_extents(&self) -> usize {
+        self.extents.values().filter(|ex| !ex.used).count()
+    }
+    /// Returns the size of the largest free extent
+    #[cfg(feature = "unstable")]
+    fn largest_free(&self) -> u64 {
+        self.extents
+            .values()
+            .filter(|ex| !ex.used)
+            .map(|ex| ex.size)
+            .max()
+            .unwrap_or(0)
+    }
+    /// Returns the share of free space outside the largest free extent
+    #[cfg(feature = "unstable")]
+    fn fragmentation(&self) -> f64 {
+        let free = self.free_space();
+        if free == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free() as f64 / free as f64
+    }
+    /// Checks that an allocation leaves enough free space for outstanding reservations
+    #[cfg(feature = "unstable")]
+    fn check_reserved(&self, size: u64) -> AMResult<()> {
+        if self.reserved > 0 && self.free_space().saturating_sub(self.reserved) < size {
+            return Err(AMErrorFS::AllocFailed.into());
+        }
+        Ok(())
+    }
+    #[cfg(feature = "unstable")]
+    fn reserve(&mut self, count: u64) -> AMResult<Reservation> {
+        self.check_reserved(count)?;
+        assert_or_err!(self.free_space() >= count, AMErrorFS::AllocFailed);
+        self.reserved += count;
+        Ok(Reservation { blocks: count })
+    }
+    #[cfg(feature = "unstable")]
+    fn release(&mut self, r: Reservation) {
+        self.reserved -= r.blocks;
+    }
+    #[cfg(feature = "unstable")]
+    fn alloc_reserved(&mut self, r: &mut Reservation, size: u64) -> AMResult<u64> {
+        assert_or_err!(r.blocks >= size, AMErrorFS::AllocFailed);
+        // The reservation's blocks are free for it to use
+        self.reserved -= size;
+        r.blocks -= size;
+        let res = self.alloc(size);
+        if res.is_err() {
+            // No extent is big enough, the space is fragmented
+            self.reserved += size;
+            r.blocks += size;
+        }
+        res
+    }
     #[cfg(feature = "stable")]
     fn alloc(&mut self, size: u64) -> AMResult<u64> {
         assert!(size > 0);
         assert_le!(size, self.size);
+        self.check_reserved(size)?;
         trace!("Allocating block of size: {:x}", size);
-        for (a, ex) in self.extents.iter_mut() {
-            if ex.used {
-                continue;
-            }
-            if ex.size == size {
-                trace!("Found exact match");
-                ex.used = true;
-                return Ok(*a);
-            }
+        let a = match self.strategy {
+            AllocStrategy::FirstFit => self.first_fit(size),
+            AllocStrategy::BestFit => self.best_fit(size),
+            AllocStrategy::NextFit => self.next_fit(size),
         }
-        let mut exs = None;
-        for (a, ex) in self.extents.iter_mut() {
-            if ex.used {
-                continue;
-            }
-            if ex.size > size {
-                trace!("Found larger extent: {:x}", ex.size);
-                exs = Some((*a, size, ex.size));
-                break;
+        .ok_or(AMErrorFS::AllocFailed)?;
+        trace!("Found extent at {:x}", a);
+        self.mark_used(a, size)?;
+        if self.strategy == AllocStrategy::NextFit {
+            self.cursor = a + size;
+        }
+        Ok(a)
+    }
+    /// Finds the first free extent that fits, preferring an exact match
+    #[cfg(feature = "unstable")]
+    fn first_fit(&self, size: u64) -> Option<u64> {
+        let free = || self.extents.iter().map(|(a, ex)| (*a, self.room(*a, ex)));
+        free()
+            .find(|(_, room)| *room == size)
+            .or_else(|| free().find(|(_, room)| *room > size))
+            .map(|(a, _)| a)
+    }
+    /// Finds the smallest free extent that fits
+    #[cfg(feature = "unstable")]
+    fn best_fit(&self, size: u64) -> Option<u64> {
+        self.extents
+            .iter()
+            .map(|(a, ex)| (*a, self.room(*a, ex)))
+            .filter(|(_, room)| *room >= size)
+            .min_by_key(|(_, room)| *room)
+            .map(|(a, _)| a)
+    }
+    /// Finds the first free extent that fits after the last allocation, wrapping around
+    #[cfg(feature = "unstable")]
+    fn next_fit(&self, size: u64) -> Option<u64> {
+        self.extents
+            .range(self.cursor..)
+            .chain(self.extents.range(..self.cursor))
+            .find(|(a, ex)| self.room(**a, ex) >= size)
+            .map(|(a, _)| *a)
+    }
+    /// Gets how much of an extent can be allocated: none if it's used, and only the part below the limit
+    #[cfg(feature = "unstable")]
+    fn room(&self, start: u64, ex: &Extent) -> u64 {
+        if ex.used {
+            return 0;
+        }
+        match self.limit {
+            Some(limit) => std::cmp::min(ex.size, limit.saturating_sub(start)),
+            None => ex.size,
+        }
+    }
+    /// Allocates at a block if it's free, else in the first free extent after it, wrapping around
+    #[cfg(feature = "unstable")]
+    fn alloc_near(&mut self, size: u64, near: u64) -> AMResult<u64> {
+        assert!(size > 0);
+        self.check_reserved(size)?;
+        trace!("Allocating block of size {:x} near {:x}", size, near);
+        if let Some((a, ex)) = self.extents.range(..=near).next_back() {
+            if a + self.room(*a, ex) >= near + size {
+                self.mark_used(near, size)?;
+                return Ok(near);
             }
         }
-        if let Some((a, sa, se)) = exs {
-            *self.extents.get_mut(&a).ok_or(AMError::TODO(0))? = Extent {
-                size: sa,
-                used: true,
-            };
-            self.extents.insert(
-                a + sa,
-                Extent {
-                    size: se - sa,
-                    used: false,
-                },
-            );
-            return Ok(a);
+        let found = self
+            .extents
+            .range(near..)
+            .chain(self.extents.range(..near))
+            .find(|(a, ex)| self.room(**a, ex) >= size)
+            .map(|(a, _)| *a)
+            .ok_or(AMErrorFS::AllocFailed)?;
+        self.mark_used(found, size)?;
+        Ok(found)
+    }
+    /// Allocates from the start of the data zone, wrapping around into the metadata zone when full
+    #[cfg(feature = "unstable")]
+    fn alloc_data(&mut self, size: u64) -> AMResult<u64> {
+        if self.meta_end == 0 {
+            return self.alloc(size);
         }
-        Err(AMErrorFS::AllocFailed.into())
+        self.alloc_near(size, self.meta_end)
     }
     #[cfg(feature = "unstable")]
     fn alloc_many(&mut self, count: u64) -> AMResult<Vec<u64>> {
@@ -256,6 +498,76 @@ impl AllocatorObj {
         }
         Ok(())
     }
+    /// Splits the range out of the allocated extent holding it, then frees it
+    #[cfg(feature = "unstable")]
+    fn free_range(&mut self, start: u64, size: u64) -> AMResult<()> {
+        let (ex_start, ex_end) = self
+            .extents
+            .range(..=start)
+            .next_back()
+            .filter(|(_, ex)| ex.used)
+            .map(|(a, ex)| (*a, *a + ex.size))
+            .ok_or(AMError::TODO(0))?;
+        assert_or_err!(size > 0 && start + size <= ex_end, AMError::TODO(0));
+        if start + size < ex_end {
+            self.extents.insert(
+                start + size,
+                Extent {
+                    size: ex_end - (start + size),
+                    used: true,
+                },
+            );
+        }
+        self.extents
+            .get_mut(&ex_start)
+            .ok_or(AMError::TODO(0))?
+            .size = start - ex_start;
+        self.extents.insert(start, Extent { size, used: true });
+        self.free(start)
+    }
+    #[cfg(feature = "unstable")]
+    fn grow(&mut self, size: u64) -> AMResult<()> {
+        assert_or_err!(size >= self.size, AMError::TODO(0));
+        if size == self.size {
+            return Ok(());
+        }
+        let old = self.size;
+        self.size = size;
+        // Add the new space used, then free it to merge it with any free space before
+        self.extents.insert(
+            old,
+            Extent {
+                size: size - old,
+                used: true,
+            },
+        );
+        self.free(old)
+    }
+    /// Cuts the allocator down to a smaller size. Everything past the new end has to be free.
+    #[cfg(feature = "unstable")]
+    fn shrink(&mut self, size: u64) -> AMResult<()> {
+        assert_or_err!(size <= self.size, AMError::TODO(0));
+        if size == self.size {
+            return Ok(());
+        }
+        let (start, ex) = self
+            .extents
+            .range(..=size)
+            .next_back()
+            .map(|(a, ex)| (*a, ex.clone()))
+            .ok_or(AMError::TODO(0))?;
+        assert_or_err!(
+            !ex.used && start + ex.size == self.size,
+            AMErrorFS::AllocFailed
+        );
+        if start == size {
+            self.extents.remove(&start);
+        } else {
+            self.extents.get_mut(&start).ok_or(AMError::TODO(0))?.size = size - start;
+        }
+        self.size = size;
+        Ok(())
+    }
     #[cfg(feature = "stable")]
     fn mark_used(&mut self, start: u64, size: u64) -> AMResult<()> {
         let containing = self.extents.range(..=start).next_back();
@@ -306,8 +618,17 @@ impl AllocatorObj {
         let a = <Vec<u64> as LinkedListGlobal<Vec<u64>>>::read(diskgroups, ptr)?;
         let mut start = 0;
         let size = *a.first().ok_or(AMErrorFS::NoAllocator)?;
-        let mut allocator = Self::new(size);
-        for l in a[1..].iter() {
+        let mut allocator = Self::new(size & !(ZONED_FLAG | BITMAP_FLAG));
+        let mut entries = &a[1..];
+        if size & ZONED_FLAG != 0 {
+            allocator.meta_end = *entries.first().ok_or(AMErrorFS::NoAllocator)?;
+            entries = &entries[1..];
+        }
+        if size & BITMAP_FLAG != 0 {
+            allocator.extents = Self::from_bitmap(allocator.size, entries)?;
+            return Ok(allocator);
+        }
+        for l in entries.iter() {
             let size = l & 0x7FFFFFFFFFFFFFFF;
             let used = (l & 0x8000000000000000) != 0;
             allocator.extents.insert(start, Extent { size, used });
@@ -315,41 +636,151 @@ impl AllocatorObj {
         }
         Ok(allocator)
     }
+    /// Rebuilds the extents from a bitmap with two bits per block: the start of an extent, and used
     #[cfg(feature = "unstable")]
-    fn write(&mut self, diskgroups: &mut [Option<DiskGroup>]) -> AMResult<AMPointerGlobal> {
-        let mut a: Vec<u64> = self
-            .extents
-            .values()
-            .map(|v| {
+    fn from_bitmap(size: u64, words: &[u64]) -> AMResult<BTreeMap<u64, Extent>> {
+        let mut extents = BTreeMap::new();
+        let mut cur: Option<(u64, Extent)> = None;
+        for b in 0..size {
+            let word = words
+                .get(usize::try_from(b / 32)?)
+                .ok_or(AMErrorFS::NoAllocator)?;
+            let bits = word >> ((b % 32) * 2);
+            let used = bits & 2 != 0;
+            match cur.as_mut() {
+                Some((_, ex)) if bits & 1 == 0 => {
+                    assert_or_err!(ex.used == used, AMErrorFS::NoAllocator);
+                    ex.size += 1;
+                }
+                _ => {
+                    if let Some((start, ex)) = cur.take() {
+                        extents.insert(start, ex);
+                    }
+                    cur = Some((b, Extent { size: 1, used }));
+                }
+            }
+        }
+        if let Some((start, ex)) = cur {
+            extents.insert(start, ex);
+        }
+        Ok(extents)
+    }
+    /// Gets the number of words in the bitmap form of the allocator
+    #[cfg(feature = "unstable")]
+    fn bitmap_len(&self) -> usize {
+        usize::try_from((self.size + 31) / 32).unwrap_or(usize::MAX)
+    }
+    /// Checks whether the extents are stored as a bitmap
+    #[cfg(feature = "unstable")]
+    fn is_bitmap(&self) -> bool {
+        let extents = self.extents.len();
+        extents > BITMAP_THRESHOLD * entries_per_block() && self.bitmap_len() < extents
+    }
+    /// Gets the number of entries in the on-disk list
+    #[cfg(feature = "unstable")]
+    fn list_len(&self) -> usize {
+        let header = if self.meta_end == 0 { 1 } else { 2 };
+        if self.is_bitmap() {
+            self.bitmap_len() + header
+        } else {
+            self.extents.len() + header
+        }
+    }
+    /// Builds the on-disk list: the size, the zone boundary if any, then each extent or the bitmap
+    #[cfg(feature = "unstable")]
+    fn to_list(&self) -> AMResult<Vec<u64>> {
+        let bitmap = self.is_bitmap();
+        let flags = if bitmap { BITMAP_FLAG } else { 0 };
+        let mut a = Vec::with_capacity(self.list_len());
+        if self.meta_end == 0 {
+            a.push(self.size | flags);
+        } else {
+            a.push(self.size | ZONED_FLAG | flags);
+            a.push(self.meta_end);
+        }
+        if bitmap {
+            let mut words = vec![0u64; self.bitmap_len()];
+            for (start, ex) in &self.extents {
+                words[usize::try_from(start / 32)?] |= 1 << ((start % 32) * 2);
+                if ex.used {
+                    for b in *start..start + ex.size {
+                        words[usize::try_from(b / 32)?] |= 2 << ((b % 32) * 2);
+                    }
+                }
+            }
+            a.append(&mut words);
+        } else {
+            a.extend(self.extents.values().map(|v| {
                 if v.used {
                     v.size | 0x8000000000000000
                 } else {
                     v.size
                 }
-            })
-            .collect();
-        a.insert(0, self.size);
-        LinkedListGlobal::write(&a, diskgroups, 0)
+            }));
+        }
+        Ok(a)
     }
+    /// Gets how many trailing blocks of the last write hold the same entries as a new list
+    #[cfg(feature = "unstable")]
+    fn unchanged_blocks(&self, list: &[u64]) -> usize {
+        let chunks = list.chunks(entries_per_block());
+        if chunks.len() != self.written.len() {
+            return 0;
+        }
+        chunks
+            .zip(&self.written)
+            .rev()
+            .take_while(|(c, (_, w))| *c == w.as_slice())
+            .count()
+    }
+    /// Gets the number of new blocks needed to write out the allocator
+    #[cfg(feature = "unstable")]
+    fn blocks_needed(&self) -> AMResult<usize> {
+        let list = self.to_list()?;
+        let blocks = (list.len() + entries_per_block() - 1) / entries_per_block();
+        Ok(blocks - self.unchanged_blocks(&list))
+    }
+    #[cfg(feature = "unstable")]
+    fn write(&mut self, diskgroups: &mut [Option<DiskGroup>]) -> AMResult<AMPointerGlobal> {
+        self.written.clear();
+        LinkedListGlobal::write(&self.to_list()?, diskgroups, 0)
+    }
+    /// Writes out the allocator into preallocated blocks.
+    /// Blocks at the end of the last write with unchanged contents are linked to rather than rewritten.
     #[cfg(feature = "unstable")]
     fn write_preallocd(
         &mut self,
         diskgroups: &mut [Option<DiskGroup>],
         blocks: &[AMPointerGlobal],
     ) -> AMResult<AMPointerGlobal> {
-        let mut a: Vec<u64> = self
-            .extents
-            .values()
-            .map(|v| {
-                if v.used {
-                    v.size | 0x8000000000000000
-                } else {
-                    v.size
-                }
-            })
-            .collect();
-        a.insert(0, self.size);
-        LinkedListGlobal::write_preallocd(&a, diskgroups, blocks)
+        let list = self.to_list()?;
+        let mut chunks: Vec<&[u64]> = list.chunks(entries_per_block()).collect();
+        assert_or_err!(
+            blocks.len() >= chunks.len() - self.unchanged_blocks(&list),
+            AMErrorFS::AllocFailed
+        );
+        // Spare blocks take over more of the end of the list, or are left empty
+        let fresh = blocks.len();
+        if chunks.len() < fresh {
+            chunks.resize(fresh, &[]);
+        }
+        let mut written = if fresh < chunks.len() {
+            self.written.split_off(fresh)
+        } else {
+            Vec::new()
+        };
+        let mut next = written.first().map_or(AMPointerGlobal::null(), |(p, _)| *p);
+        for i in (0..fresh).rev() {
+            next = write_block(diskgroups, blocks[i], chunks[i], next)?;
+            written.insert(0, (next, chunks[i].to_vec()));
+        }
+        trace!(
+            "Wrote {} allocator blocks, kept {}",
+            fresh,
+            written.len() - fresh
+        );
+        self.written = written;
+        Ok(next)
     }
 }
 
@@ -374,3 +805,258 @@ fn rw_test() {
 
     assert_eq!(a, a2);
 }
+
+#[test]
+fn test_fragmentation() {
+    #![allow(clippy::unwrap_used)]
+
+    let mut a = AllocatorObj::new(100);
+    assert_eq!(a.free_extents(), 1);
+    assert_eq!(a.largest_free(), 100);
+    assert!(a.fragmentation().abs() < 1e-9);
+
+    for _ in 0..10 {
+        a.alloc(10).unwrap();
+    }
+    assert_eq!(a.free_extents(), 0);
+    assert!(a.fragmentation().abs() < 1e-9);
+    for i in 0..5 {
+        a.free(i * 20).unwrap();
+    }
+    assert_eq!(a.free_extents(), 5);
+    assert_eq!(a.largest_free(), 10);
+    assert!((a.fragmentation() - 0.8).abs() < 1e-9);
+}
+
+#[test]
+fn test_free_range() {
+    #![allow(clippy::unwrap_used)]
+
+    let mut a = AllocatorObj::new(100);
+    let extents = |a: &AllocatorObj| -> Vec<(u64, u64, bool)> {
+        a.extents
+            .iter()
+            .map(|(start, ex)| (*start, ex.size, ex.used))
+            .collect()
+    };
+    assert_eq!(a.alloc(10).unwrap(), 0);
+    assert_eq!(a.alloc(10).unwrap(), 10);
+    // Freeing the middle of an extent keeps both ends allocated
+    a.free_range(4, 2).unwrap();
+    assert_eq!(
+        extents(&a),
+        vec![
+            (0, 4, true),
+            (4, 2, false),
+            (6, 4, true),
+            (10, 10, true),
+            (20, 80, false)
+        ]
+    );
+    // Freeing the ends merges them with the free space around them
+    a.free_range(0, 4).unwrap();
+    a.free_range(16, 4).unwrap();
+    assert_eq!(
+        extents(&a),
+        vec![(0, 6, false), (6, 4, true), (10, 6, true), (16, 84, false)]
+    );
+    // The range has to lie in one allocated extent
+    assert!(a.free_range(8, 4).is_err());
+    assert!(a.free_range(0, 1).is_err());
+}
+
+#[test]
+fn test_alloc_near() {
+    #![allow(clippy::unwrap_used)]
+
+    let mut a = AllocatorObj::new(100);
+    assert_eq!(a.alloc_near(2, 50).unwrap(), 50);
+    assert_eq!(a.alloc_near(2, 52).unwrap(), 52);
+    // Taken blocks push the allocation to the next free extent
+    assert_eq!(a.alloc_near(2, 50).unwrap(), 54);
+    assert_eq!(a.alloc_near(50, 60).unwrap(), 0);
+    assert!(a.alloc_near(50, 0).is_err());
+}
+
+#[test]
+fn test_reserve() {
+    #![allow(clippy::unwrap_used)]
+
+    let mut a = AllocatorObj::new(10);
+    a.alloc(2).unwrap();
+    assert!(a.reserve(9).is_err());
+    let mut r = a.reserve(6).unwrap();
+    // Only the unreserved blocks are left to others
+    assert!(a.alloc(3).is_err());
+    assert!(a.reserve(3).is_err());
+    a.alloc(2).unwrap();
+    a.alloc_reserved(&mut r, 4).unwrap();
+    assert_eq!(r.remaining(), 2);
+    assert!(a.alloc_reserved(&mut r, 3).is_err());
+    assert!(a.alloc(1).is_err());
+    a.release(r);
+    a.alloc(2).unwrap();
+    assert_eq!(a.free_space(), 0);
+}
+
+#[test]
+fn test_zones() {
+    #![allow(clippy::unwrap_used)]
+
+    let dg = crate::test::dg::create_dg_mem_single(100);
+
+    let mut a = AllocatorObj::new(100);
+    a.meta_end = 20;
+    assert_eq!(a.alloc(1).unwrap(), 0);
+    assert_eq!(a.alloc_data(1).unwrap(), 20);
+    assert_eq!(a.alloc(1).unwrap(), 1);
+    // A full data zone spills over into the metadata zone
+    assert_eq!(a.alloc_data(79).unwrap(), 21);
+    assert_eq!(a.alloc_data(1).unwrap(), 2);
+
+    let ptr = a.write(&mut vec![Some(dg.clone())]).unwrap();
+    let a2 = AllocatorObj::read(&vec![Some(dg)], ptr).unwrap();
+    assert_eq!(a, a2);
+}
+
+#[test]
+fn test_strategies() {
+    #![allow(clippy::unwrap_used)]
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    for strategy in [
+        AllocStrategy::FirstFit,
+        AllocStrategy::BestFit,
+        AllocStrategy::NextFit,
+    ] {
+        // The same mix of allocations and frees for each strategy
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut a = AllocatorObj::new(10000);
+        a.strategy = strategy;
+        let mut live = Vec::new();
+        for _ in 0..5000 {
+            if live.len() > 100 && rng.gen_bool(0.5) {
+                let i = rng.gen_range(0..live.len());
+                a.free(live.swap_remove(i)).unwrap();
+            } else {
+                live.push(a.alloc(rng.gen_range(1..16)).unwrap());
+            }
+        }
+        info!(
+            "{:?}: {} free extents, largest {}, fragmentation {:.3}",
+            strategy,
+            a.free_extents(),
+            a.largest_free(),
+            a.fragmentation()
+        );
+        for addr in live {
+            a.free(addr).unwrap();
+        }
+        assert_eq!(a.free_extents(), 1);
+    }
+}
+
+#[test]
+fn test_bitmap() {
+    #![allow(clippy::unwrap_used)]
+
+    let dg = crate::test::dg::create_dg_mem_single(1000);
+
+    let size = 100000;
+    let mut a = AllocatorObj::new(size);
+    a.meta_end = 50;
+    // Alternating used and free blocks make for a long extent list
+    let mut addrs = Vec::new();
+    for _ in 0..5000 {
+        addrs.push(a.alloc(2).unwrap());
+    }
+    for addr in addrs.iter().step_by(2) {
+        a.free(*addr).unwrap();
+    }
+    assert!(a.is_bitmap());
+    assert!(a.list_len() < a.extents.len());
+
+    let ptr = a.write(&mut vec![Some(dg.clone())]).unwrap();
+    let a2 = AllocatorObj::read(&vec![Some(dg.clone())], ptr).unwrap();
+    assert_eq!(a, a2);
+
+    // Once the space is defragmented, it goes back to a list
+    for addr in addrs.iter().skip(1).step_by(2) {
+        a.free(*addr).unwrap();
+    }
+    assert!(!a.is_bitmap());
+    let ptr = a.write(&mut vec![Some(dg.clone())]).unwrap();
+    let a2 = AllocatorObj::read(&vec![Some(dg)], ptr).unwrap();
+    assert_eq!(a, a2);
+}
+
+#[test_fs(mem, file, blocks = 10000)]
+#[allow(clippy::unwrap_used)]
+pub fn test_bitmap_feature(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    let bit = crate::AMFeatures::BitmapAllocators as usize;
+    fs.commit().unwrap();
+    assert!(!fs.read().unwrap().get_superblock().unwrap().features()[bit]);
+
+    // Alternating used and free blocks make for a long extent list
+    let mut a = fs.read().unwrap().get_diskgroups()[0]
+        .as_ref()
+        .unwrap()
+        .allocs[0]
+        .clone();
+    let addrs: Vec<u64> = (0..3000).map(|_| a.alloc_blocks(1).unwrap()).collect();
+    for addr in addrs.iter().step_by(2) {
+        a.free(*addr).unwrap();
+    }
+    fs.commit().unwrap();
+    assert!(a.is_bitmap());
+    assert!(fs.read().unwrap().get_superblock().unwrap().features()[bit]);
+}
+
+#[test]
+fn test_incremental_write() {
+    #![allow(clippy::unwrap_used)]
+    use crate::ondisk::linkedlist::list_blocks;
+
+    let mut dgs = vec![Some(crate::test::dg::create_dg_mem_single(1000))];
+
+    let mut a = Allocator::new(100000);
+    for _ in 0..3000 {
+        a.alloc_blocks(1).unwrap();
+    }
+    let mut blocks = Vec::new();
+    assert!(a.prealloc(&mut dgs, 0, &mut blocks).unwrap());
+    assert_eq!(blocks.len(), 6);
+    let ptr = a.write_preallocd(&mut dgs, &blocks).unwrap();
+
+    // Nothing changed, so nothing is rewritten
+    let mut blocks = Vec::new();
+    assert!(!a.prealloc(&mut dgs, 0, &mut blocks).unwrap());
+    assert_eq!(a.write_preallocd(&mut dgs, &blocks).unwrap(), ptr);
+
+    // Freeing the first extent only changes the first block
+    a.free(0).unwrap();
+    let mut blocks = Vec::new();
+    a.prealloc(&mut dgs, 0, &mut blocks).unwrap();
+    assert_eq!(blocks.len(), 1);
+    let ptr2 = a.write_preallocd(&mut dgs, &blocks).unwrap();
+    assert_eq!(Allocator::read(&dgs, ptr2).unwrap().extents(), a.extents());
+    let old = list_blocks(&dgs, ptr).unwrap();
+    let new = list_blocks(&dgs, ptr2).unwrap();
+    assert_ne!(old[0], new[0]);
+    assert_eq!(old[1..], new[1..]);
+
+    // Spare blocks take over more of the list
+    a.free(5).unwrap();
+    let mut blocks = Vec::new();
+    a.prealloc(&mut dgs, 0, &mut blocks).unwrap();
+    blocks.append(&mut dgs[0].as_mut().unwrap().alloc_many(1).unwrap());
+    let ptr3 = a.write_preallocd(&mut dgs, &blocks).unwrap();
+    assert_eq!(Allocator::read(&dgs, ptr3).unwrap().extents(), a.extents());
+    let newer = list_blocks(&dgs, ptr3).unwrap();
+    assert!(newer[..2]
+        .iter()
+        .map(|p| p.loc())
+        .eq(blocks.iter().map(|p| p.loc())));
+    assert_eq!(newer[2..], new[2..]);
+}
@@ -9,7 +9,12 @@ use amos_std::{error::AMErrorFS, AMResult};
 use endian_codec::{DecodeLE, PackedSize};
 use type_layout::TypeLayout;
 
-use crate::{AMPointerGlobal, Allocator, DiskGroup, LinkedListGlobal, BLOCK_SIZE};
+use crate::{
+    location::{at_location, ErrorLocation},
+    metrics::report_checksum_failure,
+    ondisk::linkedlist::list_blocks,
+    AMPointerGlobal, Allocator, DiskGroup, Fragment, LinkedListGlobal, Object, BLOCK_SIZE,
+};
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, TypeLayout)]
@@ -21,8 +26,18 @@ pub struct FSGroup {
     /// A pointer to the root node of the object tree
     pub objects: AMPointerGlobal,
     directory:   u64,
+    /// Keeps the 16-byte aligned txid from leaving uninitialized padding before it
+    _reserved:   u64,
     txid:        u128,
-    _padding:    [u8; BLOCK_SIZE - 88],
+    refcounts:   AMPointerGlobal,
+    last_scrub:  u64,
+    scrub_errs:  u64,
+    committed:   u64,
+    versions:    AMPointerGlobal,
+    /// Keeps the 16-byte aligned received txid from leaving uninitialized padding before it
+    _reserved2:  u64,
+    received:    u128,
+    _padding:    [u8; BLOCK_SIZE - 176],
 }
 
 #[repr(packed)]
@@ -57,6 +72,56 @@ pub struct FreeQueueEntry {
     pub block: AMPointerGlobal,
 }
 
+#[repr(C)]
+/// A reference count for a shared extent.
+#[derive(Clone, Copy, Debug, PackedSize, DecodeLE)]
+pub struct RefCountEntry {
+    /// The number of fragments referring to the extent
+    pub count:  u64,
+    _padding:   u64,
+    /// A pointer to the extent
+    pub extent: AMPointerGlobal,
+}
+
+impl RefCountEntry {
+    /// Create a refcount entry from a pointer to an extent and its count
+    pub fn new(extent: AMPointerGlobal, count: u64) -> Self {
+        Self {
+            count,
+            _padding: 0,
+            extent,
+        }
+    }
+}
+
+#[repr(C)]
+/// A fragment of a prior version of an object.
+/// Every versioned object also has one entry with a transaction ID of 0, marking it as versioned.
+#[derive(Clone, Copy, Debug, PackedSize, DecodeLE)]
+pub struct VersionEntry {
+    /// The object's ID
+    pub id:      u64,
+    /// The number of prior versions kept of the object
+    pub limit:   u64,
+    /// The transaction whose contents the version holds
+    pub txid:    u128,
+    /// The length of the fragment, in bytes. Versions without fragments have a single empty hole.
+    pub size:    u64,
+    /// The offset from the pointer location to the start of the fragment
+    pub offset:  u64,
+    /// A pointer to the fragment's data, or null for a hole
+    pub pointer: AMPointerGlobal,
+}
+
+/// The prior versions kept of an object
+#[derive(Clone, Debug, Default)]
+pub struct VersionHistory {
+    /// The number of prior versions kept
+    pub limit:    u64,
+    /// Each version kept, by the transaction whose contents it holds
+    pub versions: BTreeMap<u128, Object>,
+}
+
 impl FSGroup {
     /// Creates a new blank FS group
     #[cfg(feature = "unstable")]
@@ -67,8 +132,16 @@ impl FSGroup {
             journal:    AMPointerGlobal::null(),
             objects:    AMPointerGlobal::null(),
             directory:  0,
+            _reserved:  0,
             txid:       0,
-            _padding:   [0; BLOCK_SIZE - 88],
+            refcounts:  AMPointerGlobal::null(),
+            last_scrub: 0,
+            scrub_errs: 0,
+            committed:  0,
+            versions:   AMPointerGlobal::null(),
+            _reserved2: 0,
+            received:   0,
+            _padding:   [0; BLOCK_SIZE - 176],
         }
     }
     /// Gets this group's transaction ID
@@ -76,6 +149,52 @@ impl FSGroup {
     pub fn txid(&self) -> u128 {
         self.txid
     }
+    /// Sets this group's transaction ID
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_txid(&mut self, txid: u128) {
+        self.txid = txid;
+    }
+    /// Gets when the filesystem was last scrubbed, as seconds since the Unix epoch. 0 if never.
+    #[cfg(feature = "unstable")]
+    pub fn last_scrub(&self) -> u64 {
+        self.last_scrub
+    }
+    /// Gets how many damaged fragments the last scrub found
+    #[cfg(feature = "unstable")]
+    pub fn scrub_errors(&self) -> u64 {
+        self.scrub_errs
+    }
+    /// Records the outcome of a scrub
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_scrub(&mut self, time: u64, errors: u64) {
+        self.last_scrub = time;
+        self.scrub_errs = errors;
+    }
+    /// Gets when this group was committed, as seconds since the Unix epoch. 0 if not recorded.
+    #[cfg(feature = "unstable")]
+    pub fn committed(&self) -> u64 {
+        self.committed
+    }
+    /// Records when this group was committed
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_committed(&mut self, time: u64) {
+        self.committed = time;
+    }
+    /// Gets the transaction ID of the last root received from a send stream. 0 if none.
+    #[cfg(feature = "unstable")]
+    pub fn received(&self) -> u128 {
+        self.received
+    }
+    /// Records the transaction ID of a root received from a send stream
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_received(&mut self, txid: u128) {
+        self.received = txid;
+    }
+    /// Gets a pointer to this group's list of object versions
+    #[cfg(feature = "unstable")]
+    pub fn versions(&self) -> AMPointerGlobal {
+        self.versions
+    }
     /// Gets a pointer to this group's allocator
     #[cfg(feature = "unstable")]
     pub fn alloc(&self) -> AMPointerGlobal {
@@ -101,6 +220,11 @@ impl FSGroup {
     pub fn free_queue(&self) -> AMPointerGlobal {
         self.free_queue
     }
+    /// Gets a pointer to this group's reference count list
+    #[cfg(feature = "unstable")]
+    pub fn refcounts(&self) -> AMPointerGlobal {
+        self.refcounts
+    }
     /// Reads a FSGroup from the disk group
     #[cfg(feature = "unstable")]
     pub fn read(diskgroups: &[Option<DiskGroup>], ptr: AMPointerGlobal) -> AMResult<FSGroup> {
@@ -110,7 +234,12 @@ impl FSGroup {
 
         let mut res: FSGroup = FSGroup::new();
         ptr.read(0, BLOCK_SIZE, diskgroups, &mut res)?;
-        assert_or_err!(ptr.validate(diskgroups)?, AMErrorFS::Checksum);
+        if !ptr.validate(diskgroups)? {
+            report_checksum_failure(diskgroups, ptr);
+            return at_location(Err(AMErrorFS::Checksum.into()), || {
+                ErrorLocation::global(ptr, 0, diskgroups)
+            });
+        }
         Ok(res)
     }
     /// Writes a FSGroup to the disk group
@@ -155,11 +284,12 @@ impl FSGroup {
         }
         Ok(res)
     }
-    /// Writes out the free queue
+    /// Writes out the free queue, allocating from the `n`th disk group
     #[cfg(feature = "unstable")]
     pub fn write_free_queue(
         &mut self,
         diskgroups: &[Option<DiskGroup>],
+        n: u8,
         queue: &BTreeMap<u128, Vec<AMPointerGlobal>>,
     ) -> AMResult<()> {
         let mut res = Vec::new();
@@ -171,22 +301,122 @@ impl FSGroup {
                 });
             }
         }
-        self.free_queue = LinkedListGlobal::write(&res, diskgroups, 0)?;
+        self.free_queue = LinkedListGlobal::write(&res, diskgroups, n)?;
+        Ok(())
+    }
+    /// Loads the reference counts of shared extents
+    #[cfg(feature = "unstable")]
+    pub fn get_refcounts(
+        &self,
+        diskgroups: &[Option<DiskGroup>],
+    ) -> AMResult<BTreeMap<AMPointerGlobal, u64>> {
+        let counts: Vec<RefCountEntry> = <Vec<RefCountEntry> as LinkedListGlobal<
+            Vec<RefCountEntry>,
+        >>::read(diskgroups, self.refcounts)?;
+        Ok(counts.iter().map(|e| (e.extent, e.count)).collect())
+    }
+    /// Writes out the reference counts of shared extents, allocating from the `n`th disk group
+    #[cfg(feature = "unstable")]
+    pub fn write_refcounts(
+        &mut self,
+        diskgroups: &[Option<DiskGroup>],
+        n: u8,
+        counts: &BTreeMap<AMPointerGlobal, u64>,
+    ) -> AMResult<()> {
+        if counts.is_empty() {
+            self.refcounts = AMPointerGlobal::null();
+            return Ok(());
+        }
+        let res: Vec<RefCountEntry> = counts
+            .iter()
+            .map(|(k, v)| RefCountEntry::new(*k, *v))
+            .collect();
+        self.refcounts = LinkedListGlobal::write(&res, diskgroups, n)?;
+        Ok(())
+    }
+    /// Loads the prior versions kept of versioned objects
+    #[cfg(feature = "unstable")]
+    pub fn get_versions(
+        &self,
+        diskgroups: &[Option<DiskGroup>],
+    ) -> AMResult<BTreeMap<u64, VersionHistory>> {
+        let entries: Vec<VersionEntry> = <Vec<VersionEntry> as LinkedListGlobal<
+            Vec<VersionEntry>,
+        >>::read(diskgroups, self.versions)?;
+        let mut frags: BTreeMap<(u64, u128), Vec<Fragment>> = BTreeMap::new();
+        let mut res: BTreeMap<u64, VersionHistory> = BTreeMap::new();
+        for e in entries {
+            res.entry(e.id).or_default().limit = e.limit;
+            if e.txid != 0 {
+                let f = frags.entry((e.id, e.txid)).or_default();
+                if e.size != 0 || !e.pointer.is_null() {
+                    f.push(Fragment::new(e.size, e.offset, e.pointer));
+                }
+            }
+        }
+        for ((id, txid), f) in frags {
+            if let Some(history) = res.get_mut(&id) {
+                history.versions.insert(txid, Object::new(&f));
+            }
+        }
+        Ok(res)
+    }
+    /// Gets the pointers to the blocks of this group's list of object versions
+    #[cfg(feature = "unstable")]
+    pub fn get_version_blocks(
+        &self,
+        diskgroups: &[Option<DiskGroup>],
+    ) -> AMResult<Vec<AMPointerGlobal>> {
+        list_blocks(diskgroups, self.versions)
+    }
+    /// Writes out the prior versions kept of versioned objects, allocating from the `n`th disk group
+    #[cfg(feature = "unstable")]
+    pub fn write_versions(
+        &mut self,
+        diskgroups: &[Option<DiskGroup>],
+        n: u8,
+        versions: &BTreeMap<u64, VersionHistory>,
+    ) -> AMResult<()> {
+        if versions.is_empty() {
+            self.versions = AMPointerGlobal::null();
+            return Ok(());
+        }
+        let mut res = Vec::new();
+        for (id, history) in versions {
+            let entry = |txid, f: &Fragment| VersionEntry {
+                id: *id,
+                limit: history.limit,
+                txid,
+                size: f.size,
+                offset: f.offset,
+                pointer: f.pointer,
+            };
+            res.push(entry(0, &Fragment::hole(0)));
+            for (txid, obj) in &history.versions {
+                let frags = obj.frags();
+                if frags.is_empty() {
+                    res.push(entry(*txid, &Fragment::hole(0)));
+                }
+                res.extend(frags.iter().map(|f| entry(*txid, f)));
+            }
+        }
+        self.versions = LinkedListGlobal::write(&res, diskgroups, n)?;
         Ok(())
     }
-    /// Writes out the allocator object for each disk
+    /// Writes out the allocator object for each disk, allocating from the `n`th disk group
     #[cfg(feature = "unstable")]
     pub fn write_allocators(
         &mut self,
         diskgroups: &mut [Option<DiskGroup>],
+        n: u8,
         ad: &mut BTreeMap<u64, Allocator>,
     ) -> AMResult<()> {
-        let alloc_blocks = ad
-            .iter_mut()
-            .map(|(k, v)| Ok((*k, v.prealloc(diskgroups, 0)?)))
-            .collect::<AMResult<BTreeMap<u64, Vec<AMPointerGlobal>>>>()?;
+        let mut alloc_blocks: BTreeMap<u64, Vec<AMPointerGlobal>> =
+            ad.keys().map(|k| (*k, Vec::new())).collect();
+        Self::prealloc_allocators(diskgroups, n, ad, &mut alloc_blocks)?;
         let allocs: Vec<AllocListEntry> = Vec::new();
-        let llg_blocks = LinkedListGlobal::prealloc(&allocs, alloc_blocks.len(), diskgroups, 0)?;
+        let llg_blocks = LinkedListGlobal::prealloc(&allocs, alloc_blocks.len(), diskgroups, n)?;
+        Self::prealloc_allocators(diskgroups, n, ad, &mut alloc_blocks)?;
         let allocs = ad
             .iter_mut()
             .map(|(k, v)| {
@@ -200,6 +430,54 @@ impl FSGroup {
         self.alloc = LinkedListGlobal::write_preallocd(&allocs, diskgroups, &llg_blocks)?;
         Ok(())
     }
+    /// Preallocates blocks for each allocator until none needs more.
+    /// Allocating blocks for one allocator can change what the others have to write.
+    #[cfg(feature = "unstable")]
+    fn prealloc_allocators(
+        diskgroups: &mut [Option<DiskGroup>],
+        n: u8,
+        ad: &BTreeMap<u64, Allocator>,
+        blocks: &mut BTreeMap<u64, Vec<AMPointerGlobal>>,
+    ) -> AMResult<()> {
+        loop {
+            let mut added = false;
+            for (k, v) in ad {
+                let res = blocks.get_mut(k).ok_or(AMErrorFS::NoAllocator)?;
+                added |= v.prealloc(diskgroups, n, res)?;
+            }
+            if !added {
+                return Ok(());
+            }
+        }
+    }
+    /// Gets the pointers to the blocks of every allocator in this group
+    #[cfg(feature = "unstable")]
+    pub fn get_alloc_blocks(
+        &self,
+        diskgroups: &[Option<DiskGroup>],
+    ) -> AMResult<Vec<AMPointerGlobal>> {
+        let allocs: Vec<AllocListEntry> = <Vec<AllocListEntry> as LinkedListGlobal<
+            Vec<AllocListEntry>,
+        >>::read(diskgroups, self.alloc)?;
+        let mut res = Vec::new();
+        for a in allocs {
+            res.append(&mut list_blocks(diskgroups, a.allocator)?);
+        }
+        Ok(res)
+    }
+    /// Gets the pointers to every metadata block owned by this group.
+    /// The object set is shared between groups, and isn't included.
+    #[cfg(feature = "unstable")]
+    pub fn get_blocks(&self, diskgroups: &[Option<DiskGroup>]) -> AMResult<Vec<AMPointerGlobal>> {
+        let mut res = list_blocks(diskgroups, self.alloc)?;
+        res.append(&mut self.get_alloc_blocks(diskgroups)?);
+        res.append(&mut list_blocks(diskgroups, self.free_queue)?);
+        res.append(&mut list_blocks(diskgroups, self.refcounts)?);
+        res.append(&mut self.get_version_blocks(diskgroups)?);
+        // Journal blocks start with a pointer to the one before, like list blocks
+        res.append(&mut list_blocks(diskgroups, self.journal)?);
+        Ok(res)
+    }
     /// Gets the pointer to the objects table
     #[cfg(feature = "unstable")]
     pub fn get_obj_ptr(&self) -> AMPointerGlobal {
@@ -244,3 +522,13 @@ fn size_test_ale() {
 fn size_test_fqe() {
     assert_eq!(mem::size_of::<FreeQueueEntry>(), 32);
 }
+
+#[test]
+fn size_test_rce() {
+    assert_eq!(mem::size_of::<RefCountEntry>(), 32);
+}
+
+#[test]
+fn size_test_ve() {
+    assert_eq!(mem::size_of::<VersionEntry>(), 64);
+}
@@ -1,12 +1,28 @@
-use std::{
-    mem,
-    ops::{Deref, DerefMut},
-    slice,
+use std::convert::{TryFrom, TryInto};
+
+use amos_std::{
+    error::{AMError, AMErrorFS},
+    AMResult,
+};
+
+use crate::{
+    location::{at_location, ErrorLocation},
+    AMPointerLocal, Disk, BLOCK_SIZE,
 };
 
-use amos_std::{error::AMErrorFS, AMResult};
+/// Device IDs held by a geometry's first block
+pub const HEAD_DEVICES: usize = 256;
+/// Device IDs held by each continuation block of a geometry's device table
+pub const CONTINUATION_DEVICES: usize = (BLOCK_SIZE - 16) / 8;
 
-use crate::{AMPointerLocal, Disk, BLOCK_SIZE};
+/// Offset of the device count in the first block. Zero for tables that fit in the first block.
+const COUNT_OFFSET: usize = HEAD_DEVICES * 8;
+/// Offset of the pointer to the first continuation block
+const HEAD_NEXT_OFFSET: usize = COUNT_OFFSET + 8;
+/// Offset of the pointer to the next continuation block
+const CONTINUATION_NEXT_OFFSET: usize = CONTINUATION_DEVICES * 8;
+/// Offset of the flavor in the first block
+const FLAVOR_OFFSET: usize = BLOCK_SIZE - 1;
 
 /// Describes the way the disks are arranged into the geometry.
 #[repr(u8)]
@@ -14,19 +30,24 @@ use crate::{AMPointerLocal, Disk, BLOCK_SIZE};
 pub enum GeometryFlavor {
     /// A single disk.
     Single,
-    /// Multiple striped disks.
-    _Striped,
+    /// Multiple disks. Each extent lies on one disk, with new extents going to the emptiest.
+    Striped,
 }
 
-#[repr(packed)]
-/// Represents a particular arrangement of disks into a volume
-#[derive(Copy, Clone, Debug)]
+/// Represents a particular arrangement of disks into a volume.
+///
+/// The first block holds up to 256 device IDs, ended by a zero, as every geometry always has.
+/// Larger tables record their length after those, and continue in a chain of blocks holding
+/// 510 IDs each. Such tables need the `DeviceTable` feature.
+/// Pointers name their device in a byte, so extents can only be placed on the first 256 devices.
+#[derive(Clone, Debug)]
 pub struct Geometry {
     ///The device IDs of each disk within the arrangement
-    pub device_ids: [u64; 256],
-    _padding:       [u8; BLOCK_SIZE - 2049],
+    pub device_ids: Vec<u64>,
     ///The arrangement of disks within the geometry
     pub flavor:     GeometryFlavor,
+    /// The continuation blocks the device table was read from
+    chain:          Vec<AMPointerLocal>,
 }
 
 impl Geometry {
@@ -35,56 +56,188 @@ impl Geometry {
     pub fn new() -> Geometry {
         Geometry {
             flavor:     GeometryFlavor::Single,
-            device_ids: [0; 256],
-            _padding:   [0; BLOCK_SIZE - 2049],
+            device_ids: Vec::new(),
+            chain:      Vec::new(),
         }
     }
-    /// Reads a geometry from disk.
+    /// Reads a geometry from disk, following its device table's continuation blocks.
     #[cfg(feature = "stable")]
     pub fn read(mut d: Disk, ptr: AMPointerLocal) -> AMResult<Geometry> {
-        let mut res: Geometry = Geometry::new();
-        d.read_at(ptr.loc(), &mut res)?;
-        assert_or_err!(ptr.validate(d)?, AMErrorFS::Checksum);
+        let mut buf = [0u8; BLOCK_SIZE];
+        d.read_at(ptr.loc(), &mut buf)?;
+        if !ptr.validate(d.clone())? {
+            return at_location(Err(AMErrorFS::Checksum.into()), || {
+                ErrorLocation::local(None, ptr.loc())
+            });
+        }
+        let flavor = match buf[FLAVOR_OFFSET] {
+            0 => GeometryFlavor::Single,
+            1 => GeometryFlavor::Striped,
+            _ => return Err(AMError::TODO(0).into()),
+        };
+        let count = usize::try_from(Self::read_u64(&buf, COUNT_OFFSET)?)?;
+        let mut res = Geometry {
+            device_ids: Self::read_ids(&buf, HEAD_DEVICES)?,
+            flavor,
+            chain: Vec::new(),
+        };
+        if count == 0 {
+            // The whole table is in this block
+            if let Some(end) = res.device_ids.iter().position(|id| *id == 0) {
+                res.device_ids.truncate(end);
+            }
+            return Ok(res);
+        }
+        res.device_ids.truncate(count);
+        let mut next = Self::read_ptr(&buf, HEAD_NEXT_OFFSET)?;
+        while res.device_ids.len() < count {
+            assert_or_err!(!next.is_null(), AMErrorFS::NullPointer);
+            d.read_at(next.loc(), &mut buf)?;
+            if !next.validate(d.clone())? {
+                return at_location(Err(AMErrorFS::Checksum.into()), || {
+                    ErrorLocation::local(None, next.loc())
+                });
+            }
+            let ids = Self::read_ids(&buf, CONTINUATION_DEVICES)?;
+            let rem = count - res.device_ids.len();
+            res.device_ids.extend_from_slice(&ids[..rem.min(ids.len())]);
+            res.chain.push(next);
+            next = Self::read_ptr(&buf, CONTINUATION_NEXT_OFFSET)?;
+        }
         Ok(res)
     }
-    /// Writes a geometry to disk.
+    /// Writes a geometry whose device table fits in one block to disk.
     #[cfg(feature = "stable")]
-    pub fn write(&self, mut d: Disk, mut ptr: AMPointerLocal) -> AMResult<AMPointerLocal> {
-        d.write_at(ptr.loc(), self)?;
-        ptr.update(d)?;
-        Ok(ptr)
+    pub fn write(&self, d: Disk, ptr: AMPointerLocal) -> AMResult<AMPointerLocal> {
+        self.write_chain(d, &[ptr])
+    }
+    /// Writes a geometry to disk, using one block from `ptrs` for each of `table_blocks`.
+    /// The first block is the one superblocks point to.
+    #[cfg(feature = "unstable")]
+    pub fn write_chain(&self, mut d: Disk, ptrs: &[AMPointerLocal]) -> AMResult<AMPointerLocal> {
+        assert_or_err!(ptrs.len() == self.table_blocks(), AMError::TODO(0));
+        let mut next = AMPointerLocal::null();
+        // Write the chain back to front, so each block can hold the checksum of the next
+        for (n, mut ptr) in ptrs.iter().copied().enumerate().skip(1).rev() {
+            let start = HEAD_DEVICES + (n - 1) * CONTINUATION_DEVICES;
+            let end = self.device_ids.len().min(start + CONTINUATION_DEVICES);
+            let mut buf = [0u8; BLOCK_SIZE];
+            Self::write_ids(&mut buf, &self.device_ids[start..end]);
+            buf[CONTINUATION_NEXT_OFFSET..].copy_from_slice(&next.as_bytes());
+            d.write_at(ptr.loc(), &buf)?;
+            ptr.update(d.clone())?;
+            next = ptr;
+        }
+        let mut head = ptrs[0];
+        d.write_at(head.loc(), &self.encode_head(next)?)?;
+        head.update(d)?;
+        Ok(head)
+    }
+    /// Encodes a geometry whose device table fits in one block
+    #[cfg(feature = "unstable")]
+    pub fn to_bytes(&self) -> AMResult<[u8; BLOCK_SIZE]> {
+        assert_or_err!(self.table_blocks() == 1, AMError::TODO(0));
+        self.encode_head(AMPointerLocal::null())
+    }
+    /// Gets the number of blocks needed to store the geometry
+    #[cfg(feature = "unstable")]
+    pub fn table_blocks(&self) -> usize {
+        let rest = self.device_ids.len().saturating_sub(HEAD_DEVICES);
+        1 + (rest + CONTINUATION_DEVICES - 1) / CONTINUATION_DEVICES
+    }
+    /// Gets the continuation blocks the device table was read from, if it didn't fit in one
+    #[cfg(feature = "unstable")]
+    pub fn continuation_blocks(&self) -> &[AMPointerLocal] {
+        &self.chain
+    }
+    /// Checks if the device table is too long for drivers without the `DeviceTable` feature
+    #[cfg(feature = "unstable")]
+    pub fn is_long(&self) -> bool {
+        self.device_ids.len() > HEAD_DEVICES
     }
     /// Gets the geometry object's flavor
     #[cfg(feature = "stable")]
     pub fn flavor(&self) -> GeometryFlavor {
         self.flavor
     }
-}
-
-impl Deref for Geometry {
-    type Target = [u8];
+    /// Encodes the first block, pointing on to the continuation blocks
     #[cfg(feature = "unstable")]
-    fn deref(&self) -> &[u8] {
-        unsafe {
-            slice::from_raw_parts(
-                self as *const Geometry as *const u8,
-                mem::size_of::<Geometry>(),
-            ) as &[u8]
+    fn encode_head(&self, next: AMPointerLocal) -> AMResult<[u8; BLOCK_SIZE]> {
+        // A zero would end the table early
+        assert_or_err!(!self.device_ids.contains(&0), AMError::TODO(0));
+        let mut buf = [0u8; BLOCK_SIZE];
+        let head = self.device_ids.len().min(HEAD_DEVICES);
+        Self::write_ids(&mut buf, &self.device_ids[..head]);
+        if self.is_long() {
+            let count = u64::try_from(self.device_ids.len())?;
+            buf[COUNT_OFFSET..HEAD_NEXT_OFFSET].copy_from_slice(&count.to_le_bytes());
+            buf[HEAD_NEXT_OFFSET..HEAD_NEXT_OFFSET + 16].copy_from_slice(&next.as_bytes());
         }
+        buf[FLAVOR_OFFSET] = self.flavor as u8;
+        Ok(buf)
     }
-}
-
-impl DerefMut for Geometry {
+    /// Reads `n` device IDs from the start of a block
+    #[cfg(feature = "unstable")]
+    fn read_ids(buf: &[u8], n: usize) -> AMResult<Vec<u64>> {
+        (0..n).map(|i| Self::read_u64(buf, i * 8)).collect()
+    }
+    /// Writes device IDs to the start of a block
     #[cfg(feature = "unstable")]
-    fn deref_mut(&mut self) -> &mut [u8] {
-        unsafe {
-            slice::from_raw_parts_mut(self as *mut Geometry as *mut u8, mem::size_of::<Geometry>())
-                as &mut [u8]
+    fn write_ids(buf: &mut [u8], ids: &[u64]) {
+        for (i, id) in ids.iter().enumerate() {
+            buf[i * 8..i * 8 + 8].copy_from_slice(&id.to_le_bytes());
         }
     }
+    /// Reads a little-endian u64 from a block
+    #[cfg(feature = "unstable")]
+    fn read_u64(buf: &[u8], off: usize) -> AMResult<u64> {
+        Ok(u64::from_le_bytes(buf[off..off + 8].try_into()?))
+    }
+    /// Reads a pointer from a block
+    #[cfg(feature = "unstable")]
+    fn read_ptr(buf: &[u8], off: usize) -> AMResult<AMPointerLocal> {
+        Ok(AMPointerLocal::from_bytes(buf[off..off + 16].try_into()?))
+    }
 }
 
 #[test]
-fn size_test() {
-    assert_eq!(mem::size_of::<Geometry>(), BLOCK_SIZE);
+#[allow(clippy::unwrap_used)]
+pub fn test_device_table() {
+    crate::test::logging::init_log();
+
+    let d = crate::DiskMem::open(10);
+
+    // Small tables keep the layout older drivers read
+    let mut geo = Geometry::new();
+    geo.device_ids = vec![1, 2, 3];
+    assert_eq!(geo.table_blocks(), 1);
+    let buf = geo.to_bytes().unwrap();
+    assert_eq!(buf[8], 2);
+    assert!(buf[COUNT_OFFSET..FLAVOR_OFFSET].iter().all(|b| *b == 0));
+    let ptr = geo.write(d.clone(), AMPointerLocal::new(1)).unwrap();
+    let read = Geometry::read(d.clone(), ptr).unwrap();
+    assert_eq!(read.device_ids, vec![1, 2, 3]);
+    assert!(read.continuation_blocks().is_empty());
+
+    // Large tables continue across blocks
+    geo.device_ids = (1..=1000).collect();
+    geo.flavor = GeometryFlavor::Striped;
+    assert_eq!(geo.table_blocks(), 3);
+    assert!(geo.to_bytes().is_err());
+    assert!(geo.write(d.clone(), AMPointerLocal::new(1)).is_err());
+    let ptrs = [2, 5, 7].map(AMPointerLocal::new);
+    let ptr = geo.write_chain(d.clone(), &ptrs).unwrap();
+    let read = Geometry::read(d.clone(), ptr).unwrap();
+    assert_eq!(read.device_ids, geo.device_ids);
+    assert!(matches!(read.flavor(), GeometryFlavor::Striped));
+    let locs: Vec<u64> = read
+        .continuation_blocks()
+        .iter()
+        .map(AMPointerLocal::loc)
+        .collect();
+    assert_eq!(locs, vec![5, 7]);
+
+    // A damaged continuation block fails the read
+    crate::test::corrupt::flip_byte(&d, 7, 0).unwrap();
+    assert!(Geometry::read(d, ptr).is_err());
 }
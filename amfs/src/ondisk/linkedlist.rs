@@ -44,7 +44,7 @@ impl<T: Copy + std::fmt::Debug + DecodeLE> LinkedListGlobal<Vec<T>> for Vec<T> {
                 break;
             }
             let count;
-            assert!(p.validate(diskgroups)?);
+            assert_or_err!(p.validate(diskgroups)?, AMErrorFS::Checksum);
             p.read(0, BLOCK_SIZE, diskgroups, &mut buf)?;
             unsafe {
                 let hdr = u8_slice_as_any::<LLGHeader>(&buf);
@@ -73,7 +73,11 @@ impl<T: Copy + std::fmt::Debug + DecodeLE> LinkedListGlobal<Vec<T>> for Vec<T> {
         };
 
         let mut blockptrs = (0..blocks)
-            .map(|_| dg.as_mut().ok_or(AMErrorFS::NoDiskgroup)?.alloc_blocks(1))
+            .map(|_| {
+                dg.as_mut()
+                    .ok_or(AMErrorFS::NoDiskgroup)?
+                    .alloc_blocks(1, None)
+            })
             .collect::<AMResult<Vec<AMPointerGlobal>>>()?;
         blockptrs.push(AMPointerGlobal::null());
         let mut headers: Vec<LLGHeader> = (0..blocks)
@@ -212,6 +216,58 @@ impl<T: Copy + std::fmt::Debug + DecodeLE> LinkedListGlobal<Vec<T>> for Vec<T> {
     }
 }
 
+/// Writes a single block of a list, pointing at the next one. Returns the block's updated pointer.
+#[cfg(feature = "unstable")]
+pub(crate) fn write_block<T: Copy>(
+    diskgroups: &[Option<DiskGroup>],
+    mut ptr: AMPointerGlobal,
+    entries: &[T],
+    next: AMPointerGlobal,
+) -> AMResult<AMPointerGlobal> {
+    assert_le!(
+        std::mem::size_of::<LLGHeader>() + std::mem::size_of_val(entries),
+        BLOCK_SIZE
+    );
+    let header = LLGHeader {
+        next,
+        count: u64::try_from(entries.len())?,
+        _padding: 0,
+    };
+    let mut buf = [0; BLOCK_SIZE];
+    let mut pos = std::mem::size_of::<LLGHeader>();
+    unsafe {
+        buf[0..pos].copy_from_slice(any_as_u8_slice(&header));
+    }
+    for v in entries {
+        let next_pos = pos + std::mem::size_of::<T>();
+        unsafe {
+            buf[pos..next_pos].copy_from_slice(any_as_u8_slice(v));
+        }
+        pos = next_pos;
+    }
+    ptr.write(0, BLOCK_SIZE, diskgroups, &buf)?;
+    ptr.update(diskgroups)?;
+    Ok(ptr)
+}
+
+/// Gets the pointers to every block making up a linked list
+#[cfg(feature = "unstable")]
+pub(crate) fn list_blocks(
+    diskgroups: &[Option<DiskGroup>],
+    mut p: AMPointerGlobal,
+) -> AMResult<Vec<AMPointerGlobal>> {
+    let mut res = Vec::new();
+    let mut buf = [0; BLOCK_SIZE];
+    while !p.is_null() {
+        res.push(p);
+        p.read(0, BLOCK_SIZE, diskgroups, &mut buf)?;
+        unsafe {
+            p = u8_slice_as_any::<LLGHeader>(&buf).next;
+        }
+    }
+    Ok(res)
+}
+
 #[test]
 fn rw_test_global_empty() {
     #![allow(clippy::unwrap_used)]
@@ -1,11 +1,17 @@
 pub use self::{
-    allocator::Allocator,
-    fsgroup::{AllocListEntry, FSGroup, FreeQueueEntry},
-    geometry::{Geometry, GeometryFlavor},
+    allocator::{AllocStrategy, Allocator, Reservation, BITMAP_THRESHOLD},
+    fsgroup::{
+        AllocListEntry, FSGroup, FreeQueueEntry, RefCountEntry, VersionEntry, VersionHistory,
+    },
+    geometry::{Geometry, GeometryFlavor, CONTINUATION_DEVICES, HEAD_DEVICES},
     journal::JournalEntry,
     linkedlist::LinkedListGlobal,
-    object::{Fragment, Object, ObjectListHeader, ObjectSet},
-    pointer::{AMPointerGlobal, AMPointerLocal},
+    object::{
+        Fragment, Object, ObjectListHeader, ObjectNodeHeader, ObjectSet, FLAG_APPEND_ONLY,
+        FLAG_IMMUTABLE, FLAG_NO_COMPRESS, INLINE_FLAG, INLINE_MAX, META_OBJECTS, NODE_SIGNATURE,
+        OBJECT_FLAGS,
+    },
+    pointer::{AMPointerGlobal, AMPointerLocal, MAX_EXTENT_BLOCKS},
     superblock::Superblock,
 };
 
@@ -1,24 +1,58 @@
 use std::{
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, BTreeSet, VecDeque},
     convert::{TryFrom, TryInto},
+    io::IoSliceMut,
 };
 
-use amos_std::{error::AMError, AMResult};
+use amos_std::{
+    error::{AMError, AMErrorFS},
+    AMResult,
+};
 use endian_codec::{DecodeLE, PackedSize};
 
-use crate::{AMPointerGlobal, DiskGroup, AMFS, BLOCK_SIZE};
+use crate::{
+    location::{at_location, ErrorLocation},
+    metrics::report_checksum_failure,
+    AMFeatures, AMPointerGlobal, AllocHint, DiskGroup, AMFS, BLOCK_SIZE, MAX_EXTENT_BLOCKS,
+};
 
 pub const LIST_HEADER_SIZE: usize = 16;
 pub const FRAGMENT_SIZE: usize = 32;
+pub const POINTER_SIZE: usize = 16;
+pub const NODE_HEADER_SIZE: usize = 16;
+pub const NODE_ENTRY_SIZE: usize = 32;
+
+/// Object IDs below this are reserved for meta-objects, such as the directory tree
+pub const META_OBJECTS: u64 = 16;
+
+/// Largest object stored inline in its leaf entry rather than in its own fragments
+pub const INLINE_MAX: usize = 96;
+/// Set in a leaf entry's fragment count when the object's data is stored inline
+pub const INLINE_FLAG: u64 = 0x8000000000000000;
+/// Object flag: the object can't be written, truncated or replaced
+pub const FLAG_IMMUTABLE: u64 = 0x4000000000000000;
+/// Object flag: writes may only add to the end of the object, and it can't be truncated
+pub const FLAG_APPEND_ONLY: u64 = 0x2000000000000000;
+/// Object flag: the object's data is never to be compressed
+pub const FLAG_NO_COMPRESS: u64 = 0x1000000000000000;
+/// Every object flag, kept in a leaf entry's fragment count below [INLINE_FLAG]
+pub const OBJECT_FLAGS: u64 = FLAG_IMMUTABLE | FLAG_APPEND_ONLY | FLAG_NO_COMPRESS;
+
+/// Signature at the start of every object B-tree node
+pub const NODE_SIGNATURE: u64 = u64::from_le_bytes(*b"amfsOBJT");
 
 /// An object set- the on-disk format to store the set of all objects.
+///
+/// Objects are stored in a copy-on-write B-tree keyed by object ID.
+/// Every node is a single block, and every child pointer carries the checksum of its node.
+/// Sets written in the older flat list format are still readable, and are migrated on first write.
 #[derive(Clone, Debug)]
 pub struct ObjectSet {
     pub(crate) ptr: AMPointerGlobal,
     diskgroups:     Vec<Option<DiskGroup>>,
 }
 
-/// Header for object list
+/// Header for the legacy flat object list
 #[repr(C)]
 #[derive(PackedSize, DecodeLE)]
 pub struct ObjectListHeader {
@@ -46,77 +80,502 @@ impl ObjectListHeader {
     }
 }
 
+/// Header for an object B-tree node
+///
+/// Leaf nodes (level 0) are followed by `n_entries` objects, each stored as its ID, its fragment count, and its fragments.
+/// If [INLINE_FLAG] is set in the fragment count, the low bits are instead the length of the object's data, which follows padded to 16 bytes.
+/// The bits in [OBJECT_FLAGS] hold the object's flags, and are masked off either way.
+/// Internal nodes are followed by `n_entries` 32-byte entries: the lowest ID in the child, 8 bytes of padding, and a pointer to the child.
+#[repr(C)]
+#[derive(PackedSize, DecodeLE)]
+pub struct ObjectNodeHeader {
+    /// Always [NODE_SIGNATURE]
+    pub signature: u64,
+    /// Height of this node above the leaves
+    pub level:     u32,
+    /// Number of entries in this node
+    pub n_entries: u32,
+}
+
+impl ObjectNodeHeader {
+    /// Create header from bytes
+    #[cfg(feature = "stable")]
+    pub fn from_bytes(buf: [u8; NODE_HEADER_SIZE]) -> Self {
+        unsafe { std::ptr::read(buf.as_ptr() as *const _) }
+    }
+    /// Convert header to bytes
+    #[cfg(feature = "stable")]
+    pub fn to_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                (self as *const Self) as *const u8,
+                std::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+/// In-memory form of an object B-tree node
+#[derive(Clone, Debug)]
+enum ObjectNode {
+    /// A leaf, holding objects sorted by ID
+    Leaf(Vec<(u64, Object)>),
+    /// An internal node at a given level, holding (lowest ID, child) pairs sorted by ID
+    Internal(u32, Vec<(u64, AMPointerGlobal)>),
+}
+
+impl ObjectNode {
+    /// Gets the lowest ID stored under this node
+    #[cfg(feature = "stable")]
+    fn min_id(&self) -> u64 {
+        match self {
+            ObjectNode::Leaf(e) => e.first().map_or(0, |x| x.0),
+            ObjectNode::Internal(_, e) => e.first().map_or(0, |x| x.0),
+        }
+    }
+    /// Gets the size of the node once serialized
+    #[cfg(feature = "stable")]
+    fn size(&self) -> usize {
+        match self {
+            ObjectNode::Leaf(e) => {
+                NODE_HEADER_SIZE + e.iter().map(|(_, o)| o.entry_size()).sum::<usize>()
+            }
+            ObjectNode::Internal(_, e) => NODE_HEADER_SIZE + e.len() * NODE_ENTRY_SIZE,
+        }
+    }
+    /// Splits the node into two halves of roughly equal size
+    #[cfg(feature = "stable")]
+    fn split(self) -> (ObjectNode, ObjectNode) {
+        match self {
+            ObjectNode::Leaf(mut e) => {
+                let half = leaf_split_index(&e);
+                let tail = e.split_off(half);
+                (ObjectNode::Leaf(e), ObjectNode::Leaf(tail))
+            }
+            ObjectNode::Internal(level, mut e) => {
+                let tail = e.split_off(e.len() / 2);
+                (
+                    ObjectNode::Internal(level, e),
+                    ObjectNode::Internal(level, tail),
+                )
+            }
+        }
+    }
+    /// Parses a node from a block
+    #[cfg(feature = "stable")]
+    fn from_bytes(blk: &[u8]) -> AMResult<ObjectNode> {
+        let header = ObjectNodeHeader::from_bytes(
+            blk[..NODE_HEADER_SIZE]
+                .try_into()
+                .or(Err(AMError::TODO(0)))?,
+        );
+        assert_or_err!(header.signature == NODE_SIGNATURE, AMErrorFS::Signature);
+        let mut pos = NODE_HEADER_SIZE;
+        if header.level == 0 {
+            let mut entries = Vec::with_capacity(usize::try_from(header.n_entries)?);
+            for _ in 0..header.n_entries {
+                let id =
+                    u64::from_le_bytes(blk[pos..pos + 8].try_into().or(Err(AMError::TODO(0)))?);
+                let n_frags = u64::from_le_bytes(
+                    blk[pos + 8..pos + 16]
+                        .try_into()
+                        .or(Err(AMError::TODO(0)))?,
+                );
+                pos += 16;
+                let flags = n_frags & OBJECT_FLAGS;
+                let n_frags = n_frags & !OBJECT_FLAGS;
+                if n_frags & INLINE_FLAG != 0 {
+                    let len = usize::try_from(n_frags & !INLINE_FLAG)?;
+                    let mut obj = Object::inline(&blk[pos..pos + len]);
+                    obj.flags = flags;
+                    entries.push((id, obj));
+                    pos += inline_padded(len);
+                    continue;
+                }
+                let n_frags = usize::try_from(n_frags)?;
+                let mut frags = Vec::with_capacity(n_frags);
+                for _ in 0..n_frags {
+                    frags.push(Fragment::from_bytes(
+                        blk[pos..pos + FRAGMENT_SIZE]
+                            .try_into()
+                            .or(Err(AMError::TODO(0)))?,
+                    ));
+                    pos += FRAGMENT_SIZE;
+                }
+                let mut obj = Object::new(&frags);
+                obj.flags = flags;
+                entries.push((id, obj));
+            }
+            Ok(ObjectNode::Leaf(entries))
+        } else {
+            let mut entries = Vec::with_capacity(usize::try_from(header.n_entries)?);
+            for _ in 0..header.n_entries {
+                let id =
+                    u64::from_le_bytes(blk[pos..pos + 8].try_into().or(Err(AMError::TODO(0)))?);
+                let child = AMPointerGlobal::from_bytes(
+                    blk[pos + 16..pos + NODE_ENTRY_SIZE]
+                        .try_into()
+                        .or(Err(AMError::TODO(0)))?,
+                );
+                entries.push((id, child));
+                pos += NODE_ENTRY_SIZE;
+            }
+            Ok(ObjectNode::Internal(header.level, entries))
+        }
+    }
+    /// Serializes a node into a block
+    #[cfg(feature = "stable")]
+    fn to_bytes(&self) -> AMResult<Vec<u8>> {
+        assert_le!(self.size(), BLOCK_SIZE);
+        let mut blk = vec![0u8; BLOCK_SIZE];
+        let (level, n_entries) = match self {
+            ObjectNode::Leaf(e) => (0, e.len()),
+            ObjectNode::Internal(l, e) => (*l, e.len()),
+        };
+        let header = ObjectNodeHeader {
+            signature: NODE_SIGNATURE,
+            level,
+            n_entries: u32::try_from(n_entries)?,
+        };
+        blk[..NODE_HEADER_SIZE].copy_from_slice(header.to_bytes());
+        let mut pos = NODE_HEADER_SIZE;
+        match self {
+            ObjectNode::Leaf(e) => {
+                for (id, obj) in e {
+                    blk[pos..pos + 8].copy_from_slice(&id.to_le_bytes());
+                    if let Some(data) = &obj.inline {
+                        blk[pos + 8..pos + 16].copy_from_slice(
+                            &(u64::try_from(data.len())? | INLINE_FLAG | obj.flags).to_le_bytes(),
+                        );
+                        pos += 16;
+                        blk[pos..pos + data.len()].copy_from_slice(data);
+                        pos += inline_padded(data.len());
+                        continue;
+                    }
+                    blk[pos + 8..pos + 16].copy_from_slice(
+                        &(u64::try_from(obj.frags.len())? | obj.flags).to_le_bytes(),
+                    );
+                    pos += 16;
+                    for frag in &obj.frags {
+                        blk[pos..pos + FRAGMENT_SIZE].copy_from_slice(frag.to_bytes());
+                        pos += FRAGMENT_SIZE;
+                    }
+                }
+            }
+            ObjectNode::Internal(_, e) => {
+                for (id, child) in e {
+                    blk[pos..pos + 8].copy_from_slice(&id.to_le_bytes());
+                    blk[pos + 16..pos + NODE_ENTRY_SIZE].copy_from_slice(&child.as_bytes());
+                    pos += NODE_ENTRY_SIZE;
+                }
+            }
+        }
+        Ok(blk)
+    }
+}
+
+/// Gets the space taken by inline data of a given length
+#[cfg(feature = "stable")]
+fn inline_padded(len: usize) -> usize {
+    (len + 15) & !15
+}
+
+/// Finds the index at which to split a set of leaf entries into two halves of similar size
+#[cfg(feature = "stable")]
+fn leaf_split_index(e: &[(u64, Object)]) -> usize {
+    let total: usize = e.iter().map(|(_, o)| o.entry_size()).sum();
+    let mut acc = 0;
+    for (i, (_, o)) in e.iter().enumerate() {
+        acc += o.entry_size();
+        if acc * 2 >= total {
+            return std::cmp::max(i, 1);
+        }
+    }
+    e.len() / 2
+}
+
+/// Finds the child of an internal node which may contain a given ID
+#[cfg(feature = "stable")]
+fn child_index(entries: &[(u64, AMPointerGlobal)], id: u64) -> usize {
+    entries.partition_point(|(k, _)| *k <= id).saturating_sub(1)
+}
+
+/// Checks whether a block holds an object B-tree node rather than a legacy list
+#[cfg(feature = "stable")]
+fn is_node(blk: &[u8]) -> AMResult<bool> {
+    Ok(u64::from_le_bytes(blk[..8].try_into().or(Err(AMError::TODO(0)))?) == NODE_SIGNATURE)
+}
+
 impl ObjectSet {
     /// Creates a new object set handle
     #[cfg(feature = "stable")]
     pub fn read(diskgroups: Vec<Option<DiskGroup>>, ptr: AMPointerGlobal) -> ObjectSet {
         ObjectSet { ptr, diskgroups }
     }
+    /// Writes a fresh object set into a preallocated block.
+    /// The set contains a single empty object 0, the root of the directory tree.
+    #[cfg(feature = "unstable")]
+    pub fn create(
+        diskgroups: Vec<Option<DiskGroup>>,
+        mut ptr: AMPointerGlobal,
+    ) -> AMResult<ObjectSet> {
+        let blk = ObjectNode::Leaf(vec![(0, Object::new(&[]))]).to_bytes()?;
+        ptr.write(0, BLOCK_SIZE, &diskgroups, &blk)?;
+        ptr.update(&diskgroups)?;
+        Ok(ObjectSet { ptr, diskgroups })
+    }
+    /// Checks whether this set is still stored in the legacy flat list format
+    #[cfg(feature = "stable")]
+    pub fn is_legacy(&self) -> AMResult<bool> {
+        Ok(!is_node(&self.ptr.read_vec(&self.diskgroups)?)?)
+    }
     /// Checks the existence of an object with a given ID
     #[cfg(feature = "stable")]
     pub fn exists_object(&self, id: u64) -> AMResult<bool> {
         Ok(self.get_object(id)?.is_some())
     }
+    /// Reads and verifies a B-tree node
+    #[cfg(feature = "stable")]
+    fn read_node(&self, ptr: AMPointerGlobal) -> AMResult<ObjectNode> {
+        self.decode_node(ptr, &ptr.read_vec(&self.diskgroups)?)
+    }
+    /// Reads and verifies the root node, or returns `None` if the set is a legacy flat list
+    #[cfg(feature = "stable")]
+    fn read_root(&self) -> AMResult<Option<ObjectNode>> {
+        let blk = self.ptr.read_vec(&self.diskgroups)?;
+        if !is_node(&blk)? {
+            return Ok(None);
+        }
+        Ok(Some(self.decode_node(self.ptr, &blk)?))
+    }
+    /// Verifies a block read from a pointer, then decodes the B-tree node in it
+    #[cfg(feature = "stable")]
+    fn decode_node(&self, ptr: AMPointerGlobal, blk: &[u8]) -> AMResult<ObjectNode> {
+        if !ptr.0.validate(blk) {
+            report_checksum_failure(&self.diskgroups, ptr);
+            return at_location(Err(AMErrorFS::Checksum.into()), || {
+                ErrorLocation::global(ptr, 0, &self.diskgroups)
+            });
+        }
+        ObjectNode::from_bytes(blk)
+    }
+    /// Writes a B-tree node into a newly allocated block
+    #[cfg(feature = "unstable")]
+    fn write_node(&self, fs: &mut AMFS, node: &ObjectNode) -> AMResult<AMPointerGlobal> {
+        let blk = node.to_bytes()?;
+        let mut ptr = fs.alloc_blocks(1, None)?.ok_or(AMError::TODO(0))?;
+        ptr.write(0, BLOCK_SIZE, &self.diskgroups, &blk)?;
+        ptr.0.update(&blk);
+        Ok(ptr)
+    }
+    /// Writes a node, splitting it as many times as needed to fit.
+    /// Returns the (lowest ID, pointer) pair of each node written.
+    #[cfg(feature = "unstable")]
+    fn write_split(
+        &self,
+        fs: &mut AMFS,
+        node: ObjectNode,
+    ) -> AMResult<Vec<(u64, AMPointerGlobal)>> {
+        if node.size() <= BLOCK_SIZE {
+            let min = node.min_id();
+            return Ok(vec![(min, self.write_node(fs, &node)?)]);
+        }
+        if let ObjectNode::Leaf(e) = &node {
+            // A single object's fragment list doesn't fit in a node
+            assert_or_err!(e.len() > 1, AMErrorFS::AllocFailed);
+        }
+        let (a, b) = node.split();
+        let mut res = self.write_split(fs, a)?;
+        res.append(&mut self.write_split(fs, b)?);
+        Ok(res)
+    }
+    /// Builds a tree above a set of sibling nodes, returning the new root
+    #[cfg(feature = "unstable")]
+    fn write_root(
+        &self,
+        fs: &mut AMFS,
+        mut entries: Vec<(u64, AMPointerGlobal)>,
+    ) -> AMResult<AMPointerGlobal> {
+        while entries.len() > 1 {
+            let level = match self.read_node(entries[0].1)? {
+                ObjectNode::Leaf(_) => 1,
+                ObjectNode::Internal(l, _) => l + 1,
+            };
+            entries = self.write_split(fs, ObjectNode::Internal(level, entries))?;
+        }
+        Ok(entries[0].1)
+    }
     /// Gets the object with a given ID
     #[cfg(feature = "stable")]
     pub(crate) fn get_object(&self, id: u64) -> AMResult<Option<Object>> {
+        let mut node = match self.read_root()? {
+            Some(node) => node,
+            None => return Ok(self.get_objects_legacy()?.remove(&id)),
+        };
+        loop {
+            match node {
+                ObjectNode::Leaf(e) => {
+                    return Ok(e
+                        .binary_search_by_key(&id, |x| x.0)
+                        .ok()
+                        .map(|i| e[i].1.clone()));
+                }
+                ObjectNode::Internal(_, e) => {
+                    node = self.read_node(e[child_index(&e, id)].1)?;
+                }
+            }
+        }
+    }
+    /// Gets all objects in the filesystem
+    #[cfg(feature = "stable")]
+    pub(crate) fn get_objects(&self) -> AMResult<BTreeMap<u64, Object>> {
+        if self.is_legacy()? {
+            return self.get_objects_legacy();
+        }
+        let mut res = BTreeMap::new();
         let mut to_process = VecDeque::new();
         to_process.push_back(self.ptr);
-        loop {
-            let ptr = to_process.pop_front();
-            if ptr.is_none() {
-                break;
+        while let Some(ptr) = to_process.pop_front() {
+            match self.read_node(ptr)? {
+                ObjectNode::Leaf(e) => res.extend(e),
+                ObjectNode::Internal(_, e) => to_process.extend(e.iter().map(|x| x.1)),
             }
-            let ptr = ptr.expect("PANIC");
-            let blk = ptr.read_vec(&self.diskgroups)?;
-            let header = ObjectListHeader::from_bytes(
-                blk[..LIST_HEADER_SIZE]
-                    .try_into()
-                    .or(Err(AMError::TODO(0)))?,
+        }
+        Ok(res)
+    }
+    /// Gets the pointers to every block making up the object set
+    #[cfg(feature = "stable")]
+    pub(crate) fn get_blocks(&self) -> AMResult<Vec<AMPointerGlobal>> {
+        if self.is_legacy()? {
+            return self.get_blocks_legacy();
+        }
+        let mut res = Vec::new();
+        let mut to_process = VecDeque::new();
+        to_process.push_back(self.ptr);
+        while let Some(ptr) = to_process.pop_front() {
+            res.push(ptr);
+            if let ObjectNode::Internal(_, e) = self.read_node(ptr)? {
+                to_process.extend(e.iter().map(|x| x.1));
+            }
+        }
+        Ok(res)
+    }
+    /// Updates or inserts an object
+    #[cfg(feature = "unstable")]
+    pub fn set_object(&self, fs: &mut AMFS, id: u64, mut obj: Object) -> AMResult<ObjectSet> {
+        if NODE_HEADER_SIZE + obj.entry_size() > BLOCK_SIZE {
+            // Too many fragments to fit in a leaf, gather the data into as few extents as it'll go
+            obj.defrag(fs)?;
+            obj.coalesce(fs, &self.diskgroups)?;
+            assert_or_err!(
+                NODE_HEADER_SIZE + obj.entry_size() <= BLOCK_SIZE,
+                AMErrorFS::AllocFailed
             );
-            if header.n_entries & 0x8000000000000000 != 0 {
-                todo!();
-            } else {
-                if header.start_idx <= id {
-                    let mut pos = std::mem::size_of::<ObjectListHeader>();
-                    let mut idx = header.start_idx;
-                    while idx < id {
-                        loop {
-                            if u64::from_le_bytes(
-                                blk[pos..pos + 8].try_into().or(Err(AMError::TODO(0)))?,
-                            ) == 0
-                            {
-                                pos += 8;
-                                break;
-                            }
-                            pos += FRAGMENT_SIZE;
-                            idx += 1;
-                        }
-                    }
-                    let mut frags = Vec::new();
-                    loop {
-                        if u64::from_le_bytes(
-                            blk[pos..pos + 8].try_into().or(Err(AMError::TODO(0)))?,
-                        ) == 0
-                        {
-                            break;
-                        }
-                        frags.push(Fragment::from_bytes(
-                            blk[pos..pos + FRAGMENT_SIZE]
-                                .try_into()
-                                .or(Err(AMError::TODO(0)))?,
-                        ));
-                        pos += FRAGMENT_SIZE;
-                    }
-                    return Ok(Some(Object { frags }));
+        }
+        let mut res = if self.is_legacy()? {
+            self.migrate(fs)?
+        } else {
+            self.clone()
+        };
+        let entries = res.set_object_in(fs, res.ptr, id, &obj)?;
+        res.ptr = res.write_root(fs, entries)?;
+        Ok(res)
+    }
+    /// Updates or inserts an object in the subtree rooted at `ptr`.
+    /// Returns the node(s) replacing `ptr` in its parent.
+    #[cfg(feature = "unstable")]
+    fn set_object_in(
+        &self,
+        fs: &mut AMFS,
+        ptr: AMPointerGlobal,
+        id: u64,
+        obj: &Object,
+    ) -> AMResult<Vec<(u64, AMPointerGlobal)>> {
+        let node = match self.read_node(ptr)? {
+            ObjectNode::Leaf(mut e) => {
+                match e.binary_search_by_key(&id, |x| x.0) {
+                    Ok(i) => e[i].1 = obj.clone(),
+                    Err(i) => e.insert(i, (id, obj.clone())),
                 }
+                ObjectNode::Leaf(e)
             }
+            ObjectNode::Internal(level, mut e) => {
+                let i = child_index(&e, id);
+                let new_children = self.set_object_in(fs, e[i].1, id, obj)?;
+                e.splice(i..=i, new_children);
+                ObjectNode::Internal(level, e)
+            }
+        };
+        fs.free(ptr)?;
+        self.write_split(fs, node)
+    }
+    /// Removes an object, if it exists
+    #[cfg(feature = "unstable")]
+    pub fn remove_object(&self, fs: &mut AMFS, id: u64) -> AMResult<ObjectSet> {
+        if !self.exists_object(id)? {
+            return Ok(self.clone());
         }
-        Ok(None)
+        let mut res = if self.is_legacy()? {
+            self.migrate(fs)?
+        } else {
+            self.clone()
+        };
+        let entries = res.remove_object_in(fs, res.ptr, id)?;
+        res.ptr = if entries.is_empty() {
+            // The last object went, leaving an empty leaf as the root
+            res.write_node(fs, &ObjectNode::Leaf(Vec::new()))?
+        } else {
+            res.write_root(fs, entries)?
+        };
+        Ok(res)
     }
-    /// Gets all objects in the filesystem
+    /// Removes an object from the subtree rooted at `ptr`.
+    /// Returns the node(s) replacing `ptr` in its parent, or none if it was left empty.
+    #[cfg(feature = "unstable")]
+    fn remove_object_in(
+        &self,
+        fs: &mut AMFS,
+        ptr: AMPointerGlobal,
+        id: u64,
+    ) -> AMResult<Vec<(u64, AMPointerGlobal)>> {
+        let node = match self.read_node(ptr)? {
+            ObjectNode::Leaf(mut e) => {
+                if let Ok(i) = e.binary_search_by_key(&id, |x| x.0) {
+                    e.remove(i);
+                }
+                ObjectNode::Leaf(e)
+            }
+            ObjectNode::Internal(level, mut e) => {
+                let i = child_index(&e, id);
+                let new_children = self.remove_object_in(fs, e[i].1, id)?;
+                e.splice(i..=i, new_children);
+                ObjectNode::Internal(level, e)
+            }
+        };
+        fs.free(ptr)?;
+        match &node {
+            ObjectNode::Leaf(e) if e.is_empty() => Ok(Vec::new()),
+            ObjectNode::Internal(_, e) if e.is_empty() => Ok(Vec::new()),
+            _ => self.write_split(fs, node),
+        }
+    }
+    /// Rewrites a legacy flat object list as a B-tree, freeing the old list
+    #[cfg(feature = "unstable")]
+    pub(crate) fn migrate(&self, fs: &mut AMFS) -> AMResult<ObjectSet> {
+        info!("Migrating object list at {} to a B-tree", self.ptr);
+        let objects = self.get_objects_legacy()?;
+        let old_blocks = self.get_blocks_legacy()?;
+        let mut res = self.clone();
+        let entries = res.write_split(fs, ObjectNode::Leaf(objects.into_iter().collect()))?;
+        res.ptr = res.write_root(fs, entries)?;
+        for ptr in old_blocks {
+            fs.free(ptr)?;
+        }
+        Ok(res)
+    }
+    /// Gets all objects from a legacy flat object list
     #[cfg(feature = "stable")]
-    pub(crate) fn get_objects(&self) -> AMResult<BTreeMap<u64, Object>> {
+    fn get_objects_legacy(&self) -> AMResult<BTreeMap<u64, Object>> {
         let mut res = BTreeMap::new();
         let mut to_process = VecDeque::new();
         to_process.push_back(self.ptr);
@@ -135,7 +594,7 @@ impl ObjectSet {
             let mut pos = std::mem::size_of::<ObjectListHeader>();
             let idx = header.start_idx;
             if header.n_entries & 0x8000000000000000 != 0 {
-                todo!();
+                to_process.extend(Self::read_indirect(&blk, &header)?);
             } else {
                 for i in idx..idx + header.n_entries {
                     let mut frags = Vec::new();
@@ -154,163 +613,53 @@ impl ObjectSet {
                         ));
                         pos += FRAGMENT_SIZE;
                     }
-                    res.insert(i, Object { frags });
+                    res.insert(i, Object::new(&frags));
                 }
             }
         }
+        // Legacy lists read the directory object as empty even when it was never written
+        res.entry(0).or_insert_with(|| Object::new(&[]));
         Ok(res)
     }
-    /// Updates or inserts an object
-    #[cfg(feature = "unstable")]
-    pub fn set_object(&self, fs: &mut AMFS, id: u64, obj: Object) -> AMResult<ObjectSet> {
-        let mut res = self.clone();
+    /// Reads the child pointers out of a legacy indirect block
+    #[cfg(feature = "stable")]
+    fn read_indirect(blk: &[u8], header: &ObjectListHeader) -> AMResult<Vec<AMPointerGlobal>> {
+        let count = usize::try_from(header.n_entries & 0x7FFFFFFFFFFFFFFF)?;
+        let mut res = Vec::with_capacity(count);
+        for i in 0..count {
+            let pos = LIST_HEADER_SIZE + i * POINTER_SIZE;
+            res.push(AMPointerGlobal::from_bytes(
+                blk[pos..pos + POINTER_SIZE]
+                    .try_into()
+                    .or(Err(AMError::TODO(0)))?,
+            ));
+        }
+        Ok(res)
+    }
+    /// Gets the pointers to every block making up a legacy flat object list
+    #[cfg(feature = "stable")]
+    fn get_blocks_legacy(&self) -> AMResult<Vec<AMPointerGlobal>> {
+        let mut res = Vec::new();
         let mut to_process = VecDeque::new();
         to_process.push_back(self.ptr);
-        let parents = vec![self.ptr];
-        loop {
-            let ptr = to_process.pop_front();
-            if ptr.is_none() {
-                break;
-            }
-            let ptr = ptr.expect("PANIC");
-            let mut blk = ptr.read_vec(&self.diskgroups)?;
-            let mut header = ObjectListHeader::from_bytes(
+        while let Some(ptr) = to_process.pop_front() {
+            res.push(ptr);
+            let blk = ptr.read_vec(&self.diskgroups)?;
+            let header = ObjectListHeader::from_bytes(
                 blk[..LIST_HEADER_SIZE]
                     .try_into()
                     .or(Err(AMError::TODO(0)))?,
             );
             if header.n_entries & 0x8000000000000000 != 0 {
-                //If the high bit is set, this is an indirect block.
-                todo!();
-            } else {
-                if header.start_idx <= id {
-                    //We're in the block containing the object to update
-                    let mut pos = LIST_HEADER_SIZE;
-                    let mut idx = header.start_idx;
-                    while idx < id {
-                        //Scan forward until we're at the start of the object to update
-                        loop {
-                            if u64::from_le_bytes(
-                                blk[pos..pos + 8].try_into().or(Err(AMError::TODO(0)))?,
-                            ) == 0
-                            {
-                                pos += 8;
-                                break;
-                            }
-                            pos += FRAGMENT_SIZE;
-                        }
-                        idx += 1;
-                    }
-                    if id == header.start_idx + header.n_entries {
-                        // We're appending an object
-                        header.n_entries += 1;
-                        let obj_size = FRAGMENT_SIZE * obj.frags.len() + 8;
-                        if pos + obj_size < BLOCK_SIZE {
-                            // No action needed, we're at the right spot
-                        } else {
-                            // We need to allocate a new block
-                            todo!();
-                        }
-                    } else {
-                        // We're updating an object
-                        assert_lt!(id, header.start_idx + header.n_entries);
-                        // Calculate the size of the new object
-                        let obj_size = std::mem::size_of::<Fragment>() * obj.frags.len() + 8;
-                        let mut i = pos;
-                        // Scan forward to the end of the old object
-                        loop {
-                            if u64::from_le_bytes(
-                                blk[i..i + 8].try_into().or(Err(AMError::TODO(0)))?,
-                            ) == 0
-                            {
-                                i += 8;
-                                break;
-                            }
-                            i += FRAGMENT_SIZE;
-                        }
-                        idx += 1;
-                        // Calculate the size used by the old object
-                        let slot_size = i - pos;
-                        // Check if the new object is the same size as the old
-                        if obj_size == slot_size {
-                            // No action needed, the new object is the same size
-                        } else {
-                            let size_diff = obj_size - slot_size;
-                            let mut j = i;
-                            // Scan forward to the end of the last object in the block
-                            while idx < (header.start_idx + header.n_entries) - 1 {
-                                loop {
-                                    if u64::from_le_bytes(
-                                        blk[j..j + 8].try_into().or(Err(AMError::TODO(0)))?,
-                                    ) == 0
-                                    {
-                                        j += 8;
-                                        break;
-                                    }
-                                    j += FRAGMENT_SIZE;
-                                }
-                                idx += 1;
-                            }
-                            // Calculate the new end of the last object after shifting
-                            let new_end = j + size_diff;
-                            if new_end > BLOCK_SIZE {
-                                // We need to spill into a new block
-                                todo!();
-                            } else {
-                                blk.copy_within(i..j, i + size_diff);
-                            }
-                            /*println!(
-                                "i:{} si:{} ne:{} p:{} i:{} j:{} sd:{} nl:{}",
-                                idx,
-                                header.start_idx,
-                                header.n_entries,
-                                pos,
-                                i,
-                                j,
-                                size_diff,
-                                i + size_diff
-                            );*/
-                            //todo!();
-                        }
-                    }
-                    //println!("{}", pos);
-                    for frag in &obj.frags {
-                        blk[pos..pos + FRAGMENT_SIZE].copy_from_slice(frag.to_bytes());
-                        pos += FRAGMENT_SIZE;
-                    }
-                    blk[pos..pos + 8].copy_from_slice(&[0u8; 8]);
-
-                    //pos += 8;
-                    //println!("{}", pos);
-
-                    blk[..LIST_HEADER_SIZE].copy_from_slice(header.to_bytes());
-
-                    let mut ptr = fs.realloc(ptr)?.ok_or(AMError::TODO(0))?;
-                    for _w in parents.windows(2) {
-                        todo!();
-                    }
-                    ptr.write(0, blk.len(), &self.diskgroups, &blk)?;
-                    ptr.update(&self.diskgroups)?;
-                    res.ptr = ptr;
-                    return Ok(res);
-                } else {
-                    //We're not in the right block, keep searching
-                    println!(
-                        "{}-{} {}",
-                        header.start_idx,
-                        header.start_idx + header.n_entries,
-                        id
-                    );
-                    todo!();
-                }
+                to_process.extend(Self::read_indirect(&blk, &header)?);
             }
         }
-        panic!();
+        Ok(res)
     }
     /// Gets the size of an object
     #[cfg(feature = "stable")]
     pub fn size_object(&self, id: u64) -> AMResult<u64> {
-        self.get_object(id)?.ok_or(AMError::TODO(0))?.size()
+        self.get_object(id)?.ok_or(AMErrorFS::NoObject)?.size()
     }
     /// Reads the contents of an object
     #[cfg(feature = "stable")]
@@ -321,16 +670,58 @@ impl ObjectSet {
         data: &mut [u8],
         diskgroups: &[Option<DiskGroup>],
     ) -> AMResult<u64> {
-        self.get_object(id)?
-            .ok_or(AMError::TODO(0))?
-            .read(start, data, diskgroups)
+        let obj = self.get_object(id)?.ok_or(AMErrorFS::NoObject)?;
+        if id < META_OBJECTS && start > obj.size()? {
+            // The filesystem never reads past the end of its own structures
+            return Err(AMError::TODO(0).into());
+        }
+        obj.read(start, data, diskgroups)
+    }
+    /// Reads the contents of an object into a sequence of buffers
+    #[cfg(feature = "unstable")]
+    pub(crate) fn read_object_vectored(
+        &self,
+        id: u64,
+        start: u64,
+        bufs: &mut [IoSliceMut],
+        diskgroups: &[Option<DiskGroup>],
+    ) -> AMResult<u64> {
+        let obj = self.get_object(id)?.ok_or(AMErrorFS::NoObject)?;
+        if id < META_OBJECTS && start > obj.size()? {
+            return Err(AMError::TODO(0).into());
+        }
+        let mut res = 0;
+        for buf in bufs {
+            let n = obj.read(start + res, buf, diskgroups)?;
+            res += n;
+            if n < u64::try_from(buf.len())? {
+                break;
+            }
+        }
+        Ok(res)
+    }
+    /// Lists every object as an (ID, size, fragment count) triple, in ID order
+    #[cfg(feature = "unstable")]
+    pub(crate) fn list_objects(&self) -> AMResult<std::vec::IntoIter<(u64, u64, usize)>> {
+        let mut res = Vec::new();
+        for (id, obj) in self.get_objects()? {
+            res.push((id, obj.size()?, obj.frags().len()));
+        }
+        Ok(res.into_iter())
+    }
+    /// Gets the disk groups the set is read from
+    #[cfg(feature = "unstable")]
+    pub(crate) fn diskgroups(&self) -> &[Option<DiskGroup>] {
+        &self.diskgroups
     }
 }
 
 /// Represents one file or meta-file on disk
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Object {
-    frags: Vec<Fragment>,
+    frags:  Vec<Fragment>,
+    inline: Option<Vec<u8>>,
+    flags:  u64,
 }
 
 impl Object {
@@ -338,8 +729,139 @@ impl Object {
     #[cfg(feature = "stable")]
     pub fn new(frags: &[Fragment]) -> Object {
         Object {
-            frags: frags.to_vec(),
+            frags:  frags.to_vec(),
+            inline: None,
+            flags:  0,
+        }
+    }
+    /// Create a new object with its data stored inline
+    #[cfg(feature = "stable")]
+    pub fn inline(data: &[u8]) -> Object {
+        assert_le!(data.len(), INLINE_MAX);
+        Object {
+            frags:  Vec::new(),
+            inline: Some(data.to_vec()),
+            flags:  0,
+        }
+    }
+    /// Checks whether the object's data is stored inline
+    #[cfg(feature = "stable")]
+    pub fn is_inline(&self) -> bool {
+        self.inline.is_some()
+    }
+    /// Gets the object's flags, a combination of the bits in [OBJECT_FLAGS]
+    #[cfg(feature = "unstable")]
+    pub fn flags(&self) -> u64 {
+        self.flags
+    }
+    /// Replaces the object's flags. Bits outside [OBJECT_FLAGS] are refused.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_flags(&mut self, flags: u64) -> AMResult<()> {
+        assert_or_err!(flags & !OBJECT_FLAGS == 0, AMError::TODO(0));
+        self.flags = flags;
+        Ok(())
+    }
+    /// Refuses changes the object's flags forbid: any change to an immutable object,
+    /// and anything but writes at or past the end to an append-only one
+    #[cfg(feature = "unstable")]
+    pub(crate) fn check_modify(&self, append_from: Option<u64>) -> AMResult<()> {
+        assert_or_err!(self.flags & FLAG_IMMUTABLE == 0, AMError::TODO(0));
+        if self.flags & FLAG_APPEND_ONLY != 0 {
+            assert_or_err!(
+                matches!(append_from, Some(start) if start >= self.size()?),
+                AMError::TODO(0)
+            );
+        }
+        Ok(())
+    }
+    /// Gets the space taken by the object's entry in a leaf node
+    #[cfg(feature = "stable")]
+    fn entry_size(&self) -> usize {
+        match &self.inline {
+            Some(data) => 16 + inline_padded(data.len()),
+            None => 16 + self.frags.len() * FRAGMENT_SIZE,
+        }
+    }
+    /// Gets the byte ranges whose contents may differ from another object's.
+    /// Ranges backed by the same extent at the same offset in both are unchanged.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn changed_ranges(&self, other: &Object) -> AMResult<Vec<(u64, u64)>> {
+        let size = std::cmp::max(self.size()?, other.size()?);
+        if self.inline.is_some() || other.inline.is_some() {
+            if self == other || size == 0 {
+                return Ok(Vec::new());
+            }
+            return Ok(vec![(0, size)]);
+        }
+        let mut bounds = std::collections::BTreeSet::new();
+        bounds.insert(size);
+        for obj in &[self, other] {
+            let mut pos = 0;
+            for f in &obj.frags {
+                bounds.insert(pos);
+                pos += f.size;
+            }
+        }
+        let bounds: Vec<u64> = bounds.into_iter().collect();
+        let mut res: Vec<(u64, u64)> = Vec::new();
+        for w in bounds.windows(2) {
+            if self.backing(w[0]) == other.backing(w[0]) {
+                continue;
+            }
+            match res.last_mut() {
+                Some(last) if last.1 == w[0] => last.1 = w[1],
+                _ => res.push((w[0], w[1])),
+            }
+        }
+        Ok(res)
+    }
+    /// Gets what backs a byte of the object: nothing past the end, a hole, or a location and offset
+    #[cfg(feature = "unstable")]
+    fn backing(&self, pos: u64) -> Option<Option<(u64, u64)>> {
+        let mut start = 0;
+        for f in &self.frags {
+            if pos < start + f.size {
+                if f.is_hole() {
+                    return Some(None);
+                }
+                return Some(Some((f.pointer.loc(), f.offset + pos - start)));
+            }
+            start += f.size;
+        }
+        None
+    }
+    /// Finds where the `n`th block of the object lies on disk, as a fragment pointer and a block
+    /// index into its extent. Only blocks lying whole and block-aligned in one fragment are found.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn block_source(&self, n: u64) -> AMResult<Option<(AMPointerGlobal, u64)>> {
+        let block_size = u64::try_from(BLOCK_SIZE)?;
+        let pos = n * block_size;
+        let mut start = 0;
+        for f in &self.frags {
+            if pos < start + f.size {
+                let offset = f.offset + pos - start;
+                if f.is_hole() || pos + block_size > start + f.size || offset % block_size != 0 {
+                    return Ok(None);
+                }
+                return Ok(Some((f.pointer, offset / block_size)));
+            }
+            start += f.size;
+        }
+        Ok(None)
+    }
+    /// Moves inline data out into fragments
+    #[cfg(feature = "unstable")]
+    pub(crate) fn spill(
+        &mut self,
+        handle: &mut AMFS,
+        diskgroups: &[Option<DiskGroup>],
+    ) -> AMResult<()> {
+        if let Some(data) = self.inline.take() {
+            if !data.is_empty() {
+                self.write(handle, 0, &data, diskgroups)?;
+            }
         }
+        Ok(())
     }
     /// Return the list of fragments backing the object
     #[cfg(feature = "unstable")]
@@ -348,7 +870,24 @@ impl Object {
     }
     /// Reads the contents of an object from the disk
     #[cfg(feature = "unstable")]
-    fn read(&self, start: u64, data: &mut [u8], diskgroups: &[Option<DiskGroup>]) -> AMResult<u64> {
+    pub(crate) fn read(
+        &self,
+        start: u64,
+        data: &mut [u8],
+        diskgroups: &[Option<DiskGroup>],
+    ) -> AMResult<u64> {
+        // Reads stop at the end of the object, and return 0 at or past it
+        let size = self.size()?;
+        if start >= size {
+            return Ok(0);
+        }
+        let len = std::cmp::min(data.len(), usize::try_from(size - start)?);
+        let data = &mut data[..len];
+        if let Some(inline) = &self.inline {
+            let start = usize::try_from(start)?;
+            data.copy_from_slice(&inline[start..start + len]);
+            return Ok(u64::try_from(len)?);
+        }
         let mut res = 0;
         let mut frag_start = 0;
         let end = start + u64::try_from(data.len())?;
@@ -379,12 +918,17 @@ impl Object {
                     f.size
                 }
                 .try_into()?;
-                res += f.pointer.read(
-                    frag_read_start.try_into()?,
-                    read_len,
-                    diskgroups,
-                    &mut data[buf_read_start..buf_read_start + read_len],
-                )?;
+                if f.is_hole() {
+                    data[buf_read_start..buf_read_start + read_len].fill(0);
+                    res += read_len;
+                } else {
+                    res += f.pointer.read(
+                        (f.offset + frag_read_start).try_into()?,
+                        read_len,
+                        diskgroups,
+                        &mut data[buf_read_start..buf_read_start + read_len],
+                    )?;
+                }
             }
             frag_start = frag_end;
         }
@@ -399,71 +943,338 @@ impl Object {
         data: &[u8],
         diskgroups: &[Option<DiskGroup>],
     ) -> AMResult<u64> {
-        let mut res = 0;
-        let mut pos = 0;
-        for f in &mut self.frags {
-            if start < pos + f.size {
-                let slice_start = start - pos;
-                let slice_end = slice_start + u64::try_from(data.len())?;
-                if slice_end > f.size {
-                    todo!();
-                } else {
-                    f.pointer = handle.realloc(f.pointer)?.ok_or(AMError::TODO(0))?;
-                    res +=
-                        f.pointer
-                            .write(slice_start.try_into()?, data.len(), diskgroups, data)?;
-                    f.pointer.update(diskgroups)?;
+        let end = start + u64::try_from(data.len())?;
+        let size = self.size()?;
+        if let Some(inline) = &mut self.inline {
+            if end <= u64::try_from(INLINE_MAX)? {
+                let start = usize::try_from(start)?;
+                let end = usize::try_from(end)?;
+                if end > inline.len() {
+                    inline.resize(end, 0);
                 }
+                inline[start..end].copy_from_slice(data);
+                return Ok(u64::try_from(data.len())?);
+            }
+            self.spill(handle, diskgroups)?;
+            return self.write(handle, start, data, diskgroups);
+        }
+        if start > size {
+            // Leave a hole between the old end and the start of the write
+            self.frags.push(Fragment::hole(start - size));
+        }
+        let size = std::cmp::max(start, size);
+        if end > size {
+            // The write runs past the last fragment, extend the object
+            let mut new_frags = handle.alloc_bytes(end - size, self.last_extent())?;
+            self.frags.append(&mut new_frags);
+        }
+        self.fill_holes(handle, start, end)?;
+        let mut res = 0;
+        let mut frags = Vec::with_capacity(self.frags.len() + 2);
+        let mut frag_start = 0;
+        for f in std::mem::take(&mut self.frags) {
+            let frag_end = frag_start + f.size;
+            if frag_end <= start || frag_start >= end {
+                frags.push(f);
+            } else {
+                // Clamp the write to the part overlapping this fragment
+                let write_start = std::cmp::max(start, frag_start);
+                let write_end = std::cmp::min(end, frag_end);
+                let buf_start = usize::try_from(write_start - start)?;
+                let buf_end = usize::try_from(write_end - start)?;
+                frags.append(&mut Object::write_fragment(
+                    handle,
+                    f,
+                    write_start - frag_start,
+                    &data[buf_start..buf_end],
+                    diskgroups,
+                )?);
+                res += buf_end - buf_start;
             }
-            pos += f.size;
+            frag_start = frag_end;
         }
+        self.frags = frags;
         Ok(res.try_into()?)
     }
+    /// Writes into a fragment at a given offset, copy-on-write. Only the blocks written to are
+    /// copied, unless the extent is shared, in which case the whole of it is.
+    /// Returns the fragments replacing it.
+    #[cfg(feature = "unstable")]
+    fn write_fragment(
+        handle: &mut AMFS,
+        f: Fragment,
+        start: u64,
+        data: &[u8],
+        diskgroups: &[Option<DiskGroup>],
+    ) -> AMResult<Vec<Fragment>> {
+        let block_size = u64::try_from(BLOCK_SIZE)?;
+        let ptr = f.pointer;
+        let blocks = u64::from(ptr.length());
+        // The blocks of the extent being written to
+        let first = (f.offset + start) / block_size;
+        let last = (f.offset + start + u64::try_from(data.len())? + block_size - 1) / block_size;
+        // A fragment claiming more than its extent holds is corrupt
+        assert_or_err!(last <= blocks, AMError::TODO(0));
+        if handle.get_refcounts().contains_key(&ptr) || (first == 0 && last == blocks) {
+            let mut new = handle.realloc(ptr)?.ok_or(AMError::TODO(0))?;
+            new.write(
+                usize::try_from(f.offset + start)?,
+                data.len(),
+                diskgroups,
+                data,
+            )?;
+            new.update(diskgroups)?;
+            return Ok(vec![Fragment::new(f.size, f.offset, new)]);
+        }
+        let mut res = Vec::with_capacity(3);
+        if first > 0 {
+            let mut head = ptr.slice(0, first)?;
+            if f.offset < first * block_size {
+                head.update(diskgroups)?;
+                res.push(Fragment::new(first * block_size - f.offset, f.offset, head));
+            } else {
+                // None of the fragment lies before the blocks written to
+                handle.free(head)?;
+            }
+        }
+        let mut copy = handle
+            .realloc(ptr.slice(first, last)?)?
+            .ok_or(AMError::TODO(0))?;
+        copy.write(
+            usize::try_from(f.offset + start - first * block_size)?,
+            data.len(),
+            diskgroups,
+            data,
+        )?;
+        copy.update(diskgroups)?;
+        let copy_start = std::cmp::max(f.offset, first * block_size);
+        let copy_end = std::cmp::min(f.offset + f.size, last * block_size);
+        res.push(Fragment::new(
+            copy_end - copy_start,
+            copy_start - first * block_size,
+            copy,
+        ));
+        if last < blocks {
+            let mut tail = ptr.slice(last, blocks)?;
+            if f.offset + f.size > last * block_size {
+                tail.update(diskgroups)?;
+                res.push(Fragment::new(
+                    f.offset + f.size - last * block_size,
+                    0,
+                    tail,
+                ));
+            } else {
+                // None of the fragment lies past the blocks written to
+                handle.free(tail)?;
+            }
+        }
+        Ok(res)
+    }
     #[cfg(feature = "unstable")]
     pub(crate) fn truncate(
         &mut self,
         handle: &mut AMFS,
         size: u64,
-        _diskgroups: &[Option<DiskGroup>],
+        diskgroups: &[Option<DiskGroup>],
     ) -> AMResult<()> {
-        if self.frags.is_empty() {
-            if size == 0 {
-                // No-op
-            } else {
-                //We need to create fragments
-                todo!();
+        if let Some(inline) = &mut self.inline {
+            if size <= u64::try_from(INLINE_MAX)? {
+                inline.resize(usize::try_from(size)?, 0);
+                return Ok(());
             }
-        } else {
-            let mut cur_size = self.size()?;
-            if size < cur_size {
-                // We want to shrink
-                while let Some(lf) = self.frags.last_mut() {
-                    if cur_size - lf.size > size {
-                        // Dropping a fragment leaves us too big, continue
-                        cur_size -= lf.size;
-                        self.frags.pop();
-                        //TODO: Free the fragment
-                    } else if cur_size - lf.size == size {
-                        // Dropping a fragment leaves us the right size
-                        self.frags.pop();
-                        //TODO: Free the fragment
-                        break;
-                    } else {
-                        // Shrinking a fragment leaves us the right size
-                        lf.size = cur_size - size;
-                        break;
+            self.spill(handle, diskgroups)?;
+        }
+        let mut cur_size = self.size()?;
+        if size < cur_size {
+            // We want to shrink
+            while let Some(lf) = self.frags.last_mut() {
+                if cur_size - lf.size > size {
+                    // Dropping a fragment leaves us too big, continue
+                    cur_size -= lf.size;
+                    let f = self.frags.pop().ok_or(AMError::TODO(0))?;
+                    if !f.is_hole() {
+                        handle.free(f.pointer)?;
+                    }
+                } else if cur_size - lf.size == size {
+                    // Dropping a fragment leaves us the right size
+                    let f = self.frags.pop().ok_or(AMError::TODO(0))?;
+                    if !f.is_hole() {
+                        handle.free(f.pointer)?;
+                    }
+                    break;
+                } else {
+                    // Shrinking a fragment leaves us the right size
+                    lf.size -= cur_size - size;
+                    if !lf.is_hole() && !handle.get_refcounts().contains_key(&lf.pointer) {
+                        // Free the blocks of the extent past the new end
+                        let block_size = u64::try_from(BLOCK_SIZE)?;
+                        let needed = (lf.offset + lf.size + block_size - 1) / block_size;
+                        let blocks = u64::from(lf.pointer.length());
+                        if needed < blocks {
+                            handle.free(lf.pointer.slice(needed, blocks)?)?;
+                            lf.pointer = lf.pointer.slice(0, needed)?;
+                            lf.pointer.update(diskgroups)?;
+                        }
                     }
+                    break;
+                }
+            }
+        } else if size > cur_size {
+            // Extend with a hole, storage is allocated once it's written to
+            self.frags.push(Fragment::hole(size - cur_size));
+        }
+        Ok(())
+    }
+    /// Allocates storage for the parts of any holes overlapping a range
+    #[cfg(feature = "unstable")]
+    fn fill_holes(&mut self, handle: &mut AMFS, start: u64, end: u64) -> AMResult<()> {
+        let mut res = Vec::with_capacity(self.frags.len());
+        let mut frag_start = 0;
+        for f in self.frags.drain(..) {
+            let frag_end = frag_start + f.size;
+            if f.is_hole() && frag_start < end && frag_end > start {
+                let fill_start = std::cmp::max(start, frag_start);
+                let fill_end = std::cmp::min(end, frag_end);
+                if fill_start > frag_start {
+                    res.push(Fragment::hole(fill_start - frag_start));
+                }
+                // Follow on from the last allocated fragment before the hole
+                let hint = res
+                    .iter()
+                    .rev()
+                    .find(|f| !f.is_hole())
+                    .map(|f| AllocHint::Near(f.pointer));
+                res.append(&mut handle.alloc_bytes(fill_end - fill_start, hint)?);
+                if frag_end > fill_end {
+                    res.push(Fragment::hole(frag_end - fill_end));
                 }
             } else {
-                let mut new_frags = handle.alloc_bytes(size - self.size()?)?;
-                self.frags.append(&mut new_frags);
+                res.push(f);
+            }
+            frag_start = frag_end;
+        }
+        self.frags = res;
+        Ok(())
+    }
+    /// Moves the object's data into consecutive blocks, copy-on-write, if it isn't already.
+    /// Returns whether anything was moved.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn defrag(&mut self, handle: &mut AMFS) -> AMResult<bool> {
+        let ptrs: Vec<AMPointerGlobal> = self
+            .frags
+            .iter()
+            .filter(|f| !f.is_hole())
+            .map(|f| f.pointer)
+            .collect();
+        if ptrs
+            .windows(2)
+            .all(|w| w[1].loc() == w[0].loc() + u64::from(w[0].length()))
+        {
+            return Ok(false);
+        }
+        // Start somewhere with room for the whole object, or as much of it as one extent can hold
+        let blocks = ptrs.iter().map(|p| u64::from(p.length())).sum::<u64>();
+        let mut hint = match handle.find_free_run(std::cmp::min(blocks, MAX_EXTENT_BLOCKS)) {
+            Ok(start) => AllocHint::Near(start),
+            Err(_) => AllocHint::Data,
+        };
+        for f in self.frags.iter_mut().filter(|f| !f.is_hole()) {
+            f.pointer = handle
+                .realloc_near(f.pointer, hint)?
+                .ok_or(AMError::TODO(0))?;
+            hint = AllocHint::Near(f.pointer);
+        }
+        Ok(true)
+    }
+    /// Merges neighbouring holes, and neighbouring fragments stored back to back on disk, into
+    /// single fragments. Extents shared with other objects are left as they are.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn coalesce(
+        &mut self,
+        handle: &mut AMFS,
+        diskgroups: &[Option<DiskGroup>],
+    ) -> AMResult<()> {
+        let block_size = u64::try_from(BLOCK_SIZE)?;
+        let mut res: Vec<Fragment> = Vec::with_capacity(self.frags.len());
+        for f in self.frags.drain(..) {
+            if let Some(last) = res.last_mut() {
+                if last.is_hole() && f.is_hole() {
+                    last.size += f.size;
+                    continue;
+                }
+                let (a, b) = (last.pointer, f.pointer);
+                if !last.is_hole()
+                    && !f.is_hole()
+                    && (a.geo(), a.dev()) == (b.geo(), b.dev())
+                    && b.loc() == a.loc() + u64::from(a.length())
+                    && last.offset + last.size == u64::from(a.length()) * block_size
+                    && f.offset == 0
+                    && u64::from(a.length()) + u64::from(b.length()) <= MAX_EXTENT_BLOCKS
+                    && !handle.get_refcounts().contains_key(&a)
+                    && !handle.get_refcounts().contains_key(&b)
+                {
+                    let mut ptr =
+                        AMPointerGlobal::new(a.loc(), a.length() + b.length(), a.geo(), a.dev());
+                    ptr.update(diskgroups)?;
+                    if ptr.is_long() {
+                        handle.enable_feature(AMFeatures::LongExtents)?;
+                    }
+                    last.pointer = ptr;
+                    last.size += f.size;
+                    continue;
+                }
             }
+            res.push(f);
         }
+        self.frags = res;
         Ok(())
     }
+    /// Moves data stored at or past a block to below it, copy-on-write
+    #[cfg(feature = "unstable")]
+    pub(crate) fn evacuate(&mut self, handle: &mut AMFS, end: u64) -> AMResult<()> {
+        for f in &mut self.frags {
+            if !f.is_hole() && f.pointer.loc() + u64::from(f.pointer.length()) > end {
+                f.pointer = handle
+                    .realloc_near(f.pointer, AllocHint::Data)?
+                    .ok_or(AMError::TODO(0))?;
+            }
+        }
+        Ok(())
+    }
+    /// Moves data stored on any of a set of (geometry, device) pairs to other devices of the
+    /// same geometry, copy-on-write. Returns the extents moved, at their old places.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn rebalance(
+        &mut self,
+        handle: &mut AMFS,
+        sources: &BTreeSet<(u8, u8)>,
+    ) -> AMResult<Vec<AMPointerGlobal>> {
+        let mut moved = Vec::new();
+        for f in &mut self.frags {
+            if !f.is_hole() && sources.contains(&(f.pointer.geo(), f.pointer.dev())) {
+                moved.push(f.pointer);
+                f.pointer = handle
+                    .realloc_near(f.pointer, AllocHint::OtherDevice(f.pointer))?
+                    .ok_or(AMError::TODO(0))?;
+            }
+        }
+        Ok(moved)
+    }
+    /// Gets a hint to place new data just after the object's last allocated fragment
+    #[cfg(feature = "unstable")]
+    fn last_extent(&self) -> Option<AllocHint> {
+        self.frags
+            .iter()
+            .rev()
+            .find(|f| !f.is_hole())
+            .map(|f| AllocHint::Near(f.pointer))
+    }
     /// Fetches the size of the object
     #[cfg(feature = "stable")]
-    fn size(&self) -> AMResult<u64> {
+    pub(crate) fn size(&self) -> AMResult<u64> {
+        if let Some(inline) = &self.inline {
+            return Ok(u64::try_from(inline.len())?);
+        }
         let mut res = 0;
         for f in &self.frags {
             res += f.size;
@@ -480,7 +1291,7 @@ pub struct Fragment {
     pub size:    u64,
     /// The offset from the pointer location to the start of the fragment
     pub offset:  u64,
-    /// A pointer to the block containing the fragment's data
+    /// A pointer to the block containing the fragment's data, or null for a hole
     pub pointer: AMPointerGlobal,
 }
 
@@ -494,6 +1305,20 @@ impl Fragment {
             pointer,
         }
     }
+    /// Creates a hole- a fragment which reads as zeros and has no storage
+    #[cfg(feature = "stable")]
+    pub fn hole(size: u64) -> Fragment {
+        Fragment {
+            size,
+            offset: 0,
+            pointer: AMPointerGlobal::null(),
+        }
+    }
+    /// Checks whether the fragment is a hole
+    #[cfg(feature = "stable")]
+    pub fn is_hole(&self) -> bool {
+        self.pointer.is_null()
+    }
     /// Initializes a fragment from a slice of bytes
     #[cfg(feature = "stable")]
     pub fn from_bytes(buf: [u8; FRAGMENT_SIZE]) -> Fragment {
@@ -523,23 +1348,19 @@ fn list_fragment_size_test() {
     assert_eq!(mem::size_of::<Fragment>(), FRAGMENT_SIZE);
 }
 
-#[test]
+#[test_fs(mem, file, blocks = 100)]
 #[allow(clippy::unwrap_used)]
-pub fn test_object() {
-    crate::test::logging::init_log();
-
-    let fs = crate::test::fsinit::create_fs().unwrap();
+pub fn test_object(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
 
     let mut buf = [0u8; 4];
     assert_eq!(fs.read_object(0, 0, &mut buf).unwrap(), 0);
 }
 
-#[test]
+#[test_fs(mem, file, blocks = 100)]
 #[allow(clippy::unwrap_used)]
-pub fn test_insert() {
-    crate::test::logging::init_log();
-
-    let fs = crate::test::fsinit::create_fs().unwrap();
+pub fn test_insert(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
 
     fs.create_object(0, 1).unwrap();
     fs.create_object(1, 2).unwrap();
@@ -566,12 +1387,10 @@ pub fn test_insert() {
     fs.commit().unwrap();
 }
 
-#[test]
+#[test_fs(mem, file, blocks = 100)]
 #[allow(clippy::unwrap_used)]
-pub fn test_truncate() {
-    crate::test::logging::init_log();
-
-    let fs = crate::test::fsinit::create_fs().unwrap();
+pub fn test_truncate(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
 
     fs.create_object(0, 8).unwrap();
     fs.create_object(1, 1).unwrap();
@@ -595,3 +1414,690 @@ pub fn test_truncate() {
     assert_eq!(fs.read_object(0, 0, &mut buf[0..16]).unwrap(), 16);
     fs.commit().unwrap();
 }
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_truncate_free(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    fs.create_object(0, 1).unwrap();
+    fs.truncate_object(0, 2 * BLOCK_SIZE as u64 + 1).unwrap();
+    fs.write_object(0, 1, &[7; 2 * BLOCK_SIZE]).unwrap();
+    let frags = fs
+        .read()
+        .unwrap()
+        .get_objects()
+        .unwrap()
+        .get_object(0)
+        .unwrap()
+        .unwrap()
+        .frags();
+    assert_eq!(frags.len(), 2);
+    assert_eq!(frags[1].pointer.length(), 2);
+    let run = frags[1].pointer.loc();
+    let freed = |fs: &crate::FSHandle| -> Vec<(u64, u16)> {
+        fs.read()
+            .unwrap()
+            .get_free_queue()
+            .values()
+            .flatten()
+            .map(|p| (p.loc(), p.length()))
+            .collect()
+    };
+    // Cutting into a run frees the blocks past the new end
+    fs.truncate_object(0, 2).unwrap();
+    assert_eq!(fs.size_object(0).unwrap(), 2);
+    assert!(freed(&fs).contains(&(run + 1, 1)));
+    assert!(!freed(&fs).contains(&(run, 2)));
+    // Dropping what's left of it frees the rest
+    fs.truncate_object(0, 1).unwrap();
+    assert!(freed(&fs).contains(&(run, 1)));
+    assert!(!freed(&fs).contains(&(frags[0].pointer.loc(), 1)));
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_truncate_retire(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    fs.set_retention(crate::Retention {
+        transactions: Some(1),
+        time:         None,
+    })
+    .unwrap();
+
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[1; 8 * BLOCK_SIZE]).unwrap();
+    fs.commit().unwrap();
+    let ptr = fs
+        .read()
+        .unwrap()
+        .get_objects()
+        .unwrap()
+        .get_object(1)
+        .unwrap()
+        .unwrap()
+        .frags()[0]
+        .pointer;
+    assert_eq!(ptr.length(), 8);
+    fs.truncate_object(1, 3 * BLOCK_SIZE as u64 - 1).unwrap();
+    // Enough commits for every queued free to be retired
+    for _ in 0..4 {
+        fs.commit().unwrap();
+    }
+
+    // Only the blocks past the new end are handed back
+    let extents = fs.read().unwrap().get_diskgroups()[0]
+        .as_ref()
+        .unwrap()
+        .allocs[0]
+        .extents();
+    let (start, ex) = extents.range(..=ptr.loc()).next_back().unwrap();
+    assert_eq!((*start, ex.size, ex.used), (ptr.loc(), 3, true));
+    fs.create_object(2, 0).unwrap();
+    fs.write_object(2, 0, &[2; 8 * BLOCK_SIZE]).unwrap();
+    fs.commit().unwrap();
+    let mut buf = vec![0u8; 3 * BLOCK_SIZE - 1];
+    assert_eq!(
+        fs.read_object(1, 0, &mut buf).unwrap(),
+        3 * BLOCK_SIZE as u64 - 1
+    );
+    assert!(buf.iter().all(|x| *x == 1));
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_write_spanning(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    // Too big to be stored inline
+    fs.create_object(0, 128).unwrap();
+    // Runs past the end of the only fragment
+    assert_eq!(
+        fs.write_object(0, 124, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap(),
+        8
+    );
+    assert_eq!(fs.size_object(0).unwrap(), 132);
+    let mut buf = [0u8; 12];
+    assert_eq!(fs.read_object(0, 120, &mut buf).unwrap(), 12);
+    assert_eq!(buf, [0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+    // Spans the boundary between the two fragments
+    assert_eq!(fs.write_object(0, 126, &[9, 9, 9, 9]).unwrap(), 4);
+    assert_eq!(fs.size_object(0).unwrap(), 132);
+    assert_eq!(fs.read_object(0, 120, &mut buf).unwrap(), 12);
+    assert_eq!(buf, [0, 0, 0, 0, 1, 2, 9, 9, 9, 9, 7, 8]);
+    fs.commit().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_write_partial_cow(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    let frags = |fs: &crate::FSHandle| {
+        fs.read()
+            .unwrap()
+            .get_objects()
+            .unwrap()
+            .get_object(1)
+            .unwrap()
+            .unwrap()
+            .frags()
+    };
+    let block_size = BLOCK_SIZE as u64;
+
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[1; 64 * BLOCK_SIZE]).unwrap();
+    fs.commit().unwrap();
+    let old = frags(&fs);
+    assert_eq!(old.len(), 1);
+    let ptr = old[0].pointer;
+
+    // Only the block written to is copied, the rest stay where they were
+    assert_eq!(fs.write_object(1, 10 * block_size + 5, &[2; 3]).unwrap(), 3);
+    let new = frags(&fs);
+    assert_eq!(new.len(), 3);
+    assert_eq!(
+        (new[0].pointer.loc(), new[0].pointer.length()),
+        (ptr.loc(), 10)
+    );
+    assert_eq!(new[0].size, 10 * block_size);
+    assert_eq!(new[1].pointer.length(), 1);
+    assert_eq!(new[1].size, block_size);
+    assert_eq!(
+        (new[2].pointer.loc(), new[2].pointer.length()),
+        (ptr.loc() + 11, 53)
+    );
+    assert_eq!(new[2].size, 53 * block_size);
+    let freed: Vec<_> = fs
+        .read()
+        .unwrap()
+        .get_free_queue()
+        .values()
+        .flatten()
+        .map(|p| (p.loc(), p.length()))
+        .collect();
+    assert!(freed.contains(&(ptr.loc() + 10, 1)));
+    assert!(!freed.contains(&(ptr.loc(), 64)));
+
+    let mut buf = vec![0u8; 64 * BLOCK_SIZE];
+    assert_eq!(fs.read_object(1, 0, &mut buf).unwrap(), 64 * block_size);
+    let written = 10 * BLOCK_SIZE + 5..10 * BLOCK_SIZE + 8;
+    assert!(buf[written.clone()].iter().all(|x| *x == 2));
+    assert!(buf[..written.start].iter().all(|x| *x == 1));
+    assert!(buf[written.end..].iter().all(|x| *x == 1));
+    fs.commit().unwrap();
+
+    let report = crate::operations::fsck_single_scan(d, &Default::default(), |_, _, _| {}).unwrap();
+    assert_eq!(report.summary.errors, 0);
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_write_partial_cow_retire(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.set_retention(crate::Retention {
+        transactions: Some(1),
+        time:         None,
+    })
+    .unwrap();
+    let block_size = BLOCK_SIZE as u64;
+
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[1; 16 * BLOCK_SIZE]).unwrap();
+    fs.commit().unwrap();
+    // Copy out blocks from the middle, the start and the end of extents
+    for blk in [8, 0, 15] {
+        assert_eq!(fs.write_object(1, blk * block_size, &[2]).unwrap(), 1);
+    }
+    // Enough commits for every queued free to be retired
+    for _ in 0..4 {
+        fs.commit().unwrap();
+    }
+    // Reusing the freed blocks leaves the parts of the extents still in use alone
+    for id in 2..6 {
+        fs.create_object(id, 0).unwrap();
+        fs.write_object(id, 0, &[3; 4 * BLOCK_SIZE]).unwrap();
+    }
+    fs.commit().unwrap();
+
+    let mut buf = vec![0u8; 16 * BLOCK_SIZE];
+    assert_eq!(fs.read_object(1, 0, &mut buf).unwrap(), 16 * block_size);
+    for (i, x) in buf.iter().enumerate() {
+        let copied = [0, 8 * BLOCK_SIZE, 15 * BLOCK_SIZE].contains(&i);
+        assert_eq!(*x, if copied { 2 } else { 1 });
+    }
+    let handle = fs.read().unwrap();
+    let extents = handle.get_diskgroups()[0].as_ref().unwrap().allocs[0].extents();
+    let frags = handle
+        .get_objects()
+        .unwrap()
+        .get_object(1)
+        .unwrap()
+        .unwrap()
+        .frags();
+    for f in frags {
+        let (start, ex) = extents.range(..=f.pointer.loc()).next_back().unwrap();
+        assert!(ex.used);
+        assert!(start + ex.size >= f.pointer.loc() + u64::from(f.pointer.length()));
+    }
+    drop(handle);
+
+    let report = crate::operations::fsck_single_scan(d, &Default::default(), |_, _, _| {}).unwrap();
+    assert_eq!(report.summary.errors, 0);
+}
+
+#[test_fs(mem, file, blocks = 1000)]
+#[allow(clippy::unwrap_used)]
+pub fn test_list_overflow(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    // Entries take up to 112 bytes, so this splits the root leaf
+    for i in 0..150 {
+        fs.create_object(i, i + 1).unwrap();
+    }
+    for i in 0..150 {
+        assert_eq!(fs.size_object(i).unwrap(), i + 1);
+    }
+    assert_eq!(fs.write_object(120, 0, &[1, 2, 3]).unwrap(), 3);
+    let mut buf = [0u8; 3];
+    assert_eq!(fs.read_object(120, 0, &mut buf).unwrap(), 3);
+    assert_eq!(buf, [1, 2, 3]);
+    fs.commit().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 1000)]
+#[allow(clippy::unwrap_used)]
+pub fn test_list_grow(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    for i in 0..150 {
+        fs.create_object(i, i + 1).unwrap();
+    }
+    // Every append adds a fragment, until object 10 no longer fits in its list block
+    for i in 0..8 {
+        assert_eq!(fs.write_object(10, 11 + i, &[1]).unwrap(), 1);
+    }
+    for i in 0..150 {
+        let size = if i == 10 { 19 } else { i + 1 };
+        assert_eq!(fs.size_object(i).unwrap(), size);
+    }
+    let mut buf = [0u8; 8];
+    assert_eq!(fs.read_object(10, 11, &mut buf).unwrap(), 8);
+    assert_eq!(buf, [1; 8]);
+    fs.commit().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 2000)]
+#[allow(clippy::unwrap_used)]
+pub fn test_btree_sparse(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    // Interleave IDs so inserts land in the middle of existing leaves
+    for i in 0..300 {
+        let id = (i * 7919) % 300 * 1000 + 1;
+        fs.create_object(id, i + 1).unwrap();
+    }
+    for i in 0..300 {
+        let id = (i * 7919) % 300 * 1000 + 1;
+        assert_eq!(fs.size_object(id).unwrap(), i + 1);
+    }
+    assert!(fs.size_object(2).is_err());
+    fs.commit().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 4000)]
+#[allow(clippy::unwrap_used)]
+pub fn test_many_fragments(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    let frags = |fs: &crate::FSHandle, id| {
+        fs.read()
+            .unwrap()
+            .get_objects()
+            .unwrap()
+            .get_object(id)
+            .unwrap()
+            .unwrap()
+            .frags()
+    };
+
+    // Appending a block at a time gives a fragment per write, more than fit in a leaf
+    fs.create_object(1, 0).unwrap();
+    for i in 0..300u64 {
+        let block = [u8::try_from(i % 251).unwrap(); BLOCK_SIZE];
+        let start = i * u64::try_from(BLOCK_SIZE).unwrap();
+        assert_eq!(
+            fs.write_object(1, start, &block).unwrap(),
+            u64::try_from(BLOCK_SIZE).unwrap()
+        );
+    }
+    fs.commit().unwrap();
+    assert!(frags(&fs, 1).len() < 127);
+    let mut buf = [0u8; BLOCK_SIZE];
+    for i in 0..300 {
+        let start = i * u64::try_from(BLOCK_SIZE).unwrap();
+        fs.read_object(1, start, &mut buf).unwrap();
+        assert!(buf.iter().all(|x| u64::from(*x) == i % 251));
+    }
+
+    // Holes between every fragment can't be merged away
+    fs.create_object(2, 0).unwrap();
+    let res: Vec<_> = (0..100)
+        .map(|i| fs.write_object(2, i * 2 * u64::try_from(BLOCK_SIZE).unwrap(), &[1]))
+        .collect();
+    assert!(res[..64].iter().all(Result::is_ok));
+    assert!(res[64..].iter().all(Result::is_err));
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_truncate_empty(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    assert_eq!(fs.size_object(0).unwrap(), 0);
+    fs.truncate_object(0, 0).unwrap();
+    assert_eq!(fs.size_object(0).unwrap(), 0);
+    fs.truncate_object(0, 6).unwrap();
+    assert_eq!(fs.size_object(0).unwrap(), 6);
+    assert_eq!(fs.write_object(0, 0, &[1, 2, 3, 4, 5, 6]).unwrap(), 6);
+    let mut buf = [0u8; 6];
+    assert_eq!(fs.read_object(0, 0, &mut buf).unwrap(), 6);
+    assert_eq!(buf, [1, 2, 3, 4, 5, 6]);
+    fs.commit().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_holes(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    fs.create_object(1, 2).unwrap();
+    assert_eq!(fs.write_object(1, 0, &[1, 2]).unwrap(), 2);
+    // Growing by many blocks mustn't allocate any of them
+    fs.truncate_object(1, 1 << 30).unwrap();
+    assert_eq!(fs.size_object(1).unwrap(), 1 << 30);
+    let mut buf = [9u8; 4];
+    assert_eq!(fs.read_object(1, 0, &mut buf).unwrap(), 4);
+    assert_eq!(buf, [1, 2, 0, 0]);
+    // Writing into the middle of the hole splits it
+    assert_eq!(fs.write_object(1, 1 << 20, &[3, 4]).unwrap(), 2);
+    assert_eq!(fs.read_object(1, (1 << 20) - 1, &mut buf).unwrap(), 4);
+    assert_eq!(buf, [0, 3, 4, 0]);
+    let frags = fs
+        .read()
+        .unwrap()
+        .get_objects()
+        .unwrap()
+        .get_object(1)
+        .unwrap()
+        .unwrap()
+        .frags();
+    assert_eq!(frags.iter().filter(|f| !f.is_hole()).count(), 2);
+    assert_eq!(fs.size_object(1).unwrap(), 1 << 30);
+    fs.truncate_object(1, 1).unwrap();
+    assert_eq!(fs.size_object(1).unwrap(), 1);
+    fs.commit().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_create_sparse(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    // Objects too big to be inline start out as a single hole
+    fs.create_object(1, 10000).unwrap();
+    let frags = fs
+        .read()
+        .unwrap()
+        .get_objects()
+        .unwrap()
+        .get_object(1)
+        .unwrap()
+        .unwrap()
+        .frags();
+    assert!(frags.iter().all(Fragment::is_hole));
+    // Writes anywhere in it, up to its end, only fill what they cover
+    assert_eq!(fs.write_object(1, 9998, &[1, 2]).unwrap(), 2);
+    assert_eq!(fs.write_object(1, 5000, &[3; 16]).unwrap(), 16);
+    assert_eq!(fs.size_object(1).unwrap(), 10000);
+    let mut buf = [9u8; 4];
+    assert_eq!(fs.read_object(1, 9996, &mut buf).unwrap(), 4);
+    assert_eq!(buf, [0, 0, 1, 2]);
+    assert_eq!(fs.read_object(1, 4998, &mut buf).unwrap(), 4);
+    assert_eq!(buf, [0, 0, 3, 3]);
+    fs.commit().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_delete_sparse(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    // Data, then a hole, then data, then a trailing hole
+    fs.create_object(1, 0).unwrap();
+    assert_eq!(fs.write_object(1, 0, &[1; 8192]).unwrap(), 8192);
+    assert_eq!(fs.write_object(1, 1 << 20, &[2, 3]).unwrap(), 2);
+    fs.truncate_object(1, 1 << 30).unwrap();
+    fs.commit().unwrap();
+
+    // Dropping the holes mustn't try to free them
+    fs.clone_object(1, 2).unwrap();
+    fs.rename_object(2, 3).unwrap();
+    fs.clone_object(1, 3).unwrap();
+    fs.delete_object(1).unwrap();
+    assert!(fs.size_object(1).is_err());
+    let mut buf = [9u8; 4];
+    assert_eq!(fs.read_object(3, (1 << 20) - 1, &mut buf).unwrap(), 4);
+    assert_eq!(buf, [0, 2, 3, 0]);
+    fs.delete_object(3).unwrap();
+    fs.commit().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_list_objects(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    fs.create_object(5, 3).unwrap();
+    fs.create_object(2, 7).unwrap();
+    fs.truncate_object(2, 9).unwrap();
+    let objs: Vec<_> = fs.list_objects().unwrap().collect();
+    assert_eq!(objs, vec![(0, 0, 0), (2, 9, 0), (5, 3, 0)]);
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_clone_object(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    fs.create_object(1, 128).unwrap();
+    assert_eq!(fs.write_object(1, 0, &[1, 2, 3, 4]).unwrap(), 4);
+    fs.clone_object(1, 2).unwrap();
+    let shared = fs
+        .read()
+        .unwrap()
+        .get_objects()
+        .unwrap()
+        .get_object(1)
+        .unwrap()
+        .unwrap()
+        .frags()[0]
+        .pointer;
+    let mut buf = [0u8; 4];
+    assert_eq!(fs.read_object(2, 0, &mut buf).unwrap(), 4);
+    assert_eq!(buf, [1, 2, 3, 4]);
+    // Writing to the clone leaves the source untouched
+    assert_eq!(fs.write_object(2, 0, &[5, 6]).unwrap(), 2);
+    assert_eq!(fs.read_object(1, 0, &mut buf).unwrap(), 4);
+    assert_eq!(buf, [1, 2, 3, 4]);
+    assert_eq!(fs.read_object(2, 0, &mut buf).unwrap(), 4);
+    assert_eq!(buf, [5, 6, 3, 4]);
+    // The extent is only freed once its last referrer lets go
+    let is_freed = |fs: &crate::FSHandle| {
+        fs.read()
+            .unwrap()
+            .get_free_queue()
+            .values()
+            .flatten()
+            .any(|p| p.loc() == shared.loc())
+    };
+    assert!(!is_freed(&fs));
+    fs.truncate_object(1, 0).unwrap();
+    assert!(is_freed(&fs));
+    fs.commit().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_object_flags(d: crate::Disk) {
+    use crate::AMFeatures;
+
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[1; 200]).unwrap();
+    fs.create_object(2, 0).unwrap();
+    fs.write_object(2, 0, &[2; 4]).unwrap();
+    assert!(fs.set_object_flags(1, INLINE_FLAG).is_err());
+
+    fs.set_object_flags(1, FLAG_IMMUTABLE).unwrap();
+    assert!(fs.write_object(1, 0, &[3]).is_err());
+    assert!(fs.write_object(1, 200, &[3]).is_err());
+    assert!(fs.truncate_object(1, 0).is_err());
+    assert!(fs.create_object(1, 0).is_err());
+    assert!(fs.clone_object(2, 1).is_err());
+    // Clones can be written, even of immutable objects
+    fs.clone_object(1, 3).unwrap();
+    assert_eq!(fs.object_flags(3).unwrap(), 0);
+    fs.write_object(3, 0, &[3]).unwrap();
+
+    fs.set_object_flags(2, FLAG_APPEND_ONLY | FLAG_NO_COMPRESS)
+        .unwrap();
+    assert!(fs.write_object(2, 0, &[3]).is_err());
+    assert!(fs.truncate_object(2, 8).is_err());
+    assert_eq!(fs.write_object(2, 4, &[3]).unwrap(), 1);
+    assert_eq!(fs.size_object(2).unwrap(), 5);
+    fs.commit().unwrap();
+
+    let fs2 = crate::FSHandle::open(&[d]).unwrap();
+    assert_eq!(fs2.object_flags(1).unwrap(), FLAG_IMMUTABLE);
+    assert_eq!(
+        fs2.object_flags(2).unwrap(),
+        FLAG_APPEND_ONLY | FLAG_NO_COMPRESS
+    );
+    assert!(
+        fs2.read().unwrap().get_superblock().unwrap().features()[AMFeatures::ObjectFlags as usize]
+    );
+    fs2.set_object_flags(1, 0).unwrap();
+    fs2.write_object(1, 0, &[3]).unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_inline(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    fs.create_object(1, 4).unwrap();
+    let is_inline = |fs: &crate::FSHandle| {
+        fs.read()
+            .unwrap()
+            .get_objects()
+            .unwrap()
+            .get_object(1)
+            .unwrap()
+            .unwrap()
+            .is_inline()
+    };
+    assert!(is_inline(&fs));
+    assert_eq!(fs.write_object(1, 2, &[1, 2, 3, 4]).unwrap(), 4);
+    assert_eq!(fs.size_object(1).unwrap(), 6);
+    let mut buf = [9u8; 6];
+    assert_eq!(fs.read_object(1, 0, &mut buf).unwrap(), 6);
+    assert_eq!(buf, [0, 0, 1, 2, 3, 4]);
+    // Growing past the inline limit moves the data out to a fragment
+    assert_eq!(fs.write_object(1, INLINE_MAX as u64, &[5]).unwrap(), 1);
+    assert!(!is_inline(&fs));
+    assert_eq!(fs.size_object(1).unwrap(), INLINE_MAX as u64 + 1);
+    assert_eq!(fs.read_object(1, 0, &mut buf).unwrap(), 6);
+    assert_eq!(buf, [0, 0, 1, 2, 3, 4]);
+    fs.commit().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_vectored(d: crate::Disk) {
+    use std::io::{IoSlice, IoSliceMut};
+
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    fs.create_object(1, 128).unwrap();
+    let bufs = [
+        IoSlice::new(&[1, 2]),
+        IoSlice::new(&[]),
+        IoSlice::new(&[3, 4, 5]),
+    ];
+    assert_eq!(fs.write_object_vectored(1, 10, &bufs).unwrap(), 5);
+    let mut a = [0u8; 3];
+    let mut b = [0u8; 3];
+    let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)];
+    assert_eq!(fs.read_object_vectored(1, 9, &mut bufs).unwrap(), 6);
+    assert_eq!(a, [0, 1, 2]);
+    assert_eq!(b, [3, 4, 5]);
+    fs.commit().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_read_eof(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    fs.create_object(META_OBJECTS, 200).unwrap();
+    fs.create_object(1, 4).unwrap();
+    let mut buf = [9u8; 8];
+    // Short reads stop at the end of the object
+    assert_eq!(fs.read_object(META_OBJECTS, 196, &mut buf).unwrap(), 4);
+    assert_eq!(buf[4..], [9, 9, 9, 9]);
+    assert_eq!(fs.read_object(1, 2, &mut buf).unwrap(), 2);
+    // Reads at the end return nothing
+    assert_eq!(fs.read_object(META_OBJECTS, 200, &mut buf).unwrap(), 0);
+    assert_eq!(fs.read_object(1, 4, &mut buf).unwrap(), 0);
+    // Reads past the end are only an error on meta-objects
+    assert_eq!(fs.read_object(META_OBJECTS, 300, &mut buf).unwrap(), 0);
+    assert!(fs.read_object(1, 5, &mut buf).is_err());
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_write_past_end(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    fs.create_object(META_OBJECTS, 0).unwrap();
+    // Stays inline
+    assert_eq!(fs.write_object(META_OBJECTS, 4, &[1, 2]).unwrap(), 2);
+    assert_eq!(fs.size_object(META_OBJECTS).unwrap(), 6);
+    let mut buf = [9u8; 6];
+    assert_eq!(fs.read_object(META_OBJECTS, 0, &mut buf).unwrap(), 6);
+    assert_eq!(buf, [0, 0, 0, 0, 1, 2]);
+    // Spills out, leaving a hole before the new data
+    assert_eq!(fs.write_object(META_OBJECTS, 10000, &[3, 4]).unwrap(), 2);
+    assert_eq!(fs.size_object(META_OBJECTS).unwrap(), 10002);
+    assert_eq!(fs.read_object(META_OBJECTS, 9998, &mut buf).unwrap(), 4);
+    assert_eq!(buf[..4], [0, 0, 3, 4]);
+    assert_eq!(fs.read_object(META_OBJECTS, 2, &mut buf).unwrap(), 6);
+    assert_eq!(buf, [0, 0, 1, 2, 0, 0]);
+    fs.commit().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_write_short_extent(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    // A fragment claiming more than its one-block extent holds
+    let mut handle = fs.write().unwrap();
+    let ptr = handle.alloc_blocks(1, None).unwrap().unwrap();
+    let obj = Object::new(&[Fragment::new(10000, 0, ptr)]);
+    let objs = handle.get_objects().unwrap().clone();
+    let objs = objs.set_object(&mut handle, 1, obj).unwrap();
+    *handle.get_objects_mut().unwrap() = objs;
+    drop(handle);
+    // Writes past the end of the extent fail, rather than slicing past it
+    assert!(fs.write_object(1, 5000, &[1]).is_err());
+}
+
+#[test_fs(mem, file, blocks = 1000)]
+#[allow(clippy::unwrap_used)]
+pub fn test_free_queue_retire(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    fs.create_object(META_OBJECTS, 200).unwrap();
+    fs.commit().unwrap();
+    let txid = fs.read().unwrap().get_txid();
+    assert_eq!(fs.write_object(META_OBJECTS, 0, &[1]).unwrap(), 1);
+    assert!(fs.read().unwrap().get_free_queue().contains_key(&txid));
+    // Frees stay queued while any retained root predates them
+    for _ in 0..128 {
+        fs.commit().unwrap();
+    }
+    assert!(fs.read().unwrap().get_free_queue().contains_key(&txid));
+    fs.commit().unwrap();
+    assert!(!fs.read().unwrap().get_free_queue().contains_key(&txid));
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_alloc_locality(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[1; 3 * BLOCK_SIZE]).unwrap();
+    let obj = fs
+        .read()
+        .unwrap()
+        .get_objects()
+        .unwrap()
+        .get_object(1)
+        .unwrap()
+        .unwrap();
+    // The blocks are allocated as a single run
+    let frags = obj.frags();
+    assert_eq!(frags.len(), 1);
+    assert_eq!(frags[0].pointer.length(), 3);
+}
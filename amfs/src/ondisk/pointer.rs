@@ -1,13 +1,18 @@
-use std::{
-    convert::{TryFrom, TryInto},
-    fmt,
-};
+use std::{convert::TryFrom, fmt};
 
 use amos_std::{error::AMError, AMResult};
 use crc32fast::Hasher;
 use endian_codec::{DecodeLE, PackedSize};
 
-use crate::{Disk, DiskGroup, GeometryFlavor, BLOCK_SIZE};
+use crate::{
+    location::{at_location, ErrorLocation},
+    metrics::Metrics,
+    Disk, DiskGroup, BLOCK_SIZE,
+};
+
+/// Longest extent a pointer can address, in blocks. Extents longer than 255 blocks need the
+/// `LongExtents` feature.
+pub const MAX_EXTENT_BLOCKS: u64 = 0xFEFF;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(C)]
@@ -62,9 +67,10 @@ impl fmt::Display for AMPointerGlobal {
 }
 
 impl AMPointerGlobal {
-    /// Creates a new pointer pointing at a given address and device. Invalid until updated
+    /// Creates a new pointer pointing at a given address and device. Invalid until updated.
+    /// Panics if the length is over `MAX_EXTENT_BLOCKS`.
     #[cfg(feature = "stable")]
-    pub fn new(addr: u64, len: u8, geo: u8, dev: u8) -> Self {
+    pub fn new(addr: u64, len: u16, geo: u8, dev: u8) -> Self {
         Self(AMPointer::new(addr, len, geo, dev))
     }
     /// Creates a null pointer. Guaranteed invalid.
@@ -72,23 +78,24 @@ impl AMPointerGlobal {
     pub fn null() -> AMPointerGlobal {
         AMPointerGlobal(AMPointer::null())
     }
-    /// Validates a pointer against a block on-disk.
+    /// Validates a pointer against the extent on-disk.
     #[cfg(feature = "unstable")]
     pub fn validate(&self, d: &[Option<DiskGroup>]) -> AMResult<bool> {
         if self.is_null() {
             return Ok(false);
         }
-        assert_eq!(self.0.len, 1);
-        let mut buf = [0; BLOCK_SIZE];
-        self.read(0, BLOCK_SIZE, d, &mut buf)?;
+        let buf = self.read_vec(d)?;
         Ok(self.0.validate(&buf))
     }
+    /// Validates a pointer against data already read from its extent.
+    #[cfg(feature = "unstable")]
+    pub fn validate_data(&self, data: &[u8]) -> bool {
+        !self.is_null() && self.0.validate(data)
+    }
     /// Updates a pointer's checksum to match on-disk data.
     #[cfg(feature = "unstable")]
     pub fn update(&mut self, d: &[Option<DiskGroup>]) -> AMResult<()> {
-        assert_eq!(self.0.len, 1);
-        let mut buf = [0; BLOCK_SIZE];
-        self.read(0, BLOCK_SIZE, d, &mut buf)?;
+        let buf = self.read_vec(d)?;
         self.0.update(&buf);
         Ok(())
     }
@@ -115,11 +122,30 @@ impl AMPointerGlobal {
         assert!(!self.is_null());
         self.0.geometry
     }
-    /// Gets the length of the pointer
+    /// Gets the length of the pointer, in blocks
     #[cfg(feature = "stable")]
-    pub fn length(&self) -> u8 {
+    pub fn length(&self) -> u16 {
         assert!(!self.is_null());
-        self.0.len
+        self.0.blocks()
+    }
+    /// Creates a pointer to a range of the extent's blocks. Invalid until updated.
+    #[cfg(feature = "stable")]
+    pub fn slice(&self, from: u64, to: u64) -> AMResult<AMPointerGlobal> {
+        assert_or_err!(
+            from <= to && to <= u64::from(self.length()),
+            AMError::TODO(0)
+        );
+        Ok(AMPointerGlobal::new(
+            self.loc() + from,
+            u16::try_from(to - from)?,
+            self.geo(),
+            self.dev(),
+        ))
+    }
+    /// Checks if the pointer's extent is too long for drivers without the `LongExtents` feature
+    #[cfg(feature = "unstable")]
+    pub fn is_long(&self) -> bool {
+        !self.is_null() && self.0.blocks() > u16::from(u8::MAX)
     }
     /// Reads from the referenced location
     #[cfg(feature = "unstable")]
@@ -130,65 +156,35 @@ impl AMPointerGlobal {
         diskgroups: &[Option<DiskGroup>],
         data: &mut [u8],
     ) -> AMResult<usize> {
-        //Single whole block writes are atomic
-        if start == 0 && size == BLOCK_SIZE {
-            match diskgroups
-                .get(self.geo() as usize)
-                .ok_or(AMError::TODO(0))?
-                .as_ref()
-                .ok_or(AMError::TODO(0))?
-                .geo
-                .flavor()
-            {
-                GeometryFlavor::Single => diskgroups[self.geo() as usize]
-                    .as_ref()
-                    .ok_or(AMError::TODO(0))?
-                    .get_disk(0)?
-                    .read_at(self.loc(), data),
-                _ => unimplemented!(), // TODO(#3): Add support for additional geometries
-            }
-        } else if start % BLOCK_SIZE == 0 && size == BLOCK_SIZE {
-            match diskgroups[self.geo() as usize]
-                .as_ref()
-                .ok_or(AMError::TODO(0))?
-                .geo
-                .flavor()
-            {
-                GeometryFlavor::Single => diskgroups[self.geo() as usize]
-                    .as_ref()
-                    .ok_or(AMError::TODO(0))?
-                    .get_disk(0)?
-                    .read_at(
-                        (usize::try_from(self.loc())? + start / BLOCK_SIZE).try_into()?,
-                        data,
-                    ),
-                _ => unimplemented!(), // TODO(#3): Add support for additional geometries
-            }
-        } else {
-            let mut buf = [0u8; BLOCK_SIZE];
-            let start_block = start / BLOCK_SIZE;
-            let start_offs = start % BLOCK_SIZE;
-            let end_block = (start + size) / BLOCK_SIZE;
-            let end_offs = (start + size) % BLOCK_SIZE;
-            self.read(start_block * BLOCK_SIZE, BLOCK_SIZE, diskgroups, &mut buf)?;
-            if start_block == end_block {
-                let mut buf = [0u8; BLOCK_SIZE];
-                self.read(start_block * BLOCK_SIZE, BLOCK_SIZE, diskgroups, &mut buf)?;
-                data.clone_from_slice(&buf[start_offs..end_offs]);
-                Ok(size)
+        assert_or_err!(
+            start + size <= usize::from(self.length()) * BLOCK_SIZE,
+            AMError::TODO(0)
+        );
+        let mut pos = 0;
+        while pos < size {
+            let block = (start + pos) / BLOCK_SIZE;
+            let offs = (start + pos) % BLOCK_SIZE;
+            let len = std::cmp::min(BLOCK_SIZE - offs, size - pos);
+            if offs == 0 && len == BLOCK_SIZE {
+                //Single whole block reads go straight into the caller's buffer
+                self.read_block(block, diskgroups, &mut data[pos..pos + BLOCK_SIZE])?;
             } else {
-                todo!();
+                let mut buf = [0u8; BLOCK_SIZE];
+                self.read_block(block, diskgroups, &mut buf)?;
+                data[pos..pos + len].copy_from_slice(&buf[offs..offs + len]);
             }
+            pos += len;
         }
+        Ok(size)
     }
     /// Reads from the referenced location
     #[cfg(feature = "stable")]
     pub fn read_vec(self, diskgroups: &[Option<DiskGroup>]) -> AMResult<Vec<u8>> {
         let mut res = Vec::new();
-        res.resize(usize::from(self.0.len) * BLOCK_SIZE, 0);
+        res.resize(usize::from(self.0.blocks()) * BLOCK_SIZE, 0);
         self.read(
             0,
-            usize::from(self.0.len) * BLOCK_SIZE,
+            usize::from(self.0.blocks()) * BLOCK_SIZE,
             diskgroups,
             res.as_mut_slice(),
         )?;
@@ -203,53 +199,67 @@ impl AMPointerGlobal {
         diskgroups: &[Option<DiskGroup>],
         data: &[u8],
     ) -> AMResult<usize> {
-        //Single whole block writes are atomic
-        if start == 0 && size == BLOCK_SIZE {
-            match diskgroups[self.geo() as usize]
-                .as_ref()
-                .ok_or(AMError::TODO(0))?
-                .geo
-                .flavor()
-            {
-                GeometryFlavor::Single => diskgroups[self.geo() as usize]
-                    .as_ref()
-                    .ok_or(AMError::TODO(0))?
-                    .get_disk(0)?
-                    .write_at(self.loc(), data),
-                _ => unimplemented!(), // TODO(#3): Add support for additional geometries
-            }
-        } else if start % BLOCK_SIZE == 0 && size == BLOCK_SIZE {
-            match diskgroups[self.geo() as usize]
-                .as_ref()
-                .ok_or(AMError::TODO(0))?
-                .geo
-                .flavor()
-            {
-                GeometryFlavor::Single => diskgroups[self.geo() as usize]
-                    .as_ref()
-                    .ok_or(AMError::TODO(0))?
-                    .get_disk(0)?
-                    .write_at(
-                        (usize::try_from(self.loc())? + start / BLOCK_SIZE).try_into()?,
-                        data,
-                    ),
-                _ => unimplemented!(), // TODO(#3): Add support for additional geometries
-            }
-        } else {
-            let mut buf = [0u8; BLOCK_SIZE];
-            let start_block = start / BLOCK_SIZE;
-            let start_offs = start % BLOCK_SIZE;
-            let end_block = (start + size) / BLOCK_SIZE;
-            let end_offs = (start + size) % BLOCK_SIZE;
-            self.read(start_block * BLOCK_SIZE, BLOCK_SIZE, diskgroups, &mut buf)?;
-            if start_block == end_block {
-                buf[start_offs..end_offs].clone_from_slice(data);
-                self.write(start_block * BLOCK_SIZE, BLOCK_SIZE, diskgroups, &buf)?;
-                Ok(size)
+        assert_or_err!(
+            start + size <= usize::from(self.length()) * BLOCK_SIZE,
+            AMError::TODO(0)
+        );
+        let mut pos = 0;
+        while pos < size {
+            let block = (start + pos) / BLOCK_SIZE;
+            let offs = (start + pos) % BLOCK_SIZE;
+            let len = std::cmp::min(BLOCK_SIZE - offs, size - pos);
+            if offs == 0 && len == BLOCK_SIZE {
+                //Single whole block writes are atomic
+                self.write_block(block, diskgroups, &data[pos..pos + BLOCK_SIZE])?;
             } else {
-                todo!();
+                let mut buf = [0u8; BLOCK_SIZE];
+                self.read_block(block, diskgroups, &mut buf)?;
+                buf[offs..offs + len].copy_from_slice(&data[pos..pos + len]);
+                self.write_block(block, diskgroups, &buf)?;
             }
+            pos += len;
         }
+        Ok(size)
+    }
+    /// Reads the nth block of the extent
+    #[cfg(feature = "unstable")]
+    fn read_block(
+        self,
+        n: usize,
+        diskgroups: &[Option<DiskGroup>],
+        data: &mut [u8],
+    ) -> AMResult<usize> {
+        let dg = diskgroups
+            .get(self.geo() as usize)
+            .ok_or(AMError::TODO(0))?
+            .as_ref()
+            .ok_or(AMError::TODO(0))?;
+        let n = u64::try_from(n)?;
+        let res = dg.get_disk_for(self)?.read_at(self.loc() + n, data);
+        if res.is_ok() {
+            Metrics::add(&dg.metrics.blocks_read, 1);
+        }
+        at_location(res, || ErrorLocation::global(self, n, diskgroups))
+    }
+    /// Writes the nth block of the extent
+    #[cfg(feature = "unstable")]
+    fn write_block(
+        self,
+        n: usize,
+        diskgroups: &[Option<DiskGroup>],
+        data: &[u8],
+    ) -> AMResult<usize> {
+        let dg = diskgroups
+            .get(self.geo() as usize)
+            .ok_or(AMError::TODO(0))?
+            .as_ref()
+            .ok_or(AMError::TODO(0))?;
+        let n = u64::try_from(n)?;
+        let res = dg.get_disk_for(self)?.write_at(self.loc() + n, data);
+        if res.is_ok() {
+            Metrics::add(&dg.metrics.blocks_written, 1);
+        }
+        at_location(res, || ErrorLocation::global(self, n, diskgroups))
     }
     /// Creates a pointer from an array of bytes
     #[cfg(feature = "stable")]
@@ -320,6 +330,9 @@ impl AMPointerLocal {
     }
 }
 
+/// The on-disk pointer. `padding` is zero for null pointers and 0xFF for extents of up to
+/// 255 blocks, whose length is `len`. Longer extents keep the high byte of their length in
+/// `padding` instead.
 #[derive(Copy, Clone, Debug, PartialEq, PackedSize, DecodeLE)]
 #[repr(C)]
 pub(crate) struct AMPointer {
@@ -334,11 +347,11 @@ pub(crate) struct AMPointer {
 impl std::cmp::Ord for AMPointer {
     #[cfg(feature = "stable")]
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        (self.location, self.device, self.geometry, self.len).cmp(&(
+        (self.location, self.device, self.geometry, self.blocks()).cmp(&(
             other.location,
             other.device,
             other.geometry,
-            other.len,
+            other.blocks(),
         ))
     }
 }
@@ -346,12 +359,14 @@ impl std::cmp::Ord for AMPointer {
 impl std::cmp::PartialOrd for AMPointer {
     #[cfg(feature = "stable")]
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some((self.location, self.device, self.geometry, self.len).cmp(&(
-            other.location,
-            other.device,
-            other.geometry,
-            other.len,
-        )))
+        Some(
+            (self.location, self.device, self.geometry, self.blocks()).cmp(&(
+                other.location,
+                other.device,
+                other.geometry,
+                other.blocks(),
+            )),
+        )
     }
 }
 
@@ -359,16 +374,25 @@ impl std::cmp::Eq for AMPointer {}
 
 impl AMPointer {
     #[cfg(feature = "stable")]
-    pub fn new(addr: u64, len: u8, geo: u8, dev: u8) -> AMPointer {
+    pub fn new(addr: u64, len: u16, geo: u8, dev: u8) -> AMPointer {
+        assert!(u64::from(len) <= MAX_EXTENT_BLOCKS);
+        let [low, high] = len.to_le_bytes();
         AMPointer {
             location: addr,
-            device: dev,
+            device:   dev,
             geometry: geo,
-            len,
-            padding: 0xFF,
+            len:      low,
+            padding:  if high == 0 { 0xFF } else { high },
             checksum: 0,
         }
     }
+    #[cfg(feature = "stable")]
+    pub fn blocks(&self) -> u16 {
+        match self.padding {
+            0xFF => u16::from(self.len),
+            high => u16::from_le_bytes([self.len, high]),
+        }
+    }
     #[cfg(feature = "unstable")]
     pub fn null() -> AMPointer {
         AMPointer {
@@ -386,7 +410,7 @@ impl AMPointer {
     }
     #[cfg(feature = "stable")]
     pub fn validate(&self, target: &[u8]) -> bool {
-        if !crate::CHECKSUMS_ENABLED.load(std::sync::atomic::Ordering::Relaxed) {
+        if !crate::checksums_enabled() {
             return true;
         }
         let mut hasher = Hasher::new();
@@ -425,6 +449,28 @@ fn size_test() {
     assert_eq!(mem::size_of::<AMPointer>(), 16);
 }
 
+#[test]
+fn test_long_len() {
+    for len in [0, 1, 255, 256, 0x1234, 0xFEFF] {
+        let p = AMPointerGlobal::new(10, len, 0, 0);
+        assert!(!p.is_null());
+        assert_eq!(p.length(), len);
+        assert_eq!(p.is_long(), len > 255);
+        assert_eq!(AMPointerGlobal::from_bytes(p.as_bytes()), p);
+    }
+    assert!(AMPointerGlobal::new(10, 300, 0, 0) > AMPointerGlobal::new(10, 299, 0, 0));
+}
+
+#[test]
+fn test_slice() {
+    let p = AMPointerGlobal::new(10, 4, 0, 0);
+    let head = p.slice(0, 1).ok().map(|s| (s.loc(), s.length()));
+    let tail = p.slice(1, 4).ok().map(|s| (s.loc(), s.length()));
+    assert_eq!((head, tail), (Some((10, 1)), Some((11, 3))));
+    assert!(p.slice(2, 5).is_err());
+    assert!(p.slice(3, 2).is_err());
+}
+
 #[test]
 fn test_checksum() {
     let mut p = AMPointer::null();
@@ -433,3 +479,69 @@ fn test_checksum() {
     p.update(&data);
     assert!(p.validate(&data));
 }
+
+#[test]
+fn rw_test_multiblock() {
+    #![allow(clippy::unwrap_used)]
+
+    let mut dg = crate::test::dg::create_dg_mem_single(100);
+    let mut ptr = dg.alloc_blocks(3, None).unwrap();
+    assert_eq!(ptr.length(), 3);
+
+    let data: Vec<u8> = (0..3 * BLOCK_SIZE)
+        .map(|i| u8::try_from(i % 251).unwrap())
+        .collect();
+    let dgs = vec![Some(dg)];
+    ptr.write(0, data.len(), &dgs, &data).unwrap();
+    ptr.update(&dgs).unwrap();
+    assert!(ptr.validate(&dgs).unwrap());
+    assert_eq!(ptr.read_vec(&dgs).unwrap(), data);
+
+    // Unaligned range spanning all three blocks
+    let start = BLOCK_SIZE / 2;
+    let len = 2 * BLOCK_SIZE;
+    let mut buf = vec![0u8; len];
+    ptr.read(start, len, &dgs, &mut buf).unwrap();
+    assert_eq!(buf, data[start..start + len]);
+
+    let patch = vec![0xAAu8; len];
+    ptr.write(start, len, &dgs, &patch).unwrap();
+    assert!(!ptr.validate(&dgs).unwrap());
+    let mut expected = data;
+    expected[start..start + len].copy_from_slice(&patch);
+    assert_eq!(ptr.read_vec(&dgs).unwrap(), expected);
+}
+
+#[test_fs(mem, file, blocks = 1000)]
+fn rw_test_long_extent(d: crate::Disk) {
+    #![allow(clippy::unwrap_used)]
+
+    use crate::AMFeatures;
+
+    let mut dg = crate::test::dg::create_dg_mem_single(1000);
+    let mut ptr = dg.alloc_blocks(300, None).unwrap();
+    assert_eq!(ptr.length(), 300);
+    assert!(ptr.is_long());
+    assert!(dg.alloc_blocks(MAX_EXTENT_BLOCKS + 1, None).is_err());
+
+    let data: Vec<u8> = (0..300 * BLOCK_SIZE)
+        .map(|i| u8::try_from(i % 251).unwrap())
+        .collect();
+    let dgs = vec![Some(dg)];
+    ptr.write(0, data.len(), &dgs, &data).unwrap();
+    ptr.update(&dgs).unwrap();
+    assert!(ptr.validate(&dgs).unwrap());
+    assert_eq!(ptr.read_vec(&dgs).unwrap(), data);
+
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    let bit = AMFeatures::LongExtents as usize;
+    assert!(!fs.read().unwrap().get_superblock().unwrap().features()[bit]);
+    let ptr = fs
+        .write()
+        .unwrap()
+        .alloc_blocks(300, None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(ptr.length(), 300);
+    assert!(fs.read().unwrap().get_superblock().unwrap().features()[bit]);
+}
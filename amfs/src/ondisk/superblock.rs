@@ -1,18 +1,24 @@
 use std::{
     collections::BTreeSet,
+    convert::TryFrom,
     mem,
     ops::{Deref, DerefMut},
     slice,
+    time::Duration,
 };
 
-use amos_std::{error::AMErrorFS, AMResult};
+use amos_std::{
+    error::{AMError, AMErrorFS},
+    AMResult,
+};
 use bitvec::prelude::*;
 use crc32fast::Hasher;
 use type_layout::TypeLayout;
 
 use crate::{
-    AMFeatures, AMPointerGlobal, AMPointerLocal, Disk, DiskGroup, FSGroup, Geometry, BLOCK_SIZE,
-    SIGNATURE,
+    location::{at_location, ErrorLocation},
+    AMFeatures, AMPointerGlobal, AMPointerLocal, Disk, DiskGroup, FSGroup, FeatureClass, Geometry,
+    Retention, BLOCK_SIZE, BLOCK_SIZES, FORMAT_VERSION, SIGNATURE,
 };
 
 #[repr(C)]
@@ -24,7 +30,19 @@ pub struct Superblock {
     features:               BitArr!(for 2048),
     pub(crate) geometries:  [AMPointerLocal; 16],
     checksum:               u32,
-    _padding:               [u8; BLOCK_SIZE - 2581],
+    uuid:                   [u8; 16],
+    label:                  [u8; 64],
+    created:                [u8; 8],
+    last_mount:             [u8; 8],
+    mount_count:            [u8; 8],
+    version:                [u8; 8],
+    root_slots:             u8,
+    block_size:             [u8; 4],
+    retain_txids:           [u8; 8],
+    retain_secs:            [u8; 8],
+    generation:             [u8; 8],
+    _padding:               [u8; BLOCK_SIZE - 2738],
+    pinned:                 [u8; 16],
     pub(crate) latest_root: u8,
     pub(crate) rootnodes:   [AMPointerGlobal; 128],
 }
@@ -40,7 +58,19 @@ impl Superblock {
             geometries: [AMPointerLocal::null(); 16],
             latest_root: 0,
             checksum: 0,
-            _padding: [0; BLOCK_SIZE - 2581],
+            uuid: [0; 16],
+            label: [0; 64],
+            created: [0; 8],
+            last_mount: [0; 8],
+            mount_count: [0; 8],
+            version: FORMAT_VERSION.to_le_bytes(),
+            root_slots: 128,
+            block_size: [0; 4],
+            retain_txids: [0; 8],
+            retain_secs: [0; 8],
+            generation: [0; 8],
+            _padding: [0; BLOCK_SIZE - 2738],
+            pinned: [0; 16],
             rootnodes: [AMPointerGlobal::null(); 128],
         }
     }
@@ -48,9 +78,15 @@ impl Superblock {
     #[cfg(feature = "stable")]
     pub fn read(mut d: Disk, ptr: AMPointerLocal) -> AMResult<Superblock> {
         let mut res: Superblock = Superblock::new(0);
-        d.read_at(ptr.loc(), &mut res)?;
+        at_location(d.read_at(ptr.loc(), &mut res), || {
+            ErrorLocation::local(None, ptr.loc())
+        })?;
         assert_or_err!(&res.signature == SIGNATURE, AMErrorFS::Signature);
-        assert_or_err!(res.verify_checksum(), AMErrorFS::Checksum);
+        if !res.verify_checksum() {
+            return at_location(Err(AMErrorFS::Checksum.into()), || {
+                ErrorLocation::local(None, ptr.loc())
+            });
+        }
         assert_or_err!(res.devid != 0, AMErrorFS::DiskID);
         Ok(res)
     }
@@ -111,6 +147,58 @@ impl Superblock {
     pub fn checksum(&self) -> u32 {
         self.checksum
     }
+    /// Getter for the on-disk format version
+    #[cfg(feature = "unstable")]
+    pub fn version(&self) -> u64 {
+        u64::from_le_bytes(self.version)
+    }
+    /// Setter for the on-disk format version
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_version(&mut self, version: u64) {
+        self.version = version.to_le_bytes();
+    }
+    /// Getter for the volume UUID
+    #[cfg(feature = "unstable")]
+    pub fn uuid(&self) -> u128 {
+        u128::from_le_bytes(self.uuid)
+    }
+    /// Getter for the volume label, without its trailing padding
+    #[cfg(feature = "unstable")]
+    pub fn label(&self) -> String {
+        let len = self.label.iter().position(|b| *b == 0).unwrap_or(64);
+        String::from_utf8_lossy(&self.label[..len]).into_owned()
+    }
+    /// Getter for the creation time, as seconds since the Unix epoch
+    #[cfg(feature = "unstable")]
+    pub fn created(&self) -> u64 {
+        u64::from_le_bytes(self.created)
+    }
+    /// Getter for the last mount time, as seconds since the Unix epoch
+    #[cfg(feature = "unstable")]
+    pub fn last_mount(&self) -> u64 {
+        u64::from_le_bytes(self.last_mount)
+    }
+    /// Getter for the number of times the volume has been mounted
+    #[cfg(feature = "unstable")]
+    pub fn mount_count(&self) -> u64 {
+        u64::from_le_bytes(self.mount_count)
+    }
+    /// Sets the volume UUID, label and creation time. Fails if the label is over 64 bytes.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_identity(&mut self, uuid: u128, label: &str, created: u64) -> AMResult<()> {
+        assert_or_err!(label.len() <= 64, AMError::TODO(0));
+        self.uuid = uuid.to_le_bytes();
+        self.label = [0; 64];
+        self.label[..label.len()].copy_from_slice(label.as_bytes());
+        self.created = created.to_le_bytes();
+        Ok(())
+    }
+    /// Records a mount at a given time
+    #[cfg(feature = "unstable")]
+    pub(crate) fn record_mount(&mut self, time: u64) {
+        self.last_mount = time.to_le_bytes();
+        self.mount_count = (self.mount_count() + 1).to_le_bytes();
+    }
     /// Getter for pointer to nth geometry
     #[cfg(feature = "unstable")]
     pub fn geometries(&self, i: usize) -> AMPointerLocal {
@@ -126,6 +214,92 @@ impl Superblock {
     pub fn rootnodes(&self, i: usize) -> AMPointerGlobal {
         self.rootnodes[i]
     }
+    /// Checks whether a root node slot is pinned by a snapshot
+    #[cfg(feature = "unstable")]
+    pub fn is_pinned(&self, i: usize) -> bool {
+        u128::from_le_bytes(self.pinned) & (1 << i) != 0
+    }
+    /// Pins or unpins a root node slot
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_pinned(&mut self, i: usize, pinned: bool) {
+        let mut mask = u128::from_le_bytes(self.pinned);
+        if pinned {
+            mask |= 1 << i;
+        } else {
+            mask &= !(1 << i);
+        }
+        self.pinned = mask.to_le_bytes();
+    }
+    /// Getter for the number of root node slots in use. Disks from before it was recorded use all 128.
+    #[cfg(feature = "unstable")]
+    pub fn root_slots(&self) -> u8 {
+        if self.root_slots == 0 {
+            128
+        } else {
+            self.root_slots
+        }
+    }
+    /// Sets the number of root node slots in use. Fails unless between 2 and 128.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_root_slots(&mut self, n: u8) -> AMResult<()> {
+        assert_or_err!((2..=128).contains(&n), AMError::TODO(0));
+        self.root_slots = n;
+        Ok(())
+    }
+    /// Getter for the volume's block size. Disks from before it was recorded use 4K blocks.
+    #[cfg(feature = "unstable")]
+    pub fn block_size(&self) -> usize {
+        match u32::from_le_bytes(self.block_size) {
+            0 => BLOCK_SIZE,
+            size => size as usize,
+        }
+    }
+    /// Sets the volume's block size. Fails unless it's one of `BLOCK_SIZES`.
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_block_size(&mut self, size: usize) -> AMResult<()> {
+        assert_or_err!(BLOCK_SIZES.contains(&size), AMError::TODO(0));
+        self.block_size = u32::try_from(size)?.to_le_bytes();
+        Ok(())
+    }
+    /// Getter for how long old roots are retained. Disks from before it was recorded keep them
+    /// for as long as there are slots.
+    #[cfg(feature = "unstable")]
+    pub fn retention(&self) -> Retention {
+        let txids = u64::from_le_bytes(self.retain_txids);
+        let secs = u64::from_le_bytes(self.retain_secs);
+        Retention {
+            transactions: (txids != 0).then_some(txids),
+            time:         (secs != 0).then(|| Duration::from_secs(secs)),
+        }
+    }
+    /// Sets how long old roots are retained
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_retention(&mut self, policy: Retention) -> AMResult<()> {
+        policy.check()?;
+        self.retain_txids = policy.transactions.unwrap_or(0).to_le_bytes();
+        self.retain_secs = policy.time.map_or(0, |t| t.as_secs()).to_le_bytes();
+        Ok(())
+    }
+    /// Getter for the generation, counting the updates written to the volume's superblocks.
+    /// Every copy written by an update gets the same generation, so older copies stand out.
+    #[cfg(feature = "unstable")]
+    pub fn generation(&self) -> u64 {
+        u64::from_le_bytes(self.generation)
+    }
+    /// Sets the generation
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_generation(&mut self, generation: u64) {
+        self.generation = generation.to_le_bytes();
+    }
+    /// Gets the slot the next root node will be written to, skipping pinned slots.
+    /// The latest root is never overwritten, so this fails once every other slot is pinned.
+    #[cfg(feature = "unstable")]
+    pub fn next_root(&self) -> Option<u8> {
+        let n = self.root_slots();
+        (1..n)
+            .map(|i| (self.latest_root + i) % n)
+            .find(|i| !self.is_pinned(usize::from(*i)))
+    }
     /// Fetches the geometry object for the nth geometry spec.
     #[cfg(feature = "unstable")]
     pub fn get_geometry(&self, d: Disk, n: u8) -> AMResult<Geometry> {
@@ -142,6 +316,20 @@ impl Superblock {
         }
         true
     }
+    /// Gets the most restrictive class of the set features missing from a set of known ones.
+    /// None if every set feature is known.
+    #[cfg(feature = "stable")]
+    pub fn unknown_features(&self, known: &BTreeSet<usize>) -> Option<FeatureClass> {
+        (0..2048)
+            .filter(|i| { self.features }[*i] && !known.contains(i))
+            .map(FeatureClass::of)
+            .max()
+    }
+    /// Sets or clears a feature bit
+    #[cfg(feature = "unstable")]
+    pub(crate) fn set_feature(&mut self, bit: usize, on: bool) {
+        self.features.set(bit, on);
+    }
     /// Gets the latest valid root group
     #[cfg(feature = "stable")]
     pub fn get_group(&self, d: &[Option<DiskGroup>]) -> AMResult<FSGroup> {
@@ -204,9 +392,111 @@ fn feature_test() {
 }
 
 #[test]
+fn feature_class_test() {
+    let mut sb = Superblock::new(0);
+    let known = AMFeatures::current_set();
+    assert_eq!(sb.unknown_features(&known), None);
+    sb.set_feature(crate::COMPAT_START, true);
+    assert_eq!(sb.unknown_features(&known), Some(FeatureClass::Compat));
+    sb.set_feature(crate::RO_COMPAT_START, true);
+    assert_eq!(
+        sb.unknown_features(&known),
+        Some(FeatureClass::ReadOnlyCompat)
+    );
+    sb.set_feature(AMFeatures::Never as usize, true);
+    assert_eq!(sb.unknown_features(&known), Some(FeatureClass::Incompat));
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_superblock(d: crate::Disk) {
+    let _fs = crate::test::fsinit::mkfs_on(d).unwrap();
+}
+
+#[test_fs(mem, file, blocks = 1000)]
 #[allow(clippy::unwrap_used)]
-pub fn test_superblock() {
-    crate::test::logging::init_log();
+pub fn test_snapshot(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    let snap = fs.snapshot_create().unwrap();
+    assert_eq!(fs.snapshot_list().unwrap(), vec![snap]);
+    // Enough commits to go all the way round the root slots
+    for _ in 0..130 {
+        fs.commit().unwrap();
+    }
+    assert_eq!(fs.snapshot_list().unwrap(), vec![snap]);
+    fs.snapshot_delete(snap).unwrap();
+    assert!(fs.snapshot_list().unwrap().is_empty());
+    assert!(fs.snapshot_delete(snap).is_err());
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_open_at_root(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+
+    fs.create_object(1, 1).unwrap();
+    fs.write_object(1, 0, &[1]).unwrap();
+    let snap = fs.snapshot_create().unwrap();
+    fs.write_object(1, 0, &[2]).unwrap();
+    fs.commit().unwrap();
+
+    let old = crate::FSHandle::open_at_root(&[d.clone()], snap).unwrap();
+    let mut buf = [0u8; 1];
+    assert_eq!(old.read_object(1, 0, &mut buf).unwrap(), 1);
+    assert_eq!(buf, [1]);
+    assert!(old.write_object(1, 0, &[3]).is_err());
+    assert!(old.commit().is_err());
+    assert_eq!(fs.read_object(1, 0, &mut buf).unwrap(), 1);
+    assert_eq!(buf, [2]);
+    assert!(crate::FSHandle::open_at_root(&[d], 1000).is_err());
+}
+
+#[test_fs(mem, file, blocks = 1000)]
+#[allow(clippy::unwrap_used)]
+pub fn test_root_rotation(d: crate::Disk, d2: crate::Disk) {
+    // Without freeing superseded roots, this many commits would fill the disk
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    for _ in 0..400 {
+        fs.commit().unwrap();
+    }
+
+    // Once every other slot is pinned, the latest root can't be overwritten
+    let fs = crate::test::fsinit::mkfs_on(d2).unwrap();
+    for _ in 0..127 {
+        fs.snapshot_create().unwrap();
+    }
+    fs.commit().unwrap();
+    assert!(fs.commit().is_err());
+    fs.snapshot_delete(fs.snapshot_list().unwrap()[0]).unwrap();
+    fs.commit().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_block_size(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    assert_eq!(
+        fs.read().unwrap().get_superblock().unwrap().block_size(),
+        BLOCK_SIZE
+    );
+    drop(fs);
+    let set_block_size = |size: Option<usize>| {
+        for loc in d.get_header_locs().unwrap() {
+            let mut sb = Superblock::read(d.clone(), loc).unwrap();
+            match size {
+                Some(size) => sb.set_block_size(size).unwrap(),
+                None => sb.block_size = [0; 4],
+            }
+            sb.write(d.clone(), loc).unwrap();
+        }
+    };
 
-    let _fs = crate::test::fsinit::create_fs();
+    // Other sizes are recorded by the format, but can't be mounted
+    set_block_size(Some(8192));
+    assert!(crate::FSHandle::open(&[d.clone()]).is_err());
+    // Disks from before the size was recorded still mount
+    set_block_size(None);
+    crate::FSHandle::open(&[d]).unwrap();
+    assert!(Superblock::new(0).set_block_size(1000).is_err());
 }
@@ -0,0 +1,222 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+};
+
+use amos_std::{error::AMError, AMResult};
+
+use crate::{Allocator, FSHandle, HEAD_DEVICES};
+
+/// What a balance pass moves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BalanceOptions {
+    /// Only rewrite the object tree, leaving object data where it is
+    pub metadata_only: bool,
+    /// Move every extent off the device with this ID, rather than only evening out usage
+    pub device:        Option<u64>,
+    /// How many percentage points fuller than its geometry as a whole a device may be
+    /// before data is moved off it
+    pub threshold:     u8,
+    /// Number of objects balanced between commits
+    pub batch:         usize,
+    /// The progress record of an interrupted pass, to carry on from where it stopped
+    pub resume:        Option<BalanceProgress>,
+}
+
+impl Default for BalanceOptions {
+    #[cfg(feature = "unstable")]
+    fn default() -> Self {
+        BalanceOptions {
+            metadata_only: false,
+            device:        None,
+            threshold:     10,
+            batch:         64,
+            resume:        None,
+        }
+    }
+}
+
+/// How far a balance pass has got. Everything before `next` is committed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BalanceProgress {
+    /// The first object not yet balanced, or `None` once every object has been
+    pub next:    Option<u64>,
+    /// Number of objects balanced
+    pub objects: u64,
+    /// Number of extents moved
+    pub moved:   u64,
+}
+
+/// Moves extents off devices much fuller than the rest of their geometry onto the emptiest
+/// others, copy-on-write, committing after each batch of objects.
+/// Object tree nodes are rewritten along the way, and land on the emptiest devices too.
+/// `progress` is called after each commit, with a record that can be passed back to resume.
+#[cfg(feature = "unstable")]
+pub fn balance<F: FnMut(&BalanceProgress)>(
+    fs: &FSHandle,
+    opts: &BalanceOptions,
+    mut progress: F,
+) -> AMResult<BalanceProgress> {
+    assert_or_err!(opts.batch != 0, AMError::TODO(0));
+    let mut state = opts.resume.unwrap_or_default();
+    let start = match opts.resume {
+        None => 0,
+        Some(BalanceProgress { next: Some(id), .. }) => id,
+        // The interrupted pass had got through every object
+        Some(_) => return Ok(state),
+    };
+    let ids: Vec<u64> = fs
+        .list_objects()?
+        .map(|(id, _, _)| id)
+        .filter(|id| *id >= start)
+        .collect();
+    let mut quotas = if opts.metadata_only {
+        BTreeMap::new()
+    } else {
+        quotas(fs, opts)?
+    };
+    for (i, id) in ids.iter().enumerate() {
+        let sources: BTreeSet<(u8, u8)> = quotas
+            .iter()
+            .filter(|(_, q)| **q != 0)
+            .map(|(k, _)| *k)
+            .collect();
+        for ptr in fs.rebalance_object(*id, &sources)? {
+            if let Some(q) = quotas.get_mut(&(ptr.geo(), ptr.dev())) {
+                *q = q.saturating_sub(u64::from(ptr.length()));
+            }
+            state.moved += 1;
+        }
+        state.objects += 1;
+        if (i + 1) % opts.batch == 0 || i + 1 == ids.len() {
+            fs.commit()?;
+            state.next = ids.get(i + 1).copied();
+            progress(&state);
+        }
+    }
+    state.next = None;
+    Ok(state)
+}
+
+/// Finds how many blocks to move off each (geometry, device) pair
+#[cfg(feature = "unstable")]
+fn quotas(fs: &FSHandle, opts: &BalanceOptions) -> AMResult<BTreeMap<(u8, u8), u64>> {
+    let handle = fs.read()?;
+    let mut res = BTreeMap::new();
+    for (g, dg) in handle.get_diskgroups().iter().enumerate() {
+        // Data only moves between devices of the same geometry
+        let dg = match dg {
+            Some(dg) if dg.allocs.len() > 1 => dg,
+            _ => continue,
+        };
+        let used = u128::from(dg.allocs.iter().map(Allocator::used_space).sum::<u64>());
+        let total = u128::from(dg.allocs.iter().map(Allocator::total_space).sum::<u64>());
+        for (d, a) in dg.allocs.iter().enumerate().take(HEAD_DEVICES) {
+            let quota = match opts.device {
+                Some(id) if dg.geo.device_ids.get(d) == Some(&id) => u64::MAX,
+                Some(_) => 0,
+                None => {
+                    // The most the device may use, at the threshold above the geometry's share
+                    let limit = (used * 100 + u128::from(opts.threshold) * total)
+                        * u128::from(a.total_space())
+                        / (total * 100);
+                    a.used_space()
+                        .saturating_sub(u64::try_from(limit).unwrap_or(u64::MAX))
+                }
+            };
+            if quota != 0 {
+                res.insert((u8::try_from(g)?, u8::try_from(d)?), quota);
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_balance() {
+    use crate::{operations::mkfs_multi, GeometryFlavor, BLOCK_SIZE};
+
+    crate::test::logging::init_log();
+
+    let ds = [crate::DiskMem::open(400), crate::DiskMem::open(1000)];
+    mkfs_multi(&ds, GeometryFlavor::Striped).unwrap();
+    let fs = FSHandle::open(&ds).unwrap();
+    // New extents go to the device with the most free blocks, so the larger one fills faster
+    for id in 100..130 {
+        fs.create_object(id, 0).unwrap();
+        fs.write_object(id, 0, &[u8::try_from(id).unwrap(); 8 * BLOCK_SIZE])
+            .unwrap();
+    }
+    fs.commit().unwrap();
+    let data_on = |fs: &FSHandle, dev: u8, ids: std::ops::Range<u64>| -> u64 {
+        let handle = fs.read().unwrap();
+        let objs = handle.get_objects().unwrap().get_objects().unwrap();
+        objs.range(ids)
+            .flat_map(|(_, o)| o.frags())
+            .filter(|f| !f.is_hole() && f.pointer.dev() == dev)
+            .map(|f| u64::from(f.pointer.length()))
+            .sum()
+    };
+    let check = |fs: &FSHandle| {
+        for id in 100..130 {
+            let mut buf = [0; 8 * BLOCK_SIZE];
+            fs.read_object(id, 0, &mut buf).unwrap();
+            assert_eq!(buf, [u8::try_from(id).unwrap(); 8 * BLOCK_SIZE]);
+        }
+    };
+    let before = data_on(&fs, 1, 100..130);
+
+    let opts = BalanceOptions {
+        threshold: 5,
+        ..BalanceOptions::default()
+    };
+    let res = balance(&fs, &opts, |_| {}).unwrap();
+    assert!(res.moved > 0);
+    assert_eq!(res.next, None);
+    let after = data_on(&fs, 1, 100..130);
+    assert!(after < before && after > 0);
+    check(&fs);
+
+    // Metadata-only passes leave data where it is
+    let opts = BalanceOptions {
+        metadata_only: true,
+        threshold: 0,
+        ..BalanceOptions::default()
+    };
+    assert_eq!(balance(&fs, &opts, |_| {}).unwrap().moved, 0);
+    assert_eq!(data_on(&fs, 1, 100..130), after);
+
+    // Draining a device, picking up part way through
+    let devid = fs.read().unwrap().get_diskgroups()[0]
+        .as_ref()
+        .unwrap()
+        .geo
+        .device_ids[1];
+    let mut opts = BalanceOptions {
+        device: Some(devid),
+        batch: 4,
+        resume: Some(BalanceProgress {
+            next: Some(115),
+            ..BalanceProgress::default()
+        }),
+        ..BalanceOptions::default()
+    };
+    let res = balance(&fs, &opts, |_| {}).unwrap();
+    assert_eq!(res.objects, 15);
+    assert_eq!(data_on(&fs, 1, 115..130), 0);
+    assert!(data_on(&fs, 1, 100..115) > 0);
+
+    opts.resume = None;
+    let mut records = Vec::new();
+    let res = balance(&fs, &opts, |p| records.push(*p)).unwrap();
+    assert!(records
+        .iter()
+        .all(|p| p.next.is_none() || p.objects % 4 == 0));
+    assert_eq!(records.last().unwrap().next, None);
+    assert_eq!(data_on(&fs, 1, 100..130), 0);
+    check(&fs);
+    // A finished pass has nothing left to resume
+    opts.resume = Some(res);
+    assert_eq!(balance(&fs, &opts, |_| {}).unwrap(), res);
+}
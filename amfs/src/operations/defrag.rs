@@ -0,0 +1,131 @@
+use amos_std::AMResult;
+
+use crate::FSHandle;
+
+/// The objects a defragmentation pass works on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefragTarget {
+    /// A single object
+    Object(u64),
+    /// Every object in the filesystem
+    All,
+}
+
+/// Moves the data of fragmented objects into consecutive blocks, then commits.
+/// The old blocks are freed copy-on-write, and coalesce with their neighbours once no retained root uses them.
+/// `progress` is called after each object with the number done so far and the total.
+/// Returns the number of objects moved.
+#[cfg(feature = "unstable")]
+pub fn defrag<F: FnMut(usize, usize)>(
+    fs: &FSHandle,
+    target: DefragTarget,
+    mut progress: F,
+) -> AMResult<usize> {
+    let ids: Vec<u64> = match target {
+        DefragTarget::Object(id) => vec![id],
+        DefragTarget::All => fs.list_objects()?.map(|(id, _, _)| id).collect(),
+    };
+    let mut moved = 0;
+    for (i, id) in ids.iter().enumerate() {
+        if fs.defrag_object(*id)? {
+            moved += 1;
+        }
+        progress(i + 1, ids.len());
+    }
+    fs.commit()?;
+    Ok(moved)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+fn is_contiguous(fs: &FSHandle, id: u64) -> bool {
+    let frags = fs
+        .read()
+        .unwrap()
+        .get_objects()
+        .unwrap()
+        .get_object(id)
+        .unwrap()
+        .unwrap()
+        .frags();
+    let ptrs: Vec<_> = frags
+        .iter()
+        .filter(|f| !f.is_hole())
+        .map(|f| f.pointer)
+        .collect();
+    ptrs.windows(2)
+        .all(|w| w[1].loc() == w[0].loc() + u64::from(w[0].length()))
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_defrag(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    // Interleaved writes leave both objects' blocks interleaved too
+    fs.create_object(1, 0).unwrap();
+    fs.create_object(2, 0).unwrap();
+    for i in 0..8 {
+        fs.write_object(1, i * 4096, &[1; 4096]).unwrap();
+        fs.write_object(2, i * 4096, &[2; 4096]).unwrap();
+    }
+    assert!(!is_contiguous(&fs, 1));
+    assert!(!is_contiguous(&fs, 2));
+
+    assert_eq!(defrag(&fs, DefragTarget::Object(1), |_, _| {}).unwrap(), 1);
+    assert!(is_contiguous(&fs, 1));
+    assert!(!is_contiguous(&fs, 2));
+    assert_eq!(defrag(&fs, DefragTarget::Object(1), |_, _| {}).unwrap(), 0);
+
+    let mut calls = Vec::new();
+    assert_eq!(
+        defrag(&fs, DefragTarget::All, |done, total| calls
+            .push((done, total)))
+        .unwrap(),
+        1
+    );
+    let total = fs.list_objects().unwrap().count();
+    assert_eq!(calls.len(), total);
+    assert_eq!(calls.last(), Some(&(total, total)));
+    assert!(is_contiguous(&fs, 2));
+
+    for (id, v) in [(1, 1), (2, 2)] {
+        let mut buf = vec![0; 8 * 4096];
+        fs.read_object(id, 0, &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == v));
+    }
+}
+
+#[test_fs(mem, file, blocks = 4000)]
+#[allow(clippy::unwrap_used)]
+pub fn test_defrag_long(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    fs.set_retention(crate::Retention {
+        transactions: Some(1),
+        time:         None,
+    })
+    .unwrap();
+
+    // A free run too short for the whole object, but longer than 255 blocks, ahead of the rest
+    fs.create_object(3, 0).unwrap();
+    fs.write_object(3, 0, &vec![3; 280 * 4096]).unwrap();
+    fs.create_object(4, 0).unwrap();
+    fs.write_object(4, 0, &[4; 4096]).unwrap();
+    fs.create_object(1, 0).unwrap();
+    fs.create_object(2, 0).unwrap();
+    for i in 0..300 {
+        fs.write_object(1, i * 4096, &[1; 4096]).unwrap();
+        fs.write_object(2, i * 4096, &[2; 4096]).unwrap();
+    }
+    fs.delete_object(3).unwrap();
+    for _ in 0..4 {
+        fs.commit().unwrap();
+    }
+    assert!(!is_contiguous(&fs, 1));
+
+    assert_eq!(defrag(&fs, DefragTarget::Object(1), |_, _| {}).unwrap(), 1);
+    assert!(is_contiguous(&fs, 1));
+    let mut buf = vec![0; 300 * 4096];
+    fs.read_object(1, 0, &mut buf).unwrap();
+    assert!(buf.iter().all(|b| *b == 1));
+}
@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+
+use amos_std::AMResult;
+
+use crate::{FSHandle, Object};
+
+/// A difference in a single object between two roots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ObjectChange {
+    /// The object only exists in the newer root
+    Created(u64),
+    /// The object only exists in the older root
+    Deleted(u64),
+    /// The object's contents differ, in the given byte ranges
+    Modified(u64, Vec<(u64, u64)>),
+}
+
+/// Compares the object sets of two roots, identified by transaction ID
+#[cfg(feature = "unstable")]
+pub fn diff(fs: &FSHandle, root_a: u128, root_b: u128) -> AMResult<Vec<ObjectChange>> {
+    let handle = fs.read()?;
+    let objs_a = handle.get_objects_at(root_a)?.get_objects()?;
+    let objs_b = handle.get_objects_at(root_b)?.get_objects()?;
+    diff_objects(objs_a, objs_b)
+}
+
+/// Compares two sets of objects, ordering the changes by object ID
+#[cfg(feature = "unstable")]
+pub(crate) fn diff_objects(
+    mut objs_a: BTreeMap<u64, Object>,
+    objs_b: BTreeMap<u64, Object>,
+) -> AMResult<Vec<ObjectChange>> {
+    let mut res = Vec::new();
+    for (id, b) in objs_b {
+        if let Some(a) = objs_a.remove(&id) {
+            let ranges = a.changed_ranges(&b)?;
+            if !ranges.is_empty() {
+                res.push(ObjectChange::Modified(id, ranges));
+            }
+        } else {
+            res.push(ObjectChange::Created(id));
+        }
+    }
+    res.extend(objs_a.into_keys().map(ObjectChange::Deleted));
+    res.sort_by_key(|c| match c {
+        ObjectChange::Created(id) | ObjectChange::Deleted(id) | ObjectChange::Modified(id, _) => {
+            *id
+        }
+    });
+    Ok(res)
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_diff(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+
+    fs.create_object(1, 4).unwrap();
+    fs.create_object(2, 200).unwrap();
+    fs.create_object(3, 4).unwrap();
+    let a = fs.snapshot_create().unwrap();
+    fs.write_object(2, 0, &[1]).unwrap();
+    fs.write_object(3, 0, &[1]).unwrap();
+    fs.create_object(4, 4).unwrap();
+    let b = fs.snapshot_create().unwrap();
+
+    assert!(diff(&fs, a, a).unwrap().is_empty());
+    assert_eq!(
+        diff(&fs, a, b).unwrap(),
+        vec![
+            ObjectChange::Modified(2, vec![(0, 1)]),
+            ObjectChange::Modified(3, vec![(0, 4)]),
+            ObjectChange::Created(4),
+        ]
+    );
+    assert_eq!(diff(&fs, b, a).unwrap()[2], ObjectChange::Deleted(4));
+}
@@ -0,0 +1,1669 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    convert::{TryFrom, TryInto},
+    io::Write,
+};
+
+use amos_std::{
+    error::{AMError, AMErrorFS},
+    AMResult,
+};
+use crc32fast::Hasher;
+use endian_codec::{DecodeLE, PackedSize};
+use strum::IntoEnumIterator;
+
+use crate::{
+    operations::{diff::diff_objects, ObjectChange},
+    u8_slice_as_any, AMFeatures, AMPointerGlobal, AMPointerLocal, Allocator, Disk, DiskGroup,
+    FSGroup, Fragment, Geometry, Object, ObjectListHeader, ObjectNodeHeader, ObjectSet, Superblock,
+    BLOCK_SIZE, HEAD_DEVICES, INLINE_FLAG, NODE_SIGNATURE, OBJECT_FLAGS, SIGNATURE,
+};
+
+/// Header of an allocator list or free queue block
+#[repr(C)]
+#[derive(PackedSize, DecodeLE)]
+struct LLGHeader {
+    next:     AMPointerGlobal,
+    count:    u16,
+    _padding: u64,
+}
+
+/// Header of a journal block
+#[repr(C)]
+#[derive(PackedSize, DecodeLE)]
+struct JournalHeader {
+    prev:     AMPointerGlobal,
+    count:    u64,
+    checksum: u32,
+    _padding: u32,
+}
+
+/// Output format of a dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// Annotated hexdumps, one row per 16 bytes
+    Text,
+    /// One JSON record per line: the image first, then each block in use
+    Json,
+}
+
+/// Options for dumping a disk
+#[derive(Debug, Clone)]
+pub struct DumpOptions {
+    /// The output format
+    pub format:           DumpFormat,
+    /// Highlight checksums with ANSI colors: green if valid, red if not
+    pub color:            bool,
+    /// Verify checksums while following pointers. Off by default, so broken disks can be dumped.
+    pub verify_checksums: bool,
+    /// Only dump blocks of these types, by [BlockType::name]. Empty dumps every type.
+    pub only:             BTreeSet<String>,
+    /// Only dump blocks in this range
+    pub range:            Option<(u64, u64)>,
+    /// Only follow this root slot from the superblocks, instead of all of them
+    pub root:             Option<u8>,
+    /// Hexdump an object's contents after its fragment table, when dumping an object
+    pub object_data:      bool,
+}
+
+impl Default for DumpOptions {
+    #[cfg(feature = "unstable")]
+    fn default() -> Self {
+        Self {
+            format:           DumpFormat::Text,
+            color:            false,
+            verify_checksums: false,
+            only:             BTreeSet::new(),
+            range:            None,
+            root:             None,
+            object_data:      false,
+        }
+    }
+}
+
+/// The structure a block holds, as found by following pointers from the superblocks
+#[derive(Debug, Clone)]
+pub enum BlockType {
+    /// Not referenced by any structure
+    Unused,
+    /// A superblock
+    Superblock(Superblock),
+    /// A disk group geometry
+    Geometry(Geometry),
+    /// The root of a filesystem group
+    FSGroup(FSGroup),
+    /// An allocator
+    Alloc(AMPointerGlobal),
+    /// The list of allocators
+    AllocList(AMPointerGlobal),
+    /// A free queue block
+    FreeQueue(AMPointerGlobal),
+    /// A journal block
+    Journal(AMPointerGlobal),
+    /// A node of the object tree
+    Objects(ObjectSet),
+    /// Referenced, but couldn't be read
+    Error,
+}
+
+impl BlockType {
+    /// A short name for this type of block
+    #[cfg(feature = "unstable")]
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlockType::Unused => "unused",
+            BlockType::Superblock(_) => "superblock",
+            BlockType::Geometry(_) => "geometry",
+            BlockType::FSGroup(_) => "fsgroup",
+            BlockType::Alloc(_) => "alloc",
+            BlockType::AllocList(_) => "alloc_list",
+            BlockType::FreeQueue(_) => "free_queue",
+            BlockType::Journal(_) => "journal",
+            BlockType::Objects(_) => "objects",
+            BlockType::Error => "error",
+        }
+    }
+}
+
+/// Highlight colors for dumped bytes
+#[derive(Clone, Copy)]
+enum Color {
+    Green,
+    Red,
+    Grey,
+}
+
+impl Color {
+    /// Green for valid, red for invalid
+    #[cfg(feature = "unstable")]
+    fn status(valid: bool) -> Self {
+        if valid {
+            Color::Green
+        } else {
+            Color::Red
+        }
+    }
+    /// The ANSI escape selecting this color
+    #[cfg(feature = "unstable")]
+    fn code(self) -> &'static str {
+        match self {
+            Color::Green => "\x1b[32m",
+            Color::Red => "\x1b[31m",
+            Color::Grey => "\x1b[38;2;128;128;128m",
+        }
+    }
+}
+
+/// Builds the disk group list object sets are read with
+#[cfg(feature = "unstable")]
+fn object_groups(dg: &DiskGroup) -> Vec<Option<DiskGroup>> {
+    let mut dgs = vec![None; 16];
+    dgs[0] = Some(dg.clone());
+    dgs
+}
+
+/// Finds the type of every block on a disk by following pointers from its superblocks
+#[cfg(feature = "unstable")]
+pub fn classify(d: &Disk) -> AMResult<Vec<BlockType>> {
+    Ok(classify_group(d, None)?.0)
+}
+
+/// Classifies blocks reachable from one root slot, or all of them.
+/// Also returns the disk group used to follow global pointers.
+#[cfg(feature = "unstable")]
+fn classify_group(d: &Disk, root: Option<u8>) -> AMResult<(Vec<BlockType>, DiskGroup)> {
+    let mut d = d.clone();
+    let mut dg = DiskGroup::single(Geometry::new(), d.clone(), Allocator::new(0));
+    let sb_locs = d.get_header_locs()?;
+    let mut types = vec![(BlockType::Unused, false); usize::try_from(d.size()?)?];
+    for loc in sb_locs {
+        types[usize::try_from(loc.loc())?] = (
+            BlockType::Superblock(unsafe { Superblock::read_unchecked(d.clone(), loc)? }),
+            false,
+        );
+    }
+    loop {
+        let mut upd = false;
+        for (idx, typ) in types.clone().iter().enumerate() {
+            if typ.1 {
+                continue;
+            }
+            match &typ.0 {
+                BlockType::Unused | BlockType::Error => continue,
+                BlockType::Superblock(s) => {
+                    dg.geo.device_ids = vec![s.devid()];
+                    for i in 0..16 {
+                        let ptr = s.geometries(i);
+                        if ptr.is_null() {
+                            continue;
+                        }
+                        types[usize::try_from(ptr.loc())?] = match Geometry::read(d.clone(), ptr) {
+                            Ok(g) => (BlockType::Geometry(g), false),
+                            Err(_) => (BlockType::Error, true),
+                        };
+                    }
+                    let slots = root.map_or(0..128, |r| usize::from(r)..usize::from(r) + 1);
+                    for i in slots {
+                        let ptr = s.rootnodes(i);
+                        if ptr.is_null() {
+                            continue;
+                        }
+                        types[usize::try_from(ptr.loc())?] =
+                            match FSGroup::read(&[Some(dg.clone())], ptr) {
+                                Ok(g) => (BlockType::FSGroup(g), false),
+                                Err(_) => (BlockType::Error, true),
+                            };
+                    }
+                }
+                BlockType::AllocList(a) => {
+                    let mut buf = [0u8; BLOCK_SIZE];
+                    a.read(0, BLOCK_SIZE, &[Some(dg.clone())], &mut buf)?;
+                    let hdr = unsafe { u8_slice_as_any::<LLGHeader>(&buf) };
+                    if !hdr.next.is_null() {
+                        types[usize::try_from(hdr.next.loc())?] =
+                            (BlockType::AllocList(hdr.next), false);
+                    }
+                    for i in 0..usize::from(hdr.count) {
+                        let ptr = unsafe {
+                            u8_slice_as_any::<AMPointerGlobal>(&buf[0x30 + i * 32..0x40 + i * 32])
+                        };
+                        types[usize::try_from(ptr.loc())?] = (BlockType::Alloc(ptr), false);
+                    }
+                }
+                BlockType::Objects(_) => {
+                    let mut buf = [0u8; BLOCK_SIZE];
+                    d.read_at(idx.try_into()?, &mut buf)?;
+                    let node = unsafe { u8_slice_as_any::<ObjectNodeHeader>(&buf) };
+                    let hdr = unsafe { u8_slice_as_any::<ObjectListHeader>(&buf) };
+                    let children = if node.signature == NODE_SIGNATURE {
+                        if node.level == 0 {
+                            Vec::new()
+                        } else {
+                            (0..usize::try_from(node.n_entries)?)
+                                .map(|i| 0x20 + i * 32)
+                                .collect()
+                        }
+                    } else if hdr.n_entries & 0x8000000000000000 != 0 {
+                        (0..usize::try_from(hdr.n_entries & 0x7FFFFFFFFFFFFFFF)?)
+                            .map(|i| 0x10 + i * 16)
+                            .collect()
+                    } else {
+                        Vec::new()
+                    };
+                    for pos in children {
+                        let ptr =
+                            unsafe { u8_slice_as_any::<AMPointerGlobal>(&buf[pos..pos + 16]) };
+                        types[usize::try_from(ptr.loc())?] = (
+                            BlockType::Objects(ObjectSet::read(object_groups(&dg), ptr)),
+                            false,
+                        );
+                    }
+                }
+                BlockType::FSGroup(f) => {
+                    if !f.alloc().is_null() {
+                        types[usize::try_from(f.alloc().loc())?] =
+                            (BlockType::AllocList(f.alloc()), false);
+                    }
+                    if !f.objects().is_null() {
+                        types[usize::try_from(f.objects().loc())?] = (
+                            BlockType::Objects(ObjectSet::read(object_groups(&dg), f.objects())),
+                            false,
+                        );
+                    }
+                    if !f.free_queue().is_null() {
+                        types[usize::try_from(f.free_queue().loc())?] =
+                            (BlockType::FreeQueue(f.free_queue()), false);
+                    }
+                    if !f.journal().is_null() {
+                        types[usize::try_from(f.journal().loc())?] =
+                            (BlockType::Journal(f.journal()), false);
+                    }
+                }
+                BlockType::Geometry(_)
+                | BlockType::Alloc(_)
+                | BlockType::FreeQueue(_)
+                | BlockType::Journal(_) => {}
+            }
+            types[idx].1 = true;
+            upd = true;
+        }
+        if !upd {
+            break;
+        }
+    }
+
+    // Other structures may have claimed a header location; superblocks always win
+    for loc in sb_locs {
+        types[usize::try_from(loc.loc())?] = (
+            BlockType::Superblock(unsafe { Superblock::read_unchecked(d.clone(), loc)? }),
+            false,
+        );
+    }
+    Ok((types.into_iter().map(|t| t.0).collect(), dg))
+}
+
+/// Writes an annotated hex dump of every structure on a disk
+#[cfg(feature = "unstable")]
+pub fn dump<W: Write>(d: Disk, out: &mut W, opts: &DumpOptions) -> AMResult<()> {
+    if opts.verify_checksums {
+        dump_disk(d, out, opts)
+    } else {
+        crate::without_checksums(|| dump_disk(d, out, opts))
+    }
+}
+
+/// Reads the first valid superblock of a disk
+#[cfg(feature = "unstable")]
+fn first_superblock(d: &Disk) -> AMResult<Superblock> {
+    Ok(d.get_header_locs()?
+        .iter()
+        .find_map(|loc| Superblock::read(d.clone(), *loc).ok())
+        .ok_or(AMErrorFS::NoSuperblock)?)
+}
+
+/// Reads the object set of a disk's latest root
+#[cfg(feature = "unstable")]
+fn latest_objects(d: &Disk) -> AMResult<ObjectSet> {
+    let sb = first_superblock(d)?;
+    let mut dg = DiskGroup::single(Geometry::new(), d.clone(), Allocator::new(0));
+    dg.geo.device_ids = vec![sb.devid()];
+    let dgs = object_groups(&dg);
+    let group = FSGroup::read(&dgs, sb.rootnodes(usize::from(sb.latest_root())))?;
+    Ok(ObjectSet::read(dgs, group.objects()))
+}
+
+/// Finds an object in the latest root of a disk, along with the disk groups to read it with
+#[cfg(feature = "unstable")]
+fn find_object(d: &Disk, id: u64) -> AMResult<(Object, Vec<Option<DiskGroup>>)> {
+    let objs = latest_objects(d)?;
+    let obj = objs.get_object(id)?.ok_or(AMErrorFS::NoObject)?;
+    Ok((obj, objs.diskgroups().to_vec()))
+}
+
+/// Differences between the metadata of two images
+#[derive(Debug, Default)]
+pub struct ImageDiff {
+    /// Blocks whose type or contents differ: the block, then its type in each image
+    pub blocks:  Vec<(u64, &'static str, &'static str)>,
+    /// Root slots pointing at different blocks: the slot, then its block in each image
+    pub roots:   Vec<(u8, Option<u64>, Option<u64>)>,
+    /// The latest root slot of each image
+    pub latest:  (u8, u8),
+    /// Objects that differ between the latest roots
+    pub objects: Vec<ObjectChange>,
+}
+
+/// Compares the metadata of two images, such as a disk before and after a commit.
+/// Checksums aren't verified, so damaged images can be compared.
+#[cfg(feature = "unstable")]
+pub fn diff_images(a: &Disk, b: &Disk) -> AMResult<ImageDiff> {
+    crate::without_checksums(|| {
+        let mut res = ImageDiff::default();
+        let (types_a, types_b) = (classify(a)?, classify(b)?);
+        let (mut da, mut db) = (a.clone(), b.clone());
+        let (mut buf_a, mut buf_b) = ([0; BLOCK_SIZE], [0; BLOCK_SIZE]);
+        for idx in 0..std::cmp::max(types_a.len(), types_b.len()) {
+            let ta = types_a.get(idx).unwrap_or(&BlockType::Unused);
+            let tb = types_b.get(idx).unwrap_or(&BlockType::Unused);
+            if matches!((ta, tb), (BlockType::Unused, BlockType::Unused)) {
+                continue;
+            }
+            let blk = u64::try_from(idx)?;
+            let changed = ta.name() != tb.name()
+                || da.read_at(blk, &mut buf_a)? != BLOCK_SIZE
+                || db.read_at(blk, &mut buf_b)? != BLOCK_SIZE
+                || buf_a != buf_b;
+            if changed {
+                res.blocks.push((blk, ta.name(), tb.name()));
+            }
+        }
+
+        let (sb_a, sb_b) = (first_superblock(a)?, first_superblock(b)?);
+        res.latest = (sb_a.latest_root(), sb_b.latest_root());
+        let root = |sb: &Superblock, i| {
+            Some(sb.rootnodes(i))
+                .filter(|p| !p.is_null())
+                .map(|p| p.loc())
+        };
+        for i in 0..128 {
+            let (ra, rb) = (root(&sb_a, i), root(&sb_b, i));
+            if ra != rb {
+                res.roots.push((u8::try_from(i)?, ra, rb));
+            }
+        }
+
+        res.objects = diff_objects(
+            latest_objects(a)?.get_objects()?,
+            latest_objects(b)?.get_objects()?,
+        )?;
+        Ok(res)
+    })
+}
+
+/// Writes an object's fragment table, with the checksum status of each fragment.
+/// The object is looked up in the latest root.
+#[cfg(feature = "unstable")]
+pub fn dump_object<W: Write>(d: Disk, id: u64, out: &mut W, opts: &DumpOptions) -> AMResult<()> {
+    let (obj, dgs) = if opts.verify_checksums {
+        find_object(&d, id)?
+    } else {
+        crate::without_checksums(|| find_object(&d, id))?
+    };
+    let mut dumper = Dumper { out, opts, d, dgs };
+    if opts.format == DumpFormat::Json {
+        dumper.object_json(id, &obj)
+    } else {
+        dumper.object(id, &obj)
+    }
+}
+
+/// Copies an object's contents from the latest root to an output. Returns the number of bytes copied.
+#[cfg(feature = "unstable")]
+pub fn extract_object<W: Write>(d: Disk, id: u64, out: &mut W) -> AMResult<u64> {
+    let (obj, dgs) = find_object(&d, id)?;
+    let size = obj.size()?;
+    let mut buf = vec![0; BLOCK_SIZE];
+    let mut pos = 0;
+    while pos < size {
+        let n = obj.read(pos, &mut buf, &dgs)?;
+        assert_or_err!(n != 0, AMError::TODO(0));
+        out.write_all(&buf[..usize::try_from(n)?])?;
+        pos += n;
+    }
+    Ok(size)
+}
+
+/// What a checking pass over a disk found
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// Number of blocks in use
+    pub blocks:   u64,
+    /// Each problem found, with the block it was found in
+    pub problems: Vec<(u64, String)>,
+}
+
+/// Follows the same pointers as [dump], verifying every checksum along the way instead of printing
+#[cfg(feature = "unstable")]
+pub fn check(d: Disk) -> AMResult<CheckReport> {
+    let (types, dg) = crate::without_checksums(|| classify_group(&d, None))?;
+    let dgs = vec![Some(dg)];
+    let mut res = CheckReport::default();
+    let mut buf = [0; BLOCK_SIZE];
+    let mut d2 = d.clone();
+    for (idx, typ) in types.iter().enumerate() {
+        if matches!(typ, BlockType::Unused) {
+            continue;
+        }
+        let blk = u64::try_from(idx)?;
+        res.blocks += 1;
+        d2.read_at(blk, &mut buf)?;
+        let mut problem = |p: String| res.problems.push((blk, p));
+        match typ {
+            BlockType::Error => problem("unreadable structure".to_owned()),
+            BlockType::Superblock(s) => {
+                if !s.clone().verify_checksum() {
+                    problem("bad superblock checksum".to_owned());
+                }
+                for i in 0..16 {
+                    let p = s.geometries(i);
+                    if !p.is_null() && !p.validate(d.clone()).unwrap_or(false) {
+                        problem(format!("bad checksum on geom{}", i));
+                    }
+                }
+            }
+            BlockType::Journal(_) => {
+                let hdr = unsafe { u8_slice_as_any::<JournalHeader>(&buf) };
+                let mut hashbuf = buf;
+                hashbuf[24..28].fill(0);
+                let mut hasher = Hasher::new();
+                hasher.update(&hashbuf);
+                if hasher.finalize() != hdr.checksum {
+                    problem("bad journal checksum".to_owned());
+                }
+            }
+            _ => {}
+        }
+        for (name, p) in block_pointers(&buf, typ)? {
+            if !p.is_null() && !p.validate(&dgs).unwrap_or(false) {
+                problem(format!("bad checksum on {}", name));
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Gets the verifiable global pointers a block holds, with their names
+#[cfg(feature = "unstable")]
+fn block_pointers(buf: &[u8], typ: &BlockType) -> AMResult<Vec<(String, AMPointerGlobal)>> {
+    let ptr_at = |pos: usize| unsafe { u8_slice_as_any::<AMPointerGlobal>(&buf[pos..pos + 16]) };
+    let mut res = Vec::new();
+    match typ {
+        BlockType::Superblock(s) => {
+            for i in 0..128 {
+                res.push((format!("root{}", i), s.rootnodes(i)));
+            }
+        }
+        BlockType::FSGroup(g) => {
+            res.push(("alloc".to_owned(), g.alloc()));
+            res.push(("freequeue".to_owned(), g.free_queue()));
+            res.push(("objects".to_owned(), g.objects()));
+        }
+        BlockType::AllocList(_) => {
+            let hdr = unsafe { u8_slice_as_any::<LLGHeader>(buf) };
+            res.push(("next".to_owned(), hdr.next));
+            for i in 0..usize::from(hdr.count) {
+                res.push((format!("alloc{}", i), ptr_at(0x30 + i * 32)));
+            }
+        }
+        BlockType::Alloc(_) | BlockType::FreeQueue(_) => {
+            let hdr = unsafe { u8_slice_as_any::<LLGHeader>(buf) };
+            res.push(("next".to_owned(), hdr.next));
+        }
+        BlockType::Objects(_) => {
+            let node = unsafe { u8_slice_as_any::<ObjectNodeHeader>(buf) };
+            let hdr = unsafe { u8_slice_as_any::<ObjectListHeader>(buf) };
+            if node.signature == NODE_SIGNATURE {
+                let mut pos = std::mem::size_of::<ObjectNodeHeader>();
+                for _ in 0..node.n_entries {
+                    let id = read_u64(buf, pos)?;
+                    let n = read_u64(buf, pos + 8)? & !OBJECT_FLAGS;
+                    if node.level != 0 {
+                        res.push((format!("node {}", id), ptr_at(pos + 16)));
+                        pos += 32;
+                    } else if n & INLINE_FLAG != 0 {
+                        pos += 16 + ((usize::try_from(n & !INLINE_FLAG)? + 15) & !15);
+                    } else {
+                        pos += 16;
+                        for _ in 0..n {
+                            res.push((format!("data of object {}", id), ptr_at(pos + 16)));
+                            pos += std::mem::size_of::<Fragment>();
+                        }
+                    }
+                }
+            } else if hdr.n_entries & 0x8000000000000000 != 0 {
+                for i in 0..usize::try_from(hdr.n_entries & 0x7FFFFFFFFFFFFFFF)? {
+                    res.push((format!("list{}", i), ptr_at(0x10 + i * 16)));
+                }
+            } else {
+                let mut pos = std::mem::size_of::<ObjectListHeader>();
+                for i in 0..hdr.n_entries {
+                    while read_u64(buf, pos)? != 0 {
+                        let id = hdr.start_idx + i;
+                        res.push((format!("data of object {}", id), ptr_at(pos + 16)));
+                        pos += std::mem::size_of::<Fragment>();
+                    }
+                    pos += 8;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(res)
+}
+
+/// Classifies and dumps every block of a disk
+#[cfg(feature = "unstable")]
+fn dump_disk<W: Write>(d: Disk, out: &mut W, opts: &DumpOptions) -> AMResult<()> {
+    let (types, dg) = classify_group(&d, opts.root)?;
+    let mut dumper = Dumper {
+        out,
+        opts,
+        d,
+        dgs: vec![Some(dg)],
+    };
+    dumper.dump(types)
+}
+
+/// Formats blocks to an output
+struct Dumper<'a, W: Write> {
+    out:  &'a mut W,
+    opts: &'a DumpOptions,
+    d:    Disk,
+    dgs:  Vec<Option<DiskGroup>>,
+}
+
+impl<W: Write> Dumper<'_, W> {
+    /// Dumps each block according to its type
+    #[cfg(feature = "unstable")]
+    fn dump(&mut self, types: Vec<BlockType>) -> AMResult<()> {
+        if self.opts.format == DumpFormat::Json {
+            return self.dump_json(types);
+        }
+        writeln!(self.out, "Image is {} blocks long", self.d.size()?)?;
+        write!(self.out, "Header locations:")?;
+        for loc in self.d.get_header_locs()? {
+            write!(self.out, "{} ", loc.loc())?;
+        }
+        writeln!(self.out)?;
+        let mut buf = [0; BLOCK_SIZE];
+        for (idx, typ) in types.into_iter().enumerate() {
+            if !self.wanted(idx, &typ)? {
+                continue;
+            }
+            self.d.read_at(idx.try_into()?, &mut buf)?;
+            match typ {
+                BlockType::Unused => {}
+                BlockType::Superblock(s) => self.superblock(idx, &buf, s)?,
+                BlockType::Geometry(g) => self.geometry(idx, &buf, &g)?,
+                BlockType::FSGroup(f) => self.fsgroup(idx, &buf, &f)?,
+                BlockType::AllocList(_) => self.alloclist(idx, &buf)?,
+                BlockType::Alloc(_) => self.alloc(idx, &buf)?,
+                BlockType::Objects(_) => self.objects(idx, &buf)?,
+                BlockType::FreeQueue(_) => self.free_queue(idx, &buf)?,
+                BlockType::Journal(_) => self.journal(idx, &buf)?,
+                BlockType::Error => self.error(idx, &buf)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a block against the type and range filters
+    #[cfg(feature = "unstable")]
+    fn wanted(&self, idx: usize, typ: &BlockType) -> AMResult<bool> {
+        if matches!(typ, BlockType::Unused) {
+            return Ok(false);
+        }
+        if !self.opts.only.is_empty() && !self.opts.only.contains(typ.name()) {
+            return Ok(false);
+        }
+        let idx = u64::try_from(idx)?;
+        Ok(self
+            .opts
+            .range
+            .map_or(true, |(start, end)| (start..end).contains(&idx)))
+    }
+
+    /// Wraps text in a color, if colors are enabled
+    #[cfg(feature = "unstable")]
+    fn paint(&self, text: &str, color: Color) -> String {
+        if self.opts.color {
+            format!("{}{}\x1b[0m", color.code(), text)
+        } else {
+            text.to_owned()
+        }
+    }
+
+    /// Writes a row of 16 bytes, highlighting some of them
+    #[cfg(feature = "unstable")]
+    fn hex_marked(
+        &mut self,
+        row: usize,
+        data: &[u8],
+        marked: std::ops::Range<usize>,
+        color: Color,
+    ) -> AMResult<()> {
+        write!(self.out, "\t{:06x} : ", row * 0x10)?;
+        for (i, b) in data.iter().take(16).enumerate() {
+            let byte = format!("{:02x} ", b);
+            if marked.contains(&i) {
+                let byte = self.paint(&byte, color);
+                write!(self.out, "{}", byte)?;
+            } else {
+                write!(self.out, "{}", byte)?;
+            }
+        }
+        write!(self.out, "| ")?;
+        Ok(())
+    }
+
+    /// Writes a row of up to 16 bytes
+    #[cfg(feature = "unstable")]
+    fn hex(&mut self, row: usize, data: &[u8]) -> AMResult<()> {
+        self.hex_marked(row, data, 0..0, Color::Green)
+    }
+
+    /// Writes a row holding a local pointer, highlighting its checksum
+    #[cfg(feature = "unstable")]
+    fn hex_ptr_local(
+        &mut self,
+        row: usize,
+        data: &[u8],
+        name: &str,
+        p: AMPointerLocal,
+    ) -> AMResult<()> {
+        let valid = p.validate(self.d.clone()).unwrap_or(false);
+        self.hex_marked(row, data, 8..12, Color::status(valid))?;
+        write!(self.out, "{}:{:08x}", name, p.loc())?;
+        Ok(())
+    }
+
+    /// Writes a row holding a global pointer, highlighting its checksum
+    #[cfg(feature = "unstable")]
+    fn hex_ptr_global(
+        &mut self,
+        row: usize,
+        data: &[u8],
+        name: &str,
+        p: AMPointerGlobal,
+    ) -> AMResult<()> {
+        let valid = p.validate(&self.dgs).unwrap_or(false);
+        self.hex_ptr_colored(row, data, name, p, Color::status(valid))
+    }
+
+    /// Writes a row holding a global pointer whose checksum isn't verified
+    #[cfg(feature = "unstable")]
+    fn hex_ptr_colored(
+        &mut self,
+        row: usize,
+        data: &[u8],
+        name: &str,
+        p: AMPointerGlobal,
+        color: Color,
+    ) -> AMResult<()> {
+        self.hex_marked(row, data, 8..12, color)?;
+        if p.is_null() {
+            write!(self.out, "{}:NULL", name)?;
+        } else {
+            write!(self.out, "{}:{},{},{:08x}", name, p.geo(), p.dev(), p.loc())?;
+        }
+        Ok(())
+    }
+
+    /// Dumps a superblock
+    #[cfg(feature = "unstable")]
+    fn superblock(&mut self, idx: usize, buf: &[u8], mut s: Superblock) -> AMResult<()> {
+        let base = idx * BLOCK_SIZE;
+        writeln!(self.out, "Superblock:")?;
+        self.hex(base, buf)?;
+        let sig = format!("{:8}", String::from_utf8_lossy(s.signature()));
+        let sig = self.paint(&sig, Color::status(buf[0..8] == *SIGNATURE));
+        write!(self.out, "sig:{} ", sig)?;
+        write!(self.out, "dev:{:016x} ", s.devid())?;
+        writeln!(self.out)?;
+
+        let features: HashMap<usize, AMFeatures> =
+            AMFeatures::iter().map(|f| (f as usize, f)).collect();
+        for i in 0..16 {
+            if (i * 128..(i + 1) * 128).all(|x| !features.contains_key(&x)) {
+                continue;
+            }
+            self.hex(base + 1 + i, &buf[0x10 * (1 + i)..])?;
+            for f in i * 128..(i + 1) * 128 {
+                if let Some(feat) = features.get(&f) {
+                    let set = s.features().get(f).map_or(false, |b| *b);
+                    let name = self.paint(&format!("{:?}", feat), Color::status(set));
+                    write!(self.out, "{} ", name)?;
+                }
+            }
+            writeln!(self.out)?;
+        }
+
+        for i in 0..16 {
+            if s.geometries(i).is_null() {
+                continue;
+            }
+            self.hex_ptr_local(
+                base + 17 + i,
+                &buf[0x10 * (17 + i)..],
+                &format!("geom{}", i),
+                s.geometries(i),
+            )?;
+            writeln!(self.out)?;
+        }
+
+        let color = Color::status(s.verify_checksum());
+        self.hex_marked(base + 33, &buf[0x10 * 33..], 0..4, color)?;
+        let sum = self.paint(&format!("{:8x}", s.checksum()), color);
+        write!(self.out, "sum:{} ", sum)?;
+        writeln!(self.out)?;
+
+        self.hex(base + 127, &buf[0x10 * 127..])?;
+        write!(self.out, "latest:{} ", s.latest_root())?;
+        writeln!(self.out)?;
+
+        for i in 0..128 {
+            if s.rootnodes(i).is_null() {
+                continue;
+            }
+            self.hex_ptr_global(
+                base + 128 + i,
+                &buf[0x10 * (128 + i)..],
+                &format!("root{}", i),
+                s.rootnodes(i),
+            )?;
+            writeln!(self.out)?;
+        }
+        Ok(())
+    }
+
+    /// Dumps a geometry
+    #[cfg(feature = "unstable")]
+    fn geometry(&mut self, idx: usize, buf: &[u8], g: &Geometry) -> AMResult<()> {
+        let base = idx * BLOCK_SIZE;
+        writeln!(self.out, "Geometry:")?;
+        for i in 0..255 {
+            if buf[0x10 * i..0x10 * (i + 1)] == [0; 16] {
+                continue;
+            }
+            self.hex(base + i, &buf[0x10 * i..])?;
+            for dev in [i * 2, i * 2 + 1] {
+                // Rows past the first block's IDs hold the table's length and continuation
+                if let Some(id) = g.device_ids.get(dev).filter(|_| dev < HEAD_DEVICES) {
+                    write!(self.out, "dev{}:{:08x}", dev, id)?;
+                }
+            }
+            writeln!(self.out)?;
+        }
+        self.hex(base + 255, &buf[0x10 * 255..])?;
+        write!(self.out, "{:?}", { g.flavor })?;
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    /// Dumps the root of a filesystem group
+    #[cfg(feature = "unstable")]
+    fn fsgroup(&mut self, idx: usize, buf: &[u8], g: &FSGroup) -> AMResult<()> {
+        let base = idx * BLOCK_SIZE;
+        writeln!(self.out, "FSGroup:")?;
+        self.hex_ptr_global(base, buf, "alloc", g.alloc())?;
+        writeln!(self.out)?;
+        self.hex_ptr_global(base + 1, &buf[0x10..], "freequeue", g.free_queue())?;
+        writeln!(self.out)?;
+        self.hex_ptr_colored(base + 2, &buf[0x20..], "journal", g.journal(), Color::Grey)?;
+        writeln!(self.out)?;
+        self.hex_ptr_global(base + 3, &buf[0x30..], "objects", g.objects())?;
+        writeln!(self.out)?;
+        self.hex(base + 4, &buf[0x40..])?;
+        write!(self.out, "directory:{}", g.directory())?;
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    /// Dumps a block of the allocator list
+    #[cfg(feature = "unstable")]
+    fn alloclist(&mut self, idx: usize, buf: &[u8]) -> AMResult<()> {
+        let base = idx * BLOCK_SIZE;
+        writeln!(self.out, "AllocatorList:")?;
+        let hdr = unsafe { u8_slice_as_any::<LLGHeader>(buf) };
+        self.hex_ptr_global(base, buf, "next", hdr.next)?;
+        writeln!(self.out)?;
+        self.hex(base + 1, &buf[0x10..])?;
+        write!(self.out, "count:{}", hdr.count)?;
+        writeln!(self.out)?;
+        for i in 0..usize::from(hdr.count) {
+            let devid = unsafe { u8_slice_as_any::<u64>(&buf[0x20 + i * 32..0x28 + i * 32]) };
+            let ptr =
+                unsafe { u8_slice_as_any::<AMPointerGlobal>(&buf[0x30 + i * 32..0x40 + i * 32]) };
+            self.hex(base + 2 + i * 2, &buf[0x10 * (2 + i * 2)..])?;
+            writeln!(self.out, "dev:{:x}", devid)?;
+            self.hex_ptr_global(base + 3 + i * 2, &buf[0x10 * (3 + i * 2)..], "alloc", ptr)?;
+            writeln!(self.out)?;
+        }
+        Ok(())
+    }
+
+    /// Dumps an allocator
+    #[cfg(feature = "unstable")]
+    fn alloc(&mut self, idx: usize, buf: &[u8]) -> AMResult<()> {
+        let base = idx * BLOCK_SIZE;
+        writeln!(self.out, "Allocator:")?;
+        let hdr = unsafe { u8_slice_as_any::<LLGHeader>(buf) };
+        self.hex_ptr_global(base, buf, "next", hdr.next)?;
+        writeln!(self.out)?;
+        self.hex(base + 1, &buf[0x10..])?;
+        write!(self.out, "count:{}", hdr.count)?;
+        writeln!(self.out)?;
+        for i in 0..usize::from(hdr.count) {
+            if i % 2 == 0 {
+                self.hex(base + 2 + i / 2, &buf[0x10 * (2 + i / 2)..])?;
+            }
+            let alloc = unsafe { u8_slice_as_any::<u64>(&buf[0x20 + i * 8..0x28 + i * 8]) };
+            if i == 0 {
+                write!(self.out, "length:{:x} ", alloc)?;
+            } else if alloc & 0x8000000000000000 != 0 {
+                write!(self.out, "used:{:x} ", alloc & 0x7FFFFFFFFFFFFFFF)?;
+            } else {
+                write!(self.out, "free:{:x} ", alloc)?;
+            }
+            if i % 2 == 1 {
+                writeln!(self.out)?;
+            }
+        }
+        if hdr.count % 2 == 1 {
+            writeln!(self.out)?;
+        }
+        Ok(())
+    }
+
+    /// Dumps a free queue block
+    #[cfg(feature = "unstable")]
+    fn free_queue(&mut self, idx: usize, buf: &[u8]) -> AMResult<()> {
+        let base = idx * BLOCK_SIZE;
+        writeln!(self.out, "Free queue:")?;
+        let hdr = unsafe { u8_slice_as_any::<LLGHeader>(buf) };
+        self.hex_ptr_global(base, buf, "next", hdr.next)?;
+        writeln!(self.out)?;
+        self.hex(base + 1, &buf[0x10..])?;
+        write!(self.out, "count:{:x}", hdr.count)?;
+        writeln!(self.out)?;
+        for i in 0..usize::from(hdr.count) {
+            self.hex(base + 2 + i * 2, &buf[0x10 * (2 + i * 2)..])?;
+            let txid = unsafe { u8_slice_as_any::<u128>(&buf[0x20 + i * 32..0x30 + i * 32]) };
+            writeln!(self.out, "txid:{}", txid)?;
+            let ptr =
+                unsafe { u8_slice_as_any::<AMPointerGlobal>(&buf[0x30 + i * 32..0x40 + i * 32]) };
+            self.hex_ptr_global(base + 2 + i * 2, &buf[0x10 * (2 + i * 2)..], "block", ptr)?;
+            writeln!(self.out)?;
+        }
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    /// Dumps a journal block, checking its embedded checksum
+    #[cfg(feature = "unstable")]
+    fn journal(&mut self, idx: usize, buf: &[u8]) -> AMResult<()> {
+        let base = idx * BLOCK_SIZE;
+        writeln!(self.out, "Journal:")?;
+        let hdr = unsafe { u8_slice_as_any::<JournalHeader>(buf) };
+        self.hex_ptr_global(base, buf, "prev", hdr.prev)?;
+        writeln!(self.out)?;
+        let mut hashbuf = buf.to_vec();
+        hashbuf[24..28].fill(0);
+        let mut hasher = Hasher::new();
+        hasher.update(&hashbuf);
+        let color = Color::status(hasher.finalize() == hdr.checksum);
+        self.hex_marked(base + 1, &buf[0x10..], 8..12, color)?;
+        write!(self.out, "count:{:x} ", hdr.count)?;
+        let sum = self.paint(&format!("{:08x}", hdr.checksum), color);
+        write!(self.out, "sum:{} ", sum)?;
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    /// Dumps a node or list of the object tree
+    #[cfg(feature = "unstable")]
+    fn objects(&mut self, idx: usize, buf: &[u8]) -> AMResult<()> {
+        let base = idx * BLOCK_SIZE;
+        writeln!(self.out, "ObjectSet:")?;
+        let node = unsafe { u8_slice_as_any::<ObjectNodeHeader>(buf) };
+        if node.signature == NODE_SIGNATURE {
+            return self.object_node(idx, buf, &node);
+        }
+        let hdr = unsafe { u8_slice_as_any::<ObjectListHeader>(buf) };
+        self.hex(base, buf)?;
+        if hdr.n_entries & 0x8000000000000000 != 0 {
+            let count = usize::try_from(hdr.n_entries & 0x7FFFFFFFFFFFFFFF)?;
+            write!(self.out, "start:{} indirect:{}", hdr.start_idx, count)?;
+            writeln!(self.out)?;
+            for i in 0..count {
+                let ptr = unsafe { u8_slice_as_any::<AMPointerGlobal>(&buf[0x10 + i * 16..]) };
+                self.hex_ptr_global(base + 1 + i, &buf[0x10 * (1 + i)..], "list", ptr)?;
+                writeln!(self.out)?;
+            }
+            return Ok(());
+        }
+        write!(self.out, "start:{} count:{}", hdr.start_idx, hdr.n_entries)?;
+        writeln!(self.out)?;
+        let mut pos = std::mem::size_of::<ObjectListHeader>();
+        for _ in 0..hdr.n_entries {
+            loop {
+                let blk_offs = pos / 16;
+                let size = read_u64(buf, pos)?;
+                self.hex(base + blk_offs, &buf[blk_offs * 16..blk_offs * 16 + 16])?;
+                write!(self.out, "size:{:x} ", size)?;
+                if size == 0 {
+                    pos += 8;
+                    writeln!(self.out)?;
+                    break;
+                }
+                write!(self.out, "offs:{:x} ", read_u64(buf, pos + 8)?)?;
+                writeln!(self.out)?;
+                let ptr = unsafe { u8_slice_as_any::<AMPointerGlobal>(&buf[pos + 16..pos + 32]) };
+                self.hex_ptr_global(
+                    base + blk_offs + 1,
+                    &buf[blk_offs * 16 + 16..blk_offs * 16 + 32],
+                    "data",
+                    ptr,
+                )?;
+                writeln!(self.out)?;
+                pos += std::mem::size_of::<Fragment>();
+            }
+        }
+        Ok(())
+    }
+
+    /// Dumps a B-tree node of the object tree
+    #[cfg(feature = "unstable")]
+    fn object_node(&mut self, idx: usize, buf: &[u8], node: &ObjectNodeHeader) -> AMResult<()> {
+        let base = idx * BLOCK_SIZE;
+        self.hex(base, buf)?;
+        write!(self.out, "level:{} count:{}", node.level, node.n_entries)?;
+        writeln!(self.out)?;
+        let mut pos = std::mem::size_of::<ObjectNodeHeader>();
+        for _ in 0..node.n_entries {
+            let blk_offs = pos / 16;
+            let id = read_u64(buf, pos)?;
+            let n = read_u64(buf, pos + 8)? & !OBJECT_FLAGS;
+            self.hex(base + blk_offs, &buf[blk_offs * 16..blk_offs * 16 + 16])?;
+            if node.level != 0 {
+                write!(self.out, "start:{}", id)?;
+                writeln!(self.out)?;
+                let ptr = unsafe { u8_slice_as_any::<AMPointerGlobal>(&buf[pos + 16..pos + 32]) };
+                self.hex_ptr_global(
+                    base + blk_offs + 1,
+                    &buf[blk_offs * 16 + 16..blk_offs * 16 + 32],
+                    "node",
+                    ptr,
+                )?;
+                writeln!(self.out)?;
+                pos += 32;
+                continue;
+            }
+            pos += 16;
+            if n & INLINE_FLAG != 0 {
+                let len = usize::try_from(n & !INLINE_FLAG)?;
+                write!(self.out, "id:{} inline:{}", id, len)?;
+                writeln!(self.out)?;
+                for i in 0..(len + 15) / 16 {
+                    self.hex(
+                        base + blk_offs + 1 + i,
+                        &buf[pos + i * 16..pos + i * 16 + 16],
+                    )?;
+                    writeln!(self.out)?;
+                }
+                pos += (len + 15) & !15;
+                continue;
+            }
+            write!(self.out, "id:{} frags:{}", id, n)?;
+            writeln!(self.out)?;
+            for _ in 0..n {
+                let blk_offs = pos / 16;
+                let size = read_u64(buf, pos)?;
+                let offset = read_u64(buf, pos + 8)?;
+                self.hex(base + blk_offs, &buf[blk_offs * 16..blk_offs * 16 + 16])?;
+                write!(self.out, "size:{:x} offs:{:x} ", size, offset)?;
+                writeln!(self.out)?;
+                let ptr = unsafe { u8_slice_as_any::<AMPointerGlobal>(&buf[pos + 16..pos + 32]) };
+                self.hex_ptr_global(
+                    base + blk_offs + 1,
+                    &buf[blk_offs * 16 + 16..blk_offs * 16 + 32],
+                    "data",
+                    ptr,
+                )?;
+                writeln!(self.out)?;
+                pos += std::mem::size_of::<Fragment>();
+            }
+        }
+        Ok(())
+    }
+
+    /// Dumps an object's fragment table, and optionally its contents
+    #[cfg(feature = "unstable")]
+    fn object(&mut self, id: u64, obj: &Object) -> AMResult<()> {
+        let size = obj.size()?;
+        writeln!(self.out, "Object {}:", id)?;
+        if obj.is_inline() {
+            writeln!(self.out, "size:{:x} inline", size)?;
+        } else {
+            writeln!(self.out, "size:{:x} frags:{}", size, obj.frags().len())?;
+        }
+        let mut start = 0;
+        for f in obj.frags() {
+            write!(self.out, "\tstart:{:x} size:{:x} ", start, f.size)?;
+            if f.is_hole() {
+                writeln!(self.out, "hole")?;
+            } else {
+                let valid = f.pointer.validate(&self.dgs).unwrap_or(false);
+                let status = self.paint(
+                    if valid { "valid" } else { "invalid" },
+                    Color::status(valid),
+                );
+                let p = f.pointer;
+                writeln!(
+                    self.out,
+                    "offs:{:x} data:{},{},{:08x} {}",
+                    f.offset,
+                    p.geo(),
+                    p.dev(),
+                    p.loc(),
+                    status
+                )?;
+            }
+            start += f.size;
+        }
+        if !self.opts.object_data {
+            return Ok(());
+        }
+        writeln!(self.out, "Data:")?;
+        let mut buf = vec![0; BLOCK_SIZE];
+        let mut pos = 0;
+        while pos < size {
+            let n = usize::try_from(obj.read(pos, &mut buf, &self.dgs)?)?;
+            assert_or_err!(n != 0, AMError::TODO(0));
+            for (i, row) in buf[..n].chunks(16).enumerate() {
+                self.hex(usize::try_from(pos / 16)? + i, row)?;
+                writeln!(self.out)?;
+            }
+            pos += u64::try_from(n)?;
+        }
+        Ok(())
+    }
+
+    /// Writes an object's fragment table as a JSON record
+    #[cfg(feature = "unstable")]
+    fn object_json(&mut self, id: u64, obj: &Object) -> AMResult<()> {
+        let mut start = 0;
+        let mut frags = Vec::new();
+        for f in obj.frags() {
+            frags.push(json_object(&[
+                ("start", start.to_string()),
+                ("size", f.size.to_string()),
+                ("offset", f.offset.to_string()),
+                ("data", self.json_ptr(f.pointer)),
+            ]));
+            start += f.size;
+        }
+        let record = json_object(&[
+            ("type", json_str("object")),
+            ("id", id.to_string()),
+            ("size", obj.size()?.to_string()),
+            ("inline", obj.is_inline().to_string()),
+            ("fragments", json_array(frags)),
+        ]);
+        writeln!(self.out, "{}", record)?;
+        Ok(())
+    }
+
+    /// Dumps the first row of a block that couldn't be read
+    #[cfg(feature = "unstable")]
+    fn error(&mut self, idx: usize, buf: &[u8]) -> AMResult<()> {
+        writeln!(self.out, "Error:")?;
+        self.hex(idx * BLOCK_SIZE, buf)?;
+        writeln!(self.out)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Dumper<'_, W> {
+    /// Writes the image record, then a record for each block in use
+    #[cfg(feature = "unstable")]
+    fn dump_json(&mut self, types: Vec<BlockType>) -> AMResult<()> {
+        let locs: Vec<String> = self
+            .d
+            .get_header_locs()?
+            .iter()
+            .map(|l| l.loc().to_string())
+            .collect();
+        let image = json_object(&[
+            ("type", json_str("image")),
+            ("blocks", self.d.size()?.to_string()),
+            ("header_locations", json_array(locs)),
+        ]);
+        writeln!(self.out, "{}", image)?;
+        let mut buf = [0; BLOCK_SIZE];
+        for (idx, typ) in types.into_iter().enumerate() {
+            if !self.wanted(idx, &typ)? {
+                continue;
+            }
+            self.d.read_at(idx.try_into()?, &mut buf)?;
+            let name = typ.name();
+            let (checksum, fields) = self.json_fields(&buf, typ)?;
+            let record = json_object(&[
+                ("type", json_str(name)),
+                ("block", idx.to_string()),
+                ("offset", (idx * BLOCK_SIZE).to_string()),
+                (
+                    "checksum",
+                    checksum.map_or_else(|| "null".to_owned(), |c| c.to_string()),
+                ),
+                ("fields", json_object(&fields)),
+            ]);
+            writeln!(self.out, "{}", record)?;
+        }
+        Ok(())
+    }
+
+    /// Decodes a block into its checksum status and fields
+    #[cfg(feature = "unstable")]
+    fn json_fields(
+        &self,
+        buf: &[u8],
+        typ: BlockType,
+    ) -> AMResult<(Option<bool>, Vec<(&'static str, String)>)> {
+        Ok(match typ {
+            BlockType::Unused | BlockType::Error => (None, Vec::new()),
+            BlockType::Superblock(mut s) => {
+                let geometries = (0..16)
+                    .filter(|i| !s.geometries(*i).is_null())
+                    .map(|i| self.json_ptr_local(s.geometries(i)))
+                    .collect();
+                let roots = (0..128)
+                    .filter(|i| !s.rootnodes(*i).is_null())
+                    .map(|i| {
+                        json_object(&[
+                            ("slot", i.to_string()),
+                            ("pointer", self.json_ptr(s.rootnodes(i))),
+                        ])
+                    })
+                    .collect();
+                let fields = vec![
+                    (
+                        "signature",
+                        json_str(&String::from_utf8_lossy(s.signature())),
+                    ),
+                    ("devid", s.devid().to_string()),
+                    ("uuid", json_str(&format!("{:032x}", s.uuid()))),
+                    ("label", json_str(&s.label())),
+                    ("version", s.version().to_string()),
+                    ("latest_root", s.latest_root().to_string()),
+                    ("geometries", json_array(geometries)),
+                    ("roots", json_array(roots)),
+                ];
+                (Some(s.verify_checksum()), fields)
+            }
+            BlockType::Geometry(g) => {
+                let devices = g.device_ids.iter().map(u64::to_string).collect();
+                let fields = vec![
+                    ("flavor", json_str(&format!("{:?}", { g.flavor }))),
+                    ("devices", json_array(devices)),
+                ];
+                (None, fields)
+            }
+            BlockType::FSGroup(g) => {
+                let fields = vec![
+                    ("alloc", self.json_ptr(g.alloc())),
+                    ("free_queue", self.json_ptr(g.free_queue())),
+                    ("journal", self.json_ptr(g.journal())),
+                    ("objects", self.json_ptr(g.objects())),
+                    ("directory", g.directory().to_string()),
+                ];
+                (None, fields)
+            }
+            BlockType::AllocList(p) => {
+                let hdr = unsafe { u8_slice_as_any::<LLGHeader>(buf) };
+                let allocators = (0..usize::from(hdr.count))
+                    .map(|i| {
+                        let ptr = unsafe {
+                            u8_slice_as_any::<AMPointerGlobal>(&buf[0x30 + i * 32..0x40 + i * 32])
+                        };
+                        Ok(json_object(&[
+                            ("dev", read_u64(buf, 0x20 + i * 32)?.to_string()),
+                            ("pointer", self.json_ptr(ptr)),
+                        ]))
+                    })
+                    .collect::<AMResult<_>>()?;
+                let fields = vec![
+                    ("next", self.json_ptr(hdr.next)),
+                    ("allocators", json_array(allocators)),
+                ];
+                (Some(self.ptr_valid(p)), fields)
+            }
+            BlockType::Alloc(p) => {
+                let hdr = unsafe { u8_slice_as_any::<LLGHeader>(buf) };
+                let mut extents = Vec::new();
+                for i in 1..usize::from(hdr.count) {
+                    let e = read_u64(buf, 0x20 + i * 8)?;
+                    extents.push(json_object(&[
+                        ("used", (e & 0x8000000000000000 != 0).to_string()),
+                        ("length", (e & 0x7FFFFFFFFFFFFFFF).to_string()),
+                    ]));
+                }
+                let mut fields = vec![("next", self.json_ptr(hdr.next))];
+                if hdr.count != 0 {
+                    fields.push(("length", read_u64(buf, 0x20)?.to_string()));
+                }
+                fields.push(("extents", json_array(extents)));
+                (Some(self.ptr_valid(p)), fields)
+            }
+            BlockType::FreeQueue(p) => {
+                let hdr = unsafe { u8_slice_as_any::<LLGHeader>(buf) };
+                let entries = (0..usize::from(hdr.count))
+                    .map(|i| {
+                        let txid = unsafe { u8_slice_as_any::<u128>(&buf[0x20 + i * 32..]) };
+                        let ptr =
+                            unsafe { u8_slice_as_any::<AMPointerGlobal>(&buf[0x30 + i * 32..]) };
+                        json_object(&[("txid", txid.to_string()), ("pointer", self.json_ptr(ptr))])
+                    })
+                    .collect();
+                let fields = vec![
+                    ("next", self.json_ptr(hdr.next)),
+                    ("entries", json_array(entries)),
+                ];
+                (Some(self.ptr_valid(p)), fields)
+            }
+            BlockType::Journal(_) => {
+                let hdr = unsafe { u8_slice_as_any::<JournalHeader>(buf) };
+                let mut hashbuf = buf.to_vec();
+                hashbuf[24..28].fill(0);
+                let mut hasher = Hasher::new();
+                hasher.update(&hashbuf);
+                let fields = vec![
+                    ("prev", self.json_ptr(hdr.prev)),
+                    ("count", hdr.count.to_string()),
+                ];
+                (Some(hasher.finalize() == hdr.checksum), fields)
+            }
+            BlockType::Objects(_) => (None, self.json_objects(buf)?),
+        })
+    }
+
+    /// Decodes a node or list of the object tree
+    #[cfg(feature = "unstable")]
+    fn json_objects(&self, buf: &[u8]) -> AMResult<Vec<(&'static str, String)>> {
+        let node = unsafe { u8_slice_as_any::<ObjectNodeHeader>(buf) };
+        if node.signature == NODE_SIGNATURE {
+            let mut pos = std::mem::size_of::<ObjectNodeHeader>();
+            let mut entries = Vec::new();
+            for _ in 0..node.n_entries {
+                let id = read_u64(buf, pos)?;
+                let n = read_u64(buf, pos + 8)? & !OBJECT_FLAGS;
+                if node.level != 0 {
+                    let ptr = unsafe { u8_slice_as_any::<AMPointerGlobal>(&buf[pos + 16..]) };
+                    entries.push(json_object(&[
+                        ("start", id.to_string()),
+                        ("child", self.json_ptr(ptr)),
+                    ]));
+                    pos += 32;
+                    continue;
+                }
+                pos += 16;
+                if n & INLINE_FLAG != 0 {
+                    let len = usize::try_from(n & !INLINE_FLAG)?;
+                    entries.push(json_object(&[
+                        ("id", id.to_string()),
+                        ("inline", len.to_string()),
+                    ]));
+                    pos += (len + 15) & !15;
+                    continue;
+                }
+                let mut frags = Vec::new();
+                for _ in 0..n {
+                    frags.push(self.json_fragment(buf, pos)?);
+                    pos += std::mem::size_of::<Fragment>();
+                }
+                entries.push(json_object(&[
+                    ("id", id.to_string()),
+                    ("fragments", json_array(frags)),
+                ]));
+            }
+            return Ok(vec![
+                ("kind", json_str("node")),
+                ("level", node.level.to_string()),
+                ("entries", json_array(entries)),
+            ]);
+        }
+        let hdr = unsafe { u8_slice_as_any::<ObjectListHeader>(buf) };
+        if hdr.n_entries & 0x8000000000000000 != 0 {
+            let lists = (0..usize::try_from(hdr.n_entries & 0x7FFFFFFFFFFFFFFF)?)
+                .map(|i| {
+                    self.json_ptr(unsafe {
+                        u8_slice_as_any::<AMPointerGlobal>(&buf[0x10 + i * 16..])
+                    })
+                })
+                .collect();
+            return Ok(vec![
+                ("kind", json_str("indirect")),
+                ("start", hdr.start_idx.to_string()),
+                ("lists", json_array(lists)),
+            ]);
+        }
+        let mut pos = std::mem::size_of::<ObjectListHeader>();
+        let mut objects = Vec::new();
+        for _ in 0..hdr.n_entries {
+            let mut frags = Vec::new();
+            while read_u64(buf, pos)? != 0 {
+                frags.push(self.json_fragment(buf, pos)?);
+                pos += std::mem::size_of::<Fragment>();
+            }
+            pos += 8;
+            objects.push(json_array(frags));
+        }
+        Ok(vec![
+            ("kind", json_str("list")),
+            ("start", hdr.start_idx.to_string()),
+            ("objects", json_array(objects)),
+        ])
+    }
+
+    /// Decodes a fragment at a byte offset
+    #[cfg(feature = "unstable")]
+    fn json_fragment(&self, buf: &[u8], pos: usize) -> AMResult<String> {
+        let ptr = unsafe { u8_slice_as_any::<AMPointerGlobal>(&buf[pos + 16..pos + 32]) };
+        Ok(json_object(&[
+            ("size", read_u64(buf, pos)?.to_string()),
+            ("offset", read_u64(buf, pos + 8)?.to_string()),
+            ("data", self.json_ptr(ptr)),
+        ]))
+    }
+
+    /// Checks a global pointer against the data it points to
+    #[cfg(feature = "unstable")]
+    fn ptr_valid(&self, p: AMPointerGlobal) -> bool {
+        p.validate(&self.dgs).unwrap_or(false)
+    }
+
+    /// Formats a global pointer with its checksum status, or null
+    #[cfg(feature = "unstable")]
+    fn json_ptr(&self, p: AMPointerGlobal) -> String {
+        if p.is_null() {
+            return "null".to_owned();
+        }
+        json_object(&[
+            ("geo", p.geo().to_string()),
+            ("dev", p.dev().to_string()),
+            ("loc", p.loc().to_string()),
+            ("valid", self.ptr_valid(p).to_string()),
+        ])
+    }
+
+    /// Formats a local pointer with its checksum status
+    #[cfg(feature = "unstable")]
+    fn json_ptr_local(&self, p: AMPointerLocal) -> String {
+        json_object(&[
+            ("loc", p.loc().to_string()),
+            (
+                "valid",
+                p.validate(self.d.clone()).unwrap_or(false).to_string(),
+            ),
+        ])
+    }
+}
+
+/// Quotes and escapes a JSON string
+#[cfg(feature = "unstable")]
+fn json_str(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c if u32::from(c) < 0x20 => res.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Joins already-formatted values into a JSON array
+#[cfg(feature = "unstable")]
+fn json_array(values: Vec<String>) -> String {
+    format!("[{}]", values.join(","))
+}
+
+/// Joins keys and already-formatted values into a JSON object
+#[cfg(feature = "unstable")]
+fn json_object(fields: &[(&str, String)]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(k, v)| format!("{}:{}", json_str(k), v))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// Reads a little-endian u64 at a byte offset
+#[cfg(feature = "unstable")]
+fn read_u64(buf: &[u8], pos: usize) -> AMResult<u64> {
+    Ok(u64::from_le_bytes(
+        buf[pos..pos + 8].try_into().or(Err(AMError::TODO(0)))?,
+    ))
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_dump(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(1, 4).unwrap();
+    fs.write_object(1, 0, &[1, 2, 3, 4]).unwrap();
+    fs.commit().unwrap();
+
+    let types = classify(&d).unwrap();
+    assert_eq!(
+        types
+            .iter()
+            .filter(|t| matches!(t, BlockType::Superblock(_)))
+            .count(),
+        4
+    );
+    assert!(types.iter().any(|t| matches!(t, BlockType::FSGroup(_))));
+    assert!(!types.iter().any(|t| matches!(t, BlockType::Error)));
+
+    let mut out = Vec::new();
+    dump(d.clone(), &mut out, &DumpOptions::default()).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.starts_with(&format!("Image is {} blocks long\n", d.size().unwrap())));
+    assert!(text.contains("Superblock:"));
+    assert!(text.contains("ObjectSet:"));
+    assert!(!text.contains('\x1b'));
+
+    let mut out = Vec::new();
+    let opts = DumpOptions {
+        color: true,
+        verify_checksums: true,
+        ..DumpOptions::default()
+    };
+    dump(d, &mut out, &opts).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.contains("\x1b[32m"));
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_dump_json(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(1, 4).unwrap();
+    fs.commit().unwrap();
+
+    let mut out = Vec::new();
+    let opts = DumpOptions {
+        format: DumpFormat::Json,
+        ..DumpOptions::default()
+    };
+    dump(d.clone(), &mut out, &opts).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines[0].starts_with(&format!(
+        "{{\"type\":\"image\",\"blocks\":{},",
+        d.size().unwrap()
+    )));
+    let n_blocks = classify(&d)
+        .unwrap()
+        .iter()
+        .filter(|t| !matches!(t, BlockType::Unused))
+        .count();
+    assert_eq!(lines.len(), n_blocks + 1);
+    assert_eq!(
+        lines
+            .iter()
+            .filter(|l| l.starts_with("{\"type\":\"superblock\"") && l.contains("\"checksum\":true"))
+            .count(),
+        4
+    );
+    assert!(lines.iter().any(|l| l.starts_with("{\"type\":\"fsgroup\"")));
+    assert!(lines.iter().all(|l| l.starts_with('{') && l.ends_with('}')));
+
+    assert_eq!(json_str("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_dump_filter(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(1, 4).unwrap();
+    fs.commit().unwrap();
+
+    let dump_json = |opts: DumpOptions| {
+        let mut out = Vec::new();
+        let opts = DumpOptions {
+            format: DumpFormat::Json,
+            ..opts
+        };
+        dump(d.clone(), &mut out, &opts).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    let only = dump_json(DumpOptions {
+        only: ["superblock".to_owned(), "fsgroup".to_owned()]
+            .iter()
+            .cloned()
+            .collect(),
+        ..DumpOptions::default()
+    });
+    assert!(only.lines().skip(1).all(
+        |l| l.starts_with("{\"type\":\"superblock\"") || l.starts_with("{\"type\":\"fsgroup\"")
+    ));
+    assert_eq!(
+        only.lines()
+            .filter(|l| l.contains("\"superblock\""))
+            .count(),
+        4
+    );
+
+    let range = dump_json(DumpOptions {
+        range: Some((0, 2)),
+        ..DumpOptions::default()
+    });
+    assert_eq!(range.lines().count(), 3);
+
+    let all = dump_json(DumpOptions::default());
+    let root = dump_json(DumpOptions {
+        root: Some(0),
+        ..DumpOptions::default()
+    });
+    assert!(root.lines().count() < all.lines().count());
+    assert!(root.lines().any(|l| l.starts_with("{\"type\":\"fsgroup\"")));
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_dump_object(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    let data: Vec<u8> = (0..10000u32)
+        .map(|i| u8::try_from(i % 251).unwrap())
+        .collect();
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &data).unwrap();
+    fs.commit().unwrap();
+
+    let mut out = Vec::new();
+    let opts = DumpOptions {
+        object_data: true,
+        ..DumpOptions::default()
+    };
+    dump_object(d.clone(), 1, &mut out, &opts).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.starts_with(&format!("Object 1:\nsize:{:x} frags:", data.len())));
+    assert!(text.contains(" valid\n"));
+    assert!(!text.contains("invalid"));
+    assert!(text.contains("Data:\n\t000000 : 00 01 02 03 "));
+
+    let mut extracted = Vec::new();
+    assert_eq!(extract_object(d.clone(), 1, &mut extracted).unwrap(), 10000);
+    assert_eq!(extracted, data);
+
+    assert!(dump_object(d, 2, &mut Vec::new(), &DumpOptions::default()).is_err());
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_diff_images() {
+    crate::test::logging::init_log();
+
+    let fs = crate::test::fsinit::create_fs().unwrap();
+    fs.create_object(1, 4).unwrap();
+    fs.commit().unwrap();
+    let orig = format!("{}.orig", fs.file());
+    std::fs::copy(fs.file(), &orig).unwrap();
+
+    fs.write_object(1, 0, &[1]).unwrap();
+    fs.create_object(2, 4).unwrap();
+    fs.commit().unwrap();
+
+    let a = crate::DiskFile::open(&orig).unwrap();
+    let b = crate::DiskFile::open(fs.file()).unwrap();
+    let same = diff_images(&a, &a).unwrap();
+    let diff = diff_images(&a, &b).unwrap();
+    std::fs::remove_file(&orig).unwrap();
+
+    assert!(same.blocks.is_empty() && same.roots.is_empty() && same.objects.is_empty());
+    assert_ne!(diff.latest.0, diff.latest.1);
+    assert!(diff.roots.iter().any(|r| r.0 == diff.latest.1));
+    assert!(diff.blocks.iter().any(|b| b.2 == "fsgroup"));
+    assert!(diff
+        .objects
+        .contains(&ObjectChange::Modified(1, vec![(0, 4)])));
+    assert!(diff.objects.contains(&ObjectChange::Created(2)));
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_check(mut d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[3; 5000]).unwrap();
+    fs.commit().unwrap();
+
+    let report = check(d.clone()).unwrap();
+    assert!(report.blocks > 4);
+    assert!(report.problems.is_empty());
+
+    let (obj, _) = find_object(&d, 1).unwrap();
+    let blk = obj.frags()[0].pointer.loc();
+    let mut buf = [0; BLOCK_SIZE];
+    d.read_at(blk, &mut buf).unwrap();
+    buf[0] ^= 1;
+    d.write_at(blk, &buf).unwrap();
+    let report = check(d).unwrap();
+    assert_eq!(report.problems.len(), 1);
+    assert!(report.problems[0].1.contains("object 1"));
+}
@@ -1,15 +1,23 @@
 #![cfg(not(tarpaulin_include))]
 
-use std::{collections::BTreeSet, convert::TryInto};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::{TryFrom, TryInto},
+    panic::AssertUnwindSafe,
+    sync::{mpsc, Arc, Mutex},
+    thread::JoinHandle,
+};
 
+use amos_std::{error::AMErrorFS, AMResult};
 use bitvec::prelude::*;
 
 use crate::{
-    AMPointerGlobal, AMPointerLocal, AllocListEntry, Allocator, Disk, DiskGroup, FSHandle,
-    FreeQueueEntry, LinkedListGlobal, SIGNATURE,
+    AMFeatures, AMPointerGlobal, AMPointerLocal, AllocListEntry, Allocator, Disk, DiskGroup,
+    FSGroup, FSHandle, FreeQueueEntry, LinkedListGlobal, ObjectSet, RefCountEntry, Superblock,
+    BLOCK_SIZE, SIGNATURE,
 };
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum FSCKErrorLoc {
     Local(AMPointerLocal),
     Global(AMPointerGlobal),
@@ -27,13 +35,36 @@ impl std::convert::From<AMPointerLocal> for FSCKErrorLoc {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum FSCKErrorKind {
     InvalidSuperblock,
     MismatchedSuperblock,
     InvalidGeometry,
     InvalidRoot,
     InvalidObjectSet,
+    InvalidFragment,
+    InvalidAllocList,
+    InvalidFreeQueue,
+    InvalidRefCounts,
+    InvalidAllocator,
+    /// A pointer refers to a disk that isn't being checked
+    MissingDisk,
+    /// A block is in use, but no allocator claims it
+    UnclaimedBlock,
+    /// An allocator claims a block that nothing uses
+    LeakedBlock,
+    /// The free queue holds a block its allocator already has free
+    FreeOfFreeBlock,
+    /// The free queue holds a block the latest objects still use
+    FreeOfLiveBlock,
+    /// The free queue holds a block more than once
+    DuplicateFree,
+    /// A shared extent's reference count doesn't match the fragments referring to it
+    RefCountMismatch,
+    /// A root's transaction ID is no older than the latest root's, or repeats another root's
+    TxidRegression,
+    /// A worker thread failed, so not everything could be checked
+    CheckFailed,
 }
 
 #[derive(Debug)]
@@ -42,14 +73,170 @@ pub struct FSCKError {
     kind:     FSCKErrorKind,
 }
 
+/// How serious a finding is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FSCKSeverity {
+    /// Wasted space, but no data at risk
+    Warning,
+    /// Damaged or inconsistent metadata
+    Error,
+}
+
+/// A single problem found by a check
+#[derive(Debug, PartialEq, Eq)]
+pub struct FSCKFinding {
+    /// How serious the problem is
+    pub severity: FSCKSeverity,
+    /// Where the problem was found
+    pub location: FSCKErrorLoc,
+    /// What the problem is
+    pub kind:     FSCKErrorKind,
+    /// Whether the check repaired the problem
+    pub fixed:    bool,
+}
+
+/// A pass of the check, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FSCKPhase {
+    Superblocks,
+    Geometries,
+    Roots,
+    ObjectSets,
+    Objects,
+    AllocLists,
+    FreeQueues,
+    RefCounts,
+    Allocators,
+    /// Comparing the blocks in use against the allocators, one disk block at a time
+    Reconcile,
+    /// Looking for free queue entries that would free a block twice
+    DoubleFrees,
+}
+
+impl FSCKPhase {
+    /// Every pass, in the order they run
+    pub const ALL: [FSCKPhase; 11] = [
+        FSCKPhase::Superblocks,
+        FSCKPhase::Geometries,
+        FSCKPhase::Roots,
+        FSCKPhase::ObjectSets,
+        FSCKPhase::Objects,
+        FSCKPhase::AllocLists,
+        FSCKPhase::FreeQueues,
+        FSCKPhase::RefCounts,
+        FSCKPhase::Allocators,
+        FSCKPhase::Reconcile,
+        FSCKPhase::DoubleFrees,
+    ];
+}
+
+/// How a check is run
+#[derive(Debug, Clone)]
+pub struct FsckOptions {
+    /// Stop at the first damaged structure instead of carrying on
+    pub stop_on_error: bool,
+    /// Log every block checked, rather than only each pass and its problems
+    pub verbose:       bool,
+    /// Repair what can be repaired, committing the result
+    pub fix:           bool,
+    /// The passes to run. Superblocks, geometries and roots are always checked, as everything
+    /// else is found through them. Reconciling only runs along with every other pass.
+    pub passes:        BTreeSet<FSCKPhase>,
+}
+
+impl Default for FsckOptions {
+    #[cfg(feature = "unstable")]
+    fn default() -> Self {
+        Self {
+            stop_on_error: false,
+            verbose:       true,
+            fix:           false,
+            passes:        FSCKPhase::ALL.iter().copied().collect(),
+        }
+    }
+}
+
+/// How many fragments are read before their checksums are checked together
+const VALIDATE_BATCH: usize = 256;
+
+/// How many blocks the reconcile pass checks between progress reports
+const PROGRESS_INTERVAL: usize = 65536;
+
+/// Totals over the findings of a check
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FSCKSummary {
+    /// Findings of error severity
+    pub errors:         usize,
+    /// Errors the check repaired
+    pub fixed:          usize,
+    /// Findings of warning severity
+    pub warnings:       usize,
+    /// Blocks found in use and checked
+    pub blocks_scanned: u64,
+    /// How many findings there were of each kind
+    pub kinds:          BTreeMap<FSCKErrorKind, usize>,
+}
+
+impl FSCKSummary {
+    /// Totals up a list of findings
+    #[cfg(feature = "unstable")]
+    pub fn new(findings: &[FSCKFinding], blocks_scanned: u64) -> Self {
+        let mut res = Self {
+            blocks_scanned,
+            ..Self::default()
+        };
+        for f in findings {
+            match f.severity {
+                FSCKSeverity::Error if f.fixed => {
+                    res.errors += 1;
+                    res.fixed += 1;
+                }
+                FSCKSeverity::Error => res.errors += 1,
+                FSCKSeverity::Warning => res.warnings += 1,
+            }
+            *res.kinds.entry(f.kind).or_insert(0) += 1;
+        }
+        res
+    }
+}
+
+/// Everything a check found
+#[derive(Debug)]
+pub struct FSCKReport {
+    /// Each problem found, in the order found
+    pub findings: Vec<FSCKFinding>,
+    /// Totals over the findings
+    pub summary:  FSCKSummary,
+}
+
+macro_rules! report {
+    ($findings:expr, $severity:ident, $loc:expr, $kind:expr) => {
+        $findings.push(FSCKFinding {
+            severity: FSCKSeverity::$severity,
+            location: $loc.into(),
+            kind:     $kind,
+            fixed:    false,
+        })
+    };
+}
+
+macro_rules! detail {
+    ($opts:expr, $($arg:tt)*) => {
+        if $opts.verbose {
+            info!($($arg)*);
+        }
+    };
+}
+
 macro_rules! return_error {
-    ($loc:expr, $err:expr) => {
-        if cfg!(feature = "halt_on_err") {
+    ($opts:expr, $findings:expr, $loc:expr, $err:expr) => {
+        if $opts.stop_on_error {
             return Err(FSCKError {
                 location: $loc.into(),
                 kind:     $err,
             });
         }
+        report!($findings, Error, $loc, $err);
     };
 }
 
@@ -62,9 +249,97 @@ macro_rules! return_error_always {
     };
 }
 
-/// Checks the filesystem on a single disk
+/// A fragment's result from a worker: its index in the batch, and whether it passed, unless the
+/// check panicked
+type Validated = (usize, std::thread::Result<bool>);
+
+/// Worker threads that read fragments and check their checksums, kept for a whole scan
+struct Validator {
+    jobs:    mpsc::Sender<(usize, AMPointerGlobal)>,
+    results: mpsc::Receiver<Validated>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Validator {
+    /// Starts a worker for each available CPU, each reading through its own copy of the disk groups
+    #[cfg(feature = "unstable")]
+    fn new(dgs: &[Option<DiskGroup>]) -> Self {
+        let (jobs, queue) = mpsc::channel::<(usize, AMPointerGlobal)>();
+        let queue = Arc::new(Mutex::new(queue));
+        let (done, results) = mpsc::channel();
+        let workers = (0..std::thread::available_parallelism().map_or(1, |n| n.get()))
+            .map(|_| {
+                let (queue, done, dgs) = (queue.clone(), done.clone(), dgs.to_vec());
+                std::thread::spawn(move || loop {
+                    // The queue is only locked while taking a job, so reads run in parallel
+                    let job = match queue.lock() {
+                        Ok(queue) => queue.recv(),
+                        Err(_) => break,
+                    };
+                    let (i, ptr) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let ok = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        ptr.read_vec(&dgs).is_ok_and(|b| ptr.validate_data(&b))
+                    }));
+                    if done.send((i, ok)).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+        Self {
+            jobs,
+            results,
+            workers,
+        }
+    }
+    /// Checks a batch of fragments. Fragments that couldn't be read fail.
+    /// Returns whether each passed, or a fragment whose check couldn't finish.
+    #[cfg(feature = "unstable")]
+    fn validate(&self, ptrs: &[(u64, AMPointerGlobal)]) -> Result<Vec<bool>, AMPointerGlobal> {
+        for (i, (_, ptr)) in ptrs.iter().enumerate() {
+            self.jobs.send((i, *ptr)).or(Err(*ptr))?;
+        }
+        let mut res = vec![false; ptrs.len()];
+        let mut failed = None;
+        for (_, ptr) in ptrs {
+            match self.results.recv() {
+                Ok((i, Ok(ok))) => res[i] = ok,
+                Ok((i, Err(_))) => failed = failed.or(Some(ptrs[i].1)),
+                Err(_) => return Err(*ptr),
+            }
+        }
+        failed.map_or(Ok(res), Err)
+    }
+    /// Stops the workers, returning whether they all exited cleanly
+    #[cfg(feature = "unstable")]
+    fn finish(self) -> bool {
+        drop(self.jobs);
+        self.workers.into_iter().all(|w| w.join().is_ok())
+    }
+}
+
+/// Checks the filesystem on a single disk, returning everything found wrong and a summary.
+/// Unless `opts` says to stop on errors, only an unreadable superblock stops the check early.
+/// `progress` is called with each phase and how many of its blocks have been checked out of how many.
 #[cfg(feature = "unstable")]
-pub fn fsck_single_scan(d: Disk) -> Result<(), FSCKError> {
+pub fn fsck_single_scan<F: FnMut(FSCKPhase, u64, u64)>(
+    d: Disk,
+    opts: &FsckOptions,
+    mut progress: F,
+) -> Result<FSCKReport, FSCKError> {
+    // Each phase runs in its own span, entered when it first reports progress
+    let mut phase_span: Option<(FSCKPhase, tracing::span::EnteredSpan)> = None;
+    let mut progress = |phase: FSCKPhase, done: u64, total: u64| {
+        if phase_span.as_ref().map(|(p, _)| *p) != Some(phase) {
+            phase_span = None;
+            phase_span = Some((phase, tracing::info_span!("fsck", ?phase).entered()));
+        }
+        progress(phase, done, total);
+    };
+    let mut findings = Vec::new();
     let mut allocs_ok = true;
 
     let mut blockmap = BitVec::<u8, Msb0>::new();
@@ -82,10 +357,13 @@ pub fn fsck_single_scan(d: Disk) -> Result<(), FSCKError> {
     info!("Verifying superblocks...");
     let mut geom_locs = BTreeSet::new();
     let mut root_locs = BTreeSet::new();
+    let mut latest_root = None;
     let mut d_id = None;
-    for loc in sb_locs {
+    let total = sb_locs.len() as u64;
+    for (n, loc) in sb_locs.iter().copied().enumerate() {
+        progress(FSCKPhase::Superblocks, n as u64, total);
         blockmap.set(loc.loc().try_into().expect("E"), true);
-        info!("\tVerifying superblock at {}", loc);
+        detail!(opts, "\tVerifying superblock at {}", loc);
         let sb = crate::Superblock::read(d.clone(), loc).ok();
         let sb = if let Some(sb) = sb {
             for i in 0..16 {
@@ -94,8 +372,9 @@ pub fn fsck_single_scan(d: Disk) -> Result<(), FSCKError> {
             for i in 0..128 {
                 root_locs.insert(sb.rootnodes(i));
             }
+            latest_root = Some(sb.rootnodes(usize::from(sb.latest_root())));
             d_id = Some(sb.devid());
-            info!("\t\tOK!");
+            detail!(opts, "\t\tOK!");
             sb
         } else {
             warn!("\t\tNot OK");
@@ -120,7 +399,7 @@ pub fn fsck_single_scan(d: Disk) -> Result<(), FSCKError> {
             {
                 warn!("\t\t\tMismatched device ID");
                 allocs_ok = false;
-                return_error!(loc, FSCKErrorKind::MismatchedSuperblock);
+                return_error!(opts, findings, loc, FSCKErrorKind::MismatchedSuperblock);
             }
             if sb.features()
                 != fs
@@ -132,7 +411,7 @@ pub fn fsck_single_scan(d: Disk) -> Result<(), FSCKError> {
             {
                 warn!("\t\t\tMismatched feature flags");
                 allocs_ok = false;
-                return_error!(loc, FSCKErrorKind::MismatchedSuperblock);
+                return_error!(opts, findings, loc, FSCKErrorKind::MismatchedSuperblock);
             }
             if sb.latest_root()
                 != fs
@@ -144,7 +423,22 @@ pub fn fsck_single_scan(d: Disk) -> Result<(), FSCKError> {
             {
                 warn!("\t\t\tMismatched latest root index");
                 allocs_ok = false;
-                return_error!(loc, FSCKErrorKind::MismatchedSuperblock);
+                return_error!(opts, findings, loc, FSCKErrorKind::MismatchedSuperblock);
+            }
+            let generation = fs
+                .read()
+                .expect("Poisoned mutex")
+                .get_superblock()
+                .expect("Invalid superblock")
+                .generation();
+            if sb.generation() != generation {
+                warn!(
+                    "\t\t\tStale copy, generation {} of {}",
+                    sb.generation(),
+                    generation
+                );
+                allocs_ok = false;
+                return_error!(opts, findings, loc, FSCKErrorKind::MismatchedSuperblock);
             }
             for i in 0..128 {
                 if sb.rootnodes(i)
@@ -157,28 +451,35 @@ pub fn fsck_single_scan(d: Disk) -> Result<(), FSCKError> {
                 {
                     warn!("\t\t\tMismatched root node {}", i);
                     allocs_ok = false;
-                    return_error!(loc, FSCKErrorKind::MismatchedSuperblock);
+                    return_error!(opts, findings, loc, FSCKErrorKind::MismatchedSuperblock);
                 }
             }
         }
     }
+    progress(FSCKPhase::Superblocks, total, total);
     let mut d_geo = None;
     info!("Verifying geometries...");
-    for loc in geom_locs {
+    let total = geom_locs.len() as u64;
+    for (n, loc) in geom_locs.into_iter().enumerate() {
+        progress(FSCKPhase::Geometries, n as u64, total);
         if loc.is_null() {
             continue;
         }
         blockmap.set(loc.loc().try_into().expect("Bitness error"), true);
-        info!("\tVerifying geometry at {}", loc);
+        detail!(opts, "\tVerifying geometry at {}", loc);
         let geo = crate::Geometry::read(d.clone(), loc).ok();
         if let Some(geo) = geo {
+            for blk in geo.continuation_blocks() {
+                blockmap.set(blk.loc().try_into().expect("Bitness error"), true);
+            }
             d_geo = Some(geo);
-            info!("\t\tOK!");
+            detail!(opts, "\t\tOK!");
         } else {
             warn!("\t\tNot OK");
-            return_error!(loc, FSCKErrorKind::InvalidGeometry);
+            return_error!(opts, findings, loc, FSCKErrorKind::InvalidGeometry);
         }
     }
+    progress(FSCKPhase::Geometries, total, total);
     let diskgroups = DiskGroup::from_geo(
         d_geo.expect("No intact geometry"),
         &[d_id.expect("No intact superblock")],
@@ -189,7 +490,12 @@ pub fn fsck_single_scan(d: Disk) -> Result<(), FSCKError> {
     let mut alloclist_locs = BTreeSet::new();
     let mut objectset_locs = BTreeSet::new();
     let mut freequeue_locs = BTreeSet::new();
-    for loc in root_locs {
+    let mut refcount_locs = BTreeSet::new();
+    let mut roots = Vec::new();
+    let mut txids = BTreeMap::new();
+    let total = root_locs.len() as u64;
+    for (n, loc) in root_locs.into_iter().enumerate() {
+        progress(FSCKPhase::Roots, n as u64, total);
         if loc.is_null() {
             continue;
         }
@@ -197,25 +503,50 @@ pub fn fsck_single_scan(d: Disk) -> Result<(), FSCKError> {
             blockmap.set(loc.loc().try_into().expect("Bitness error"), true);
         } else {
             warn!("We don't have a disk for {}", loc);
+            report!(findings, Warning, loc, FSCKErrorKind::MissingDisk);
         }
-        info!("\tVerifying rootnode at {}", loc);
+        detail!(opts, "\tVerifying rootnode at {}", loc);
         let root = crate::FSGroup::read(&[Some(diskgroups.clone())], loc).ok();
         if let Some(root) = root {
-            info!("\t\tOK!");
+            detail!(opts, "\t\tOK!");
+            txids.insert(loc, root.txid());
             alloclist_locs.insert(root.alloc());
             objectset_locs.insert(root.objects());
             if !root.free_queue().is_null() {
                 freequeue_locs.insert(root.free_queue());
             }
+            if !root.refcounts().is_null() {
+                refcount_locs.insert(root.refcounts());
+            }
+            roots.push(root);
         } else {
             warn!("\t\tNot OK");
-            return_error!(loc, FSCKErrorKind::InvalidRoot);
+            return_error!(opts, findings, loc, FSCKErrorKind::InvalidRoot);
             allocs_ok = false;
         }
     }
+    // Commits number roots in order, so the latest root must be the newest
+    if let Some((latest, newest)) = latest_root.and_then(|p| Some((p, *txids.get(&p)?))) {
+        let mut seen = BTreeSet::new();
+        for (loc, txid) in txids.iter().filter(|(loc, _)| **loc != latest) {
+            if *txid >= newest || !seen.insert(*txid) {
+                warn!(
+                    "\tRoot at {} has txid {}, but the latest root's is {}",
+                    loc, txid, newest
+                );
+                return_error!(opts, findings, *loc, FSCKErrorKind::TxidRegression);
+            }
+        }
+    }
+    progress(FSCKPhase::Roots, total, total);
     info!("Verifying objectsets...");
     let mut objects = BTreeSet::new();
-    for loc in objectset_locs {
+    if !opts.passes.contains(&FSCKPhase::ObjectSets) {
+        objectset_locs.clear();
+    }
+    let total = objectset_locs.len() as u64;
+    for (n, loc) in objectset_locs.into_iter().enumerate() {
+        progress(FSCKPhase::ObjectSets, n as u64, total);
         if loc.is_null() {
             continue;
         }
@@ -223,9 +554,10 @@ pub fn fsck_single_scan(d: Disk) -> Result<(), FSCKError> {
             blockmap.set(loc.loc().try_into().expect("Bitness error"), true);
         } else {
             warn!("We don't have a disk for {}", loc);
+            report!(findings, Warning, loc, FSCKErrorKind::MissingDisk);
         }
-        info!("\tVerifying objectset at {}", loc);
-        let objs = crate::ObjectSet::read(
+        detail!(opts, "\tVerifying objectset at {}", loc);
+        let objset = crate::ObjectSet::read(
             vec![
                 Some(diskgroups.clone()),
                 None,
@@ -245,57 +577,126 @@ pub fn fsck_single_scan(d: Disk) -> Result<(), FSCKError> {
                 None,
             ],
             loc,
-        )
-        .get_objects()
-        .ok();
+        );
+        if let Ok(blocks) = objset.get_blocks() {
+            for blk in blocks {
+                if blk.dev() == 0 && blk.geo() == 0 {
+                    blockmap.set(blk.loc().try_into().expect("Bitness error"), true);
+                }
+            }
+        }
+        let objs = objset.get_objects().ok();
         if let Some(objs) = objs {
-            info!("\t\tOK!");
+            detail!(opts, "\t\tOK!");
             for (i, o) in objs {
                 objects.insert((i, o));
             }
         } else {
             warn!("\t\tNot OK");
-            return_error!(loc, FSCKErrorKind::InvalidObjectSet);
+            return_error!(opts, findings, loc, FSCKErrorKind::InvalidObjectSet);
         }
     }
+    progress(FSCKPhase::ObjectSets, total, total);
     info!("Verifying objects...");
-    for (id, obj) in objects {
-        for frag in obj.frags() {
-            info!("\tVerifying object {}, fragment at {}", id, frag.pointer);
-            if frag
-                .pointer
-                .validate(&[Some(diskgroups.clone())])
-                .expect("E")
-            {
-                info!("\t\tOK!");
+    if !opts.passes.contains(&FSCKPhase::Objects) {
+        objects.clear();
+    }
+    let dgs = [Some(diskgroups.clone())];
+    // Versions kept of objects are checked along with the objects
+    for root in roots
+        .iter()
+        .filter(|_| opts.passes.contains(&FSCKPhase::Objects))
+    {
+        if let Ok(blocks) = root.get_version_blocks(&dgs) {
+            for blk in blocks {
+                blockmap.set(blk.loc().try_into().expect("Bitness error"), true);
+            }
+        }
+        match root.get_versions(&dgs) {
+            Ok(versions) => {
+                for (id, history) in versions {
+                    objects.extend(history.versions.into_values().map(|obj| (id, obj)));
+                }
+            }
+            Err(_) => {
+                warn!("\tVersions of root {} Not OK", root.txid());
+                return_error!(
+                    opts,
+                    findings,
+                    root.versions(),
+                    FSCKErrorKind::InvalidObjectSet
+                );
+            }
+        }
+    }
+    let frags: Vec<(u64, AMPointerGlobal)> = objects
+        .iter()
+        .flat_map(|(id, obj)| {
+            obj.frags()
+                .into_iter()
+                .filter(|f| !f.is_hole())
+                .map(move |f| (*id, f.pointer))
+        })
+        .collect();
+    let total = frags.len() as u64;
+    let validator = Validator::new(&dgs);
+    for (n, batch) in frags.chunks(VALIDATE_BATCH).enumerate() {
+        progress(FSCKPhase::Objects, (n * VALIDATE_BATCH) as u64, total);
+        let valid = match validator.validate(batch) {
+            Ok(valid) => valid,
+            Err(ptr) => {
+                return_error_always!(ptr, FSCKErrorKind::CheckFailed);
+            }
+        };
+        for ((id, ptr), ok) in batch.iter().zip(valid) {
+            detail!(opts, "\tVerifying object {}, fragment at {}", id, ptr);
+            if ok {
+                detail!(opts, "\t\tOK!");
             } else {
                 warn!("\t\tNot OK!");
+                return_error!(opts, findings, *ptr, FSCKErrorKind::InvalidFragment);
             }
-            blockmap.set(frag.pointer.loc().try_into().expect("Bitness error"), true);
+            blockmap.set(ptr.loc().try_into().expect("Bitness error"), true);
         }
     }
+    if !validator.finish() {
+        return_error_always!(AMPointerGlobal::null(), FSCKErrorKind::CheckFailed);
+    }
+    progress(FSCKPhase::Objects, total, total);
     info!("Verifying alloclists...");
     let mut alloc_locs = BTreeSet::new();
-    for loc in alloclist_locs {
-        info!("\tVerifying alloclist at {}", loc);
+    if !opts.passes.contains(&FSCKPhase::AllocLists) {
+        alloclist_locs.clear();
+    }
+    let total = alloclist_locs.len() as u64;
+    for (n, loc) in alloclist_locs.into_iter().enumerate() {
+        progress(FSCKPhase::AllocLists, n as u64, total);
+        detail!(opts, "\tVerifying alloclist at {}", loc);
         let allocs: Option<Vec<AllocListEntry>> = <Vec<AllocListEntry> as LinkedListGlobal<
             Vec<AllocListEntry>,
         >>::read(&[Some(diskgroups.clone())], loc)
         .ok();
         if let Some(allocs) = allocs {
-            info!("\t\tOK!");
+            detail!(opts, "\t\tOK!");
             for alloc in allocs {
                 alloc_locs.insert(alloc.allocator);
             }
         } else {
             warn!("\t\tNot OK!");
+            return_error!(opts, findings, loc, FSCKErrorKind::InvalidAllocList);
             allocs_ok = false;
         }
         blockmap.set(loc.loc().try_into().expect("Bitness error"), true);
     }
+    progress(FSCKPhase::AllocLists, total, total);
     info!("Verifying freequeue...");
-    for loc in freequeue_locs {
-        info!("\tVerifying freequeue at {}", loc);
+    if !opts.passes.contains(&FSCKPhase::FreeQueues) {
+        freequeue_locs.clear();
+    }
+    let total = freequeue_locs.len() as u64;
+    for (n, loc) in freequeue_locs.into_iter().enumerate() {
+        progress(FSCKPhase::FreeQueues, n as u64, total);
+        detail!(opts, "\tVerifying freequeue at {}", loc);
         let queue: Option<Vec<FreeQueueEntry>> = <Vec<FreeQueueEntry> as LinkedListGlobal<
             Vec<FreeQueueEntry>,
         >>::read(&[Some(diskgroups.clone())], loc)
@@ -304,28 +705,59 @@ pub fn fsck_single_scan(d: Disk) -> Result<(), FSCKError> {
             for e in queue {
                 blockmap.set(e.block.loc().try_into().expect("Bitness error"), true);
             }
-            info!("\t\tOK!");
+            detail!(opts, "\t\tOK!");
         } else {
             warn!("\t\tNot OK!");
+            return_error!(opts, findings, loc, FSCKErrorKind::InvalidFreeQueue);
             allocs_ok = false;
         }
         blockmap.set(loc.loc().try_into().expect("Bitness error"), true);
     }
+    progress(FSCKPhase::FreeQueues, total, total);
+    info!("Verifying refcounts...");
+    if !opts.passes.contains(&FSCKPhase::RefCounts) {
+        refcount_locs.clear();
+    }
+    let total = refcount_locs.len() as u64;
+    for (n, loc) in refcount_locs.into_iter().enumerate() {
+        progress(FSCKPhase::RefCounts, n as u64, total);
+        detail!(opts, "\tVerifying refcounts at {}", loc);
+        let counts: Option<Vec<RefCountEntry>> = <Vec<RefCountEntry> as LinkedListGlobal<
+            Vec<RefCountEntry>,
+        >>::read(&[Some(diskgroups.clone())], loc)
+        .ok();
+        if counts.is_some() {
+            detail!(opts, "\t\tOK!");
+        } else {
+            warn!("\t\tNot OK!");
+            return_error!(opts, findings, loc, FSCKErrorKind::InvalidRefCounts);
+            allocs_ok = false;
+        }
+        blockmap.set(loc.loc().try_into().expect("Bitness error"), true);
+    }
+    progress(FSCKPhase::RefCounts, total, total);
     info!("Verifying allocators...");
     let mut allocs = Vec::new();
-    for loc in alloc_locs {
-        info!("\tVerifying allocator at {}", loc);
+    if !opts.passes.contains(&FSCKPhase::Allocators) {
+        alloc_locs.clear();
+    }
+    let total = alloc_locs.len() as u64;
+    for (n, loc) in alloc_locs.into_iter().enumerate() {
+        progress(FSCKPhase::Allocators, n as u64, total);
+        detail!(opts, "\tVerifying allocator at {}", loc);
         let alloc = Allocator::read(&[Some(diskgroups.clone())], loc).ok();
         if let Some(alloc) = alloc {
-            info!("\t\tOK!");
+            detail!(opts, "\t\tOK!");
             allocs.push(alloc);
         } else {
             warn!("\t\tNot OK!");
+            return_error!(opts, findings, loc, FSCKErrorKind::InvalidAllocator);
             allocs_ok = false;
         }
         blockmap.set(loc.loc().try_into().expect("Bitness error"), true);
     }
-    if allocs_ok {
+    progress(FSCKPhase::Allocators, total, total);
+    if allocs_ok && FSCKPhase::ALL.iter().all(|p| opts.passes.contains(p)) {
         info!("Reconciling claimed blocks...");
         let mut blockmap_alloc = BitVec::<u8, Msb0>::new();
         blockmap_alloc.resize(
@@ -345,20 +777,570 @@ pub fn fsck_single_scan(d: Disk) -> Result<(), FSCKError> {
             }
         }
         let mut ok = true;
+        let total = blockmap.len() as u64;
         for i in 0..blockmap.len() {
+            if i % PROGRESS_INTERVAL == 0 {
+                progress(FSCKPhase::Reconcile, i as u64, total);
+            }
             if blockmap[i] && !blockmap_alloc[i] {
                 error!("\tBlock {} in use but unclaimed", i);
+                let loc = AMPointerLocal::new(i as u64);
+                return_error!(opts, findings, loc, FSCKErrorKind::UnclaimedBlock);
                 ok = false;
             }
             if !blockmap[i] && blockmap_alloc[i] {
                 warn!("\tBlock {} unused but claimed", i);
+                let loc = AMPointerLocal::new(i as u64);
+                report!(findings, Warning, loc, FSCKErrorKind::LeakedBlock);
                 ok = false;
             }
         }
+        progress(FSCKPhase::Reconcile, total, total);
         if ok {
-            info!("\tOK!");
+            detail!(opts, "\tOK!");
+        }
+    }
+    // These run last, as fixing commits a new root the other passes haven't seen
+    if let (Some(fs), true) = (&fs, opts.passes.contains(&FSCKPhase::RefCounts)) {
+        info!("Cross-checking refcounts...");
+        match refcount_mismatches(fs) {
+            Ok((counts, bad)) => {
+                let fixed = opts.fix && !bad.is_empty() && fix_refcounts(fs, counts).is_ok();
+                for loc in bad {
+                    warn!("\tRefcount of extent at {:?} is wrong", loc);
+                    return_error!(opts, findings, loc, FSCKErrorKind::RefCountMismatch);
+                    if let Some(f) = findings.last_mut() {
+                        f.fixed = fixed;
+                    }
+                }
+            }
+            Err(e) => warn!("\tCould not count references: {:?}", e),
+        }
+    }
+    if let (Some(fs), true) = (&fs, opts.passes.contains(&FSCKPhase::DoubleFrees)) {
+        info!("Cross-checking free queue...");
+        progress(FSCKPhase::DoubleFrees, 0, 1);
+        match double_frees(fs) {
+            Ok(bad) => {
+                let fixed = opts.fix && !bad.is_empty() && fix_double_frees(fs, &bad).is_ok();
+                for (_, ptr, kind) in bad {
+                    warn!("\tFree queue entry {} is a double free: {:?}", ptr, kind);
+                    return_error!(opts, findings, ptr, kind);
+                    if let Some(f) = findings.last_mut() {
+                        f.fixed = fixed;
+                    }
+                }
+            }
+            Err(e) => match crate::take_error_location() {
+                Some(loc) => warn!("\tCould not read free queue: {:?} at {}", e, loc),
+                None => warn!("\tCould not read free queue: {:?}", e),
+            },
+        }
+        progress(FSCKPhase::DoubleFrees, 1, 1);
+    }
+
+    let summary = FSCKSummary::new(&findings, blockmap.count_ones() as u64);
+    info!(
+        "{} errors ({} fixed), {} warnings, {} blocks scanned",
+        summary.errors, summary.fixed, summary.warnings, summary.blocks_scanned
+    );
+    Ok(FSCKReport { findings, summary })
+}
+
+/// Finds free queue entries that would free a block twice: blocks their allocator already has free,
+/// blocks the latest objects still use, and blocks queued more than once
+#[cfg(feature = "unstable")]
+fn double_frees(fs: &FSHandle) -> AMResult<Vec<(u128, AMPointerGlobal, FSCKErrorKind)>> {
+    let handle = fs.read()?;
+    let objs = handle.get_objects()?;
+    let mut live: BTreeSet<u64> = objs.get_blocks()?.iter().map(|b| b.loc()).collect();
+    for obj in objs.get_objects()?.values() {
+        for f in obj.frags() {
+            if !f.is_hole() {
+                live.insert(f.pointer.loc());
+            }
+        }
+    }
+    let extents = handle.get_diskgroups()[0]
+        .as_ref()
+        .ok_or(AMErrorFS::NoDiskgroup)?
+        .allocs[0]
+        .extents();
+    let mut seen = BTreeSet::new();
+    let mut res = Vec::new();
+    for (txid, ptrs) in handle.get_free_queue() {
+        for ptr in ptrs {
+            let allocated = extents
+                .range(..=ptr.loc())
+                .next_back()
+                .is_some_and(|(start, ext)| ext.used && start + ext.size > ptr.loc());
+            let kind = if !seen.insert(ptr.loc()) {
+                FSCKErrorKind::DuplicateFree
+            } else if !allocated {
+                FSCKErrorKind::FreeOfFreeBlock
+            } else if live.contains(&ptr.loc()) {
+                FSCKErrorKind::FreeOfLiveBlock
+            } else {
+                continue;
+            };
+            res.push((*txid, *ptr, kind));
         }
     }
+    Ok(res)
+}
+
+/// Counts the fragments referring to each extent of the latest objects, and compares the counts
+/// of shared extents against the stored refcounts. Returns the counts the refcounts should hold,
+/// along with the extents whose stored count is wrong or missing.
+#[cfg(feature = "unstable")]
+fn refcount_mismatches(
+    fs: &FSHandle,
+) -> AMResult<(BTreeMap<AMPointerGlobal, u64>, Vec<FSCKErrorLoc>)> {
+    let handle = fs.read()?;
+    let mut refs: BTreeMap<AMPointerGlobal, u64> = BTreeMap::new();
+    let objs = handle.get_objects()?.get_objects()?;
+    let kept = handle
+        .get_versions()
+        .values()
+        .flat_map(|h| h.versions.values());
+    for obj in objs.values().chain(kept) {
+        for f in obj.frags() {
+            if !f.is_hole() {
+                *refs.entry(f.pointer).or_insert(0) += 1;
+            }
+        }
+    }
+    let counts: BTreeMap<AMPointerGlobal, u64> = refs.into_iter().filter(|(_, n)| *n > 1).collect();
+    let stored = handle.get_refcounts();
+    let mut bad = Vec::new();
+    for (ptr, n) in &counts {
+        if stored.get(ptr) != Some(n) {
+            bad.push(FSCKErrorLoc::Global(*ptr));
+        }
+    }
+    for ptr in stored.keys() {
+        if !counts.contains_key(ptr) {
+            bad.push(FSCKErrorLoc::Global(*ptr));
+        }
+    }
+    Ok((counts, bad))
+}
+
+/// Replaces the stored refcounts with the counted ones, then commits
+#[cfg(feature = "unstable")]
+fn fix_refcounts(fs: &FSHandle, counts: BTreeMap<AMPointerGlobal, u64>) -> AMResult<()> {
+    fs.write()?.set_refcounts(counts);
+    fs.commit()
+}
+
+/// Drops double frees from the free queue, then commits
+#[cfg(feature = "unstable")]
+fn fix_double_frees(fs: &FSHandle, bad: &[(u128, AMPointerGlobal, FSCKErrorKind)]) -> AMResult<()> {
+    let entries: Vec<(u128, AMPointerGlobal)> = bad.iter().map(|(t, p, _)| (*t, *p)).collect();
+    fs.write()?.drop_frees(&entries);
+    fs.commit()?;
+    let blocks = entries.iter().map(|(_, p)| u64::from(p.length())).sum();
+    fs.record_repair(blocks);
+    Ok(())
+}
 
+/// Marks an extent reachable. Extent starts are kept apart, so neighbouring extents stay separate.
+#[cfg(feature = "unstable")]
+fn mark_reachable(
+    used: &mut BitVec<u8, Msb0>,
+    starts: &mut BitVec<u8, Msb0>,
+    start: u64,
+    size: u64,
+) -> AMResult<()> {
+    let start = usize::try_from(start)?;
+    assert_or_err!(start < used.len(), AMErrorFS::NullPointer);
+    starts.set(start, true);
+    for i in start..std::cmp::min(start + usize::try_from(size)?, used.len()) {
+        used.set(i, true);
+    }
     Ok(())
 }
+
+/// Rebuilds the allocator of a single disk from the blocks reachable from its latest intact root,
+/// recovering a volume whose allocators are too damaged to mount.
+/// The rebuilt root group is written to a fresh slot and replaces every other root.
+#[cfg(feature = "unstable")]
+pub fn fsck_single_rebuild(mut d: Disk) -> AMResult<()> {
+    let header_locs = d.get_header_locs()?;
+    info!("Finding latest root...");
+    let mut latest: Option<(Superblock, DiskGroup, FSGroup)> = None;
+    for loc in header_locs {
+        let found = Superblock::read(d.clone(), loc).and_then(|sb| {
+            let dg =
+                DiskGroup::from_geo(sb.get_geometry(d.clone(), 0)?, &[sb.devid()], &[d.clone()])?;
+            let root = sb.get_group(&[Some(dg.clone())])?;
+            Ok((sb, dg, root))
+        });
+        if let Ok((sb, dg, root)) = found {
+            if latest
+                .as_ref()
+                .is_none_or(|l| (root.txid(), sb.generation()) > (l.2.txid(), l.0.generation()))
+            {
+                latest = Some((sb, dg, root));
+            }
+        } else {
+            warn!("\tSuperblock at {} unusable", loc);
+        }
+    }
+    let (mut sb, mut dg, old_root) = latest.ok_or(AMErrorFS::NoSuperblock)?;
+    info!("\tUsing root group {}", old_root.txid());
+
+    info!("Marking reachable blocks...");
+    let size = usize::try_from(d.size()?)?;
+    let mut used = BitVec::<u8, Msb0>::repeat(false, size);
+    let mut starts = BitVec::<u8, Msb0>::repeat(false, size);
+    for loc in header_locs {
+        mark_reachable(&mut used, &mut starts, loc.loc(), 1)?;
+    }
+    for i in 0..16 {
+        if !sb.geometries(i).is_null() {
+            mark_reachable(&mut used, &mut starts, sb.geometries(i).loc(), 1)?;
+            if let Ok(geo) = crate::Geometry::read(d.clone(), sb.geometries(i)) {
+                for blk in geo.continuation_blocks() {
+                    mark_reachable(&mut used, &mut starts, blk.loc(), 1)?;
+                }
+            }
+        }
+    }
+    let mut dgs = vec![None; 16];
+    dgs[0] = Some(dg.clone());
+    let objs = ObjectSet::read(dgs, old_root.objects);
+    for blk in objs.get_blocks()? {
+        mark_reachable(&mut used, &mut starts, blk.loc(), 1)?;
+    }
+    let block_size = u64::try_from(BLOCK_SIZE)?;
+    for obj in objs.get_objects()?.values() {
+        for frag in obj.frags() {
+            if !frag.is_hole() {
+                let blocks = (frag.offset + frag.size + block_size - 1) / block_size;
+                mark_reachable(&mut used, &mut starts, frag.pointer.loc(), blocks)?;
+            }
+        }
+    }
+    let refcounts = old_root.get_refcounts(&[Some(dg.clone())])?;
+
+    info!("Building allocator...");
+    let mut alloc = Allocator::new(d.size()?);
+    let mut i = 0;
+    while i < size {
+        if starts[i] {
+            let mut end = i + 1;
+            while end < size && used[end] && !starts[end] {
+                end += 1;
+            }
+            alloc.mark_used(i.try_into()?, (end - i).try_into()?)?;
+            i = end;
+        } else {
+            i += 1;
+        }
+    }
+    info!(
+        "\t{} of {} blocks in use",
+        alloc.used_space(),
+        alloc.total_space()
+    );
+
+    info!("Writing new root group...");
+    dg.load_allocators(std::iter::once((sb.devid(), alloc.clone())).collect())?;
+    let mut root = FSGroup::new();
+    root.objects = old_root.objects;
+    root.set_txid(old_root.txid() + 1);
+    root.write_refcounts(&[Some(dg.clone())], 0, &refcounts)?;
+    let mut root_ptr = dg.alloc_blocks(1, None)?;
+    let mut allocs = BTreeMap::new();
+    allocs.insert(sb.devid(), alloc);
+    root.write_allocators(&mut [Some(dg.clone())], 0, &mut allocs)?;
+    if allocs.values().any(Allocator::is_bitmap) {
+        sb.set_feature(AMFeatures::BitmapAllocators as usize, true);
+    }
+    root.write(&[Some(dg)], &mut root_ptr)?;
+    d.sync()?;
+    let slot = sb.next_root().ok_or(AMErrorFS::NoFSGroup)?;
+    sb.rootnodes = [AMPointerGlobal::null(); 128];
+    for i in 0..128 {
+        sb.set_pinned(i, false);
+    }
+    sb.rootnodes[usize::from(slot)] = root_ptr;
+    sb.latest_root = slot;
+    for loc in header_locs {
+        sb.write(d.clone(), loc)?;
+    }
+    d.sync()?;
+    info!("\tOK!");
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+fn wipe_allocators(d: &mut Disk) {
+    let sb = Superblock::read(d.clone(), d.get_header_locs().unwrap()[0]).unwrap();
+    let dg = DiskGroup::from_geo(
+        sb.get_geometry(d.clone(), 0).unwrap(),
+        &[sb.devid()],
+        &[d.clone()],
+    )
+    .unwrap();
+    let dgs = [Some(dg)];
+    for blk in sb.get_group(&dgs).unwrap().get_alloc_blocks(&dgs).unwrap() {
+        d.write_at(blk.loc(), &[0; BLOCK_SIZE]).unwrap();
+    }
+    d.sync().unwrap();
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_scan_findings(mut d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[1; 4 * BLOCK_SIZE]).unwrap();
+    fs.commit().unwrap();
+
+    let mut phases = Vec::new();
+    let findings = fsck_single_scan(d.clone(), &FsckOptions::default(), |phase, done, total| {
+        assert!(done <= total);
+        phases.push((phase, done, total));
+    })
+    .unwrap()
+    .findings;
+    assert!(findings.iter().all(|f| f.severity < FSCKSeverity::Error));
+    assert_eq!(phases[0], (FSCKPhase::Superblocks, 0, 4));
+    assert!(phases
+        .iter()
+        .any(|p| p.0 == FSCKPhase::Reconcile && p.1 == p.2));
+    assert_eq!(*phases.last().unwrap(), (FSCKPhase::DoubleFrees, 1, 1));
+
+    wipe_allocators(&mut d);
+    let report = fsck_single_scan(d.clone(), &FsckOptions::default(), |_, _, _| {}).unwrap();
+    assert!(report.summary.errors > report.summary.fixed);
+    assert!(report.summary.kinds[&FSCKErrorKind::InvalidAllocator] > 0);
+    let findings = report.findings;
+    assert!(findings
+        .iter()
+        .any(|f| f.kind == FSCKErrorKind::InvalidAllocator && f.severity == FSCKSeverity::Error));
+
+    let mut opts = FsckOptions::default();
+    opts.passes.remove(&FSCKPhase::Allocators);
+    let report = fsck_single_scan(d.clone(), &opts, |_, _, _| {}).unwrap();
+    assert_eq!(report.summary.errors, 0);
+
+    let opts = FsckOptions {
+        stop_on_error: true,
+        ..FsckOptions::default()
+    };
+    assert!(fsck_single_scan(d, &opts, |_, _, _| {}).is_err());
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_refcount_mismatch(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[1; 2 * BLOCK_SIZE]).unwrap();
+    fs.clone_object(1, 2).unwrap();
+    fs.commit().unwrap();
+
+    let mismatched = |report: &FSCKReport| {
+        report
+            .findings
+            .iter()
+            .filter(|f| f.kind == FSCKErrorKind::RefCountMismatch)
+            .count()
+    };
+    let scan = |fix| {
+        let opts = FsckOptions {
+            fix,
+            ..FsckOptions::default()
+        };
+        fsck_single_scan(d.clone(), &opts, |_, _, _| {}).unwrap()
+    };
+    assert_eq!(mismatched(&scan(false)), 0);
+
+    // Losing the counts would let the first object freed take the shared blocks with it
+    fs.write().unwrap().set_refcounts(BTreeMap::new());
+    fs.commit().unwrap();
+    let report = scan(true);
+    assert!(mismatched(&report) > 0);
+    assert_eq!(report.summary.fixed, mismatched(&report));
+    assert_eq!(mismatched(&scan(false)), 0);
+
+    // A count for the same location on another device is a different extent
+    let fs = FSHandle::open(&[d.clone()]).unwrap();
+    let mut counts = fs.read().unwrap().get_refcounts().clone();
+    let shared = *counts.keys().next().unwrap();
+    let stray = AMPointerGlobal::new(shared.loc(), shared.length(), shared.geo(), 1);
+    counts.insert(stray, 2);
+    fs.write().unwrap().set_refcounts(counts);
+    fs.commit().unwrap();
+    let report = scan(false);
+    assert_eq!(mismatched(&report), 1);
+    assert!(report
+        .findings
+        .iter()
+        .any(|f| f.kind == FSCKErrorKind::RefCountMismatch
+            && matches!(f.location, FSCKErrorLoc::Global(p) if p == stray)));
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_double_frees(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[1; 4 * BLOCK_SIZE]).unwrap();
+    fs.commit().unwrap();
+    // Rewriting queues the old copies
+    fs.write_object(1, 0, &[2; 4 * BLOCK_SIZE]).unwrap();
+    fs.commit().unwrap();
+
+    let double_free = |f: &FSCKFinding| {
+        matches!(
+            f.kind,
+            FSCKErrorKind::FreeOfFreeBlock
+                | FSCKErrorKind::FreeOfLiveBlock
+                | FSCKErrorKind::DuplicateFree
+        )
+    };
+    let scan = |fix| {
+        let opts = FsckOptions {
+            fix,
+            ..FsckOptions::default()
+        };
+        fsck_single_scan(d.clone(), &opts, |_, _, _| {}).unwrap()
+    };
+    assert!(!scan(false).findings.iter().any(double_free));
+
+    // Queue a block object 1 still uses
+    {
+        let mut handle = fs.write().unwrap();
+        let live = handle
+            .get_objects()
+            .unwrap()
+            .get_object(1)
+            .unwrap()
+            .unwrap()
+            .frags()[0]
+            .pointer;
+        handle.free(live).unwrap();
+        handle.free(live).unwrap();
+    }
+    fs.commit().unwrap();
+    let report = scan(true);
+    assert_eq!(report.summary.fixed, 2);
+    let bad: Vec<&FSCKFinding> = report.findings.iter().filter(|f| double_free(f)).collect();
+    assert_eq!(bad.len(), 2);
+    assert!(bad.iter().any(|f| f.kind == FSCKErrorKind::FreeOfLiveBlock));
+    assert!(bad.iter().any(|f| f.kind == FSCKErrorKind::DuplicateFree));
+    assert!(bad.iter().all(|f| f.fixed));
+    assert!(!scan(false).findings.iter().any(double_free));
+}
+
+#[test_fs(mem, file, blocks = 4000)]
+#[allow(clippy::unwrap_used)]
+pub fn test_parallel_scan(d: crate::Disk) {
+    use std::time::Instant;
+
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    for id in 0..16 {
+        fs.create_object(id, 0).unwrap();
+        fs.write_object(id, 0, &vec![u8::try_from(id).unwrap(); 64 * BLOCK_SIZE])
+            .unwrap();
+    }
+    fs.commit().unwrap();
+
+    // Damage some fragments, so there's something for the workers to find
+    let mut damaged = Vec::new();
+    for id in [3, 11] {
+        let ptr = fs
+            .read()
+            .unwrap()
+            .get_objects()
+            .unwrap()
+            .get_object(id)
+            .unwrap()
+            .unwrap()
+            .frags()[0]
+            .pointer;
+        crate::test::corrupt::flip_byte(&d, ptr.loc(), 0).unwrap();
+        damaged.push(FSCKErrorLoc::from(ptr));
+    }
+    let opts = FsckOptions {
+        verbose: false,
+        ..FsckOptions::default()
+    };
+    let mut reports = Vec::new();
+    for _ in 0..2 {
+        let start = Instant::now();
+        reports.push(fsck_single_scan(d.clone(), &opts, |_, _, _| {}).unwrap());
+        info!("Scanned in {:?}", start.elapsed());
+    }
+    // Results come back from the workers out of order, but are reported in order
+    assert_eq!(reports[0].findings, reports[1].findings);
+    assert_eq!(reports[0].summary, reports[1].summary);
+    let bad: Vec<_> = reports[0]
+        .findings
+        .iter()
+        .filter(|f| f.kind == FSCKErrorKind::InvalidFragment)
+        .map(|f| &f.location)
+        .collect();
+    assert!(damaged.iter().all(|l| bad.contains(&l)));
+    assert!(bad.iter().all(|l| damaged.contains(l)));
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_rebuild(mut d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    for id in 0..4 {
+        fs.create_object(id, 0).unwrap();
+        fs.write_object(id, 0, &vec![u8::try_from(id).unwrap(); 8 * BLOCK_SIZE])
+            .unwrap();
+    }
+    fs.commit().unwrap();
+    fs.snapshot_create().unwrap();
+
+    wipe_allocators(&mut d);
+    assert!(FSHandle::open(&[d.clone()]).is_err());
+
+    fsck_single_rebuild(d.clone()).unwrap();
+    let fs2 = FSHandle::open(&[d]).unwrap();
+    assert!(fs2.snapshot_list().unwrap().is_empty());
+    // New data mustn't land on top of the old
+    fs2.create_object(4, 0).unwrap();
+    fs2.write_object(4, 0, &vec![4; 8 * BLOCK_SIZE]).unwrap();
+    fs2.commit().unwrap();
+    for id in 0..5 {
+        let mut buf = vec![0; 8 * BLOCK_SIZE];
+        fs2.read_object(id, 0, &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == u8::try_from(id).unwrap()));
+    }
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_txid_regression(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(1, 0).unwrap();
+    fs.commit().unwrap();
+    fs.commit().unwrap();
+    let latest = fs.read().unwrap().get_superblock().unwrap().latest_root();
+
+    let txid = crate::test::corrupt::find_fsgroup(&d, None)
+        .unwrap()
+        .1
+        .txid();
+    let regressions = |d: &Disk| {
+        fsck_single_scan(d.clone(), &FsckOptions::default(), |_, _, _| {})
+            .unwrap()
+            .findings
+            .iter()
+            .filter(|f| f.kind == FSCKErrorKind::TxidRegression)
+            .count()
+    };
+    assert_eq!(regressions(&d), 0);
+    crate::test::corrupt::set_fsgroup_txid(&d, latest - 1, txid).unwrap();
+    assert_eq!(regressions(&d), 1);
+}
@@ -0,0 +1,57 @@
+use amos_std::AMResult;
+
+use crate::FSHandle;
+
+/// Takes in space added to the end of the disks since the filesystem was made, then commits.
+/// The two trailing superblocks move to the new end of each disk. Returns the number of blocks added.
+#[cfg(feature = "unstable")]
+pub fn grow(fs: &FSHandle) -> AMResult<u64> {
+    let added = fs.grow()?;
+    if added > 0 {
+        fs.commit()?;
+    }
+    Ok(added)
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_grow() {
+    use crate::{DiskFile, Superblock, BLOCK_SIZE};
+
+    crate::test::logging::init_log();
+
+    let fs = crate::test::fsinit::create_fs_sized(500).unwrap();
+    let total = |fs: &FSHandle| {
+        fs.read().unwrap().get_diskgroups()[0]
+            .as_ref()
+            .unwrap()
+            .allocs[0]
+            .total_space()
+    };
+    assert_eq!(grow(&fs).unwrap(), 0);
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(fs.file())
+        .unwrap()
+        .set_len(3000 * BLOCK_SIZE as u64)
+        .unwrap();
+    assert_eq!(grow(&fs).unwrap(), 2500);
+    assert_eq!(total(&fs), 3000);
+    assert_eq!(grow(&fs).unwrap(), 0);
+
+    // More than the old disk could hold
+    for id in 0..10 {
+        fs.create_object(id, 0).unwrap();
+        fs.write_object(id, 0, &vec![1; 64 * BLOCK_SIZE]).unwrap();
+    }
+    fs.commit().unwrap();
+
+    let d = DiskFile::open(fs.file()).unwrap();
+    for loc in d.get_header_locs().unwrap() {
+        Superblock::read(d.clone(), loc).unwrap();
+    }
+    let fs2 = FSHandle::open(&[d]).unwrap();
+    assert_eq!(total(&fs2), 3000);
+    assert_eq!(fs2.size_object(9).unwrap(), 64 * BLOCK_SIZE as u64);
+}
@@ -1,62 +1,188 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use amos_std::AMResult;
+use amos_std::{error::AMError, AMResult};
 
 use crate::{
-    AMPointerLocal, Allocator, Disk, DiskGroup, FSGroup, Geometry, Superblock, BLOCK_SIZE,
+    operations::populate, AMFeatures, AMPointerLocal, Allocator, Disk, DiskGroup, FSGroup,
+    FSHandle, Geometry, GeometryFlavor, ObjectSet, Retention, Superblock, BLOCK_SIZE,
 };
 
 /// Makes a new AMFS filesystem composed of a single disk.
 #[cfg(feature = "unstable")]
-pub fn mkfs_single(mut d: Disk) -> AMResult<()> {
-    //Erase disk
-    let disk_size = d.size()?;
-    for i in 0..disk_size {
-        d.write_at(i, &[0; BLOCK_SIZE])?;
-    }
-    //Generate device ID
-    let devid = rand::random::<u64>();
-    //Calculate header locations
-    let header_locs = d.get_header_locs()?;
-    //Create free block map, mark headers used.
-    let mut free = Allocator::new(d.size()?);
-    for loc in header_locs {
-        free.mark_used(loc.loc(), 1)?;
+pub fn mkfs_single(d: Disk) -> AMResult<()> {
+    mkfs(d, &MkfsOptions::default())
+}
+
+/// Makes a new AMFS filesystem composed of a single disk,
+/// keeping its first `meta_blocks` blocks for metadata. With 0, metadata and data are mixed.
+#[cfg(feature = "unstable")]
+pub fn mkfs_single_zoned(d: Disk, meta_blocks: u64) -> AMResult<()> {
+    mkfs(
+        d,
+        &MkfsOptions {
+            meta_blocks,
+            ..MkfsOptions::default()
+        },
+    )
+}
+
+/// Options for making a filesystem
+#[derive(Debug, Clone)]
+pub struct MkfsOptions {
+    /// Human-readable volume label, of up to 64 bytes
+    pub label:       String,
+    /// Volume UUID. Randomly generated if not given.
+    pub uuid:        Option<u128>,
+    /// Number of root node slots to keep old roots in, between 2 and 128
+    pub root_slots:  u8,
+    /// Number of blocks at the start of the disk kept for metadata
+    pub meta_blocks: u64,
+    /// Feature bits to enable on top of the current ones
+    pub features:    BTreeSet<usize>,
+    /// Host directory to copy into the new filesystem
+    pub populate:    Option<PathBuf>,
+    /// Zero every block, rather than only writing the ones the filesystem needs
+    pub full_erase:  bool,
+    /// Discard every block before writing, letting the disk drop their old contents
+    pub discard:     bool,
+    /// How long old roots are retained, unless a mount says otherwise
+    pub retention:   Retention,
+}
+
+impl Default for MkfsOptions {
+    #[cfg(feature = "unstable")]
+    fn default() -> Self {
+        Self {
+            label:       String::new(),
+            uuid:        None,
+            root_slots:  128,
+            meta_blocks: 0,
+            features:    BTreeSet::new(),
+            populate:    None,
+            full_erase:  false,
+            discard:     false,
+            retention:   Retention::default(),
+        }
     }
+}
 
-    let mut superblocks = [Superblock::new(devid); 4];
+/// Makes a new AMFS filesystem composed of a single disk, with the given options.
+#[cfg(feature = "unstable")]
+pub fn mkfs(d: Disk, opts: &MkfsOptions) -> AMResult<()> {
+    mkfs_devices(&[d], GeometryFlavor::Single, opts)
+}
 
-    //Create geometries
+/// Makes a new AMFS filesystem spanning several disks, arranged by a geometry of the given flavor.
+#[cfg(feature = "unstable")]
+pub fn mkfs_multi(ds: &[Disk], flavor: GeometryFlavor) -> AMResult<()> {
+    mkfs_devices(ds, flavor, &MkfsOptions::default())
+}
+
+/// Makes a new AMFS filesystem on a set of disks, writing superblocks to every one
+#[cfg(feature = "unstable")]
+fn mkfs_devices(ds: &[Disk], flavor: GeometryFlavor, opts: &MkfsOptions) -> AMResult<()> {
+    assert_or_err!(!ds.is_empty(), AMError::TODO(0));
+    assert_or_err!(
+        ds.len() == 1 || !matches!(flavor, GeometryFlavor::Single),
+        AMError::TODO(0)
+    );
+    //Generate volume identity
+    let uuid = opts.uuid.unwrap_or_else(rand::random::<u128>);
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_secs());
     let mut geom = Geometry::new();
-    geom.device_ids[0] = devid;
-    for sb in &mut superblocks {
-        //Create geometry
-        let geo_ptr = free.alloc_blocks(1)?;
-        let geo_ptr = geom.write(d.clone(), AMPointerLocal::new(geo_ptr))?;
+    geom.flavor = flavor;
+    let mut devices = Vec::with_capacity(ds.len());
+    for d in ds {
+        let mut d = d.clone();
+        d.check_alignment()?;
+        //Generate device ID
+        let devid = rand::random::<u64>();
+        geom.device_ids.push(devid);
+        let mut sb = Superblock::new(devid);
+        sb.set_identity(uuid, &opts.label, created)?;
+        sb.set_root_slots(opts.root_slots)?;
+        // Everything below lays out BLOCK_SIZE blocks
+        sb.set_block_size(BLOCK_SIZE)?;
+        sb.set_retention(opts.retention)?;
+        for bit in &opts.features {
+            assert_or_err!(*bit < 2048, AMError::TODO(0));
+            sb.set_feature(*bit, true);
+        }
+        if opts.meta_blocks != 0 {
+            sb.set_feature(AMFeatures::ZonedAllocators as usize, true);
+        }
+        //Erase disk. Every block the filesystem uses is written below, so this is optional.
+        let disk_size = d.size()?;
+        if opts.discard {
+            d.discard(0, disk_size)?;
+        }
+        if opts.full_erase {
+            for i in 0..disk_size {
+                d.write_at(i, &[0; BLOCK_SIZE])?;
+            }
+        }
+        //Calculate header locations
+        let header_locs = d.get_header_locs()?;
+        //Create free block map, mark headers used.
+        let mut free = Allocator::new(d.size()?);
+        free.set_meta_zone(opts.meta_blocks);
+        for loc in header_locs {
+            free.mark_used(loc.loc(), 1)?;
+        }
+        devices.push((devid, d, header_locs, free, [sb; 4]));
+    }
+
+    //Create geometries, now every device ID is known
+    for (_, d, _, free, superblocks) in &mut devices {
+        for sb in superblocks.iter_mut() {
+            let mut geo_ptrs = Vec::with_capacity(geom.table_blocks());
+            for _ in 0..geom.table_blocks() {
+                geo_ptrs.push(AMPointerLocal::new(free.alloc_blocks(1)?));
+            }
+            let geo_ptr = geom.write_chain(d.clone(), &geo_ptrs)?;
 
-        sb.geometries[0] = geo_ptr;
+            sb.geometries[0] = geo_ptr;
+            if geom.is_long() {
+                sb.set_feature(AMFeatures::DeviceTable as usize, true);
+            }
+        }
     }
     //Create disk group
-    let mut dg = DiskGroup::single(geom, d.clone(), free.clone());
+    let mut dg = DiskGroup::multi(
+        geom,
+        devices.iter().map(|x| x.1.clone()).collect(),
+        devices.iter().map(|x| x.3.clone()).collect(),
+    );
     //Create root group
     let mut root_group = FSGroup::new();
-    root_group.objects = dg.alloc_blocks(1)?;
+    let mut dgs = vec![None; 16];
+    dgs[0] = Some(dg.clone());
+    root_group.objects = ObjectSet::create(dgs, dg.alloc_blocks(1, None)?)?.ptr;
     //Write root group
-    let mut alloc_map = BTreeMap::new();
-    alloc_map.insert(devid, free);
-    let mut root_ptr = dg.alloc_blocks(1)?;
-    root_group.write_allocators(&mut [Some(dg.clone())], &mut alloc_map)?;
+    let mut alloc_map: BTreeMap<u64, Allocator> =
+        devices.iter().map(|x| (x.0, x.3.clone())).collect();
+    let mut root_ptr = dg.alloc_blocks(1, None)?;
+    root_group.write_allocators(&mut [Some(dg.clone())], 0, &mut alloc_map)?;
     root_group.write(&[Some(dg)], &mut root_ptr)?;
-    for sb in &mut superblocks {
-        sb.rootnodes[0] = root_ptr;
-        sb.latest_root = 0;
-    }
     //Write superblocks
-    for i in 0..4 {
-        superblocks[i].write(d.clone(), header_locs[i])?;
+    for (_, d, header_locs, _, superblocks) in &mut devices {
+        for i in 0..4 {
+            superblocks[i].rootnodes[0] = root_ptr;
+            superblocks[i].latest_root = 0;
+            superblocks[i].write(d.clone(), header_locs[i])?;
+        }
+        //Sync disk
+        d.sync()?;
+    }
+    if let Some(path) = &opts.populate {
+        populate(&FSHandle::open(ds)?, path)?;
     }
-    //Sync disk
-    d.sync()?;
     Ok(())
 }
 
@@ -66,3 +192,187 @@ pub fn test_mkfs() {
     let d = crate::disk::DiskFile::open("test.img").unwrap();
     mkfs_single(d).unwrap();
 }
+
+#[test]
+pub fn test_mkfs_zoned() {
+    #![allow(clippy::unwrap_used)]
+    let d = crate::DiskMem::open(1000);
+    mkfs_single_zoned(d.clone(), 100).unwrap();
+    let fs = crate::FSHandle::open(&[d]).unwrap();
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[1; 3 * BLOCK_SIZE]).unwrap();
+    fs.commit().unwrap();
+
+    let handle = fs.read().unwrap();
+    let bit = AMFeatures::ZonedAllocators as usize;
+    assert!(handle.get_superblock().unwrap().features()[bit]);
+    let objs = handle.get_objects().unwrap();
+    assert!(objs.ptr.loc() < 100);
+    for f in objs.get_object(1).unwrap().unwrap().frags() {
+        assert!(f.pointer.loc() >= 100);
+    }
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_mkfs_identity() {
+    let d = crate::DiskMem::open(1000);
+    let opts = MkfsOptions {
+        label: "scratch".to_string(),
+        uuid: Some(0x1234),
+        ..MkfsOptions::default()
+    };
+    mkfs(d.clone(), &opts).unwrap();
+    let fs = crate::FSHandle::open(&[d.clone()]).unwrap();
+    assert_eq!(fs.uuid().unwrap(), 0x1234);
+    assert_eq!(fs.label().unwrap(), "scratch");
+    assert!(fs.created().unwrap() > 0);
+    assert_eq!(fs.mount_count().unwrap(), 1);
+    assert!(fs.last_mount().unwrap() >= fs.created().unwrap());
+    drop(fs);
+    let fs = crate::FSHandle::open(&[d.clone()]).unwrap();
+    assert_eq!(fs.mount_count().unwrap(), 2);
+
+    let opts = MkfsOptions {
+        label: "x".repeat(65),
+        ..MkfsOptions::default()
+    };
+    assert!(mkfs(d, &opts).is_err());
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_mkfs_options() {
+    let d = crate::DiskMem::open(1000);
+    let opts = MkfsOptions {
+        root_slots: 4,
+        features: [crate::COMPAT_START].iter().copied().collect(),
+        ..MkfsOptions::default()
+    };
+    mkfs(d.clone(), &opts).unwrap();
+    let fs = crate::FSHandle::open(&[d.clone()]).unwrap();
+    for _ in 0..10 {
+        fs.commit().unwrap();
+    }
+    let sb = fs.read().unwrap().get_superblock().unwrap();
+    assert_eq!(sb.root_slots(), 4);
+    assert!(sb.latest_root() < 4);
+    assert!((4..128).all(|i| sb.rootnodes(i).is_null()));
+    assert!(sb.features()[crate::COMPAT_START]);
+    assert_eq!(sb.block_size(), BLOCK_SIZE);
+    drop(fs);
+
+    for root_slots in [0, 1, 129] {
+        let opts = MkfsOptions {
+            root_slots,
+            ..MkfsOptions::default()
+        };
+        assert!(mkfs(d.clone(), &opts).is_err());
+    }
+    let opts = MkfsOptions {
+        features: [2048].iter().copied().collect(),
+        ..MkfsOptions::default()
+    };
+    assert!(mkfs(d, &opts).is_err());
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_mkfs_multi() {
+    use std::convert::TryFrom;
+
+    let ds = [crate::DiskMem::open(200), crate::DiskMem::open(300)];
+    assert!(mkfs_multi(&ds, GeometryFlavor::Single).is_err());
+    mkfs_multi(&ds, GeometryFlavor::Striped).unwrap();
+
+    let fs = crate::FSHandle::open(&ds).unwrap();
+    for id in 0..4 {
+        fs.create_object(id, 0).unwrap();
+        fs.write_object(id, 0, &vec![u8::try_from(id).unwrap(); 40 * BLOCK_SIZE])
+            .unwrap();
+    }
+    fs.commit().unwrap();
+    let devs: BTreeSet<u8> = {
+        let handle = fs.read().unwrap();
+        let objs = handle.get_objects().unwrap().get_objects().unwrap();
+        objs.values()
+            .flat_map(|o| o.frags())
+            .map(|f| f.pointer.dev())
+            .collect()
+    };
+    assert_eq!(devs.len(), 2);
+    drop(fs);
+
+    let fs = crate::FSHandle::open(&ds).unwrap();
+    for id in 0..4 {
+        let mut buf = vec![0; 40 * BLOCK_SIZE];
+        fs.read_object(id, 0, &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == u8::try_from(id).unwrap()));
+    }
+    for d in &ds {
+        for loc in d.get_header_locs().unwrap() {
+            Superblock::read(d.clone(), loc).unwrap();
+        }
+    }
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_mkfs_erase() {
+    let d = crate::DiskMem::open(1000);
+    let check = |opts: &MkfsOptions, expect: u8| {
+        let mut d = d.clone();
+        for i in 0..1000 {
+            d.write_at(i, &[0xAA; BLOCK_SIZE]).unwrap();
+        }
+        mkfs(d.clone(), opts).unwrap();
+        let mut buf = [0; BLOCK_SIZE];
+        d.read_at(500, &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == expect));
+        let fs = crate::FSHandle::open(&[d]).unwrap();
+        fs.create_object(1, 0).unwrap();
+        fs.write_object(1, 0, &[1; 5000]).unwrap();
+        fs.commit().unwrap();
+    };
+    check(&MkfsOptions::default(), 0xAA);
+    check(
+        &MkfsOptions {
+            full_erase: true,
+            ..MkfsOptions::default()
+        },
+        0,
+    );
+    check(
+        &MkfsOptions {
+            discard: true,
+            ..MkfsOptions::default()
+        },
+        0,
+    );
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_mkfs_device_table() {
+    let ds: Vec<Disk> = (0..300).map(|_| crate::DiskMem::open(100)).collect();
+    mkfs_multi(&ds, GeometryFlavor::Striped).unwrap();
+
+    let loc = ds[0].get_header_locs().unwrap()[0];
+    let sb = Superblock::read(ds[0].clone(), loc).unwrap();
+    assert!(sb.features()[AMFeatures::DeviceTable as usize]);
+    let geo = sb.get_geometry(ds[0].clone(), 0).unwrap();
+    assert_eq!(geo.device_ids.len(), 300);
+    assert_eq!(geo.continuation_blocks().len(), 1);
+    let last = Superblock::read(ds[299].clone(), loc).unwrap();
+    assert_eq!(geo.device_ids[299], last.devid());
+
+    let fs = crate::FSHandle::open(&ds).unwrap();
+    fs.create_object(100, 0).unwrap();
+    fs.write_object(100, 0, &[1; 4 * BLOCK_SIZE]).unwrap();
+    fs.commit().unwrap();
+    drop(fs);
+    let fs = crate::FSHandle::open(&ds).unwrap();
+    let mut buf = [0; 4 * BLOCK_SIZE];
+    fs.read_object(100, 0, &mut buf).unwrap();
+    assert_eq!(buf, [1; 4 * BLOCK_SIZE]);
+}
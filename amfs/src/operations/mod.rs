@@ -1,8 +1,44 @@
 #![allow(unknown_lints)]
 #![allow(require_stability_comment)]
 
-pub use fsck::fsck_single_scan;
-pub use mkfs::mkfs_single;
+pub use balance::{balance, BalanceOptions, BalanceProgress};
+pub use defrag::{defrag, DefragTarget};
+pub use diff::{diff, ObjectChange};
+pub use dump::{
+    check, classify, diff_images, dump, dump_object, extract_object, BlockType, CheckReport,
+    DumpFormat, DumpOptions, ImageDiff,
+};
+pub use fsck::{
+    fsck_single_rebuild, fsck_single_scan, FSCKError, FSCKErrorKind, FSCKErrorLoc, FSCKFinding,
+    FSCKPhase, FSCKReport, FSCKSeverity, FSCKSummary, FsckOptions,
+};
+pub use grow::grow;
+pub use mkfs::{mkfs, mkfs_multi, mkfs_single, mkfs_single_zoned, MkfsOptions};
+pub use orphans::{find_orphans, recover_orphans};
+pub use populate::{
+    export_object, import_file, lookup, populate, read_dir, DirEntry, ObjectTarget, ROOT_DIR,
+};
+pub use rollback::{list_roots, rollback, RootSlot};
+pub use scrub::{scrub, ScrubReport};
+pub use send::{receive, send, SEND_MAGIC};
+pub use shrink::shrink;
+pub use superblocks::{find_superblocks, recover_superblocks};
+pub use tar::{pack_tar, unpack_tar};
+pub use upgrade::upgrade;
 
+mod balance;
+mod defrag;
+mod diff;
+mod dump;
 mod fsck;
+mod grow;
 mod mkfs;
+mod orphans;
+mod populate;
+mod rollback;
+mod scrub;
+mod send;
+mod shrink;
+mod superblocks;
+mod tar;
+mod upgrade;
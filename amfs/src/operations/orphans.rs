@@ -0,0 +1,68 @@
+use amos_std::AMResult;
+
+use crate::{AMPointerGlobal, FSHandle};
+
+/// Finds extents that are allocated but unreachable from any retained root, committing first.
+/// These are left behind by interrupted operations or damage the allocators missed.
+#[cfg(feature = "unstable")]
+pub fn find_orphans(fs: &FSHandle) -> AMResult<Vec<AMPointerGlobal>> {
+    fs.find_orphans()
+}
+
+/// Gathers orphaned extents into new objects, one per extent, then commits.
+/// Returns the IDs of the new objects, which belong under lost+found once directories exist.
+#[cfg(feature = "unstable")]
+pub fn recover_orphans(fs: &FSHandle) -> AMResult<Vec<u64>> {
+    let ids = fs.recover_orphans()?;
+    if !ids.is_empty() {
+        fs.commit()?;
+    }
+    Ok(ids)
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_orphans(d: crate::Disk) {
+    use crate::{AllocHint, BLOCK_SIZE, META_OBJECTS};
+
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    fs.create_object(META_OBJECTS, 0).unwrap();
+    fs.write_object(META_OBJECTS, 0, &[1; 2 * BLOCK_SIZE])
+        .unwrap();
+    let before = find_orphans(&fs).unwrap();
+
+    // An extent allocated and written, but never linked into an object
+    let ptr = fs
+        .write()
+        .unwrap()
+        .alloc_blocks(1, Some(AllocHint::Data))
+        .unwrap()
+        .unwrap();
+    ptr.write(
+        0,
+        BLOCK_SIZE,
+        fs.read().unwrap().get_diskgroups(),
+        &[7; BLOCK_SIZE],
+    )
+    .unwrap();
+    let after = find_orphans(&fs).unwrap();
+    assert_eq!(after.len(), before.len() + 1);
+    assert!(after.iter().any(|p| p.loc() == ptr.loc()));
+
+    let ids = recover_orphans(&fs).unwrap();
+    assert_eq!(ids.len(), after.len());
+    assert!(ids.iter().all(|id| *id > META_OBJECTS));
+    assert!(find_orphans(&fs).unwrap().is_empty());
+    let recovered = ids
+        .iter()
+        .find(|id| {
+            let mut buf = [0; BLOCK_SIZE];
+            fs.read_object(**id, 0, &mut buf).unwrap();
+            buf == [7; BLOCK_SIZE]
+        })
+        .copied();
+    assert!(recovered.is_some());
+    let mut buf = [0; 2 * BLOCK_SIZE];
+    fs.read_object(META_OBJECTS, 0, &mut buf).unwrap();
+    assert!(buf.iter().all(|b| *b == 1));
+}
@@ -0,0 +1,335 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+};
+
+use amos_std::{
+    error::{AMError, AMErrorFS},
+    AMResult,
+};
+
+use crate::{FSHandle, BLOCK_SIZE, META_OBJECTS};
+
+/// The object holding the root directory
+pub const ROOT_DIR: u64 = 0;
+
+/// Marks a directory entry for a plain object
+const ENTRY_FILE: u8 = 1;
+/// Marks a directory entry for another directory
+const ENTRY_DIR: u8 = 2;
+
+/// An entry of a directory object. Directory objects are a sequence of entries, each stored as
+/// the object ID, the kind, the name length as a u16, then the name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// ID of the object the entry names
+    pub id:   u64,
+    /// Whether the object is itself a directory
+    pub dir:  bool,
+    /// Name of the entry
+    pub name: String,
+}
+
+/// Reads the entries of a directory object
+#[cfg(feature = "unstable")]
+pub fn read_dir(fs: &FSHandle, id: u64) -> AMResult<Vec<DirEntry>> {
+    let mut buf = vec![0; usize::try_from(fs.size_object(id)?)?];
+    fs.read_object(id, 0, &mut buf)?;
+    let mut res = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        assert_or_err!(pos + 11 <= buf.len(), AMError::TODO(0));
+        let id = u64::from_le_bytes(buf[pos..pos + 8].try_into().or(Err(AMError::TODO(0)))?);
+        let kind = buf[pos + 8];
+        let len = usize::from(u16::from_le_bytes([buf[pos + 9], buf[pos + 10]]));
+        pos += 11;
+        assert_or_err!(pos + len <= buf.len(), AMError::TODO(0));
+        let name = String::from_utf8(buf[pos..pos + len].to_vec()).or(Err(AMError::TODO(0)))?;
+        pos += len;
+        res.push(DirEntry {
+            id,
+            dir: kind == ENTRY_DIR,
+            name,
+        });
+    }
+    Ok(res)
+}
+
+/// Copies a host directory tree into the root directory, then commits.
+/// Files become objects, and directories become directory objects. Anything else is skipped.
+/// Returns the number of objects created.
+#[cfg(feature = "unstable")]
+pub fn populate(fs: &FSHandle, path: &Path) -> AMResult<u64> {
+    let first = next_free_id(fs)?;
+    let mut next = first;
+    copy_dir(fs, path, ROOT_DIR, &mut next)?;
+    fs.commit()?;
+    Ok(next - first)
+}
+
+/// Finds the first ID past every existing object, and past the metadata objects
+#[cfg(feature = "unstable")]
+pub(crate) fn next_free_id(fs: &FSHandle) -> AMResult<u64> {
+    Ok(fs
+        .read()?
+        .get_objects()?
+        .get_objects()?
+        .keys()
+        .next_back()
+        .map_or(META_OBJECTS, |i| std::cmp::max(i + 1, META_OBJECTS)))
+}
+
+/// Appends an encoded directory entry to a listing
+#[cfg(feature = "unstable")]
+pub(crate) fn push_entry(listing: &mut Vec<u8>, id: u64, dir: bool, name: &str) -> AMResult<()> {
+    listing.extend_from_slice(&id.to_le_bytes());
+    listing.push(if dir { ENTRY_DIR } else { ENTRY_FILE });
+    listing.extend_from_slice(&u16::try_from(name.len())?.to_le_bytes());
+    listing.extend_from_slice(name.as_bytes());
+    Ok(())
+}
+
+/// Finds the object a slash-separated path names, starting from the root directory
+#[cfg(feature = "unstable")]
+pub fn lookup(fs: &FSHandle, path: &str) -> AMResult<u64> {
+    let mut id = ROOT_DIR;
+    for name in path.split('/').filter(|n| !n.is_empty()) {
+        id = read_dir(fs, id)?
+            .into_iter()
+            .find(|e| e.name == name)
+            .ok_or(AMErrorFS::NoObject)?
+            .id;
+    }
+    Ok(id)
+}
+
+/// Where a file is copied to or from within a filesystem
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ObjectTarget {
+    /// An object, by ID
+    Id(u64),
+    /// A path through the directory tree
+    Path(String),
+}
+
+impl ObjectTarget {
+    /// Reads a target: digits name an object ID, anything else a path
+    #[cfg(feature = "unstable")]
+    pub fn parse(s: &str) -> Self {
+        s.parse()
+            .map_or_else(|_| ObjectTarget::Path(s.to_owned()), ObjectTarget::Id)
+    }
+}
+
+/// Copies an object's contents to a host file or other output. Returns the number of bytes copied.
+#[cfg(feature = "unstable")]
+pub fn export_object<W: Write>(fs: &FSHandle, target: &ObjectTarget, out: &mut W) -> AMResult<u64> {
+    let id = match target {
+        ObjectTarget::Id(id) => *id,
+        ObjectTarget::Path(path) => lookup(fs, path)?,
+    };
+    let size = fs.size_object(id)?;
+    let mut buf = vec![0; 16 * BLOCK_SIZE];
+    let mut pos = 0;
+    while pos < size {
+        let n = fs.read_object(id, pos, &mut buf)?;
+        assert_or_err!(n != 0, AMError::TODO(0));
+        out.write_all(&buf[..usize::try_from(n)?])?;
+        pos += n;
+    }
+    Ok(size)
+}
+
+/// Copies a host file into an object, replacing its contents, then commits.
+/// A path that doesn't exist yet is created as a new entry of its parent directory.
+/// Returns the ID of the object written.
+#[cfg(feature = "unstable")]
+pub fn import_file(fs: &FSHandle, target: &ObjectTarget, src: &Path) -> AMResult<u64> {
+    let id = match target {
+        ObjectTarget::Id(id) => *id,
+        ObjectTarget::Path(path) => match lookup(fs, path) {
+            Ok(id) => id,
+            Err(_) => {
+                let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+                assert_or_err!(!name.is_empty(), AMErrorFS::NoObject);
+                let dir = lookup(fs, parent)?;
+                let id = next_free_id(fs)?;
+                add_entry(fs, dir, id, false, name)?;
+                id
+            }
+        },
+    };
+    if fs.size_object(id).is_err() {
+        fs.create_object(id, 0)?;
+    }
+    fs.truncate_object(id, 0)?;
+    copy_file(fs, src, id)?;
+    fs.commit()?;
+    Ok(id)
+}
+
+/// Appends an entry to a directory object, creating the directory object if needed
+#[cfg(feature = "unstable")]
+pub(crate) fn add_entry(
+    fs: &FSHandle,
+    dir: u64,
+    id: u64,
+    is_dir: bool,
+    name: &str,
+) -> AMResult<()> {
+    let mut listing = Vec::new();
+    push_entry(&mut listing, id, is_dir, name)?;
+    if fs.size_object(dir).is_err() {
+        fs.create_object(dir, 0)?;
+    }
+    fs.write_object(dir, fs.size_object(dir)?, &listing)?;
+    Ok(())
+}
+
+/// Copies the contents of a host directory into a directory object, taking new IDs from `next`
+#[cfg(feature = "unstable")]
+fn copy_dir(fs: &FSHandle, path: &Path, dir: u64, next: &mut u64) -> AMResult<()> {
+    let mut entries = std::fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    let mut listing = Vec::new();
+    for entry in entries {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(name) => {
+                warn!("Skipping {:?}: name isn't UTF-8", name);
+                continue;
+            }
+        };
+        let kind = entry.file_type()?;
+        let id = *next;
+        if kind.is_dir() {
+            *next += 1;
+            fs.create_object(id, 0)?;
+            copy_dir(fs, &entry.path(), id, next)?;
+        } else if kind.is_file() {
+            *next += 1;
+            fs.create_object(id, 0)?;
+            copy_file(fs, &entry.path(), id)?;
+        } else {
+            warn!("Skipping {:?}: not a file or directory", entry.path());
+            continue;
+        }
+        debug!("Copied {:?} as object {}", entry.path(), id);
+        push_entry(&mut listing, id, kind.is_dir(), &name)?;
+    }
+    if fs.size_object(dir).is_err() {
+        fs.create_object(dir, 0)?;
+    }
+    if !listing.is_empty() {
+        fs.write_object(dir, fs.size_object(dir)?, &listing)?;
+    }
+    Ok(())
+}
+
+/// Copies a host file into an object
+#[cfg(feature = "unstable")]
+fn copy_file(fs: &FSHandle, path: &Path, id: u64) -> AMResult<()> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 16 * BLOCK_SIZE];
+    let mut pos = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        fs.write_object(id, pos, &buf[..n])?;
+        pos += u64::try_from(n)?;
+    }
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_populate() {
+    use crate::operations::{mkfs, MkfsOptions};
+
+    crate::test::logging::init_log();
+
+    let host = std::env::temp_dir().join(format!("amfs-populate-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(host.join("boot")).unwrap();
+    std::fs::write(host.join("boot/kernel"), vec![7; 3 * BLOCK_SIZE + 5]).unwrap();
+    std::fs::write(host.join("motd"), b"hello").unwrap();
+    std::fs::create_dir(host.join("empty")).unwrap();
+
+    let d = crate::DiskMem::open(1000);
+    let opts = MkfsOptions {
+        populate: Some(host.clone()),
+        ..MkfsOptions::default()
+    };
+    mkfs(d.clone(), &opts).unwrap();
+    std::fs::remove_dir_all(&host).unwrap();
+
+    let fs = FSHandle::open(&[d]).unwrap();
+    let root = read_dir(&fs, ROOT_DIR).unwrap();
+    let names: Vec<&str> = root.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, ["boot", "empty", "motd"]);
+    assert!(root[0].dir && root[1].dir && !root[2].dir);
+    assert!(read_dir(&fs, root[1].id).unwrap().is_empty());
+
+    let mut buf = [0u8; 5];
+    fs.read_object(root[2].id, 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    let boot = read_dir(&fs, root[0].id).unwrap();
+    assert_eq!(boot.len(), 1);
+    assert_eq!(boot[0].name, "kernel");
+    assert_eq!(
+        fs.size_object(boot[0].id).unwrap(),
+        3 * BLOCK_SIZE as u64 + 5
+    );
+    let mut buf = vec![0; 3 * BLOCK_SIZE + 5];
+    fs.read_object(boot[0].id, 0, &mut buf).unwrap();
+    assert!(buf.iter().all(|b| *b == 7));
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_import_export() {
+    use crate::operations::{mkfs, MkfsOptions};
+
+    crate::test::logging::init_log();
+
+    let host = std::env::temp_dir().join(format!("amfs-import-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(host.join("etc")).unwrap();
+    std::fs::write(host.join("etc/hosts"), b"localhost").unwrap();
+    let file = host.with_extension("new");
+    std::fs::write(&file, vec![9; BLOCK_SIZE + 3]).unwrap();
+
+    let d = crate::DiskMem::open(1000);
+    let opts = MkfsOptions {
+        populate: Some(host.clone()),
+        ..MkfsOptions::default()
+    };
+    mkfs(d.clone(), &opts).unwrap();
+    let fs = FSHandle::open(&[d]).unwrap();
+
+    let mut out = Vec::new();
+    let hosts = ObjectTarget::parse("/etc/hosts");
+    assert_eq!(export_object(&fs, &hosts, &mut out).unwrap(), 9);
+    assert_eq!(out, b"localhost");
+
+    let id = import_file(&fs, &ObjectTarget::parse("etc/motd"), &file).unwrap();
+    assert_eq!(lookup(&fs, "/etc/motd").unwrap(), id);
+    let mut out = Vec::new();
+    export_object(&fs, &ObjectTarget::parse(&id.to_string()), &mut out).unwrap();
+    assert_eq!(out, vec![9; BLOCK_SIZE + 3]);
+
+    assert_eq!(
+        import_file(&fs, &hosts, &file).unwrap(),
+        lookup(&fs, "etc/hosts").unwrap()
+    );
+    assert_eq!(
+        fs.size_object(lookup(&fs, "etc/hosts").unwrap()).unwrap(),
+        BLOCK_SIZE as u64 + 3
+    );
+    assert!(import_file(&fs, &ObjectTarget::parse("nodir/file"), &file).is_err());
+
+    std::fs::remove_dir_all(&host).unwrap();
+    std::fs::remove_file(&file).unwrap();
+}
@@ -0,0 +1,140 @@
+use amos_std::{
+    error::{AMError, AMErrorFS},
+    AMResult,
+};
+
+use crate::{AMPointerGlobal, AMPointerLocal, Disk, DiskGroup, FSGroup, Superblock};
+
+/// A root node slot of a superblock
+#[derive(Debug, Clone, Copy)]
+pub struct RootSlot {
+    /// Index of the slot
+    pub slot:    u8,
+    /// Pointer to the root group
+    pub pointer: AMPointerGlobal,
+    /// Transaction ID of the root group, if it could be read
+    pub txid:    Option<u128>,
+    /// Whether the slot is the superblock's latest root
+    pub latest:  bool,
+    /// Whether the slot is pinned by a snapshot
+    pub pinned:  bool,
+}
+
+/// Lists the non-null root node slots of every superblock on a disk.
+/// Superblocks that can't be read have no slots listed.
+#[cfg(feature = "unstable")]
+pub fn list_roots(d: &Disk) -> AMResult<Vec<(AMPointerLocal, Option<Vec<RootSlot>>)>> {
+    let mut res = Vec::new();
+    for loc in d.get_header_locs()? {
+        let sb = match Superblock::read(d.clone(), loc) {
+            Ok(sb) => sb,
+            Err(_) => {
+                res.push((loc, None));
+                continue;
+            }
+        };
+        let dgs = DiskGroup::from_geo(sb.get_geometry(d.clone(), 0)?, &[sb.devid()], &[d.clone()])
+            .map(|dg| vec![Some(dg)])
+            .unwrap_or_default();
+        let mut slots = Vec::new();
+        for i in 0..128 {
+            let pointer = sb.rootnodes(usize::from(i));
+            if pointer.is_null() {
+                continue;
+            }
+            slots.push(RootSlot {
+                slot: i,
+                pointer,
+                txid: FSGroup::read(&dgs, pointer).ok().map(|g| g.txid()),
+                latest: sb.latest_root() == i,
+                pinned: sb.is_pinned(usize::from(i)),
+            });
+        }
+        res.push((loc, Some(slots)));
+    }
+    Ok(res)
+}
+
+/// Makes the root in a given slot the latest on an unmounted disk, forgetting every newer root.
+/// Fails if the root can't be read or a newer root is a snapshot.
+/// Each superblock is read back before the next is written, so a failure leaves the rest intact.
+#[cfg(feature = "unstable")]
+pub fn rollback(d: Disk, slot: u8) -> AMResult<()> {
+    let header_locs = d.get_header_locs()?;
+    let mut latest: Option<(Superblock, DiskGroup, u128)> = None;
+    for loc in header_locs {
+        let found = Superblock::read(d.clone(), loc).and_then(|sb| {
+            let dg =
+                DiskGroup::from_geo(sb.get_geometry(d.clone(), 0)?, &[sb.devid()], &[d.clone()])?;
+            let txid = sb.get_group(&[Some(dg.clone())])?.txid();
+            Ok((sb, dg, txid))
+        });
+        if let Ok((sb, dg, txid)) = found {
+            if latest.as_ref().is_none_or(|l| txid > l.2) {
+                latest = Some((sb, dg, txid));
+            }
+        }
+    }
+    let (mut sb, dg, _) = latest.ok_or(AMErrorFS::NoSuperblock)?;
+    let dgs = [Some(dg)];
+    let target = FSGroup::read(&dgs, sb.rootnodes(usize::from(slot)))?.txid();
+    info!("Rolling back to root group {} in slot {}", target, slot);
+
+    let newer: Vec<usize> = (0..128)
+        .filter(|i| FSGroup::read(&dgs, sb.rootnodes(*i)).is_ok_and(|g| g.txid() > target))
+        .collect();
+    assert_or_err!(newer.iter().all(|i| !sb.is_pinned(*i)), AMError::TODO(0));
+    for i in newer {
+        debug!("\tForgetting root in slot {}", i);
+        sb.rootnodes[i] = AMPointerGlobal::null();
+    }
+    sb.latest_root = slot;
+
+    for loc in header_locs {
+        sb.write(d.clone(), loc)?;
+        let written = Superblock::read(d.clone(), loc)?;
+        assert_or_err!(written.latest_root() == slot, AMErrorFS::Checksum);
+    }
+    Ok(())
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_rollback(d: crate::Disk) {
+    use crate::FSHandle;
+
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[1, 2, 3, 4]).unwrap();
+    fs.commit().unwrap();
+    let good = fs.read().unwrap().get_superblock().unwrap().latest_root();
+    fs.truncate_object(1, 2).unwrap();
+    fs.create_object(2, 0).unwrap();
+    fs.commit().unwrap();
+    let snap = fs.snapshot_create().unwrap();
+
+    let roots = list_roots(&d).unwrap();
+    assert_eq!(roots.len(), 4);
+    for (_, slots) in &roots {
+        let slots = slots.as_ref().unwrap();
+        assert_eq!(slots.iter().filter(|s| s.latest).count(), 1);
+        assert!(slots.iter().all(|s| s.txid.is_some()));
+        assert!(slots.iter().any(|s| s.pinned && s.txid == Some(snap)));
+    }
+
+    assert!(rollback(d.clone(), good).is_err());
+    fs.snapshot_delete(snap).unwrap();
+    rollback(d.clone(), good).unwrap();
+    for (_, slots) in list_roots(&d).unwrap() {
+        let slots = slots.unwrap();
+        let latest = slots.iter().find(|s| s.latest).unwrap();
+        assert_eq!(latest.slot, good);
+        assert!(slots.iter().all(|s| s.txid <= latest.txid));
+    }
+
+    let fs2 = FSHandle::open(&[d]).unwrap();
+    assert_eq!(fs2.size_object(1).unwrap(), 4);
+    assert!(fs2.size_object(2).is_err());
+    fs2.write_object(1, 0, &[5]).unwrap();
+    fs2.commit().unwrap();
+}
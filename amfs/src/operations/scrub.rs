@@ -0,0 +1,87 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use amos_std::AMResult;
+
+use crate::{metrics::report_checksum_failure, AMPointerGlobal, FSHandle};
+
+/// What a scrub found
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    /// How many fragments were read
+    pub fragments: u64,
+    /// Each fragment whose data didn't match its checksum, with the object it belongs to
+    pub damaged:   Vec<(u64, AMPointerGlobal)>,
+}
+
+/// Reads every fragment of every object and checks it against its pointer's checksum,
+/// then records the time and the number of damaged fragments in the next root and commits.
+/// Single-disk geometries keep no redundant copies, so damage is reported but can't be repaired.
+#[cfg(feature = "unstable")]
+pub fn scrub(fs: &FSHandle) -> AMResult<ScrubReport> {
+    let mut report = ScrubReport::default();
+    {
+        let handle = fs.read()?;
+        let dgs = handle.get_diskgroups();
+        for (id, obj) in handle.get_objects()?.get_objects()? {
+            for f in obj.frags() {
+                if f.is_hole() {
+                    continue;
+                }
+                report.fragments += 1;
+                if !f.pointer.validate(dgs).unwrap_or(false) {
+                    warn!("Object {} fragment at {} is damaged", id, f.pointer);
+                    report_checksum_failure(dgs, f.pointer);
+                    report.damaged.push((id, f.pointer));
+                }
+            }
+        }
+    }
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    info!(
+        "Scrubbed {} fragments, {} damaged",
+        report.fragments,
+        report.damaged.len()
+    );
+    fs.set_scrub_result(time, report.damaged.len() as u64)?;
+    fs.commit()?;
+    Ok(report)
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_scrub(mut d: crate::Disk) {
+    use crate::BLOCK_SIZE;
+
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    assert_eq!(fs.scrub_status().unwrap(), (0, 0));
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[1; 4 * BLOCK_SIZE]).unwrap();
+    fs.commit().unwrap();
+
+    let report = scrub(&fs).unwrap();
+    assert_eq!(report.fragments, 1);
+    assert!(report.damaged.is_empty());
+    let (time, errors) = fs.scrub_status().unwrap();
+    assert!(time > 0);
+    assert_eq!(errors, 0);
+
+    let ptr = fs
+        .read()
+        .unwrap()
+        .get_objects()
+        .unwrap()
+        .get_object(1)
+        .unwrap()
+        .unwrap()
+        .frags()[0]
+        .pointer;
+    d.write_at(ptr.loc(), &[0; BLOCK_SIZE]).unwrap();
+    d.sync().unwrap();
+
+    let report = scrub(&fs).unwrap();
+    assert_eq!(report.damaged, vec![(1, ptr)]);
+    assert_eq!(fs.scrub_status().unwrap().1, 1);
+    assert_eq!(fs.metrics().checksum_failures, 1);
+}
@@ -0,0 +1,227 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    io::{Read, Write},
+};
+
+use amos_std::{
+    error::{AMError, AMErrorFS},
+    AMResult,
+};
+
+use crate::{
+    operations::{diff, ObjectChange},
+    DiskGroup, FSHandle, Object, BLOCK_SIZE,
+};
+
+/// Signature at the start of every send stream
+pub const SEND_MAGIC: u64 = u64::from_le_bytes(*b"amfsSEND");
+
+/// Marks the end of the stream
+const REC_END: u64 = 0;
+/// Sets an object's size, clearing its contents first if requested
+const REC_OBJECT: u64 = 1;
+/// Carries a range of an object's contents
+const REC_DATA: u64 = 2;
+/// Removes an object
+const REC_DELETE: u64 = 3;
+
+/// Writes a fixed-size record header: kind, object ID, and two arguments
+#[cfg(feature = "unstable")]
+fn write_record<W: Write>(out: &mut W, kind: u64, id: u64, a: u64, b: u64) -> AMResult<()> {
+    for v in &[kind, id, a, b] {
+        out.write_all(&v.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Reads a record header written by `write_record`
+#[cfg(feature = "unstable")]
+fn read_record<R: Read>(input: &mut R) -> AMResult<[u64; 4]> {
+    let mut buf = [0u8; 32];
+    input.read_exact(&mut buf)?;
+    let mut res = [0; 4];
+    for (i, v) in res.iter_mut().enumerate() {
+        *v = u64::from_le_bytes(buf[i * 8..i * 8 + 8].try_into().or(Err(AMError::TODO(0)))?);
+    }
+    Ok(res)
+}
+
+/// Sends a byte range of an object as data records, one block at a time.
+/// When `sparse` is set, blocks of zeroes are left out.
+#[cfg(feature = "unstable")]
+fn send_range<W: Write>(
+    out: &mut W,
+    id: u64,
+    obj: &Object,
+    range: (u64, u64),
+    sparse: bool,
+    diskgroups: &[Option<DiskGroup>],
+) -> AMResult<()> {
+    let mut pos = range.0;
+    while pos < range.1 {
+        let len = std::cmp::min(u64::try_from(BLOCK_SIZE)?, range.1 - pos);
+        let mut buf = vec![0; usize::try_from(len)?];
+        obj.read(pos, &mut buf, diskgroups)?;
+        if !sparse || buf.iter().any(|b| *b != 0) {
+            write_record(out, REC_DATA, id, pos, len)?;
+            out.write_all(&buf)?;
+        }
+        pos += len;
+    }
+    Ok(())
+}
+
+/// Serializes the objects of the root with a given transaction ID into a stream.
+/// With a base root, only objects and ranges changed since that root are sent.
+#[cfg(feature = "unstable")]
+pub fn send<W: Write>(fs: &FSHandle, root: u128, base: Option<u128>, out: &mut W) -> AMResult<()> {
+    let changes = match base {
+        Some(base) => diff(fs, base, root)?,
+        None => fs
+            .read()?
+            .get_objects_at(root)?
+            .get_objects()?
+            .into_keys()
+            .map(ObjectChange::Created)
+            .collect(),
+    };
+    let handle = fs.read()?;
+    let objs = handle.get_objects_at(root)?;
+    out.write_all(&SEND_MAGIC.to_le_bytes())?;
+    out.write_all(&u64::from(base.is_some()).to_le_bytes())?;
+    out.write_all(&base.unwrap_or(0).to_le_bytes())?;
+    out.write_all(&root.to_le_bytes())?;
+    for change in changes {
+        let (id, ranges, fresh) = match change {
+            ObjectChange::Deleted(id) => {
+                write_record(out, REC_DELETE, id, 0, 0)?;
+                continue;
+            }
+            ObjectChange::Created(id) => (id, None, true),
+            ObjectChange::Modified(id, ranges) => (id, Some(ranges), false),
+        };
+        let obj = objs.get_object(id)?.ok_or(AMErrorFS::NoObject)?;
+        let size = obj.size()?;
+        write_record(out, REC_OBJECT, id, size, u64::from(fresh))?;
+        for (start, end) in ranges.unwrap_or_else(|| vec![(0, size)]) {
+            // Ranges of a shrunk object can reach past its new end
+            let range = (start, std::cmp::min(end, size));
+            send_range(out, id, &obj, range, fresh, handle.get_diskgroups())?;
+        }
+    }
+    write_record(out, REC_END, 0, 0, 0)?;
+    Ok(())
+}
+
+/// Applies a stream produced by `send`, then commits. Returns the transaction ID of the sent root.
+/// A full stream needs a destination without objects, and an incremental one a destination whose
+/// last received root is the stream's base.
+#[cfg(feature = "unstable")]
+pub fn receive<R: Read>(fs: &FSHandle, input: &mut R) -> AMResult<u128> {
+    let mut hdr = [0u8; 48];
+    input.read_exact(&mut hdr)?;
+    assert_or_err!(
+        u64::from_le_bytes(hdr[0..8].try_into().or(Err(AMError::TODO(0)))?) == SEND_MAGIC,
+        AMErrorFS::Signature
+    );
+    let incremental = u64::from_le_bytes(hdr[8..16].try_into().or(Err(AMError::TODO(0)))?);
+    let base = u128::from_le_bytes(hdr[16..32].try_into().or(Err(AMError::TODO(0)))?);
+    let root = u128::from_le_bytes(hdr[32..48].try_into().or(Err(AMError::TODO(0)))?);
+    let matches = match incremental {
+        0 => fs.list_objects()?.next().is_none(),
+        1 => fs.received()? == base,
+        _ => false,
+    };
+    assert_or_err!(matches, AMError::TODO(0));
+    loop {
+        let [kind, id, a, b] = read_record(input)?;
+        match kind {
+            REC_END => break,
+            REC_OBJECT => {
+                if fs.size_object(id).is_err() {
+                    fs.create_object(id, 0)?;
+                } else if b != 0 {
+                    fs.truncate_object(id, 0)?;
+                }
+                fs.truncate_object(id, a)?;
+            }
+            REC_DATA => {
+                assert_or_err!(b <= u64::try_from(BLOCK_SIZE)?, AMError::TODO(0));
+                let mut buf = vec![0; usize::try_from(b)?];
+                input.read_exact(&mut buf)?;
+                fs.write_object(id, a, &buf)?;
+            }
+            REC_DELETE => fs.delete_object(id)?,
+            _ => return Err(AMError::TODO(0).into()),
+        }
+    }
+    fs.set_received(root)?;
+    fs.commit()?;
+    Ok(root)
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_send_receive(d: crate::Disk, d2: crate::Disk) {
+    let src = crate::test::fsinit::mkfs_on(d).unwrap();
+    let dst = crate::test::fsinit::mkfs_on(d2).unwrap();
+
+    src.create_object(1, 4).unwrap();
+    src.write_object(1, 0, &[1, 2, 3, 4]).unwrap();
+    src.create_object(2, 0).unwrap();
+    src.write_object(2, 5000, &[5; 200]).unwrap();
+    let a = src.snapshot_create().unwrap();
+
+    let mut stream = Vec::new();
+    send(&src, a, None, &mut stream).unwrap();
+    assert_eq!(receive(&dst, &mut stream.as_slice()).unwrap(), a);
+    let mut buf = [0u8; 4];
+    dst.read_object(1, 0, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4]);
+    assert_eq!(dst.size_object(2).unwrap(), 5200);
+    let mut buf = [0u8; 200];
+    dst.read_object(2, 5000, &mut buf).unwrap();
+    assert_eq!(buf, [5; 200]);
+
+    src.write_object(2, 0, &[7; 8]).unwrap();
+    src.truncate_object(1, 2).unwrap();
+    let b = src.snapshot_create().unwrap();
+
+    let mut stream = Vec::new();
+    send(&src, b, Some(a), &mut stream).unwrap();
+    receive(&dst, &mut stream.as_slice()).unwrap();
+    assert_eq!(dst.size_object(1).unwrap(), 2);
+    let mut buf = [0u8; 8];
+    dst.read_object(2, 0, &mut buf).unwrap();
+    assert_eq!(buf, [7; 8]);
+    assert_eq!(dst.received().unwrap(), b);
+
+    src.delete_object(2).unwrap();
+    let c = src.snapshot_create().unwrap();
+    let mut stream = Vec::new();
+    send(&src, c, Some(b), &mut stream).unwrap();
+    receive(&dst, &mut stream.as_slice()).unwrap();
+    assert!(dst.size_object(2).is_err());
+
+    // Streams not based on the destination's last received root are refused
+    let mut stream = Vec::new();
+    send(&src, c, Some(a), &mut stream).unwrap();
+    assert!(receive(&dst, &mut stream.as_slice()).is_err());
+    let mut stream = Vec::new();
+    send(&src, c, None, &mut stream).unwrap();
+    assert!(receive(&dst, &mut stream.as_slice()).is_err());
+
+    assert!(receive(&dst, &mut [0u8; 48].as_slice()).is_err());
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_receive_oversized(d: crate::Disk) {
+    let fs = crate::test::fsinit::mkfs_on(d).unwrap();
+    let mut stream = Vec::new();
+    stream.extend_from_slice(&SEND_MAGIC.to_le_bytes());
+    stream.extend_from_slice(&[0; 40]);
+    write_record(&mut stream, REC_OBJECT, 1, 0, 1).unwrap();
+    write_record(&mut stream, REC_DATA, 1, 0, u64::MAX).unwrap();
+    assert!(receive(&fs, &mut stream.as_slice()).is_err());
+}
@@ -0,0 +1,84 @@
+use amos_std::AMResult;
+
+use crate::FSHandle;
+
+/// Shrinks a single-disk filesystem to `new_size` blocks, moving data past the new end down first.
+/// Older roots are dropped along the way, so this refuses while snapshots are held,
+/// and refuses without changing the allocators if the live data won't fit.
+#[cfg(feature = "unstable")]
+pub fn shrink(fs: &FSHandle, new_size: u64) -> AMResult<()> {
+    fs.shrink(new_size)
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_shrink() {
+    use std::convert::TryFrom;
+
+    use crate::{AllocStrategy, DiskFile, Superblock, BLOCK_SIZE};
+
+    crate::test::logging::init_log();
+
+    let fs = crate::test::fsinit::create_fs_sized(1000).unwrap();
+    let total = |fs: &FSHandle| {
+        fs.read().unwrap().get_diskgroups()[0]
+            .as_ref()
+            .unwrap()
+            .allocs[0]
+            .total_space()
+    };
+    let highest = |fs: &FSHandle| {
+        let handle = fs.read().unwrap();
+        let objs = handle.get_objects().unwrap().get_objects().unwrap();
+        objs.values()
+            .flat_map(|o| o.frags())
+            .map(|f| f.pointer.loc())
+            .max()
+            .unwrap()
+    };
+    let check = |fs: &FSHandle| {
+        for id in 5..10 {
+            let mut buf = vec![0; 40 * BLOCK_SIZE];
+            fs.read_object(id, 0, &mut buf).unwrap();
+            assert!(buf.iter().all(|b| *b == u8::try_from(id).unwrap()));
+        }
+    };
+
+    // Walk the allocations towards the end of the disk
+    fs.set_alloc_strategy(0, AllocStrategy::NextFit).unwrap();
+    for id in 0..10 {
+        fs.create_object(id, 0).unwrap();
+        fs.write_object(id, 0, &vec![u8::try_from(id).unwrap(); 40 * BLOCK_SIZE])
+            .unwrap();
+    }
+    for id in 0..5 {
+        fs.truncate_object(id, 0).unwrap();
+    }
+    fs.commit().unwrap();
+    assert!(highest(&fs) >= 500);
+
+    assert!(shrink(&fs, 100).is_err());
+    assert_eq!(total(&fs), 1000);
+
+    shrink(&fs, 500).unwrap();
+    assert_eq!(total(&fs), 500);
+    assert!(highest(&fs) < 498);
+    check(&fs);
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(fs.file())
+        .unwrap()
+        .set_len(500 * BLOCK_SIZE as u64)
+        .unwrap();
+    let d = DiskFile::open(fs.file()).unwrap();
+    for loc in d.get_header_locs().unwrap() {
+        Superblock::read(d.clone(), loc).unwrap();
+    }
+    let fs2 = FSHandle::open(&[d]).unwrap();
+    assert_eq!(total(&fs2), 500);
+    check(&fs2);
+
+    fs2.snapshot_create().unwrap();
+    assert!(shrink(&fs2, 400).is_err());
+}
@@ -0,0 +1,94 @@
+use amos_std::{error::AMErrorFS, AMResult};
+
+use crate::{AMPointerLocal, Disk, DiskGroup, Superblock};
+
+/// Scans every block of a disk for a valid superblock, header locations included.
+/// Finds superblocks by their signature, so works even if the partition has been resized or moved.
+#[cfg(feature = "unstable")]
+pub fn find_superblocks(d: &Disk) -> AMResult<Vec<(AMPointerLocal, Superblock)>> {
+    let mut res = Vec::new();
+    for i in 0..d.size()? {
+        let ptr = AMPointerLocal::new(i);
+        if let Ok(sb) = Superblock::read(d.clone(), ptr) {
+            res.push((ptr, sb));
+        }
+    }
+    Ok(res)
+}
+
+/// Gets the transaction ID of the root group a superblock leads to
+#[cfg(feature = "unstable")]
+fn root_txid(d: &Disk, sb: &Superblock) -> AMResult<u128> {
+    let dg = DiskGroup::from_geo(sb.get_geometry(d.clone(), 0)?, &[sb.devid()], &[d.clone()])?;
+    Ok(sb.get_group(&[Some(dg)])?.txid())
+}
+
+/// Rewrites every header location without a valid superblock. Returns the number rewritten.
+/// The copy used is whichever superblock found on the disk leads to the newest root group.
+/// Copies whose root group can't be read, such as ones inside stored disk images, are ignored.
+#[cfg(feature = "unstable")]
+pub fn recover_superblocks(d: Disk) -> AMResult<usize> {
+    let broken: Vec<AMPointerLocal> = d
+        .get_header_locs()?
+        .iter()
+        .copied()
+        .filter(|loc| Superblock::read(d.clone(), *loc).is_err())
+        .collect();
+    if broken.is_empty() {
+        return Ok(0);
+    }
+    let mut best: Option<(Superblock, u128)> = None;
+    for (loc, sb) in find_superblocks(&d)? {
+        match root_txid(&d, &sb) {
+            Ok(txid) => {
+                if best.as_ref().is_none_or(|b| txid > b.1) {
+                    best = Some((sb, txid));
+                }
+            }
+            Err(_) => warn!("Superblock at {} has no usable root", loc),
+        }
+    }
+    let (mut sb, txid) = best.ok_or(AMErrorFS::NoSuperblock)?;
+    info!(
+        "Recovering {} superblocks from root group {}",
+        broken.len(),
+        txid
+    );
+    for loc in &broken {
+        sb.write(d.clone(), *loc)?;
+    }
+    Ok(broken.len())
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_recover_superblocks(mut d: crate::Disk) {
+    use crate::{FSHandle, BLOCK_SIZE};
+
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[1, 2, 3, 4]).unwrap();
+    fs.commit().unwrap();
+
+    let locs = d.get_header_locs().unwrap();
+    assert_eq!(recover_superblocks(d.clone()).unwrap(), 0);
+    let found: Vec<u64> = find_superblocks(&d)
+        .unwrap()
+        .iter()
+        .map(|(loc, _)| loc.loc())
+        .collect();
+    assert_eq!(found, locs.iter().map(|l| l.loc()).collect::<Vec<_>>());
+
+    d.write_at(locs[0].loc(), &[0; BLOCK_SIZE]).unwrap();
+    d.write_at(locs[3].loc(), &[0; BLOCK_SIZE]).unwrap();
+    assert_eq!(find_superblocks(&d).unwrap().len(), 2);
+    assert_eq!(recover_superblocks(d.clone()).unwrap(), 2);
+    for loc in locs {
+        Superblock::read(d.clone(), loc).unwrap();
+    }
+
+    let fs2 = FSHandle::open(&[d]).unwrap();
+    let mut buf = [0u8; 4];
+    fs2.read_object(1, 0, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4]);
+}
@@ -0,0 +1,346 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    io::{Read, Write},
+};
+
+use amos_std::{
+    error::{AMError, AMErrorFS},
+    AMResult,
+};
+
+use crate::{
+    operations::{
+        populate::{add_entry, next_free_id},
+        read_dir, ROOT_DIR,
+    },
+    FSHandle, BLOCK_SIZE, META_OBJECTS,
+};
+
+/// Size of a tar header, and the unit archive contents are padded to
+const TAR_BLOCK: usize = 512;
+/// Type flag of a regular file
+const TYPE_FILE: u8 = b'0';
+/// Type flag of a directory
+const TYPE_DIR: u8 = b'5';
+/// Directory holding objects not reachable from the root directory, named by ID
+const LOOSE_DIR: &str = ".objects/";
+
+/// Writes an octal number into a NUL-terminated header field
+#[cfg(feature = "unstable")]
+fn put_octal(field: &mut [u8], v: u64) -> AMResult<()> {
+    let s = format!("{:0width$o}", v, width = field.len() - 1);
+    assert_or_err!(s.len() < field.len(), AMError::TODO(0));
+    field[..s.len()].copy_from_slice(s.as_bytes());
+    field[s.len()] = 0;
+    Ok(())
+}
+
+/// Reads an octal header field, which may be padded with spaces or NULs
+#[cfg(feature = "unstable")]
+fn get_octal(field: &[u8]) -> AMResult<u64> {
+    let s = String::from_utf8_lossy(field);
+    let s = s.trim_matches(|c| c == ' ' || c == '\0');
+    if s.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(s, 8).or(Err(AMError::TODO(0).into()))
+}
+
+/// Sums a header with its checksum field counted as spaces
+#[cfg(feature = "unstable")]
+fn header_sum(hdr: &[u8; TAR_BLOCK]) -> u64 {
+    hdr.iter()
+        .enumerate()
+        .map(|(i, b)| {
+            if (148..156).contains(&i) {
+                32
+            } else {
+                u64::from(*b)
+            }
+        })
+        .sum()
+}
+
+/// Writes a ustar header. Names too long for the name field are split into the prefix field.
+#[cfg(feature = "unstable")]
+fn write_header<W: Write>(out: &mut W, name: &str, size: u64, kind: u8) -> AMResult<()> {
+    let mut hdr = [0u8; TAR_BLOCK];
+    let (prefix, name) = if name.len() <= 100 {
+        ("", name)
+    } else {
+        let split = name[..std::cmp::min(name.len(), 156)]
+            .rfind('/')
+            .ok_or(AMError::TODO(0))?;
+        (&name[..split], &name[split + 1..])
+    };
+    assert_or_err!(name.len() <= 100 && prefix.len() <= 155, AMError::TODO(0));
+    hdr[..name.len()].copy_from_slice(name.as_bytes());
+    put_octal(
+        &mut hdr[100..108],
+        if kind == TYPE_DIR { 0o755 } else { 0o644 },
+    )?;
+    put_octal(&mut hdr[108..116], 0)?;
+    put_octal(&mut hdr[116..124], 0)?;
+    put_octal(&mut hdr[124..136], size)?;
+    put_octal(&mut hdr[136..148], 0)?;
+    hdr[156] = kind;
+    hdr[257..263].copy_from_slice(b"ustar\0");
+    hdr[263..265].copy_from_slice(b"00");
+    hdr[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    let sum = header_sum(&hdr);
+    put_octal(&mut hdr[148..155], sum)?;
+    hdr[155] = b' ';
+    out.write_all(&hdr)?;
+    Ok(())
+}
+
+/// Number of padding bytes after `size` bytes of contents
+#[cfg(feature = "unstable")]
+fn padding(size: u64) -> AMResult<usize> {
+    Ok((TAR_BLOCK - usize::try_from(size % u64::try_from(TAR_BLOCK)?)?) % TAR_BLOCK)
+}
+
+/// Writes an object as a regular file entry
+#[cfg(feature = "unstable")]
+fn pack_object<W: Write>(fs: &FSHandle, out: &mut W, id: u64, name: &str) -> AMResult<()> {
+    let size = fs.size_object(id)?;
+    write_header(out, name, size, TYPE_FILE)?;
+    let mut buf = vec![0; 16 * BLOCK_SIZE];
+    let mut pos = 0;
+    while pos < size {
+        let n = fs.read_object(id, pos, &mut buf)?;
+        assert_or_err!(n != 0, AMError::TODO(0));
+        out.write_all(&buf[..usize::try_from(n)?])?;
+        pos += n;
+    }
+    out.write_all(&vec![0; padding(size)?])?;
+    Ok(())
+}
+
+/// Writes the entries of a directory object under a path prefix, recording the IDs visited
+#[cfg(feature = "unstable")]
+fn pack_dir<W: Write>(
+    fs: &FSHandle,
+    out: &mut W,
+    dir: u64,
+    prefix: &str,
+    seen: &mut BTreeSet<u64>,
+) -> AMResult<u64> {
+    let mut count = 0;
+    for entry in read_dir(fs, dir)? {
+        if !seen.insert(entry.id) {
+            warn!(
+                "Skipping {}{}: object {} already packed",
+                prefix, entry.name, entry.id
+            );
+            continue;
+        }
+        let path = format!("{}{}", prefix, entry.name);
+        if entry.dir {
+            write_header(out, &format!("{}/", path), 0, TYPE_DIR)?;
+            count += 1 + pack_dir(fs, out, entry.id, &format!("{}/", path), seen)?;
+        } else {
+            pack_object(fs, out, entry.id, &path)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Writes the directory tree of a filesystem as a tar archive.
+/// Objects not reachable from the root directory are stored under `.objects/`, named by ID.
+/// Returns the number of entries written.
+#[cfg(feature = "unstable")]
+pub fn pack_tar<W: Write>(fs: &FSHandle, out: &mut W) -> AMResult<u64> {
+    let mut seen = BTreeSet::new();
+    let mut count = 0;
+    if fs.size_object(ROOT_DIR).is_ok() {
+        seen.insert(ROOT_DIR);
+        count += pack_dir(fs, out, ROOT_DIR, "", &mut seen)?;
+    }
+    let ids: Vec<u64> = fs
+        .read()?
+        .get_objects()?
+        .get_objects()?
+        .into_keys()
+        .collect();
+    for id in ids {
+        if id >= META_OBJECTS && !seen.contains(&id) {
+            pack_object(fs, out, id, &format!("{}{}", LOOSE_DIR, id))?;
+            count += 1;
+        }
+    }
+    out.write_all(&[0; 2 * TAR_BLOCK])?;
+    Ok(count)
+}
+
+/// Finds or creates the directory object for a path, creating its parents as needed
+#[cfg(feature = "unstable")]
+fn ensure_dir(
+    fs: &FSHandle,
+    path: &str,
+    dirs: &mut BTreeMap<String, u64>,
+    next: &mut u64,
+) -> AMResult<u64> {
+    if let Some(id) = dirs.get(path) {
+        return Ok(*id);
+    }
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let parent = ensure_dir(fs, parent, dirs, next)?;
+    let existing = if fs.size_object(parent).is_ok() {
+        read_dir(fs, parent)?.into_iter().find(|e| e.name == name)
+    } else {
+        None
+    };
+    let id = match existing {
+        Some(entry) => {
+            assert_or_err!(entry.dir, AMError::TODO(0));
+            entry.id
+        }
+        None => {
+            let id = *next;
+            *next += 1;
+            fs.create_object(id, 0)?;
+            add_entry(fs, parent, id, true, name)?;
+            id
+        }
+    };
+    dirs.insert(path.to_owned(), id);
+    Ok(id)
+}
+
+/// Copies a tar archive into a filesystem, then commits.
+/// Directories and regular files are added under the root directory, and entries under
+/// `.objects/` are restored by ID where that ID is free. Other entry types are skipped.
+/// Returns the number of entries copied.
+#[cfg(feature = "unstable")]
+pub fn unpack_tar<R: Read>(fs: &FSHandle, input: &mut R) -> AMResult<u64> {
+    let mut next = next_free_id(fs)?;
+    let mut dirs = BTreeMap::new();
+    dirs.insert(String::new(), ROOT_DIR);
+    if fs.size_object(ROOT_DIR).is_err() {
+        fs.create_object(ROOT_DIR, 0)?;
+    }
+    let mut count = 0;
+    loop {
+        let mut hdr = [0u8; TAR_BLOCK];
+        input.read_exact(&mut hdr)?;
+        if hdr.iter().all(|b| *b == 0) {
+            break;
+        }
+        assert_or_err!(
+            get_octal(&hdr[148..156])? == header_sum(&hdr),
+            AMErrorFS::Checksum
+        );
+        let field = |r: std::ops::Range<usize>| {
+            let f = &hdr[r];
+            String::from_utf8_lossy(&f[..f.iter().position(|b| *b == 0).unwrap_or(f.len())])
+                .into_owned()
+        };
+        let (name, prefix) = (field(0..100), field(345..500));
+        let path = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let path = path.trim_start_matches("./").trim_matches('/').to_owned();
+        let size = get_octal(&hdr[124..136])?;
+        match hdr[156] {
+            TYPE_DIR => {
+                ensure_dir(fs, &path, &mut dirs, &mut next)?;
+                count += 1;
+            }
+            TYPE_FILE | 0 => {
+                let loose = path
+                    .strip_prefix(LOOSE_DIR)
+                    .and_then(|id| id.parse::<u64>().ok())
+                    .filter(|id| *id >= META_OBJECTS && fs.size_object(*id).is_err());
+                let id = match loose {
+                    Some(id) => {
+                        next = std::cmp::max(next, id + 1);
+                        id
+                    }
+                    None => {
+                        let (parent, name) = path.rsplit_once('/').unwrap_or(("", &path));
+                        assert_or_err!(!name.is_empty(), AMErrorFS::NoObject);
+                        let parent = ensure_dir(fs, parent, &mut dirs, &mut next)?;
+                        let id = next;
+                        next += 1;
+                        add_entry(fs, parent, id, false, name)?;
+                        id
+                    }
+                };
+                fs.create_object(id, 0)?;
+                let mut buf = vec![0; 16 * BLOCK_SIZE];
+                let mut pos = 0;
+                while pos < size {
+                    let len = std::cmp::min(u64::try_from(buf.len())?, size - pos);
+                    let chunk = &mut buf[..usize::try_from(len)?];
+                    input.read_exact(chunk)?;
+                    fs.write_object(id, pos, chunk)?;
+                    pos += len;
+                }
+                debug!("Unpacked {} as object {}", path, id);
+                count += 1;
+            }
+            kind => {
+                warn!(
+                    "Skipping {}: unsupported entry type {:?}",
+                    path,
+                    char::from(kind)
+                );
+                std::io::copy(&mut input.by_ref().take(size), &mut std::io::sink())?;
+            }
+        }
+        input.read_exact(&mut vec![0; padding(size)?])?;
+    }
+    fs.commit()?;
+    Ok(count)
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_tar(d: crate::Disk, d2: crate::Disk) {
+    let src = crate::test::fsinit::mkfs_on(d).unwrap();
+    src.create_object(ROOT_DIR, 0).unwrap();
+    src.create_object(16, 0).unwrap();
+    add_entry(&src, ROOT_DIR, 16, true, "boot").unwrap();
+    src.create_object(17, 0).unwrap();
+    src.write_object(17, 0, &vec![7; 3 * BLOCK_SIZE + 5])
+        .unwrap();
+    add_entry(&src, 16, 17, false, "kernel").unwrap();
+    let long = "n".repeat(99);
+    src.create_object(18, 0).unwrap();
+    src.write_object(18, 0, b"hello").unwrap();
+    add_entry(&src, 16, 18, false, &long).unwrap();
+    src.create_object(40, 0).unwrap();
+    src.write_object(40, 0, &[1, 2, 3]).unwrap();
+    src.commit().unwrap();
+
+    let mut archive = Vec::new();
+    assert_eq!(pack_tar(&src, &mut archive).unwrap(), 4);
+    assert_eq!(archive.len() % TAR_BLOCK, 0);
+
+    let dst = crate::test::fsinit::mkfs_on(d2).unwrap();
+    assert_eq!(unpack_tar(&dst, &mut archive.as_slice()).unwrap(), 4);
+    let root = read_dir(&dst, ROOT_DIR).unwrap();
+    assert_eq!(root.len(), 1);
+    assert!(root[0].dir && root[0].name == "boot");
+    let boot = read_dir(&dst, root[0].id).unwrap();
+    let names: Vec<&str> = boot.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, ["kernel", long.as_str()]);
+    assert_eq!(
+        dst.size_object(boot[0].id).unwrap(),
+        3 * u64::try_from(BLOCK_SIZE).unwrap() + 5
+    );
+    let mut buf = [0u8; 5];
+    dst.read_object(boot[1].id, 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+    let mut buf = [0u8; 3];
+    dst.read_object(40, 0, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3]);
+
+    archive[0] ^= 1;
+    assert!(unpack_tar(&dst, &mut archive.as_slice()).is_err());
+}
@@ -0,0 +1,89 @@
+use std::convert::TryFrom;
+
+use amos_std::{
+    error::{AMError, AMErrorFS},
+    AMResult,
+};
+
+use crate::{AMPointerLocal, Disk, Superblock, FORMAT_VERSION};
+
+/// Migrates a superblock, and whatever it leads to, from one format version to the next.
+/// Runs once per superblock copy, so changes to anything else must be idempotent.
+type Migration = fn(Disk, &mut Superblock) -> AMResult<()>;
+
+/// Migrations, indexed by the version they upgrade from
+const MIGRATIONS: &[Migration] = &[upgrade_v0];
+
+/// Version 0 disks predate the version field, but are otherwise laid out as version 1
+#[cfg(feature = "unstable")]
+fn upgrade_v0(_d: Disk, _sb: &mut Superblock) -> AMResult<()> {
+    Ok(())
+}
+
+/// Upgrades an unmounted disk to the current format version. Returns the version it was at.
+/// Each superblock copy is written after every step, so an interrupted upgrade can be rerun.
+#[cfg(feature = "unstable")]
+pub fn upgrade(mut d: Disk) -> AMResult<u64> {
+    let mut sbs: Vec<(AMPointerLocal, Superblock)> = d
+        .get_header_locs()?
+        .iter()
+        .filter_map(|loc| Some((*loc, Superblock::read(d.clone(), *loc).ok()?)))
+        .collect();
+    let from = sbs
+        .iter()
+        .map(|(_, sb)| sb.version())
+        .min()
+        .ok_or(AMErrorFS::NoSuperblock)?;
+    assert_or_err!(
+        sbs.iter().all(|(_, sb)| sb.version() <= FORMAT_VERSION),
+        AMError::TODO(0)
+    );
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(usize::try_from(from)?) {
+        let version = u64::try_from(version)?;
+        info!("Upgrading from format version {}", version);
+        for (loc, sb) in &mut sbs {
+            if sb.version() == version {
+                migration(d.clone(), sb)?;
+                sb.set_version(version + 1);
+                sb.write(d.clone(), *loc)?;
+            }
+        }
+    }
+    d.sync()?;
+    Ok(from)
+}
+
+#[test_fs(mem, file, blocks = 100)]
+#[allow(clippy::unwrap_used)]
+pub fn test_upgrade(d: crate::Disk) {
+    use crate::FSHandle;
+
+    assert_eq!(u64::try_from(MIGRATIONS.len()).unwrap(), FORMAT_VERSION);
+
+    let fs = crate::test::fsinit::mkfs_on(d.clone()).unwrap();
+    fs.create_object(1, 0).unwrap();
+    fs.write_object(1, 0, &[1, 2, 3, 4]).unwrap();
+    fs.commit().unwrap();
+
+    assert_eq!(upgrade(d.clone()).unwrap(), FORMAT_VERSION);
+
+    let set_version = |version| {
+        for loc in d.get_header_locs().unwrap() {
+            let mut sb = Superblock::read(d.clone(), loc).unwrap();
+            sb.set_version(version);
+            sb.write(d.clone(), loc).unwrap();
+        }
+    };
+    set_version(0);
+    assert!(FSHandle::open(&[d.clone()]).is_err());
+    assert_eq!(upgrade(d.clone()).unwrap(), 0);
+    let fs2 = FSHandle::open(&[d.clone()]).unwrap();
+    let mut buf = [0u8; 4];
+    fs2.read_object(1, 0, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4]);
+    drop(fs2);
+
+    set_version(FORMAT_VERSION + 1);
+    assert!(FSHandle::open(&[d.clone()]).is_err());
+    assert!(upgrade(d).is_err());
+}
@@ -0,0 +1,19 @@
+use std::convert::TryInto;
+
+use rand::{prelude::StdRng, Rng, SeedableRng};
+
+use crate::{test::fsinit::CleanOnDrop, Disk, DiskFile, DiskMem, BLOCK_SIZE};
+
+pub fn mem(blocks: usize) -> CleanOnDrop<Disk> {
+    CleanOnDrop::new(DiskMem::open(blocks), String::new())
+}
+
+pub fn file(blocks: usize) -> CleanOnDrop<Disk> {
+    let id: usize = StdRng::from_entropy().gen();
+    let name = format!("{}.img", id);
+    let f = std::fs::File::create(&name).unwrap();
+    f.set_len((blocks * BLOCK_SIZE).try_into().unwrap())
+        .unwrap();
+    drop(f);
+    CleanOnDrop::new(DiskFile::open(&name).unwrap(), name)
+}
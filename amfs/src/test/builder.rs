@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+
+use amos_std::{error::AMError, AMResult};
+
+use crate::{
+    AMPointerLocal, Allocator, Disk, DiskGroup, DiskMem, FSGroup, FSHandle, Geometry, ObjectSet,
+    Superblock,
+};
+
+/// Composes test images a structure at a time, in the order mkfs writes them.
+/// Each stage needs the ones before it: objects need an allocator, which needs an FSGroup,
+/// which needs a geometry. Stages left out leave their pointers null.
+#[derive(Debug, Clone)]
+pub struct ImageBuilder {
+    blocks:    usize,
+    devid:     u64,
+    geometry:  Option<Vec<u64>>,
+    fsgroup:   bool,
+    allocator: bool,
+    objects:   Option<Vec<(u64, Vec<u8>)>>,
+}
+
+impl ImageBuilder {
+    /// Starts an image of the given size holding only superblocks
+    pub fn new(blocks: usize) -> Self {
+        ImageBuilder {
+            blocks,
+            devid: 0x0807060504030201,
+            geometry: None,
+            fsgroup: false,
+            allocator: false,
+            objects: None,
+        }
+    }
+
+    /// Sets the device ID written to the superblocks
+    pub fn devid(mut self, devid: u64) -> Self {
+        self.devid = devid;
+        self
+    }
+
+    /// Adds a geometry listing this device
+    pub fn geometry(self) -> Self {
+        let devid = self.devid;
+        self.geometry_ids(&[devid])
+    }
+
+    /// Adds a geometry listing the given device IDs, which need not include this device's
+    pub fn geometry_ids(mut self, ids: &[u64]) -> Self {
+        self.geometry = Some(ids.to_vec());
+        self
+    }
+
+    /// Adds an empty FSGroup as the root node in slot 0
+    pub fn fsgroup(mut self) -> Self {
+        self.fsgroup = true;
+        self
+    }
+
+    /// Adds the allocator list and this device's allocator
+    pub fn allocator(mut self) -> Self {
+        self.allocator = true;
+        self
+    }
+
+    /// Adds an empty object set
+    pub fn objects(mut self) -> Self {
+        self.objects.get_or_insert_with(Vec::new);
+        self
+    }
+
+    /// Adds an object with the given contents. Objects are written through a mount once the
+    /// rest of the image is built, so they land in a second root.
+    pub fn object(mut self, id: u64, data: &[u8]) -> Self {
+        self.objects
+            .get_or_insert_with(Vec::new)
+            .push((id, data.to_vec()));
+        self
+    }
+
+    /// Writes the image to a new in-memory disk
+    pub fn build_mem(&self) -> AMResult<Disk> {
+        let d = DiskMem::open(self.blocks);
+        self.build(d.clone())?;
+        Ok(d)
+    }
+
+    /// Writes the image to a disk, which must hold at least as many blocks as asked for
+    pub fn build(&self, mut d: Disk) -> AMResult<()> {
+        assert_or_err!(d.size()? >= self.blocks as u64, AMError::TODO(0));
+        assert_or_err!(!self.fsgroup || self.geometry.is_some(), AMError::TODO(0));
+        assert_or_err!(!self.allocator || self.fsgroup, AMError::TODO(0));
+        assert_or_err!(self.objects.is_none() || self.allocator, AMError::TODO(0));
+        let header_locs = d.get_header_locs()?;
+        let mut free = Allocator::new(d.size()?);
+        for loc in header_locs {
+            free.mark_used(loc.loc(), 1)?;
+        }
+        let mut sb = Superblock::new(self.devid);
+
+        if let Some(ids) = &self.geometry {
+            let mut geom = Geometry::new();
+            geom.device_ids = ids.clone();
+            let mut geo_ptrs = Vec::new();
+            for _ in 0..geom.table_blocks() {
+                geo_ptrs.push(AMPointerLocal::new(free.alloc_blocks(1)?));
+            }
+            sb.geometries[0] = geom.write_chain(d.clone(), &geo_ptrs)?;
+
+            if self.fsgroup {
+                let mut dg = DiskGroup::single(geom, d.clone(), free.clone());
+                let mut root_group = FSGroup::new();
+                if self.allocator && self.objects.is_some() {
+                    let mut dgs = vec![None; 16];
+                    dgs[0] = Some(dg.clone());
+                    root_group.objects = ObjectSet::create(dgs, dg.alloc_blocks(1, None)?)?.ptr;
+                }
+                let mut root_ptr = dg.alloc_blocks(1, None)?;
+                if self.allocator {
+                    let mut alloc_map = BTreeMap::new();
+                    alloc_map.insert(self.devid, free);
+                    root_group.write_allocators(&mut [Some(dg.clone())], 0, &mut alloc_map)?;
+                }
+                root_group.write(&[Some(dg)], &mut root_ptr)?;
+                sb.rootnodes[0] = root_ptr;
+            }
+        }
+        for loc in header_locs {
+            sb.write(d.clone(), loc)?;
+        }
+        d.sync()?;
+
+        if let Some(objects) = self.objects.as_ref().filter(|o| !o.is_empty()) {
+            let fs = FSHandle::open(&[d])?;
+            for (id, data) in objects {
+                fs.create_object(*id, 0)?;
+                fs.write_object(*id, 0, data)?;
+            }
+            fs.commit()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+pub fn test_builder() {
+    crate::test::logging::init_log();
+
+    let d = ImageBuilder::new(100).build_mem().unwrap();
+    let loc = d.get_header_locs().unwrap()[0];
+    let sb = Superblock::read(d.clone(), loc).unwrap();
+    assert_eq!(sb.devid(), 0x0807060504030201);
+    assert!(sb.geometries(0).is_null());
+
+    let d = ImageBuilder::new(100)
+        .geometry_ids(&[1])
+        .build_mem()
+        .unwrap();
+    let sb = Superblock::read(d.clone(), loc).unwrap();
+    let geo = sb.get_geometry(d.clone(), 0).unwrap();
+    assert!(DiskGroup::from_geo(geo, &[sb.devid()], &[d]).is_err());
+
+    let d = ImageBuilder::new(100)
+        .geometry()
+        .fsgroup()
+        .build_mem()
+        .unwrap();
+    let sb = Superblock::read(d.clone(), loc).unwrap();
+    let geo = sb.get_geometry(d.clone(), 0).unwrap();
+    let dg = DiskGroup::from_geo(geo, &[sb.devid()], &[d]).unwrap();
+    let group = sb.get_group(&[Some(dg)]).unwrap();
+    assert!(group.alloc().is_null() && group.objects.is_null());
+
+    assert!(ImageBuilder::new(100).fsgroup().build_mem().is_err());
+
+    let d = ImageBuilder::new(1000)
+        .geometry()
+        .fsgroup()
+        .allocator()
+        .object(100, b"hello")
+        .build_mem()
+        .unwrap();
+    let fs = FSHandle::open(&[d]).unwrap();
+    let mut buf = [0u8; 5];
+    fs.read_object(100, 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
@@ -0,0 +1,156 @@
+use std::convert::TryFrom;
+
+use amos_std::{error::AMErrorFS, AMResult};
+use crc32fast::Hasher;
+use rand::{prelude::StdRng, Rng, SeedableRng};
+
+use crate::{
+    operations::{classify, BlockType},
+    u8_slice_as_any, AMPointerGlobal, Disk, FSGroup, Superblock, BLOCK_SIZE,
+};
+
+/// Reads a block, lets `f` change it, then writes it back
+pub fn modify_block(d: &Disk, blk: u64, f: impl FnOnce(&mut [u8])) -> AMResult<()> {
+    let mut d = d.clone();
+    let mut buf = [0; BLOCK_SIZE];
+    d.read_at(blk, &mut buf)?;
+    f(&mut buf);
+    d.write_at(blk, &buf)?;
+    Ok(())
+}
+
+/// Inverts one byte of a block
+pub fn flip_byte(d: &Disk, blk: u64, offset: usize) -> AMResult<()> {
+    modify_block(d, blk, |buf| buf[offset] ^= 0xFF)
+}
+
+/// Zeroes a whole block
+pub fn zero_block(d: &Disk, blk: u64) -> AMResult<()> {
+    modify_block(d, blk, |buf| buf.fill(0))
+}
+
+/// Inverts one byte of the superblock at the given header location
+pub fn flip_superblock_byte(d: &Disk, which: usize, offset: usize) -> AMResult<()> {
+    let loc = d.get_header_locs()?[which];
+    flip_byte(d, loc.loc(), offset)
+}
+
+/// Finds the FSGroup of a root slot, or of the latest root, using the first valid superblock
+pub fn find_fsgroup(d: &Disk, slot: Option<u8>) -> AMResult<(AMPointerGlobal, FSGroup)> {
+    let sb = d
+        .get_header_locs()?
+        .iter()
+        .find_map(|loc| Superblock::read(d.clone(), *loc).ok())
+        .ok_or(AMErrorFS::NoSuperblock)?;
+    let ptr = sb.rootnodes(usize::from(slot.unwrap_or_else(|| sb.latest_root())));
+    if ptr.is_null() {
+        return Err(AMErrorFS::NullPointer.into());
+    }
+    let mut dg = crate::test::dg::load_dg_disk_single(d.clone());
+    dg.geo.device_ids = vec![sb.devid()];
+    let dgs = [Some(dg)];
+    Ok((ptr, FSGroup::read(&dgs, ptr)?))
+}
+
+/// Zeroes the FSGroup of a root slot, or of the latest root
+pub fn zero_fsgroup(d: &Disk, slot: Option<u8>) -> AMResult<()> {
+    let (ptr, _) = find_fsgroup(d, slot)?;
+    zero_block(d, ptr.loc())
+}
+
+/// Rewrites the FSGroup of a root slot with another transaction ID, keeping its checksum valid
+pub fn set_fsgroup_txid(d: &Disk, slot: u8, txid: u128) -> AMResult<()> {
+    let (mut ptr, mut group) = find_fsgroup(d, Some(slot))?;
+    group.set_txid(txid);
+    let locs = d.get_header_locs()?;
+    let mut dg = crate::test::dg::load_dg_disk_single(d.clone());
+    let sb = locs
+        .iter()
+        .find_map(|loc| Superblock::read(d.clone(), *loc).ok())
+        .ok_or(AMErrorFS::NoSuperblock)?;
+    dg.geo.device_ids = vec![sb.devid()];
+    group.write(&[Some(dg)], &mut ptr)?;
+    for loc in locs {
+        let mut sb = Superblock::read(d.clone(), loc)?;
+        sb.rootnodes[usize::from(slot)] = ptr;
+        sb.write(d.clone(), loc)?;
+    }
+    Ok(())
+}
+
+/// Cuts the journal chain of the latest root after its newest block, keeping that block's checksum
+/// valid. Returns false if there was no older block to cut off.
+pub fn truncate_journal(d: &Disk) -> AMResult<bool> {
+    let (_, group) = find_fsgroup(d, None)?;
+    let head = group.journal();
+    if head.is_null() {
+        return Ok(false);
+    }
+    let mut cut = false;
+    modify_block(d, head.loc(), |buf| {
+        let prev = unsafe { u8_slice_as_any::<AMPointerGlobal>(&buf[..16]) };
+        if prev.is_null() {
+            return;
+        }
+        cut = true;
+        buf[..16].fill(0);
+        buf[24..28].fill(0);
+        let mut hasher = Hasher::new();
+        hasher.update(buf);
+        let sum = hasher.finalize();
+        buf[24..28].copy_from_slice(&sum.to_le_bytes());
+    })?;
+    Ok(cut)
+}
+
+/// Inverts `count` randomly chosen bytes of blocks in use, chosen the same way for the same seed.
+/// Returns the corrupted locations as (block, offset) pairs.
+pub fn corrupt_random(d: &Disk, seed: u64, count: usize) -> AMResult<Vec<(u64, usize)>> {
+    let used: Vec<u64> = classify(d)?
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| !matches!(t, BlockType::Unused))
+        .map(|(i, _)| u64::try_from(i))
+        .collect::<Result<_, _>>()?;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut res = Vec::new();
+    for _ in 0..count {
+        let blk = used[rng.gen_range(0..used.len())];
+        let offset = rng.gen_range(0..BLOCK_SIZE);
+        flip_byte(d, blk, offset)?;
+        res.push((blk, offset));
+    }
+    Ok(res)
+}
+
+#[test]
+#[allow(clippy::unwrap_used)]
+pub fn test_corrupt() {
+    crate::test::logging::init_log();
+
+    let fs = crate::test::fsinit::create_fs().unwrap();
+    fs.create_object(1, 4).unwrap();
+    fs.commit().unwrap();
+    let d = crate::DiskFile::open(fs.file()).unwrap();
+    let problems = |d: &Disk| crate::operations::check(d.clone()).unwrap().problems;
+    assert!(problems(&d).is_empty());
+
+    let sb_loc = d.get_header_locs().unwrap()[1].loc();
+    flip_superblock_byte(&d, 1, BLOCK_SIZE - 1).unwrap();
+    assert_eq!(
+        problems(&d),
+        vec![(sb_loc, "bad superblock checksum".to_owned())]
+    );
+    flip_superblock_byte(&d, 1, BLOCK_SIZE - 1).unwrap();
+    assert!(problems(&d).is_empty());
+
+    zero_fsgroup(&d, None).unwrap();
+    assert!(!problems(&d).is_empty());
+
+    let other = crate::DiskMem::open(1000);
+    crate::operations::mkfs_single(other.clone()).unwrap();
+    let a = corrupt_random(&other, 7, 3).unwrap();
+    let again = crate::DiskMem::open(1000);
+    crate::operations::mkfs_single(again.clone()).unwrap();
+    assert_eq!(corrupt_random(&again, 7, 3).unwrap(), a);
+}
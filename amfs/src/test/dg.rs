@@ -5,7 +5,7 @@ pub fn create_dg_mem_single(size: usize) -> DiskGroup {
 
     let mut geo = Geometry::new();
 
-    geo.device_ids[0] = 1;
+    geo.device_ids = vec![1];
     geo.flavor = GeometryFlavor::Single;
 
     let alloc = Allocator::new(size as u64);
@@ -18,7 +18,7 @@ pub fn create_dg_file_single(name: &str) -> DiskGroup {
 
     let mut geo = Geometry::new();
 
-    geo.device_ids[0] = 1;
+    geo.device_ids = vec![1];
     geo.flavor = GeometryFlavor::Single;
 
     let alloc = Allocator::new(d.size().unwrap() as u64);
@@ -29,7 +29,7 @@ pub fn create_dg_file_single(name: &str) -> DiskGroup {
 pub fn load_dg_disk_single(d: Disk) -> DiskGroup {
     let mut geo = Geometry::new();
 
-    geo.device_ids[0] = 1;
+    geo.device_ids = vec![1];
     geo.flavor = GeometryFlavor::Single;
 
     let alloc = Allocator::new(d.size().unwrap() as u64);
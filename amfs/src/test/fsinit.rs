@@ -1,7 +1,9 @@
+use std::convert::TryInto;
+
 use amos_std::{error::AMError, AMResult};
 use rand::{prelude::StdRng, Rng, SeedableRng};
 
-use crate::{operations::mkfs_single, DiskFile, FSHandle};
+use crate::{operations::mkfs_single, Disk, DiskFile, FSHandle, BLOCK_SIZE};
 
 pub struct CleanOnDrop<T> {
     contents: T,
@@ -25,6 +27,16 @@ impl<T> CleanOnDrop<AMResult<T>> {
     }
 }
 
+impl<T> CleanOnDrop<T> {
+    pub fn new(contents: T, file: String) -> Self {
+        CleanOnDrop { contents, file }
+    }
+
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+}
+
 impl<T> std::ops::Deref for CleanOnDrop<T> {
     type Target = T;
 
@@ -42,6 +54,25 @@ impl<T> Drop for CleanOnDrop<T> {
 pub fn create_fs() -> CleanOnDrop<AMResult<FSHandle>> {
     let id: usize = StdRng::from_entropy().gen();
     let d = DiskFile::open(&format!("{}.img", id)).unwrap();
+    create_fs_on(id, d)
+}
+
+pub fn create_fs_sized(blocks: usize) -> CleanOnDrop<AMResult<FSHandle>> {
+    let id: usize = StdRng::from_entropy().gen();
+    let f = std::fs::File::create(format!("{}.img", id)).unwrap();
+    f.set_len((blocks * BLOCK_SIZE).try_into().unwrap())
+        .unwrap();
+    drop(f);
+    let d = DiskFile::open(&format!("{}.img", id)).unwrap();
+    create_fs_on(id, d)
+}
+
+pub fn mkfs_on(d: Disk) -> AMResult<FSHandle> {
+    mkfs_single(d.clone())?;
+    FSHandle::open(&[d])
+}
+
+fn create_fs_on(id: usize, d: Disk) -> CleanOnDrop<AMResult<FSHandle>> {
     mkfs_single(d.clone()).unwrap();
     drop(d);
 
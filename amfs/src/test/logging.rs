@@ -9,24 +9,7 @@ lazy_static! {
 pub fn init_log() {
     let mut lock = MUTEX.lock().unwrap();
     if *lock == 0 {
-        use log::LevelFilter;
-        use log4rs::{
-            append::console::ConsoleAppender,
-            config::{Appender, Config, Root},
-            encode::pattern::PatternEncoder,
-        };
-
-        let encoder = PatternEncoder::new("{h({l:>5})} {t:.<25} - {m}{n}");
-
-        let stdout = ConsoleAppender::builder()
-            .encoder(Box::new(encoder))
-            .build();
-        let config = Config::builder()
-            .appender(Appender::builder().build("stdout", Box::new(stdout)))
-            .build(Root::builder().appender("stdout").build(LevelFilter::Debug))
-            .unwrap();
-
-        log4rs::init_config(config).unwrap();
+        crate::logging::init_console(log::LevelFilter::Debug).unwrap();
         *lock = 1;
     }
 }
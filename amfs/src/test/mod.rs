@@ -4,6 +4,9 @@
 #![allow(unknown_lints)]
 #![allow(require_stability_comment)]
 
+pub mod backend;
+pub mod builder;
+pub mod corrupt;
 pub mod dg;
 pub mod fsinit;
 